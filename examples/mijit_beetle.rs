@@ -0,0 +1,6 @@
+//! Builds the C API in [`mijit::beetle::capi`] as a shared and a static
+//! library that C can link against. Run e.g.
+//! `cargo build --release --features capi --example mijit_beetle`
+//! and find the libraries in "target/release/examples/".
+
+pub use mijit::beetle::capi::*;
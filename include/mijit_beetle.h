@@ -0,0 +1,133 @@
+/* A C API for the Beetle virtual machine implemented using Mijit.
+ *
+ * Build the "mijit_beetle" example with the "capi" feature to get a shared
+ * and a static library that define these functions:
+ *
+ *     cargo build --release --features capi --example mijit_beetle
+ *
+ * Ownership: `mijit_beetle_new()` returns a VM owned by the caller, which
+ * must eventually pass it to `mijit_beetle_free()` exactly once. The VM owns
+ * its memory; `mijit_beetle_load_object()` copies into it.
+ *
+ * Thread-safety: a VM may be moved between threads, but must not be passed
+ * to two functions concurrently. Different VMs are independent.
+ *
+ * Errors: no function aborts or unwinds into C. Functions that return `int`
+ * return `MIJIT_BEETLE_OK` or a negative error code.
+ */
+
+#ifndef MIJIT_BEETLE_H
+#define MIJIT_BEETLE_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Success. */
+#define MIJIT_BEETLE_OK (0)
+/* An argument was invalid, e.g. an address was out of range. */
+#define MIJIT_BEETLE_ERROR_ARGUMENT (-1)
+/* Mijit panicked. The VM may be in an inconsistent state. */
+#define MIJIT_BEETLE_ERROR_PANIC (-2)
+
+/* `mijit_beetle_run()` executed `HALT`. */
+#define MIJIT_BEETLE_HALT (0)
+/* `mijit_beetle_run()` reached an instruction that is not implemented. */
+#define MIJIT_BEETLE_NOT_IMPLEMENTED (1)
+/* `mijit_beetle_run()` stopped because a push would overflow a stack. The
+ * stack pointer is unchanged, but the instruction may have been partly
+ * executed. */
+#define MIJIT_BEETLE_STACK_OVERFLOW (2)
+/* `mijit_beetle_run()` stopped because an exception could not be passed to
+ * the handler, because `SP`, `'THROW` or the handler address was invalid. */
+#define MIJIT_BEETLE_BAD_THROW (3)
+
+/* Register numbers for `mijit_beetle_get()` and `mijit_beetle_set()`. */
+#define MIJIT_BEETLE_EP (0)
+#define MIJIT_BEETLE_I (1)
+#define MIJIT_BEETLE_A (2)
+#define MIJIT_BEETLE_SP (3)
+#define MIJIT_BEETLE_RP (4)
+#define MIJIT_BEETLE_NEXT_COUNT (5)
+#define MIJIT_BEETLE_NEXT_PERIOD (6)
+#define MIJIT_BEETLE_NEXT_HOOK (7)
+#define MIJIT_BEETLE_BAD (8)
+#define MIJIT_BEETLE_THROW (9)
+#define MIJIT_BEETLE_MEMORY (10)
+#define MIJIT_BEETLE_NOT_ADDRESS (11)
+#define MIJIT_BEETLE_S_LIMIT (12)
+#define MIJIT_BEETLE_R_LIMIT (13)
+#define MIJIT_BEETLE_S0 (14)
+#define MIJIT_BEETLE_R0 (15)
+
+/* An opaque Beetle virtual machine, including its memory. */
+typedef struct mijit_beetle_vm mijit_beetle_vm;
+
+/* The result of `mijit_beetle_run()`. */
+typedef struct {
+    /* `MIJIT_BEETLE_HALT`, `MIJIT_BEETLE_NOT_IMPLEMENTED`,
+     * `MIJIT_BEETLE_STACK_OVERFLOW`, `MIJIT_BEETLE_BAD_THROW`, or an error
+     * code. */
+    int32_t kind;
+    /* For `MIJIT_BEETLE_HALT`, the halt code. For
+     * `MIJIT_BEETLE_NOT_IMPLEMENTED`, the `A` register, whose low byte is the
+     * opcode. */
+    uint32_t code;
+    /* The `BAD` register, i.e. the value of `EP` when the most recent
+     * exception was raised. */
+    uint32_t bad;
+    /* The `-ADDRESS` register, i.e. the address that caused the most recent
+     * invalid address exception. */
+    uint32_t not_address;
+} mijit_beetle_outcome;
+
+/* Constructs a VM with `memory_cells` cells of memory. The return stack
+ * occupies the last `return_cells` cells, the data stack the `data_cells`
+ * cells before that, and a `HALT` instruction the cell before that. Returns
+ * NULL on failure. */
+mijit_beetle_vm *mijit_beetle_new(uint32_t memory_cells, uint32_t data_cells, uint32_t return_cells);
+
+/* Frees `vm`. Does nothing if `vm` is NULL. */
+void mijit_beetle_free(mijit_beetle_vm *vm);
+
+/* Copies `len` cells from `object` into the memory of `vm`, starting at
+ * address `addr`, which must be cell-aligned. */
+int mijit_beetle_load_object(mijit_beetle_vm *vm, const uint32_t *object, uint32_t len, uint32_t addr);
+
+/* Runs `vm` starting at address `ep` until it executes `HALT`, reaches an
+ * instruction that is not implemented, overflows a stack, or cannot pass an
+ * exception to the handler. On `HALT`, the halt code is popped from the data
+ * stack. `LIB` routines 0 (milliseconds), 1 (time and date) and 2 (sleep)
+ * are performed without returning. Memory accesses are bounds checked. */
+mijit_beetle_outcome mijit_beetle_run(mijit_beetle_vm *vm, uint32_t ep);
+
+/* Reads register `id` of `vm` into `*value`. */
+int mijit_beetle_get(mijit_beetle_vm *vm, uint32_t id, uint32_t *value);
+
+/* Sets register `id` of `vm` to `value`. `MIJIT_BEETLE_MEMORY` must be a
+ * multiple of 4 and must not exceed the size of the memory. */
+int mijit_beetle_set(mijit_beetle_vm *vm, uint32_t id, uint32_t value);
+
+/* Pushes `item` onto the data stack of `vm`. Fails if `SP` would go below
+ * `MIJIT_BEETLE_S_LIMIT`. */
+int mijit_beetle_push(mijit_beetle_vm *vm, uint32_t item);
+
+/* Pops an item from the data stack of `vm` into `*item`. Fails if `SP` would
+ * go above `MIJIT_BEETLE_S0`. */
+int mijit_beetle_pop(mijit_beetle_vm *vm, uint32_t *item);
+
+/* Pushes `item` onto the return stack of `vm`. Fails if `RP` would go below
+ * `MIJIT_BEETLE_R_LIMIT`. */
+int mijit_beetle_rpush(mijit_beetle_vm *vm, uint32_t item);
+
+/* Pops an item from the return stack of `vm` into `*item`. Fails if `RP`
+ * would go above `MIJIT_BEETLE_R0`. */
+int mijit_beetle_rpop(mijit_beetle_vm *vm, uint32_t *item);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MIJIT_BEETLE_H */
@@ -0,0 +1,458 @@
+//! A C API for [`Beetle`], enabled by the "capi" feature.
+//!
+//! The declarations are in "include/mijit_beetle.h", which documents the
+//! ownership and thread-safety rules. The "mijit_beetle" example builds a
+//! library that C can link against, e.g.
+//! `cargo build --release --features capi --example mijit_beetle`.
+//!
+//! The functions operate on a [`VM`], and check their arguments so that it
+//! does not panic.
+//!
+//! No panic crosses the API boundary. A function that would panic returns an
+//! error code instead.
+//!
+//! [`Beetle`]: super::Beetle
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use super::vm::{VM, BeetleExit};
+use super::{Registers, CELL};
+
+/// Success.
+pub const MIJIT_BEETLE_OK: i32 = 0;
+/// An argument was invalid, e.g. an address was out of range.
+pub const MIJIT_BEETLE_ERROR_ARGUMENT: i32 = -1;
+/// Mijit panicked. The VM may be in an inconsistent state.
+pub const MIJIT_BEETLE_ERROR_PANIC: i32 = -2;
+
+/// `mijit_beetle_run()` executed `HALT`.
+pub const MIJIT_BEETLE_HALT: i32 = 0;
+/// `mijit_beetle_run()` reached an instruction that is not implemented.
+pub const MIJIT_BEETLE_NOT_IMPLEMENTED: i32 = 1;
+/// `mijit_beetle_run()` stopped because a push would overflow a stack.
+pub const MIJIT_BEETLE_STACK_OVERFLOW: i32 = 2;
+/// `mijit_beetle_run()` stopped because an exception could not be passed to
+/// the handler.
+pub const MIJIT_BEETLE_BAD_THROW: i32 = 3;
+
+/// Register `EP`.
+pub const MIJIT_BEETLE_EP: u32 = 0;
+/// Register `I`.
+pub const MIJIT_BEETLE_I: u32 = 1;
+/// Register `A`.
+pub const MIJIT_BEETLE_A: u32 = 2;
+/// Register `SP`, the data stack pointer.
+pub const MIJIT_BEETLE_SP: u32 = 3;
+/// Register `RP`, the return stack pointer.
+pub const MIJIT_BEETLE_RP: u32 = 4;
+/// Register [`Registers::next_count`].
+pub const MIJIT_BEETLE_NEXT_COUNT: u32 = 5;
+/// Register [`Registers::next_period`].
+pub const MIJIT_BEETLE_NEXT_PERIOD: u32 = 6;
+/// Register [`Registers::next_hook`].
+pub const MIJIT_BEETLE_NEXT_HOOK: u32 = 7;
+/// Register `BAD`.
+pub const MIJIT_BEETLE_BAD: u32 = 8;
+/// Register `'THROW`.
+pub const MIJIT_BEETLE_THROW: u32 = 9;
+/// Register `MEMORY`. See [`Registers::memory_size`].
+pub const MIJIT_BEETLE_MEMORY: u32 = 10;
+/// Register `-ADDRESS`.
+pub const MIJIT_BEETLE_NOT_ADDRESS: u32 = 11;
+/// Register [`Registers::s_limit`].
+pub const MIJIT_BEETLE_S_LIMIT: u32 = 12;
+/// Register [`Registers::r_limit`].
+pub const MIJIT_BEETLE_R_LIMIT: u32 = 13;
+/// Register `S0`. See [`Registers::s0`].
+pub const MIJIT_BEETLE_S0: u32 = 14;
+/// Register `R0`. See [`Registers::r0`].
+pub const MIJIT_BEETLE_R0: u32 = 15;
+
+/// The result of `mijit_beetle_run()`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// `MIJIT_BEETLE_HALT`, `MIJIT_BEETLE_NOT_IMPLEMENTED`,
+    /// `MIJIT_BEETLE_STACK_OVERFLOW`, `MIJIT_BEETLE_BAD_THROW`, or an error
+    /// code.
+    pub kind: i32,
+    /// For `MIJIT_BEETLE_HALT`, the halt code.
+    /// For `MIJIT_BEETLE_NOT_IMPLEMENTED`, the `A` register, whose low byte
+    /// is the opcode.
+    pub code: u32,
+    /// The `BAD` register, i.e. the value of `EP` when the most recent
+    /// exception was raised.
+    pub bad: u32,
+    /// The `-ADDRESS` register, i.e. the address that caused the most recent
+    /// invalid address exception.
+    pub not_address: u32,
+}
+
+/// Returns the index into the memory of `vm` of the cell at `addr`, if
+/// valid.
+fn index(vm: &VM, addr: u32) -> Option<usize> {
+    let index = (addr / CELL as u32) as usize;
+    if addr % CELL as u32 == 0 && index < vm.memory().len() { Some(index) } else { None }
+}
+
+fn register(vm: &mut VM, id: u32) -> Option<&mut u32> {
+    let r: &mut Registers = &mut vm.registers;
+    Some(match id {
+        MIJIT_BEETLE_EP => &mut r.ep,
+        MIJIT_BEETLE_I => &mut r.i,
+        MIJIT_BEETLE_A => &mut r.a,
+        MIJIT_BEETLE_SP => &mut r.sp,
+        MIJIT_BEETLE_RP => &mut r.rp,
+        MIJIT_BEETLE_NEXT_COUNT => &mut r.next_count,
+        MIJIT_BEETLE_NEXT_PERIOD => &mut r.next_period,
+        MIJIT_BEETLE_NEXT_HOOK => &mut r.next_hook,
+        MIJIT_BEETLE_BAD => &mut r.bad,
+        MIJIT_BEETLE_THROW => &mut r.throw,
+        MIJIT_BEETLE_MEMORY => &mut r.memory_size,
+        MIJIT_BEETLE_NOT_ADDRESS => &mut r.not_address,
+        MIJIT_BEETLE_S_LIMIT => &mut r.s_limit,
+        MIJIT_BEETLE_R_LIMIT => &mut r.r_limit,
+        MIJIT_BEETLE_S0 => &mut r.s0,
+        MIJIT_BEETLE_R0 => &mut r.r0,
+        _ => return None,
+    })
+}
+
+/// Decrements the stack pointer `sp` and stores `item` there, if that does
+/// not move it below `limit`.
+fn push(vm: &mut VM, sp: u32, limit: u32, item: u32) -> Option<()> {
+    let new_sp = register(vm, sp)?.checked_sub(CELL as u32)?;
+    if new_sp < *register(vm, limit)? { return None; }
+    index(vm, new_sp)?;
+    *register(vm, sp)? = new_sp;
+    vm.store(new_sp, item);
+    Some(())
+}
+
+/// Loads the item at stack pointer `sp` and increments it, if that does not
+/// move it above `base`.
+fn pop(vm: &mut VM, sp: u32, base: u32) -> Option<u32> {
+    let old_sp = *register(vm, sp)?;
+    if old_sp.checked_add(CELL as u32)? > *register(vm, base)? { return None; }
+    index(vm, old_sp)?;
+    *register(vm, sp)? = old_sp + CELL as u32;
+    Some(vm.load(old_sp))
+}
+
+/// Runs `callback` on `*vm`, catching panics. Returns `MIJIT_BEETLE_OK` if
+/// `callback` returns `Some`.
+unsafe fn with_vm(vm: *mut VM, callback: impl FnOnce(&mut VM) -> Option<()>) -> i32 {
+    let vm = match vm.as_mut() {
+        Some(vm) => vm,
+        None => return MIJIT_BEETLE_ERROR_ARGUMENT,
+    };
+    match catch_unwind(AssertUnwindSafe(|| callback(vm))) {
+        Ok(Some(())) => MIJIT_BEETLE_OK,
+        Ok(None) => MIJIT_BEETLE_ERROR_ARGUMENT,
+        Err(_) => MIJIT_BEETLE_ERROR_PANIC,
+    }
+}
+
+/// Constructs a VM with `memory_cells` cells of memory, laid out as by
+/// [`VM::new()`]. The return stack occupies the last `return_cells` cells,
+/// the data stack the `data_cells` cells before that, and a `HALT`
+/// instruction the cell before that. Returns null on failure.
+#[no_mangle]
+pub extern "C" fn mijit_beetle_new(memory_cells: u32, data_cells: u32, return_cells: u32) -> *mut VM {
+    let used_cells = data_cells.checked_add(return_cells).and_then(|cells| cells.checked_add(1));
+    if used_cells.map_or(true, |cells| cells > memory_cells) || memory_cells > u32::MAX / CELL as u32 {
+        return std::ptr::null_mut();
+    }
+    match catch_unwind(|| VM::new(memory_cells, data_cells, return_cells)) {
+        Ok(vm) => Box::into_raw(Box::new(vm)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Frees a VM constructed by `mijit_beetle_new()`. Does nothing if `vm` is
+/// null.
+///
+/// # Safety
+///
+/// `vm` must be null or a VM that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_beetle_free(vm: *mut VM) {
+    if !vm.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(vm))));
+    }
+}
+
+/// Copies `len` cells from `object` into the memory of `vm`, starting at
+/// address `addr`.
+///
+/// # Safety
+///
+/// `vm` must be a live VM. `object` must point to `len` readable cells.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_beetle_load_object(vm: *mut VM, object: *const u32, len: u32, addr: u32) -> i32 {
+    if object.is_null() && len > 0 { return MIJIT_BEETLE_ERROR_ARGUMENT; }
+    with_vm(vm, |vm| {
+        let start = index(vm, addr)?;
+        let end = start.checked_add(len as usize)?;
+        if end > vm.memory().len() { return None; }
+        if len > 0 {
+            let object = std::slice::from_raw_parts(object, len as usize);
+            for (i, &cell) in object.iter().enumerate() {
+                vm.store(addr + (i * CELL as usize) as u32, cell);
+            }
+        }
+        Some(())
+    })
+}
+
+/// Runs `vm` starting at address `ep` until it executes `HALT`, reaches an
+/// instruction that is not implemented, overflows a stack, or cannot pass an
+/// exception to the handler. On `HALT`, the halt code is popped from the
+/// data stack. See [`VM::run()`], which also services `LIB` calls.
+///
+/// Memory accesses are bounds checked.
+///
+/// # Safety
+///
+/// `vm` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_beetle_run(vm: *mut VM, ep: u32) -> Outcome {
+    let mut outcome = Outcome {kind: MIJIT_BEETLE_ERROR_ARGUMENT, code: 0, bad: 0, not_address: 0};
+    let status = with_vm(vm, |vm| {
+        index(vm, ep)?;
+        let exit = vm.run(ep);
+        outcome.bad = vm.bad;
+        outcome.not_address = vm.not_address;
+        (outcome.kind, outcome.code) = match exit {
+            BeetleExit::Halt(code) => (MIJIT_BEETLE_HALT, code),
+            BeetleExit::NotImplemented(_) => (MIJIT_BEETLE_NOT_IMPLEMENTED, vm.a),
+            BeetleExit::StackOverflow => (MIJIT_BEETLE_STACK_OVERFLOW, 0),
+            BeetleExit::BadThrow => (MIJIT_BEETLE_BAD_THROW, 0),
+        };
+        Some(())
+    });
+    if status != MIJIT_BEETLE_OK { outcome.kind = status; }
+    outcome
+}
+
+/// Reads register `id` of `vm` into `*value`. The registers are numbered:
+/// 0 `EP`, 1 `I`, 2 `A`, 3 `SP`, 4 `RP`, 5 `next_count`, 6 `next_period`,
+/// 7 `next_hook`, 8 `BAD`, 9 `'THROW`, 10 `MEMORY`, 11 `-ADDRESS`,
+/// 12 `s_limit`, 13 `r_limit`, 14 `S0`, 15 `R0`. See `MIJIT_BEETLE_EP` etc.
+///
+/// # Safety
+///
+/// `vm` must be a live VM. `value` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_beetle_get(vm: *mut VM, id: u32, value: *mut u32) -> i32 {
+    if value.is_null() { return MIJIT_BEETLE_ERROR_ARGUMENT; }
+    with_vm(vm, |vm| {
+        *value = *register(vm, id)?;
+        Some(())
+    })
+}
+
+/// Sets register `id` of `vm` to `value`. See `mijit_beetle_get()`.
+/// `MEMORY` must be a multiple of [`CELL`] and must not exceed the size of
+/// the memory.
+///
+/// # Safety
+///
+/// `vm` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_beetle_set(vm: *mut VM, id: u32, value: u32) -> i32 {
+    with_vm(vm, |vm| {
+        if id == MIJIT_BEETLE_MEMORY && (value % CELL as u32 != 0 || value as usize > vm.memory().len() * CELL as usize) {
+            // The memory accesses are only checked against `MEMORY`.
+            return None;
+        }
+        *register(vm, id)? = value;
+        Some(())
+    })
+}
+
+/// Pushes `item` onto the data stack of `vm`. Fails if the stack is full,
+/// i.e. if `SP` would go below `s_limit`.
+///
+/// # Safety
+///
+/// `vm` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_beetle_push(vm: *mut VM, item: u32) -> i32 {
+    with_vm(vm, |vm| push(vm, MIJIT_BEETLE_SP, MIJIT_BEETLE_S_LIMIT, item))
+}
+
+/// Pops an item from the data stack of `vm` into `*item`. Fails if the
+/// stack is empty, i.e. if `SP` would go above `S0`.
+///
+/// # Safety
+///
+/// `vm` must be a live VM. `item` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_beetle_pop(vm: *mut VM, item: *mut u32) -> i32 {
+    if item.is_null() { return MIJIT_BEETLE_ERROR_ARGUMENT; }
+    with_vm(vm, |vm| {
+        *item = pop(vm, MIJIT_BEETLE_SP, MIJIT_BEETLE_S0)?;
+        Some(())
+    })
+}
+
+/// Pushes `item` onto the return stack of `vm`. Fails if the stack is full,
+/// i.e. if `RP` would go below `r_limit`.
+///
+/// # Safety
+///
+/// `vm` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_beetle_rpush(vm: *mut VM, item: u32) -> i32 {
+    with_vm(vm, |vm| push(vm, MIJIT_BEETLE_RP, MIJIT_BEETLE_R_LIMIT, item))
+}
+
+/// Pops an item from the return stack of `vm` into `*item`. Fails if the
+/// stack is empty, i.e. if `RP` would go above `R0`.
+///
+/// # Safety
+///
+/// `vm` must be a live VM. `item` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn mijit_beetle_rpop(vm: *mut VM, item: *mut u32) -> i32 {
+    if item.is_null() { return MIJIT_BEETLE_ERROR_ARGUMENT; }
+    with_vm(vm, |vm| {
+        *item = pop(vm, MIJIT_BEETLE_RP, MIJIT_BEETLE_R0)?;
+        Some(())
+    })
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::{ackermann_object};
+
+    /// The C header.
+    const HEADER: &str = include_str!("../../include/mijit_beetle.h");
+
+    /// Test that "mijit_beetle.h" declares exactly the functions defined here.
+    #[test]
+    fn header() {
+        // Find every identifier followed by `(`.
+        let mut declared: Vec<&str> = HEADER.split('(')
+            .filter_map(|before| before.rsplit(|c: char| !(c.is_alphanumeric() || c == '_')).next())
+            .filter(|word| word.starts_with("mijit_beetle_"))
+            .collect();
+        declared.sort_unstable();
+        declared.dedup();
+        assert_eq!(declared, [
+            "mijit_beetle_free", "mijit_beetle_get", "mijit_beetle_load_object",
+            "mijit_beetle_new", "mijit_beetle_pop", "mijit_beetle_push",
+            "mijit_beetle_rpop", "mijit_beetle_rpush", "mijit_beetle_run",
+            "mijit_beetle_set",
+        ]);
+        for (name, value) in [
+            ("MIJIT_BEETLE_OK", MIJIT_BEETLE_OK),
+            ("MIJIT_BEETLE_ERROR_ARGUMENT", MIJIT_BEETLE_ERROR_ARGUMENT),
+            ("MIJIT_BEETLE_ERROR_PANIC", MIJIT_BEETLE_ERROR_PANIC),
+            ("MIJIT_BEETLE_HALT", MIJIT_BEETLE_HALT),
+            ("MIJIT_BEETLE_NOT_IMPLEMENTED", MIJIT_BEETLE_NOT_IMPLEMENTED),
+            ("MIJIT_BEETLE_STACK_OVERFLOW", MIJIT_BEETLE_STACK_OVERFLOW),
+            ("MIJIT_BEETLE_BAD_THROW", MIJIT_BEETLE_BAD_THROW),
+        ] {
+            assert!(HEADER.contains(&format!("#define {} ({})", name, value)), "{}", name);
+        }
+        for (name, id) in [
+            ("MIJIT_BEETLE_EP", MIJIT_BEETLE_EP),
+            ("MIJIT_BEETLE_I", MIJIT_BEETLE_I),
+            ("MIJIT_BEETLE_A", MIJIT_BEETLE_A),
+            ("MIJIT_BEETLE_SP", MIJIT_BEETLE_SP),
+            ("MIJIT_BEETLE_RP", MIJIT_BEETLE_RP),
+            ("MIJIT_BEETLE_NEXT_COUNT", MIJIT_BEETLE_NEXT_COUNT),
+            ("MIJIT_BEETLE_NEXT_PERIOD", MIJIT_BEETLE_NEXT_PERIOD),
+            ("MIJIT_BEETLE_NEXT_HOOK", MIJIT_BEETLE_NEXT_HOOK),
+            ("MIJIT_BEETLE_BAD", MIJIT_BEETLE_BAD),
+            ("MIJIT_BEETLE_THROW", MIJIT_BEETLE_THROW),
+            ("MIJIT_BEETLE_MEMORY", MIJIT_BEETLE_MEMORY),
+            ("MIJIT_BEETLE_NOT_ADDRESS", MIJIT_BEETLE_NOT_ADDRESS),
+            ("MIJIT_BEETLE_S_LIMIT", MIJIT_BEETLE_S_LIMIT),
+            ("MIJIT_BEETLE_R_LIMIT", MIJIT_BEETLE_R_LIMIT),
+            ("MIJIT_BEETLE_S0", MIJIT_BEETLE_S0),
+            ("MIJIT_BEETLE_R0", MIJIT_BEETLE_R0),
+        ] {
+            assert!(HEADER.contains(&format!("#define {} ({})", name, id)), "{}", name);
+        }
+        for field in ["int32_t kind;", "uint32_t code;", "uint32_t bad;", "uint32_t not_address;"] {
+            assert!(HEADER.contains(field), "{}", field);
+        }
+    }
+
+    #[test]
+    fn ackermann() {
+        unsafe {
+            let vm = mijit_beetle_new(1 << 16, 1 << 12, 1 << 12);
+            assert!(!vm.is_null());
+            let object = ackermann_object();
+            assert_eq!(mijit_beetle_load_object(vm, object.as_ptr(), object.len() as u32, 0), MIJIT_BEETLE_OK);
+            // A `HALT` instruction, preceded by `0` so that the halt code is 0.
+            let halt_addr = 0x1000;
+            let halt = [0x5519];
+            assert_eq!(mijit_beetle_load_object(vm, halt.as_ptr(), 1, halt_addr), MIJIT_BEETLE_OK);
+            let mut initial_sp = 0;
+            assert_eq!(mijit_beetle_get(vm, 3, &mut initial_sp), MIJIT_BEETLE_OK);
+            assert_eq!(mijit_beetle_push(vm, 3), MIJIT_BEETLE_OK);
+            assert_eq!(mijit_beetle_push(vm, 5), MIJIT_BEETLE_OK);
+            assert_eq!(mijit_beetle_rpush(vm, halt_addr), MIJIT_BEETLE_OK);
+            let outcome = mijit_beetle_run(vm, 0);
+            assert_eq!((outcome.kind, outcome.code), (MIJIT_BEETLE_HALT, 0));
+            let mut bad = 0;
+            assert_eq!(mijit_beetle_get(vm, MIJIT_BEETLE_BAD, &mut bad), MIJIT_BEETLE_OK);
+            assert_eq!(outcome.bad, bad);
+            let mut not_address = 0;
+            assert_eq!(mijit_beetle_get(vm, MIJIT_BEETLE_NOT_ADDRESS, &mut not_address), MIJIT_BEETLE_OK);
+            assert_eq!(outcome.not_address, not_address);
+            let mut result = 0;
+            assert_eq!(mijit_beetle_pop(vm, &mut result), MIJIT_BEETLE_OK);
+            assert_eq!(result, 253);
+            let mut sp = 0;
+            assert_eq!(mijit_beetle_get(vm, 3, &mut sp), MIJIT_BEETLE_OK);
+            assert_eq!(sp, initial_sp);
+            mijit_beetle_free(vm);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        unsafe {
+            assert!(mijit_beetle_new(16, 16, 1).is_null());
+            assert!(mijit_beetle_new(16, 8, 8).is_null());
+            let vm = mijit_beetle_new(16, 4, 4);
+            let mut x = 0;
+            assert_eq!(mijit_beetle_get(vm, 16, &mut x), MIJIT_BEETLE_ERROR_ARGUMENT);
+            assert_eq!(mijit_beetle_set(vm, 16, 0), MIJIT_BEETLE_ERROR_ARGUMENT);
+            // The stack limits and bases are readable and writable.
+            for (id, expected) in [
+                (MIJIT_BEETLE_S_LIMIT, 32), (MIJIT_BEETLE_R_LIMIT, 48),
+                (MIJIT_BEETLE_S0, 48), (MIJIT_BEETLE_R0, 64),
+            ] {
+                assert_eq!(mijit_beetle_get(vm, id, &mut x), MIJIT_BEETLE_OK);
+                assert_eq!(x, expected);
+                assert_eq!(mijit_beetle_set(vm, id, expected - 4), MIJIT_BEETLE_OK);
+                assert_eq!(mijit_beetle_get(vm, id, &mut x), MIJIT_BEETLE_OK);
+                assert_eq!(x, expected - 4);
+            }
+            assert_eq!(mijit_beetle_set(vm, 0, 4), MIJIT_BEETLE_OK);
+            assert_eq!(mijit_beetle_get(vm, 0, &mut x), MIJIT_BEETLE_OK);
+            assert_eq!(x, 4);
+            assert_eq!(mijit_beetle_set(vm, 10, 6), MIJIT_BEETLE_ERROR_ARGUMENT);
+            assert_eq!(mijit_beetle_set(vm, 10, 68), MIJIT_BEETLE_ERROR_ARGUMENT);
+            assert_eq!(mijit_beetle_set(vm, 10, 32), MIJIT_BEETLE_OK);
+            assert_eq!(mijit_beetle_load_object(vm, [0; 2].as_ptr(), 2, 60), MIJIT_BEETLE_ERROR_ARGUMENT);
+            assert_eq!(mijit_beetle_load_object(vm, [0; 2].as_ptr(), 2, 2), MIJIT_BEETLE_ERROR_ARGUMENT);
+            assert_eq!(mijit_beetle_pop(vm, &mut x), MIJIT_BEETLE_ERROR_ARGUMENT);
+            assert_eq!(mijit_beetle_set(vm, MIJIT_BEETLE_SP, 28), MIJIT_BEETLE_OK);
+            assert_eq!(mijit_beetle_push(vm, 0), MIJIT_BEETLE_ERROR_ARGUMENT);
+            assert_eq!(mijit_beetle_run(vm, 64).kind, MIJIT_BEETLE_ERROR_ARGUMENT);
+            assert_eq!(mijit_beetle_run(std::ptr::null_mut(), 0).kind, MIJIT_BEETLE_ERROR_ARGUMENT);
+            mijit_beetle_free(vm);
+        }
+    }
+}
@@ -5,17 +5,26 @@
 
 use memoffset::{offset_of};
 
-use super::code::{UnaryOp, BinaryOp, Width, Register, REGISTERS, GLOBAL, EBB, Marshal};
+use std::collections::{HashSet};
+
+use super::code::{UnaryOp, BinaryOp, Width, Register, REGISTERS, GLOBAL, Variable, Address, Action, Ending, EBB, Marshal};
 use UnaryOp::*;
 use BinaryOp::*;
 use Width::*;
-use super::target::{Word, Target};
-use super::jit::{EntryId, Jit};
+use super::optimizer::{Options};
+use super::target::{Target};
+use super::jit::{EntryId, Jit, Interpreter, SharedJit, Run};
 use super::code::builder::{build, build_block, Builder};
 
 mod registers;
 pub use registers::{Registers, M0Registers};
 
+pub mod vm;
+pub use vm::{VM, VMState, StateError, BeetleExit};
+
+#[cfg(feature = "capi")]
+pub mod capi;
+
 /// The number of bytes in a cell.
 pub const CELL: i32 = 4;
 
@@ -44,53 +53,485 @@ macro_rules! register {
 
 /// The return code used to indicate normal exit from the hot code.
 const NOT_IMPLEMENTED: i64 = 0;
+/// The return code used to indicate that a push would overflow a stack.
+const STACK_OVERFLOW: i64 = 1;
+/// The return code used to indicate the end of an instruction in
+/// single-step mode.
+const STEP: i64 = 2;
+/// The return code used to indicate that an exception could not be passed
+/// to the handler.
+const BAD_THROW: i64 = 3;
 /// Dummy return code which should never actually occur.
 const UNDEFINED: i64 = i64::MAX;
 
 //-----------------------------------------------------------------------------
 
-/// Computes into `BI` the native address corresponding to `addr`.
-fn native_address(b: &mut Builder<EntryId>, addr: Register) {
+// Aliasing.
+//
+// Beetle memory, including both stacks, is a single address space: guest code
+// can obtain the address of a stack item and read or write it with `@` or
+// `!`. We therefore never tell the optimizer that two memory accesses are
+// independent. Every native address is computed from `M0`, and every access
+// is followed by `Send(M0, BI)`, so each access depends on the previous one
+// and none can be reordered. Anyone who wants to let stack accesses move past
+// other accesses must first detect guest accesses to the stacks through the
+// general memory opcodes; `stack_aliasing` in the tests checks that they work.
+// In strict mode, `define()` checks that every access to Beetle memory goes
+// through an address computed from `M0`.
+
+/// Panics unless every memory access in `ebb` is either to the [`Registers`]
+/// via `REGS`, or to Beetle memory via an address computed from `M0` on the
+/// same path.
+fn check_addresses(ebb: &EBB<EntryId>) {
+    fn walk(ebb: &EBB<EntryId>, mut from_m0: HashSet<Variable>) {
+        for action in &*ebb.actions {
+            let check = |addr: &Address| assert!(
+                addr.base == REGS.into() || from_m0.contains(&addr.base),
+                "Memory access not computed from M0: {:?}", action,
+            );
+            let (dest, is_from_m0) = match *action {
+                Action::Move(dest, src) => (dest, from_m0.contains(&src)),
+                Action::Constant(_, dest, _) |
+                Action::Unary(_, _, dest, _) |
+                Action::Select(_, dest, _, _, _) |
+                Action::Call(dest, _, _, _) |
+                Action::SlotAddress(dest, _) => (dest.into(), false),
+                Action::Binary(_, _, dest, src1, src2) =>
+                    (dest.into(), from_m0.contains(&src1) || from_m0.contains(&src2)),
+                Action::Send(dest, src1, _) => (dest.into(), from_m0.contains(&src1)),
+                Action::Load(dest, addr) | Action::AtomicCas(dest, _, _, addr) => {
+                    check(&addr);
+                    (dest.into(), false)
+                },
+                Action::Store(dest, _, addr) => {
+                    check(&addr);
+                    (dest.into(), from_m0.contains(&addr.base))
+                },
+                Action::Push(_, _) | Action::Drop(_) => {
+                    // Forget the `Slot`s, which have been renumbered.
+                    from_m0.retain(|v| !matches!(v, Variable::Slot(_)));
+                    continue;
+                },
+                Action::Debug(_) | Action::Fence(_) => continue,
+            };
+            if is_from_m0 { from_m0.insert(dest); } else { from_m0.remove(&dest); }
+        }
+        if let Ending::Switch(_, switch) = &ebb.ending {
+            for case in switch.cases.iter().chain(std::iter::once(&*switch.default_)) {
+                walk(case, from_m0.clone());
+            }
+        }
+    }
+    walk(ebb, std::iter::once(M0.into()).collect());
+}
+
+/// Appends to `definitions` a definition of `entry` as `ebb`. In strict
+/// mode, first checks `ebb` using [`check_addresses()`].
+fn define(definitions: &mut Vec<(EntryId, EBB<EntryId>)>, entry: EntryId, ebb: EBB<EntryId>) {
+    if cfg!(any(debug_assertions, feature = "strict")) { check_addresses(&ebb); }
+    definitions.push((entry, ebb));
+}
+
+/// Where to jump if an address is invalid. See [`native_address()`].
+#[derive(Debug, Copy, Clone)]
+struct BadAddress {
+    /// Where to jump if the address is out of range.
+    range: EntryId,
+    /// Where to jump if the address is not aligned.
+    alignment: EntryId,
+}
+
+/// Computes into `BI` the native address corresponding to `addr`, for an
+/// access of `width`.
+///
+/// First checks that `addr` is a multiple of `width`, and that `addr`
+/// divided by `width` is less than [`Registers::memory_size`] divided by
+/// `width`, rounding down. It follows that the whole access is below
+/// `memory_size`. If not, stores `addr` in [`Registers::not_address`] and
+/// jumps to `bad_address.alignment` or `bad_address.range`.
+fn native_address(b: &mut Builder<EntryId>, width: Width, addr: Register, bad_address: BadAddress) {
+    assert_ne!(addr, BI);
+    b.load(BI, register!(memory_size));
+    match width {
+        One => {
+            b.binary32(Ult, BI, addr, BI);
+            b.guard(BI, true, build(|mut b| {
+                b.store(addr, register!(not_address));
+                b.jump(bad_address.range)
+            }));
+        },
+        _ => {
+            // Check both conditions with one guard, by rotating any low bits
+            // of `addr` to the top and comparing it with the size in units
+            // of `width`.
+            let shift = width as i32;
+            b.const_binary32(RotR, addr, addr, shift);
+            b.const_binary32(Lsr, BI, BI, shift);
+            b.binary32(Ult, BI, addr, BI);
+            b.const_binary32(RotL, addr, addr, shift);
+            b.guard(BI, true, build(|mut b| {
+                b.store(addr, register!(not_address));
+                b.const_binary32(And, BI, addr, (1 << shift) - 1);
+                b.if_(BI,
+                    build(|b| b.jump(bad_address.alignment)),
+                    build(|b| b.jump(bad_address.range)),
+                )
+            }));
+        },
+    }
     b.binary64(Add, BI, M0, addr);
 }
 
-/// Loads `dest` from `addr`. `BI` is corrupted.
-fn load(b: &mut Builder<EntryId>, dest: Register, addr: Register) {
-    native_address(b, addr);
-    b.load(dest, (BI, 0, Four));
+/// Loads `width` bytes at `addr` into `dest`, zero-extended. `BI` is
+/// corrupted.
+/// See [`native_address()`] for the checks on `addr`.
+fn load_width(
+    b: &mut Builder<EntryId>,
+    width: Width,
+    dest: Register,
+    addr: Register,
+    bad_address: BadAddress,
+) {
+    native_address(b, width, addr, bad_address);
+    b.load(dest, (BI, 0, width));
     b.send(M0, BI);
 }
 
-/// Stores `dest` at `addr`. `BI` is corrupted.
-fn store(b: &mut Builder<EntryId>, src: Register, addr: Register) {
-    native_address(b, addr);
-    b.store(src, (BI, 0, Four));
+/// Stores the low `width` bytes of `src` at `addr`. `BI` is corrupted.
+/// See [`native_address()`] for the checks on `addr`.
+fn store_width(
+    b: &mut Builder<EntryId>,
+    width: Width,
+    src: Register,
+    addr: Register,
+    bad_address: BadAddress,
+) {
+    native_address(b, width, addr, bad_address);
+    b.store(src, (BI, 0, width));
     b.send(M0, BI);
 }
 
+/// Loads `dest` from `addr`. `BI` is corrupted.
+/// See [`native_address()`] for the meaning of `bad_address`.
+fn load(b: &mut Builder<EntryId>, dest: Register, addr: Register, bad_address: BadAddress) {
+    load_width(b, Four, dest, addr, bad_address);
+}
+
+/// Stores `dest` at `addr`. `BI` is corrupted.
+/// See [`native_address()`] for the meaning of `bad_address`.
+fn store(b: &mut Builder<EntryId>, src: Register, addr: Register, bad_address: BadAddress) {
+    store_width(b, Four, src, addr, bad_address);
+}
+
+/// Loads the byte at `addr` into `dest`, zero-extended. `BI` is corrupted.
+/// See [`native_address()`] for the meaning of `bad_address`.
+fn load_byte(b: &mut Builder<EntryId>, dest: Register, addr: Register, bad_address: BadAddress) {
+    load_width(b, One, dest, addr, bad_address);
+}
+
+/// Stores the low byte of `src` at `addr`. `BI` is corrupted.
+/// See [`native_address()`] for the meaning of `bad_address`.
+fn store_byte(b: &mut Builder<EntryId>, src: Register, addr: Register, bad_address: BadAddress) {
+    store_width(b, One, src, addr, bad_address);
+}
+
 /// Pops `dest` from the stack at `sp`. `BI` is corrupted.
-fn pop(b: &mut Builder<EntryId>, dest: Register, sp: Register) {
-    load(b, dest, sp);
+///
+/// If `exception` is not `None`, `sp` must be `BSP` or `BRP`, and first
+/// checks that the stack is not empty, i.e. that `sp` is below
+/// [`Registers::s0`] or [`Registers::r0`]. If it is empty, leaves `sp`
+/// unchanged and raises [`STACK_UNDERFLOW`] or [`RETURN_STACK_UNDERFLOW`].
+/// See [`native_address()`] for the meaning of `bad_address`.
+fn pop(
+    b: &mut Builder<EntryId>,
+    dest: Register,
+    sp: Register,
+    exception: impl Into<Option<EntryId>>,
+    bad_address: BadAddress,
+) {
+    if let Some(exception) = exception.into() {
+        let code = if sp == BSP {
+            b.load(BI, register!(s0));
+            STACK_UNDERFLOW
+        } else {
+            assert_eq!(sp, BRP);
+            b.load(BI, register!(r0));
+            RETURN_STACK_UNDERFLOW
+        };
+        b.binary32(Ult, BI, sp, BI);
+        b.guard(BI, true, build(|b| raise(b, code, exception)));
+    }
+    load(b, dest, sp, bad_address);
     b.const_binary32(Add, sp, sp, CELL);
 }
 
-/// Pushes `src` to the stack at `sp`. `BI` is corrupted.
-fn push(b: &mut Builder<EntryId>, src: Register, sp: Register) {
+/// If `underflow` is not `None`, checks that the data stack holds at least
+/// `depth` items, i.e. that `BSP` is at least `depth` cells below
+/// [`Registers::s0`]. If not, leaves `BSP` unchanged and raises
+/// [`STACK_UNDERFLOW`]. `BI` is corrupted.
+///
+/// Instructions that read more than one item, or that read an item without
+/// popping it, call this first, so that they either fail before changing
+/// anything or succeed without checking each access.
+fn check_depth(b: &mut Builder<EntryId>, depth: i32, underflow: Option<EntryId>) {
+    if let Some(exception) = underflow {
+        b.load(BI, register!(s0));
+        b.const_binary32(Sub, BI, BI, (depth - 1) * CELL);
+        b.binary32(Ult, BI, BSP, BI);
+        b.guard(BI, true, build(|b| raise(b, STACK_UNDERFLOW, exception)));
+    }
+}
+
+/// Pushes `src` to the stack at `sp`, which must be `BSP` or `BRP`. `BI` is
+/// corrupted.
+///
+/// If `stack_overflow` is not `None`, first checks that the decremented `sp`
+/// is not below [`Registers::s_limit`] or [`Registers::r_limit`]. If it is,
+/// leaves `sp` unchanged and jumps to `stack_overflow`.
+/// See [`native_address()`] for the meaning of `bad_address`.
+fn push(
+    b: &mut Builder<EntryId>,
+    src: Register,
+    sp: Register,
+    stack_overflow: impl Into<Option<EntryId>>,
+    bad_address: BadAddress,
+) {
     b.const_binary32(Sub, sp, sp, CELL);
-    store(b, src, sp);
+    if let Some(stack_overflow) = stack_overflow.into() {
+        if sp == BSP {
+            b.load(BI, register!(s_limit));
+        } else {
+            assert_eq!(sp, BRP);
+            b.load(BI, register!(r_limit));
+        }
+        b.binary32(Ult, BI, sp, BI);
+        b.guard(BI, false, build(|mut b| {
+            b.const_binary32(Add, sp, sp, CELL);
+            b.jump(stack_overflow)
+        }));
+    }
+    store(b, src, sp, bad_address);
+}
+
+/// Pops `BA` from `BEP`, i.e. loads the next instruction word. `BI` is
+/// corrupted.
+///
+/// If `BEP` is not aligned or is out of range, jumps to `bad_address` as for
+/// [`native_address()`].
+fn fetch(b: &mut Builder<EntryId>, bad_address: BadAddress) {
+    pop(b, BA, BEP, None, bad_address);
+}
+
+/// Returns the code for the NEXT instruction, which fetches the next
+/// instruction word and jumps to `next`.
+///
+/// If `next_hook` is `true`, first decrements [`Registers::next_count`] and,
+/// if it reaches zero, reloads it and calls [`Registers::next_hook`]. If it
+/// is `false`, the code is the same as if the hook did not exist.
+fn next_instruction(
+    next_hook: bool,
+    next: EntryId,
+    stack_overflow: EntryId,
+    bad_address: BadAddress,
+) -> EBB<EntryId> {
+    build(|mut b| {
+        if next_hook {
+            b.load(R1, register!(next_count));
+            b.const_binary32(Sub, R1, R1, 1);
+            b.store(R1, register!(next_count));
+            b.guard(R1, true, build(|mut b| {
+                // Reload the counter and call the hook.
+                b.load(R1, register!(next_period));
+                b.store(R1, register!(next_count));
+                push(&mut b, BEP, BRP, stack_overflow, bad_address);
+                b.load(BEP, register!(next_hook));
+                fetch(&mut b, bad_address);
+                b.jump(next)
+            }));
+        }
+        fetch(&mut b, bad_address);
+        b.jump(next)
+    })
+}
+
+/// The exception code for popping from an empty data stack.
+const STACK_UNDERFLOW: i64 = -4;
+/// The exception code for popping from an empty return stack.
+const RETURN_STACK_UNDERFLOW: i64 = -6;
+/// The exception code for an invalid address.
+const INVALID_ADDRESS: i64 = -9;
+/// The exception code for division by zero.
+const DIVISION_BY_ZERO: i64 = -10;
+/// The exception code for an unaligned address.
+const ADDRESS_ALIGNMENT: i64 = -23;
+
+/// Puts `code` in `BA` and jumps to `exception`, which pushes it and throws.
+fn raise(mut b: Builder<EntryId>, code: i64, exception: EntryId) -> EBB<EntryId> {
+    b.const_(BA, code);
+    b.jump(exception)
+}
+
+/// Prepends to `ebb` an [`Action::Debug`] of `BI`, which holds the opcode
+/// being dispatched.
+fn traced(ebb: EBB<EntryId>) -> EBB<EntryId> {
+    let actions = std::iter::once(Action::Debug(BI.into()))
+        .chain(ebb.actions.into_vec())
+        .collect();
+    EBB {actions, ending: ebb.ending}
+}
+
+/// Raises an exception if `R2` is zero.
+fn check_divisor(b: &mut Builder<EntryId>, exception: EntryId) {
+    b.guard(R2, true, build(|b| raise(b, DIVISION_BY_ZERO, exception)));
+}
+
+/// Divides `R3` by `R2`, leaving the quotient in `R1` and the remainder in
+/// `R3`. The quotient is rounded towards minus infinity if `floored`,
+/// otherwise towards zero. `R2` is preserved. `BI` is corrupted.
+fn signed_div_mod(b: &mut Builder<EntryId>, floored: bool) {
+    b.binary32(SDiv, R1, R3, R2);
+    b.binary32(SRem, R3, R3, R2);
+    if floored {
+        // Adjust if the remainder is non-zero and its sign differs from `R2`.
+        b.const_binary32(Eq, BI, R3, 0);
+        b.unary32(Not, BI, BI);
+        b.binary32(Xor, R2, R2, R3);
+        b.binary32(And, BI, BI, R2);
+        b.const_binary32(Lt, BI, BI, 0);
+        b.binary32(Xor, R2, R2, R3);
+        b.binary32(Add, R1, R1, BI);
+        b.binary32(And, BI, BI, R2);
+        b.binary32(Add, R3, R3, BI);
+    }
+}
+
+/// Divides `R3` by `R2` unsigned, leaving the quotient in `R1` and the
+/// remainder in `R3`. `R2` is preserved.
+fn unsigned_div_mod(b: &mut Builder<EntryId>) {
+    b.binary32(UDiv, R1, R3, R2);
+    b.binary32(URem, R3, R3, R2);
+}
+
+/// Why [`Beetle::run()`] returned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// The low byte of [`Registers::a`] is an opcode that the compiled code
+    /// does not implement, e.g. `HALT` or `LIB`.
+    NotImplemented,
+    /// A push would have overflowed a stack.
+    StackOverflow,
+    /// The code was compiled for single-step mode and has executed one
+    /// instruction.
+    Step,
+    /// An exception was raised, but it could not be passed to the handler,
+    /// because `sp`, [`Registers::throw`] or the handler address was
+    /// invalid. The invalid address is in [`Registers::not_address`].
+    BadThrow,
+}
+
+/// Options for compiling [`Beetle`], which can be combined. The default is
+/// none of them, which gives the fastest code. See [`Beetle::with_options()`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct BeetleOptions {
+    /// Compile a NEXT-time hook. Each NEXT decrements
+    /// [`Registers::next_count`] and, when it reaches zero, reloads it from
+    /// [`Registers::next_period`] and calls the word at
+    /// [`Registers::next_hook`], as if by CALL. This is sufficient to
+    /// implement round-robin multitasking in guest code.
+    pub next_hook: bool,
+    /// Compile in single-step mode, in which [`Beetle::run()`] returns
+    /// [`Stop::Step`] after every instruction. This is slow, but useful for
+    /// debugging.
+    pub single_step: bool,
+    /// Trace the opcodes dispatched. Each dispatch executes an
+    /// [`Action::Debug`] of the opcode, which prints it. The `Debug`s are
+    /// kept even in release builds. This is slow, but useful for debugging.
+    pub tracing: bool,
+    /// Check for stack underflow. An instruction that needs more items than
+    /// the data stack holds raises exception -4, and popping from an empty
+    /// return stack raises -6, as if by `THROW`. The stack pointer is left
+    /// unchanged. The checks make the code slower.
+    pub checked_stacks: bool,
+    /// Profile the compiled code. See [`Jit::set_profiling()`]. The profile
+    /// of [`Beetle::root`] counts the instructions dispatched. Only
+    /// [`Jit`]s can profile.
+    pub profiling: bool,
 }
 
 /// The performance-critical part of the virtual machine.
-#[derive(Debug)]
-pub struct Beetle<T: Target> {
-    pub jit: Jit<T>,
+#[derive(Debug, Clone)]
+pub struct Beetle<J: Run> {
+    pub jit: J,
     pub root: EntryId,
+    single_step: bool,
 }
 
-impl<T: Target> Beetle<T> {
-    #[allow(clippy::too_many_lines)]
+impl<T: Target> Beetle<Jit<T>> {
+    /// Compiles the virtual machine.
     pub fn new(target: T) -> Self {
-        let mut jit = Jit::new(target);
+        Self::with_options(target, BeetleOptions::default())
+    }
+
+    /// Compiles the virtual machine with `options`.
+    pub fn with_options(target: T, options: BeetleOptions) -> Self {
+        Self::with_threads(target, options, 1)
+    }
+
+    /// Like `with_options()` but shares the work of optimizing between up
+    /// to `num_threads` threads. The compiled code does not depend on
+    /// `num_threads`. See [`Jit::define_all()`]. With `num_threads == 1`,
+    /// each entry is defined in turn using [`Jit::define()`].
+    pub fn with_threads(target: T, options: BeetleOptions, num_threads: usize) -> Self {
+        let mut jit_options = Options::new(T::NUM_REGISTERS);
+        if options.tracing { jit_options.keep_debug = true; }
+        let mut jit = Jit::with_options(target, jit_options);
+        jit.set_profiling(options.profiling);
+        Self::with_jit(jit, options, num_threads)
+    }
+
+    /// Compiles superinstructions for the instruction sequences that have
+    /// been executed most often, if `self` was compiled with
+    /// [`BeetleOptions::profiling`]. See [`Jit::specialize_hot()`]. Returns
+    /// the number of superinstructions compiled.
+    pub fn specialize(&mut self, threshold: u64, budget: usize) -> usize {
+        self.jit.specialize_hot(self.root, threshold, budget)
+    }
+}
+
+impl Beetle<Interpreter> {
+    /// Constructs the virtual machine without compiling it. See
+    /// [`Interpreter`].
+    pub fn interpreted() -> Self {
+        Self::with_jit(Interpreter::new(), BeetleOptions::default(), 1)
+    }
+
+    /// Like [`interpreted()`] but in single-step mode. See
+    /// [`BeetleOptions::single_step`]. This is useful for comparing the
+    /// [`Interpreter`] with the compiled code one instruction at a time.
+    ///
+    /// [`interpreted()`]: Self::interpreted
+    pub fn interpreted_with_single_step() -> Self {
+        Self::with_jit(Interpreter::new(), BeetleOptions {single_step: true, ..BeetleOptions::default()}, 1)
+    }
+}
+
+impl<T: Target> Beetle<SharedJit<T>> {
+    /// Compiles the virtual machine so that it can be shared between
+    /// threads. Clones of the result share the compiled code, but each
+    /// thread can run it with its own [`Registers`] and memory at the same
+    /// time. See [`SharedJit`].
+    pub fn shared(target: T) -> Self {
+        Self::with_jit(SharedJit::new(target), BeetleOptions::default(), 1)
+    }
+}
+
+impl<J: Run> Beetle<J> {
+    /// Compiles the virtual machine using `jit`, sharing the work between up
+    /// to `num_threads` threads. `options.profiling` is ignored; `jit` must
+    /// already be set up to profile, if desired.
+    #[allow(clippy::too_many_lines)]
+    fn with_jit(mut jit: J, options: BeetleOptions, num_threads: usize) -> Self {
+        let BeetleOptions {next_hook, single_step, tracing, checked_stacks, ..} = options;
         let marshal = Marshal {
             prologue: build_block(|b| {
                 b.move_(REGS, GLOBAL);
@@ -113,391 +554,889 @@ impl<T: Target> Beetle<T> {
             }),
         };
         let root = jit.new_entry(&marshal, UNDEFINED);
+        let mut definitions = Vec::new();
+
+        // Stack overflow. This returns to the host.
+        let stack_overflow = jit.new_entry(&marshal, STACK_OVERFLOW);
+
+        // Where to go at the end of each instruction. In single-step mode
+        // this returns to the host.
+        let next = if single_step { jit.new_entry(&marshal, STEP) } else { root };
+
+        // An invalid address in the exception handler. This returns to the
+        // host, since raising another exception would fail in the same way.
+        let bad_throw = jit.new_entry(&marshal, BAD_THROW);
+        let bad_throw = BadAddress {range: bad_throw, alignment: bad_throw};
+
+        // Exception handler.
+        let throw = jit.new_entry(&marshal, UNDEFINED);
+        define(&mut definitions, throw, build(|mut b| {
+            b.store(BEP, register!(bad));
+            b.load(R1, register!(throw));
+            load(&mut b, BEP, R1, bad_throw);
+            fetch(&mut b, bad_throw);
+            b.jump(next)
+        }));
+
+        // Raise the exception whose code is in `BA`.
+        let exception = jit.new_entry(&marshal, UNDEFINED);
+        define(&mut definitions, exception, build(|mut b| {
+            push(&mut b, BA, BSP, None, bad_throw);
+            b.jump(throw)
+        }));
+
+        // Stack underflow, when checked.
+        let underflow = if checked_stacks { Some(exception) } else { None };
+
+        // Invalid address.
+        let invalid_address = jit.new_entry(&marshal, UNDEFINED);
+        define(&mut definitions, invalid_address, build(|b| raise(b, INVALID_ADDRESS, exception)));
+
+        // Unaligned address.
+        let unaligned_address = jit.new_entry(&marshal, UNDEFINED);
+        define(&mut definitions, unaligned_address, build(|b| raise(b, ADDRESS_ALIGNMENT, exception)));
+
+        let bad_address = BadAddress {range: invalid_address, alignment: unaligned_address};
 
         // Immediate branch.
         let branchi = jit.new_entry(&marshal, UNDEFINED);
-        jit.define(branchi, &build(|mut b| {
+        define(&mut definitions, branchi, build(|mut b| {
             b.const_binary32(Mul, R1, BA, CELL);
             b.binary32(Add, BEP, BEP, R1);
-            pop(&mut b, BA, BEP);
-            b.jump(root)
+            pop(&mut b, BA, BEP, None, bad_address);
+            b.jump(next)
+        }));
+
+        // Block memory operations. These loop until `BA` is zero, then resume
+        // the instruction stream. Only the registers of `marshal` survive a
+        // jump to the start of the loop, so they save `BA` in
+        // [`Registers::a`], keep the count in `BA` and an address in `BI`,
+        // and push their other operand onto the data stack.
+
+        // Copy `BA` bytes from `BI` to `BI` plus the top of the stack,
+        // upwards.
+        let copy_up = jit.new_entry(&marshal, UNDEFINED);
+        define(&mut definitions, copy_up, build(|b| {
+            b.if_(BA,
+                build(|mut b| {
+                    b.move_(R2, BI);
+                    load(&mut b, R3, BSP, bad_address);
+                    b.binary32(Add, R3, R2, R3);
+                    load_byte(&mut b, R1, R2, bad_address);
+                    store_byte(&mut b, R1, R3, bad_address);
+                    b.const_binary32(Add, BI, R2, 1);
+                    b.const_binary32(Sub, BA, BA, 1);
+                    b.jump(copy_up)
+                }),
+                build(|mut b| {
+                    b.const_binary32(Add, BSP, BSP, CELL);
+                    b.load(BA, register!(a));
+                    b.jump(next)
+                }),
+            )
+        }));
+
+        // Copy `BA` bytes ending just before `BI` to just before `BI` plus
+        // the top of the stack, downwards.
+        let copy_down = jit.new_entry(&marshal, UNDEFINED);
+        define(&mut definitions, copy_down, build(|b| {
+            b.if_(BA,
+                build(|mut b| {
+                    b.const_binary32(Sub, R2, BI, 1);
+                    load(&mut b, R3, BSP, bad_address);
+                    b.binary32(Add, R3, R2, R3);
+                    load_byte(&mut b, R1, R2, bad_address);
+                    store_byte(&mut b, R1, R3, bad_address);
+                    b.move_(BI, R2);
+                    b.const_binary32(Sub, BA, BA, 1);
+                    b.jump(copy_down)
+                }),
+                build(|mut b| {
+                    b.const_binary32(Add, BSP, BSP, CELL);
+                    b.load(BA, register!(a));
+                    b.jump(next)
+                }),
+            )
+        }));
+
+        // Store the top of the stack in `BA` bytes starting at `BI`.
+        let fill = jit.new_entry(&marshal, UNDEFINED);
+        define(&mut definitions, fill, build(|b| {
+            b.if_(BA,
+                build(|mut b| {
+                    b.move_(R2, BI);
+                    load(&mut b, R3, BSP, bad_address);
+                    store_byte(&mut b, R3, R2, bad_address);
+                    b.const_binary32(Add, BI, R2, 1);
+                    b.const_binary32(Sub, BA, BA, 1);
+                    b.jump(fill)
+                }),
+                build(|mut b| {
+                    b.const_binary32(Add, BSP, BSP, CELL);
+                    b.load(BA, register!(a));
+                    b.jump(next)
+                }),
+            )
+        }));
+
+        // Compare `BA` bytes starting at `BI` with those starting at `BI` plus
+        // the top of the stack. If they differ, replace the item below the
+        // top of the stack with the result.
+        let compare = jit.new_entry(&marshal, UNDEFINED);
+        define(&mut definitions, compare, build(|b| {
+            b.if_(BA,
+                build(|mut b| {
+                    b.move_(R2, BI);
+                    load(&mut b, R3, BSP, bad_address);
+                    b.binary32(Add, R3, R2, R3);
+                    load_byte(&mut b, R1, R2, bad_address);
+                    load_byte(&mut b, R3, R3, bad_address);
+                    b.const_binary32(Add, BI, R2, 1);
+                    b.binary32(Ult, R2, R1, R3);
+                    b.binary32(Ult, R3, R3, R1);
+                    b.binary32(Sub, R3, R2, R3);
+                    b.if_(R3,
+                        build(|mut b| {
+                            b.const_binary32(Add, BSP, BSP, CELL);
+                            store(&mut b, R3, BSP, bad_address);
+                            b.load(BA, register!(a));
+                            b.jump(next)
+                        }),
+                        build(|mut b| {
+                            b.const_binary32(Sub, BA, BA, 1);
+                            b.jump(compare)
+                        }),
+                    )
+                }),
+                build(|mut b| {
+                    b.const_binary32(Add, BSP, BSP, CELL);
+                    b.load(BA, register!(a));
+                    b.jump(next)
+                }),
+            )
         }));
 
         // Not implemented.
         let not_implemented2 = jit.new_entry(&marshal, NOT_IMPLEMENTED);
         let not_implemented = jit.new_entry(&marshal, UNDEFINED);
-        jit.define(not_implemented, &build(|mut b| {
+        define(&mut definitions, not_implemented, build(|mut b| {
             b.const_binary32(Lsl, BA, BA, 8);
             b.binary32(Or, BA, BA, BI);
             b.jump(not_implemented2)
         }));
 
         // Op-code dispatch routines.
-        let mut actions: Box<[EBB<EntryId>]> = (0..0x63).map(|_| {
+        let mut actions: Box<[EBB<EntryId>]> = (0..0x6C).map(|_| {
             build(|b| b.jump(not_implemented))
         }).collect();
 
         // NEXT
-        actions[0x00] = build(|mut b| {
-            pop(&mut b, BA, BEP);
-            b.jump(root)
-        });
+        actions[0x00] = next_instruction(next_hook, next, stack_overflow, bad_address);
 
         // DUP
         actions[0x01] = build(|mut b| {
-            load(&mut b, R2, BSP);
-            push(&mut b, R2, BSP);
-            b.jump(root)
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R2, BSP, bad_address);
+            push(&mut b, R2, BSP, stack_overflow, bad_address);
+            b.jump(next)
         });
 
         // DROP
         actions[0x02] = build(|mut b| {
+            check_depth(&mut b, 1, underflow);
             b.const_binary32(Add, BSP, BSP, CELL);
-            b.jump(root)
+            b.jump(next)
         });
 
         // SWAP
         actions[0x03] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
-            store(&mut b, R2, BSP);
-            push(&mut b, R3, BSP);
-            b.jump(root)
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
+            store(&mut b, R2, BSP, bad_address);
+            push(&mut b, R3, BSP, stack_overflow, bad_address);
+            b.jump(next)
         });
 
         // OVER
         actions[0x04] = build(|mut b| {
+            check_depth(&mut b, 2, underflow);
             b.const_binary32(Add, R1, BSP, CELL);
-            load(&mut b, R2, R1);
-            push(&mut b, R2, BSP);
-            b.jump(root)
+            load(&mut b, R2, R1, bad_address);
+            push(&mut b, R2, BSP, stack_overflow, bad_address);
+            b.jump(next)
         });
 
         // ROT
         actions[0x05] = build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 3, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.const_binary32(Add, R1, BSP, CELL);
-            load(&mut b, R3, R1);
-            store(&mut b, R2, R1);
+            load(&mut b, R3, R1, bad_address);
+            store(&mut b, R2, R1, bad_address);
             b.const_binary32(Add, R1, BSP, 2 * CELL);
-            load(&mut b, R2, R1);
-            store(&mut b, R3, R1);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            load(&mut b, R2, R1, bad_address);
+            store(&mut b, R3, R1, bad_address);
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // -ROT
         actions[0x06] = build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 3, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.const_binary32(Add, R1, BSP, 2 * CELL);
-            load(&mut b, R3, R1);
-            store(&mut b, R2, R1);
+            load(&mut b, R3, R1, bad_address);
+            store(&mut b, R2, R1, bad_address);
             b.const_binary32(Add, R1, BSP, CELL);
-            load(&mut b, R2, R1);
-            store(&mut b, R3, R1);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            load(&mut b, R2, R1, bad_address);
+            store(&mut b, R3, R1, bad_address);
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // TUCK
         actions[0x07] = build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 2, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.const_binary32(Add, R1, BSP, CELL);
-            load(&mut b, R3, R1);
-            store(&mut b, R2, R1);
-            store(&mut b, R3, BSP);
-            push(&mut b, R2, BSP);
-            b.jump(root)
+            load(&mut b, R3, R1, bad_address);
+            store(&mut b, R2, R1, bad_address);
+            store(&mut b, R3, BSP, bad_address);
+            push(&mut b, R2, BSP, stack_overflow, bad_address);
+            b.jump(next)
         });
 
         // NIP
         actions[0x08] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // <
         actions[0x0F] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Lt, R2, R3, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // >
         actions[0x10] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Lt, R2, R2, R3);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // =
         actions[0x11] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Eq, R2, R3, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // <>
         actions[0x12] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Eq, R2, R3, R2);
             b.unary32(Not, R2, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // 0<
         actions[0x13] = build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.const_binary32(Lt, R2, R2, 0);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // 0>
         actions[0x14] = build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.const_(R3, 0);
             b.binary32(Lt, R2, R3, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // 0=
         actions[0x15] = build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.const_binary32(Eq, R2, R2, 0);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // 0<>
         actions[0x16] = build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.const_binary32(Eq, R2, R2, 0);
             b.unary32(Not, R2, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // U<
         actions[0x17] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Ult, R2, R3, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // U>
         actions[0x18] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Ult, R2, R2, R3);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // 0
         actions[0x19] = build(|mut b| {
             b.const_(R2, 0);
-            push(&mut b, R2, BSP);
-            b.jump(root)
+            push(&mut b, R2, BSP, stack_overflow, bad_address);
+            b.jump(next)
         });
 
         // 1
         actions[0x1A] = build(|mut b| {
             b.const_(R2, 1);
-            push(&mut b, R2, BSP);
-            b.jump(root)
+            push(&mut b, R2, BSP, stack_overflow, bad_address);
+            b.jump(next)
         });
 
         // -1
         actions[0x1B] = build(|mut b| {
             b.const_(R2, -1i32 as u32 as i64);
-            push(&mut b, R2, BSP);
-            b.jump(root)
+            push(&mut b, R2, BSP, stack_overflow, bad_address);
+            b.jump(next)
         });
 
         // +
         actions[0x1E] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Add, R2, R3, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // -
         actions[0x1F] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Sub, R2, R3, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // >-<
         actions[0x20] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Sub, R2, R2, R3);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // 1+
         actions[0x21] = build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.const_binary32(Add, R2, R2, 1);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // 1-
         actions[0x22] = build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.const_binary32(Sub, R2, R2, 1);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // *
         actions[0x25] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Mul, R2, R3, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
+        });
+
+        // /
+        actions[0x26] = build(|mut b| {
+            check_depth(&mut b, 2, underflow);
+            load(&mut b, R2, BSP, bad_address);
+            check_divisor(&mut b, exception);
+            b.const_binary32(Add, BSP, BSP, CELL);
+            load(&mut b, R3, BSP, bad_address);
+            signed_div_mod(&mut b, true);
+            store(&mut b, R1, BSP, bad_address);
+            b.jump(next)
+        });
+
+        // MOD
+        actions[0x27] = build(|mut b| {
+            check_depth(&mut b, 2, underflow);
+            load(&mut b, R2, BSP, bad_address);
+            check_divisor(&mut b, exception);
+            b.const_binary32(Add, BSP, BSP, CELL);
+            load(&mut b, R3, BSP, bad_address);
+            signed_div_mod(&mut b, true);
+            store(&mut b, R3, BSP, bad_address);
+            b.jump(next)
+        });
+
+        // /MOD
+        actions[0x28] = build(|mut b| {
+            check_depth(&mut b, 2, underflow);
+            load(&mut b, R2, BSP, bad_address);
+            check_divisor(&mut b, exception);
+            b.const_binary32(Add, R1, BSP, CELL);
+            load(&mut b, R3, R1, bad_address);
+            signed_div_mod(&mut b, true);
+            b.const_binary32(Add, R2, BSP, CELL);
+            store(&mut b, R3, R2, bad_address);
+            store(&mut b, R1, BSP, bad_address);
+            b.jump(next)
+        });
+
+        // U/MOD
+        actions[0x29] = build(|mut b| {
+            check_depth(&mut b, 2, underflow);
+            load(&mut b, R2, BSP, bad_address);
+            check_divisor(&mut b, exception);
+            b.const_binary32(Add, R1, BSP, CELL);
+            load(&mut b, R3, R1, bad_address);
+            unsigned_div_mod(&mut b);
+            b.const_binary32(Add, R2, BSP, CELL);
+            store(&mut b, R3, R2, bad_address);
+            store(&mut b, R1, BSP, bad_address);
+            b.jump(next)
+        });
+
+        // S/REM
+        actions[0x2A] = build(|mut b| {
+            check_depth(&mut b, 2, underflow);
+            load(&mut b, R2, BSP, bad_address);
+            check_divisor(&mut b, exception);
+            b.const_binary32(Add, R1, BSP, CELL);
+            load(&mut b, R3, R1, bad_address);
+            signed_div_mod(&mut b, false);
+            b.const_binary32(Add, R2, BSP, CELL);
+            store(&mut b, R3, R2, bad_address);
+            store(&mut b, R1, BSP, bad_address);
+            b.jump(next)
         });
 
         // ABS
         actions[0x2D] = build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.unary32(Abs, R2, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // NEGATE
         actions[0x2E] = build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.unary32(Negate, R2, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // MAX
         actions[0x2F] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Max, R2, R3, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         actions[0x30] = // MIN
         build(|mut b| {
-            pop(&mut b, R2, BSP);
-            load(&mut b, R3, BSP);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            load(&mut b, R3, BSP, bad_address);
             b.binary32(Min, R2, R3, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         actions[0x31] = // INVERT
         build(|mut b| {
-            load(&mut b, R2, BSP);
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R2, BSP, bad_address);
             b.unary32(Not, R2, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // @
         actions[0x39] = build(|mut b| {
-            load(&mut b, R2, BSP);
-            load(&mut b, R2, R2);
-            store(&mut b, R2, BSP);
-            b.jump(root)
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R2, BSP, bad_address);
+            load(&mut b, R2, R2, bad_address);
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
         });
 
         // !
         actions[0x3A] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            pop(&mut b, R3, BSP);
-            store(&mut b, R3, R2);
-            b.jump(root)
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            pop(&mut b, R3, BSP, None, bad_address);
+            store(&mut b, R3, R2, bad_address);
+            b.jump(next)
         });
 
         // +!
         actions[0x3D] = build(|mut b| {
-            pop(&mut b, R2, BSP);
-            pop(&mut b, R3, BSP);
-            load(&mut b, R1, R2);
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R2, BSP, None, bad_address);
+            pop(&mut b, R3, BSP, None, bad_address);
+            load(&mut b, R1, R2, bad_address);
             b.binary32(Add, R3, R1, R3);
-            store(&mut b, R3, R2);
-            b.jump(root)
+            store(&mut b, R3, R2, bad_address);
+            b.jump(next)
+        });
+
+        // BRANCH
+        actions[0x42] = build(|mut b| {
+            load(&mut b, BEP, BEP, bad_address);
+            fetch(&mut b, bad_address);
+            b.jump(next)
         });
 
         // BRANCHI
         actions[0x43] = build(|b| { b.jump(branchi) });
 
+        // ?BRANCH
+        actions[0x44] = build(|mut b| {
+            pop(&mut b, BI, BSP, underflow, bad_address);
+            b.if_(BI,
+                build(|mut b| {
+                    b.const_binary32(Add, BEP, BEP, CELL);
+                    pop(&mut b, BA, BEP, None, bad_address);
+                    b.jump(next)
+                }),
+                build(|mut b| {
+                    load(&mut b, BEP, BEP, bad_address);
+                    fetch(&mut b, bad_address);
+                    b.jump(next)
+                }),
+            )
+        });
+
         // ?BRANCHI
         actions[0x45] = build(|mut b| {
-            pop(&mut b, BI, BSP);
+            pop(&mut b, BI, BSP, underflow, bad_address);
             b.if_(BI,
                 build(|mut b| {
-                    pop(&mut b, BA, BEP);
-                    b.jump(root)
+                    pop(&mut b, BA, BEP, None, bad_address);
+                    b.jump(next)
                 }),
                 build(|b| { b.jump(branchi) }),
             )
         });
 
+        // CALL
+        actions[0x48] = build(|mut b| {
+            load(&mut b, R1, BEP, bad_address);
+            b.const_binary32(Add, BEP, BEP, CELL);
+            push(&mut b, BEP, BRP, stack_overflow, bad_address);
+            b.move_(BEP, R1);
+            fetch(&mut b, bad_address);
+            b.jump(next)
+        });
+
         // CALLI
         actions[0x49] = build(|mut b| {
-            push(&mut b, BEP, BRP);
+            push(&mut b, BEP, BRP, stack_overflow, bad_address);
             b.jump(branchi)
         });
 
         // EXIT
         actions[0x4A] = build(|mut b| {
-            pop(&mut b, BEP, BRP);
-            pop(&mut b, BA, BEP);
-            b.jump(root)
+            pop(&mut b, BEP, BRP, underflow, bad_address);
+            fetch(&mut b, bad_address);
+            b.jump(next)
+        });
+
+        // EXECUTE
+        actions[0x4B] = build(|mut b| {
+            pop(&mut b, R1, BSP, underflow, bad_address);
+            push(&mut b, BEP, BRP, stack_overflow, bad_address);
+            b.move_(BEP, R1);
+            fetch(&mut b, bad_address);
+            b.jump(next)
         });
 
         // (LITERAL)I
         actions[0x53] = build(|mut b| {
-            push(&mut b, BA, BSP);
-            pop(&mut b, BA, BEP);
-            b.jump(root)
+            push(&mut b, BA, BSP, stack_overflow, bad_address);
+            pop(&mut b, BA, BEP, None, bad_address);
+            b.jump(next)
+        });
+
+        // THROW
+        actions[0x5E] = build(|b| { b.jump(throw) });
+
+        // The following opcodes are extensions, and are not part of the
+        // Beetle specification.
+
+        // MOVE ( addr1 addr2 u -- )
+        actions[0x63] = build(|mut b| {
+            check_depth(&mut b, 3, underflow);
+            pop(&mut b, R1, BSP, None, bad_address);
+            pop(&mut b, R3, BSP, None, bad_address);
+            load(&mut b, R2, BSP, bad_address);
+            b.binary32(Sub, R3, R3, R2);
+            store(&mut b, R3, BSP, bad_address);
+            b.store(BA, register!(a));
+            b.move_(BA, R1);
+            b.const_binary32(Lt, R3, R3, 0);
+            b.if_(R3,
+                build(|mut b| {
+                    b.move_(BI, R2);
+                    b.jump(copy_up)
+                }),
+                build(|mut b| {
+                    b.binary32(Add, BI, R2, R1);
+                    b.jump(copy_down)
+                }),
+            )
+        });
+
+        // CMOVE ( c-addr1 c-addr2 u -- )
+        actions[0x64] = build(|mut b| {
+            check_depth(&mut b, 3, underflow);
+            pop(&mut b, R1, BSP, None, bad_address);
+            pop(&mut b, R3, BSP, None, bad_address);
+            load(&mut b, R2, BSP, bad_address);
+            b.binary32(Sub, R3, R3, R2);
+            store(&mut b, R3, BSP, bad_address);
+            b.store(BA, register!(a));
+            b.move_(BA, R1);
+            b.move_(BI, R2);
+            b.jump(copy_up)
         });
 
+        // CMOVE> ( c-addr1 c-addr2 u -- )
+        actions[0x65] = build(|mut b| {
+            check_depth(&mut b, 3, underflow);
+            pop(&mut b, R1, BSP, None, bad_address);
+            pop(&mut b, R3, BSP, None, bad_address);
+            load(&mut b, R2, BSP, bad_address);
+            b.binary32(Sub, R3, R3, R2);
+            store(&mut b, R3, BSP, bad_address);
+            b.store(BA, register!(a));
+            b.move_(BA, R1);
+            b.binary32(Add, BI, R2, R1);
+            b.jump(copy_down)
+        });
+
+        // FILL ( c-addr u char -- )
+        actions[0x66] = build(|mut b| {
+            check_depth(&mut b, 3, underflow);
+            pop(&mut b, R3, BSP, None, bad_address);
+            pop(&mut b, R1, BSP, None, bad_address);
+            load(&mut b, R2, BSP, bad_address);
+            store(&mut b, R3, BSP, bad_address);
+            b.store(BA, register!(a));
+            b.move_(BA, R1);
+            b.move_(BI, R2);
+            b.jump(fill)
+        });
+
+        // The double-cell words access both cells with one access if the
+        // address is a multiple of two cells, and otherwise access the cells
+        // separately, so that both addresses are checked.
+
+        // 2@ ( a-addr -- x1 x2 )
+        actions[0x67] = build(|mut b| {
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R1, BSP, bad_address);
+            b.const_binary32(And, R2, R1, 2 * CELL - 1);
+            b.if_(R2,
+                build(|mut b| {
+                    b.const_binary32(Add, R2, R1, CELL);
+                    load(&mut b, R3, R2, bad_address);
+                    load(&mut b, R2, R1, bad_address);
+                    store(&mut b, R3, BSP, bad_address);
+                    push(&mut b, R2, BSP, stack_overflow, bad_address);
+                    b.jump(next)
+                }),
+                build(|mut b| {
+                    load_width(&mut b, Eight, R2, R1, bad_address);
+                    b.const_binary64(Lsr, R3, R2, CELL_BITS.into());
+                    store(&mut b, R3, BSP, bad_address);
+                    push(&mut b, R2, BSP, stack_overflow, bad_address);
+                    b.jump(next)
+                }),
+            )
+        });
+
+        // 2! ( x1 x2 a-addr -- )
+        actions[0x68] = build(|mut b| {
+            check_depth(&mut b, 3, underflow);
+            pop(&mut b, R1, BSP, None, bad_address);
+            b.const_binary32(And, R2, R1, 2 * CELL - 1);
+            b.if_(R2,
+                build(|mut b| {
+                    pop(&mut b, R2, BSP, None, bad_address);
+                    store(&mut b, R2, R1, bad_address);
+                    pop(&mut b, R3, BSP, None, bad_address);
+                    b.const_binary32(Add, R1, R1, CELL);
+                    store(&mut b, R3, R1, bad_address);
+                    b.jump(next)
+                }),
+                build(|mut b| {
+                    pop(&mut b, R2, BSP, None, bad_address);
+                    pop(&mut b, R3, BSP, None, bad_address);
+                    b.const_binary64(Lsl, R3, R3, CELL_BITS.into());
+                    b.binary64(Or, R3, R3, R2);
+                    store_width(&mut b, Eight, R3, R1, bad_address);
+                    b.jump(next)
+                }),
+            )
+        });
+
+        // W@ ( addr -- x )
+        actions[0x69] = build(|mut b| {
+            check_depth(&mut b, 1, underflow);
+            load(&mut b, R1, BSP, bad_address);
+            load_width(&mut b, Two, R2, R1, bad_address);
+            store(&mut b, R2, BSP, bad_address);
+            b.jump(next)
+        });
+
+        // W! ( x addr -- )
+        actions[0x6A] = build(|mut b| {
+            check_depth(&mut b, 2, underflow);
+            pop(&mut b, R1, BSP, None, bad_address);
+            pop(&mut b, R2, BSP, None, bad_address);
+            store_width(&mut b, Two, R2, R1, bad_address);
+            b.jump(next)
+        });
+
+        // COMPARE ( c-addr1 u1 c-addr2 u2 -- n )
+        // While comparing, the stack holds the result if the common prefix
+        // matches, and `c-addr2` minus `c-addr1`.
+        actions[0x6B] = build(|mut b| {
+            check_depth(&mut b, 4, underflow);
+            b.store(BA, register!(a));
+            pop(&mut b, R1, BSP, None, bad_address);
+            pop(&mut b, R2, BSP, None, bad_address);
+            pop(&mut b, R3, BSP, None, bad_address);
+            load(&mut b, BA, BSP, bad_address);
+            b.binary32(Sub, R2, R2, BA);
+            b.const_binary32(Sub, BSP, BSP, CELL);
+            store(&mut b, R2, BSP, bad_address);
+            b.binary32(Ult, R2, R3, R1);
+            b.binary32(Ult, BI, R1, R3);
+            b.select32(R1, R2, R3, R1);
+            b.binary32(Sub, R2, R2, BI);
+            b.const_binary32(Add, R3, BSP, CELL);
+            store(&mut b, R2, R3, bad_address);
+            b.move_(BI, BA);
+            b.move_(BA, R1);
+            b.jump(compare)
+        });
+
+        if tracing {
+            actions = actions.into_vec().into_iter().map(traced).collect();
+        }
+
         // Main dispatch loop.
-        jit.define(root, &build(|mut b| {
+        // `BA` holds the undispatched opcodes of the current cell, and it
+        // lives in a register from the prologue to the epilogue, so
+        // dispatching an opcode touches neither the `Registers` struct nor
+        // the Beetle memory. Only NEXT (opcode zero, which is what remains
+        // when the cell is exhausted) and the branches fetch a new cell.
+        // Decoding the cell into separate per-byte globals would need four
+        // more registers than we have, and would gain nothing.
+        define(&mut definitions, root, build(|mut b| {
             b.const_binary32(And, BI, BA, 0xFF);
             b.const_binary32(Asr, BA, BA, 8);
             b.index(BI, actions, build(|b| b.jump(not_implemented)))
         }));
+        if num_threads == 1 {
+            for (entry, ebb) in &definitions { jit.define(*entry, ebb); }
+        } else {
+            let definitions: Vec<(EntryId, &EBB<EntryId>)> =
+                definitions.iter().map(|(entry, ebb)| (*entry, ebb)).collect();
+            jit.define_all(&definitions, num_threads);
+        }
+
+        Self {jit, root, single_step}
+    }
 
-        Self {jit, root}
+    /// Returns `true` if `self` was compiled with
+    /// [`BeetleOptions::single_step`].
+    pub fn is_single_step(&self) -> bool { self.single_step }
+
+    /// Returns the number of instructions dispatched so far, or `None` if
+    /// `self` was not compiled with profiling. See [`Jit::set_profiling()`].
+    pub fn instructions(&self) -> Option<u64> {
+        self.jit.profile(self.root).map(|profile| profile.count)
     }
 
+    /// Runs the code until it reaches an instruction that it does not
+    /// implement, until a push would overflow a stack, until an exception
+    /// cannot be thrown, or in single-step mode until it has executed one
+    /// instruction. If a stack overflows, the
+    /// instruction may have been partly executed, but the stack pointer has
+    /// not been moved below its limit.
+    ///
     /// # Safety
     ///
-    /// There is no memory bounds checking in this implementation of Beetle.
-    pub unsafe fn run(&mut self, registers: &mut M0Registers) {
+    /// Memory accesses are checked against [`Registers::memory_size`], which
+    /// must be a multiple of [`CELL`] and must not exceed the size of the
+    /// memory at `registers.m0`.
+    pub unsafe fn run(&mut self, registers: &mut M0Registers) -> Stop {
         let result = self.jit.run(self.root, registers);
-        assert_eq!(result, Word {s: NOT_IMPLEMENTED});
+        match result.s {
+            NOT_IMPLEMENTED => Stop::NotImplemented,
+            STACK_OVERFLOW => Stop::StackOverflow,
+            STEP => Stop::Step,
+            BAD_THROW => Stop::BadThrow,
+            s => panic!("Unexpected return code {}", s),
+        }
     }
 }
 
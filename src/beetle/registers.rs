@@ -1,12 +1,69 @@
 /// Beetle's registers.
 #[repr(C)]
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct Registers {
     pub ep: u32,
     pub i: u32,
     pub a: u32,
     pub sp: u32,
     pub rp: u32,
+    /// The number of NEXTs remaining before the NEXT-time hook is called.
+    pub next_count: u32,
+    /// The value to which `next_count` is reset when it reaches zero.
+    pub next_period: u32,
+    /// The address of the word called by the NEXT-time hook.
+    pub next_hook: u32,
+    /// The value of `ep` when the most recent exception was raised.
+    pub bad: u32,
+    /// The address of the cell holding the address of the exception handler.
+    pub throw: u32,
+    /// The size of the memory in bytes. Addresses must be less than this.
+    pub memory_size: u32,
+    /// The address that caused the most recent invalid address exception.
+    pub not_address: u32,
+    /// The lowest address of the data stack. A push that would move `sp`
+    /// below this stops the VM. Zero disables the check.
+    pub s_limit: u32,
+    /// The lowest address of the return stack. A push that would move `rp`
+    /// below this stops the VM. Zero disables the check.
+    pub r_limit: u32,
+    /// The address just above the data stack, i.e. the value of `sp` when
+    /// the stack is empty. When checking stacks, a pop that would move `sp`
+    /// above this raises an exception instead.
+    pub s0: u32,
+    /// The address just above the return stack. See `s0`.
+    pub r0: u32,
+}
+
+impl Registers {
+    /// The number of registers.
+    pub const COUNT: usize = 16;
+
+    /// Returns the registers in declaration order.
+    pub fn to_array(&self) -> [u32; Self::COUNT] {
+        [
+            self.ep, self.i, self.a, self.sp, self.rp,
+            self.next_count, self.next_period, self.next_hook,
+            self.bad, self.throw, self.memory_size, self.not_address,
+            self.s_limit, self.r_limit, self.s0, self.r0,
+        ]
+    }
+
+    /// The inverse of `to_array()`.
+    pub fn from_array(array: [u32; Self::COUNT]) -> Self {
+        let [
+            ep, i, a, sp, rp,
+            next_count, next_period, next_hook,
+            bad, throw, memory_size, not_address,
+            s_limit, r_limit, s0, r0,
+        ] = array;
+        Registers {
+            ep, i, a, sp, rp,
+            next_count, next_period, next_hook,
+            bad, throw, memory_size, not_address,
+            s_limit, r_limit, s0, r0,
+        }
+    }
 }
 
 impl std::fmt::Debug for Registers {
@@ -17,6 +74,17 @@ impl std::fmt::Debug for Registers {
             .field("a", &format!("{:#x}", self.a))
             .field("sp", &format!("{:#x}", self.sp))
             .field("rp", &format!("{:#x}", self.rp))
+            .field("next_count", &self.next_count)
+            .field("next_period", &self.next_period)
+            .field("next_hook", &format!("{:#x}", self.next_hook))
+            .field("bad", &format!("{:#x}", self.bad))
+            .field("throw", &format!("{:#x}", self.throw))
+            .field("memory_size", &format!("{:#x}", self.memory_size))
+            .field("not_address", &format!("{:#x}", self.not_address))
+            .field("s_limit", &format!("{:#x}", self.s_limit))
+            .field("r_limit", &format!("{:#x}", self.r_limit))
+            .field("s0", &format!("{:#x}", self.s0))
+            .field("r0", &format!("{:#x}", self.r0))
             .finish()
     }
 }
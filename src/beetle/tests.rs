@@ -1,155 +1,11 @@
-use super::super::target::{Native, native};
-
-use super::{Registers, M0Registers, CELL, Beetle};
-
-/// The suggested size of the Beetle memory, in cells.
-pub const MEMORY_CELLS: u32 = 1 << 20;
-/// The suggested size of the Beetle data stack, in cells.
-pub const DATA_CELLS: u32 = 1 << 18;
-/// The suggested size of the Beetle return stack, in cells.
-pub const RETURN_CELLS: u32 = 1 << 18;
-
-pub struct VM {
-    /// The compiled code.
-    beetle: super::Beetle<Native>,
-    /// The Beetle state (other than the memory).
-    state: M0Registers,
-    /// The Beetle memory.
-    memory: Vec<u32>,
-    /// The amount of unallocated memory, in cells.
-    free_cells: u32,
-    /// The address of a HALT instruction.
-    halt_addr: u32,
-}
-
-impl VM {
-    /// Constructs a Beetle virtual machine with the specified parameters.
-    ///
-    /// The memory is `memory_cells` cells. The data stack occupies the last
-    /// `data_cells` cells of the memory, and the return stack occupies
-    /// the last `return_cells` cells before that. The cells before that
-    /// are free for the program's use.
-    pub fn new(
-        memory_cells: u32,
-        data_cells: u32,
-        return_cells: u32,
-    ) -> Self {
-        let mut vm = VM {
-            beetle: Beetle::new(native()),
-            state: M0Registers {
-                m0: std::ptr::null_mut(),
-                registers: Registers::default(),
-            },
-            memory: vec![0; memory_cells as usize],
-            free_cells: memory_cells,
-            halt_addr: 0,
-        };
-        // Allocate the return stack.
-        vm.rp = vm.allocate(return_cells).1;
-        // Allocate the data stack.
-        vm.sp = vm.allocate(data_cells).1;
-        // Allocate a word to hold a HALT instruction.
-        vm.halt_addr = vm.allocate(1).0;
-        vm.store(vm.halt_addr, 0x5519);
-        vm
-    }
-
-    /// Read the memory.
-    pub fn memory(&self) -> &[u32] { &self.memory }
-
-    /// Allocate `cells` cells and return a (start, end) Beetle pointer pair.
-    /// Allocation starts at the top of memory and is permanent.
-    pub fn allocate(&mut self, cells: u32) -> (u32, u32) {
-        assert!(cells <= self.free_cells);
-        let end = self.free_cells.checked_mul(CELL as u32)
-            .expect("Address out of range");
-        self.free_cells = self.free_cells.checked_sub(cells)
-            .expect("Out of memory");
-        let start = self.free_cells.checked_mul(CELL as u32)
-            .expect("Address out of range");
-        (start, end)
-    }
-
-    /// Load `object` at address zero, i.e. in the unallocated memory.
-    pub fn load_object(&mut self, object: &[u32]) {
-        assert!(object.len() <= self.free_cells as usize);
-        for (i, &cell) in object.iter().enumerate() {
-            self.memory[i] = cell;
-        }
-    }
-
-    /// Return the value of the word at address `addr`.
-    pub fn load(&self, addr: u32) -> u32 {
-        assert_eq!(addr & 0x3, 0);
-        self.memory[(addr >> 2) as usize]
-    }
-
-    /// Set the word at address `addr` to `value`.
-    pub fn store(&mut self, addr: u32, value: u32) {
-        assert_eq!(addr & 0x3, 0);
-        self.memory[(addr >> 2) as usize] = value;
-    }
+use super::vm::*;
+use super::*;
+use crate::code::Action;
+use crate::code::builder::build;
+use crate::target::native;
 
-    /// Push `item` onto the data stack.
-    pub fn push(&mut self, item: u32) {
-        self.sp -= CELL as u32;
-        self.store(self.sp, item);
-    }
-
-    /// Pop an item from the data stack.
-    pub fn pop(&mut self) -> u32 {
-        let item = self.load(self.sp);
-        self.sp += CELL as u32;
-        item
-    }
-
-    /// Push `item` onto the return stack.
-    pub fn rpush(&mut self, item: u32) {
-        self.rp -= CELL as u32;
-        self.store(self.rp, item);
-    }
-
-    /// Run the code at address `ep`. If it `HALT`s, return the code.
-    pub unsafe fn run(&mut self, ep: u32) -> Option<u32> {
-        assert!(Self::is_aligned(ep));
-        self.ep = ep;
-        self.state.m0 = self.memory.as_mut_ptr();
-        self.beetle.run(&mut self.state);
-        if self.a & 0xFF == 0x55 {
-            // Halt.
-            self.a >>= 8;
-            Some(self.pop())
-        } else {
-            // Some other not implemented case.
-            None
-        }
-    }
+use std::time::{Duration, Instant};
 
-    /// Indicate whether an address is cell-aligned.
-    pub fn is_aligned(addr: u32) -> bool {
-        addr & 0x3 == 0
-    }
-}
-
-impl std::fmt::Debug for VM {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-        f.debug_struct("VM")
-            .field("state", &self.state)
-            .field("m0", &format!("{:#x}", self.memory().as_ptr() as u64))
-            .finish()
-    }
-}
-
-impl std::ops::Deref for VM {
-    type Target = M0Registers;
-    fn deref(&self) -> &Self::Target { &self.state }
-}
-
-impl std::ops::DerefMut for VM {
-    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.state }
-}
-
-//-----------------------------------------------------------------------------
 
 pub fn ackermann_object() -> Vec<u32> {
     // Forth source:
@@ -203,13 +59,25 @@ pub fn halt() {
     let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
     let initial_sp = vm.sp;
     let initial_rp = vm.rp;
-    let entry_address = vm.halt_addr;
+    let entry_address = vm.halt_addr();
     let exit = unsafe { vm.run(entry_address) };
-    assert_eq!(exit, Some(0));
+    assert_eq!(exit, BeetleExit::Halt(0));
     assert_eq!(vm.sp, initial_sp);
     assert_eq!(vm.rp, initial_rp);
 }
 
+#[test]
+#[should_panic(expected = "Address out of range")]
+pub fn memory_too_big() {
+    VM::new(u32::MAX / CELL as u32 + 1, DATA_CELLS, RETURN_CELLS);
+}
+
+#[test]
+#[should_panic]
+pub fn stacks_too_big() {
+    VM::new(MEMORY_CELLS, u32::MAX - 1, 2);
+}
+
 #[test]
 pub fn ackermann() {
     let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
@@ -218,11 +86,1448 @@ pub fn ackermann() {
     let initial_rp = vm.rp;
     vm.push(3);
     vm.push(5);
-    vm.rpush(vm.halt_addr);
+    vm.rpush(vm.halt_addr());
     let exit = unsafe { vm.run(0) };
-    assert_eq!(exit, Some(0));
+    assert_eq!(exit, BeetleExit::Halt(0));
     let result = vm.pop();
     assert_eq!(vm.sp, initial_sp);
     assert_eq!(vm.rp, initial_rp);
     assert_eq!(result, 253);
 }
+
+/// Test that the Ackermann benchmark gives the same result when Beetle is
+/// interpreted instead of compiled.
+#[test]
+pub fn ackermann_interpreted() {
+    let mut vm = VM::interpreted(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(ackermann_object().as_ref());
+    let initial_sp = vm.sp;
+    let initial_rp = vm.rp;
+    vm.push(3);
+    vm.push(5);
+    vm.rpush(vm.halt_addr());
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    let result = vm.pop();
+    assert_eq!(vm.sp, initial_sp);
+    assert_eq!(vm.rp, initial_rp);
+    assert_eq!(result, 253);
+}
+
+/// Test that several threads can run Ackermann simultaneously, each with its
+/// own memory, using the same compiled code.
+#[test]
+pub fn ackermann_threads() {
+    let beetle = Beetle::shared(native());
+    let threads: Vec<_> = (0..4).map(|i| {
+        let beetle = beetle.clone();
+        std::thread::spawn(move || {
+            let mut vm = VM::with_shared(&beetle, MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+            vm.load_object(ackermann_object().as_ref());
+            vm.push(3);
+            vm.push(i);
+            vm.rpush(vm.halt_addr());
+            let exit = unsafe { vm.run(0) };
+            assert_eq!(exit, BeetleExit::Halt(0));
+            vm.pop()
+        })
+    }).collect();
+    let results: Vec<u32> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    // A(3, n) = 2^(n+3) - 3.
+    assert_eq!(results, [5, 13, 29, 61]);
+}
+
+/// Test that the [`Interpreter`] and the compiled code agree on the registers
+/// and the memory after each of the first instructions of the Ackermann
+/// benchmark.
+///
+/// [`Interpreter`]: crate::jit::Interpreter
+#[test]
+pub fn single_step_interpreted() {
+    const STEPS: usize = 1000;
+    let options = BeetleOptions {single_step: true, ..BeetleOptions::default()};
+    let mut jit_vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 0);
+    let mut interpreted_vm = VM::interpreted_with_single_step(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    jit_vm.load_object(ackermann_object().as_ref());
+    interpreted_vm.load_object(ackermann_object().as_ref());
+    jit_vm.push(3);
+    jit_vm.push(5);
+    jit_vm.rpush(jit_vm.halt_addr());
+    jit_vm.ep = 0;
+    interpreted_vm.push(3);
+    interpreted_vm.push(5);
+    interpreted_vm.rpush(interpreted_vm.halt_addr());
+    interpreted_vm.ep = 0;
+    // `i` is used as a temporary, and may hold a native address.
+    let registers = |registers: &Registers| Registers {i: 0, ..registers.clone()}.to_array();
+    for step in 0..STEPS {
+        let jit_exit = unsafe { jit_vm.step() };
+        let interpreted_exit = unsafe { interpreted_vm.step() };
+        assert_eq!(interpreted_exit, jit_exit, "exit after step {}", step);
+        assert_eq!(interpreted_vm.last_opcode(), jit_vm.last_opcode(), "opcode at step {}", step);
+        assert_eq!(registers(&interpreted_vm), registers(&jit_vm), "registers after step {}", step);
+        assert!(interpreted_vm.memory() == jit_vm.memory(), "memory after step {}", step);
+        assert_eq!(jit_exit, None);
+    }
+}
+
+#[test]
+pub fn single_step() {
+    let options = BeetleOptions {single_step: true, ..BeetleOptions::default()};
+    let mut vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 0);
+    vm.load_object(ackermann_object().as_ref());
+    let initial_sp = vm.sp;
+    vm.push(3);
+    vm.push(5);
+    vm.rpush(vm.halt_addr());
+    vm.ep = 0;
+    let stack = |vm: &VM| {
+        let mut stack = Vec::new();
+        let mut sp = vm.sp;
+        while sp < initial_sp {
+            stack.insert(0, vm.load(sp));
+            sp += CELL as u32;
+        }
+        stack
+    };
+    for (opcode, expected) in [
+        (0x00, vec![3, 5]), // NEXT
+        (0x04, vec![3, 5, 3]), // OVER
+        (0x15, vec![3, 5, 0]), // 0=
+        (0x00, vec![3, 5, 0]), // NEXT
+        (0x45, vec![3, 5]), // ?BRANCHI
+    ] {
+        assert_eq!(unsafe { vm.step() }, None);
+        assert_eq!(vm.last_opcode(), Some(opcode));
+        assert_eq!(stack(&vm), expected);
+    }
+    assert_eq!(vm.ep, 0x14);
+    // Finish the program.
+    let exit = loop {
+        if let Some(exit) = unsafe { vm.step() } { break exit; }
+    };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    assert_eq!(vm.pop(), 253);
+}
+
+/// Test that the Beetle registers are passed between instructions in
+/// registers, so that dispatching an instruction and returning to `root`
+/// loads nothing from the [`Registers`] struct.
+#[test]
+pub fn registers_between_instructions() {
+    let beetle = Beetle::new(native());
+    let convention = beetle.jit.convention(beetle.root);
+    assert_eq!(convention.slots_used, 0);
+    for r in [BEP, BI, BA, BSP, BRP, M0, REGS] {
+        assert!(convention.lives.contains(&r.into()), "{:?} is not live", r);
+    }
+}
+
+/// Test that compiling Beetle twice gives the same code, whatever the order
+/// in which the live values are found.
+#[test]
+pub fn deterministic() {
+    let beetle1 = Beetle::new(native());
+    let beetle2 = Beetle::new(native());
+    assert_eq!(beetle1.jit.convention(beetle1.root).lives, beetle2.jit.convention(beetle2.root).lives);
+    assert_eq!(beetle1.jit.code_bytes(beetle1.root), beetle2.jit.code_bytes(beetle2.root));
+}
+
+/// Test that the compiled code does not depend on the number of threads
+/// that optimize it.
+#[test]
+pub fn compile_threads() {
+    for options in [
+        BeetleOptions::default(),
+        BeetleOptions {next_hook: true, checked_stacks: true, ..BeetleOptions::default()},
+    ] {
+        let beetle1 = Beetle::with_threads(native(), options, 1);
+        let beetle4 = Beetle::with_threads(native(), options, 4);
+        assert_eq!(beetle1.jit.fingerprint(), beetle4.jit.fingerprint());
+        assert_eq!(beetle1.jit.code_bytes(beetle1.root), beetle4.jit.code_bytes(beetle4.root));
+    }
+}
+
+/// Compares the time taken to compile Beetle with one thread and with one
+/// per CPU. Run it with `cargo test --release -- --ignored compile_time`.
+/// With only one CPU there is nothing to compare.
+#[test]
+#[ignore]
+pub fn compile_time() {
+    const REPEATS: u32 = 20;
+    let num_threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let time = |num_threads| {
+        let start = Instant::now();
+        for _ in 0..REPEATS {
+            Beetle::with_threads(native(), BeetleOptions::default(), num_threads);
+        }
+        start.elapsed() / REPEATS
+    };
+    let sequential = time(1);
+    let parallel = time(num_threads);
+    println!("1 thread: {:?}, {} threads: {:?}", sequential, num_threads, parallel);
+    if num_threads > 1 { assert!(parallel < sequential); }
+}
+
+#[test]
+pub fn tracing() {
+    let root = Beetle::new(native()).root;
+    let ebb = build(|mut b| {
+        b.const_binary32(Add, R1, R1, 1);
+        b.jump(root)
+    });
+    let traced_ebb = traced(ebb.clone());
+    assert_eq!(traced_ebb.actions.len(), ebb.actions.len() + 1);
+    assert_eq!(traced_ebb.actions[0], Action::Debug(BI.into()));
+    assert_eq!(traced_ebb.actions[1..], ebb.actions[..]);
+    // The `Debug`s are compiled even in release builds.
+    let nodes_created = |beetle: Beetle<Jit<_>>| beetle.jit.stats(beetle.root).expect("Missing stats").nodes_created;
+    let options = BeetleOptions {tracing: true, ..BeetleOptions::default()};
+    assert!(nodes_created(Beetle::with_options(native(), options)) > nodes_created(Beetle::new(native())));
+    // The trace does not change the results.
+    let mut vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 0);
+    vm.load_object(ackermann_object().as_ref());
+    vm.push(2);
+    vm.push(3);
+    vm.rpush(vm.halt_addr());
+    assert_eq!(unsafe { vm.run(0) }, BeetleExit::Halt(0));
+    assert_eq!(vm.pop(), 9);
+}
+
+#[test]
+pub fn profiling() {
+    let run = |mut vm: VM| {
+        vm.load_object(ackermann_object().as_ref());
+        vm.push(2);
+        vm.push(3);
+        vm.rpush(vm.halt_addr());
+        vm.ep = 0;
+        vm
+    };
+    // Count the instructions.
+    let options = BeetleOptions {single_step: true, ..BeetleOptions::default()};
+    let mut vm = run(VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 0));
+    let mut steps = 0;
+    while unsafe { vm.step() }.is_none() { steps += 1; }
+    steps += 1;
+    // Count the dispatches.
+    let options = BeetleOptions {profiling: true, ..BeetleOptions::default()};
+    let mut vm = run(VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 0));
+    assert_eq!(unsafe { vm.run(0) }, BeetleExit::Halt(0));
+    assert_eq!(vm.pop(), 9);
+    let beetle = vm.beetle();
+    let profile = beetle.jit.profile(beetle.root).unwrap();
+    assert_eq!(profile.count, steps);
+    // Every dispatch leaves the dispatch code exactly once.
+    assert_eq!(profile.exits.iter().map(|&(_, count)| count).sum::<u64>(), steps);
+    // The code compiled without profiling has no profile.
+    let vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    assert_eq!(vm.beetle().jit.profile(vm.beetle().root), None);
+}
+
+#[test]
+pub fn superinstructions() {
+    let options = BeetleOptions {profiling: true, ..BeetleOptions::default()};
+    let mut vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 0);
+    vm.load_object(ackermann_object().as_ref());
+    // Returns the number of dispatches from the root so far.
+    let root_count = |vm: &VM| vm.beetle().jit.profile(vm.beetle().root).unwrap().count;
+    let mut counts = Vec::new();
+    for _ in 0..3 {
+        let before = root_count(&vm);
+        vm.push(3);
+        vm.push(5);
+        vm.rpush(vm.halt_addr());
+        assert_eq!(unsafe { vm.run(0) }, BeetleExit::Halt(0));
+        assert_eq!(vm.pop(), 253);
+        counts.push(root_count(&vm) - before);
+        // Specializing changes the version and the code of the root.
+        let root = vm.beetle().root;
+        let version = vm.beetle().jit.entry_version(root);
+        let code_len = |vm: &VM| vm.beetle().jit.code_bytes(root).unwrap().len();
+        let len = code_len(&vm);
+        assert_eq!(vm.specialize(u64::MAX, 50), 0);
+        assert!(vm.beetle().jit.is_current(root, version));
+        assert_eq!(code_len(&vm), len);
+        assert!(vm.specialize(100, 50) > 0);
+        assert!(!vm.beetle().jit.is_current(root, version));
+        assert!(code_len(&vm) > len);
+    }
+    assert!(counts[1] < counts[0], "{:?}", counts);
+    assert!(counts[2] < counts[1], "{:?}", counts);
+}
+
+/// Runs `opcode` on the stack `[n1, n2]` and returns the resulting stack.
+fn run_opcode(opcode: u32, n1: u32, n2: u32) -> Vec<u32> {
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    // Beetle assembler:
+    // $00: opcode
+    //      0
+    //      HALT
+    vm.load_object(&[0x00551900 | opcode]);
+    let initial_sp = vm.sp;
+    vm.push(n1);
+    vm.push(n2);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    let mut stack = Vec::new();
+    while vm.sp < initial_sp {
+        stack.insert(0, vm.pop());
+    }
+    stack
+}
+
+#[test]
+pub fn one_minus() {
+    const ONE_MINUS: u32 = 0x22;
+    assert_eq!(run_opcode(ONE_MINUS, 7, 5), [7, 4]);
+    assert_eq!(run_opcode(ONE_MINUS, 7, 0), [7, 0xFFFFFFFF]);
+    assert_eq!(run_opcode(ONE_MINUS, 7, 0x80000000), [7, 0x7FFFFFFF]);
+}
+
+#[test]
+pub fn stack_overflow() {
+    const DATA_CELLS: u32 = 4;
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    // Beetle assembler:
+    // $00: DUP DUP DUP DUP
+    vm.load_object(&[0x01010101]);
+    let initial_sp = vm.sp;
+    vm.push(7);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::StackOverflow);
+    assert_eq!(vm.sp, vm.s_limit);
+    assert_eq!(vm.s_limit, initial_sp - DATA_CELLS * CELL as u32);
+    for _ in 0..DATA_CELLS { assert_eq!(vm.pop(), 7); }
+    // The cell below the data stack is unharmed.
+    assert_eq!(vm.load(vm.halt_addr()), 0x5519);
+}
+
+#[test]
+pub fn double_cells() {
+    const TWO_FETCH: u32 = 0x67;
+    const TWO_STORE: u32 = 0x68;
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    let initial_sp = vm.sp;
+    // One access of eight bytes, and two accesses of four.
+    for addr in [0x100, 0x104] {
+        // Beetle assembler:
+        // $00: 2! 0 HALT
+        vm.load_object(&[0x00551900 | TWO_STORE]);
+        vm.push(0x11111111);
+        vm.push(0x22222222);
+        vm.push(addr);
+        assert_eq!(unsafe { vm.run(0) }, BeetleExit::Halt(0));
+        assert_eq!(vm.sp, initial_sp);
+        // `x2` is at the lower address, so it is the low half of the pair.
+        let pair = u64::from(vm.load(addr)) | (u64::from(vm.load(addr + CELL as u32)) << 32);
+        assert_eq!(pair, 0x11111111_22222222);
+        // Beetle assembler:
+        // $00: 2@ 0 HALT
+        vm.load_object(&[0x00551900 | TWO_FETCH]);
+        vm.push(addr);
+        assert_eq!(unsafe { vm.run(0) }, BeetleExit::Halt(0));
+        assert_eq!(vm.pop(), 0x22222222);
+        assert_eq!(vm.pop(), 0x11111111);
+        assert_eq!(vm.sp, initial_sp);
+    }
+}
+
+#[test]
+pub fn half_words() {
+    const W_FETCH: u32 = 0x69;
+    const W_STORE: u32 = 0x6A;
+    const ADDR: u32 = 0x100;
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    let initial_sp = vm.sp;
+    vm.store(ADDR, 0x11111111);
+    // Beetle assembler:
+    // $00: W! 0 HALT
+    vm.load_object(&[0x00551900 | W_STORE]);
+    vm.push(0x12345678);
+    vm.push(ADDR + 2);
+    assert_eq!(unsafe { vm.run(0) }, BeetleExit::Halt(0));
+    assert_eq!(vm.sp, initial_sp);
+    // Only the low half is stored, in the high half of the cell.
+    assert_eq!(vm.load(ADDR), 0x56781111);
+    // Beetle assembler:
+    // $00: W@ 0 HALT
+    vm.load_object(&[0x00551900 | W_FETCH]);
+    for (addr, expected) in [(ADDR, 0x1111), (ADDR + 2, 0x5678)] {
+        vm.push(addr);
+        assert_eq!(unsafe { vm.run(0) }, BeetleExit::Halt(0));
+        assert_eq!(vm.pop(), expected);
+        assert_eq!(vm.sp, initial_sp);
+    }
+}
+
+#[test]
+pub fn division() {
+    const SLASH: u32 = 0x26;
+    const MOD: u32 = 0x27;
+    const SLASH_MOD: u32 = 0x28;
+    const U_SLASH_MOD: u32 = 0x29;
+    const S_SLASH_REM: u32 = 0x2A;
+    let m7 = -7i32 as u32;
+    let m2 = -2i32 as u32;
+    assert_eq!(run_opcode(SLASH, 7, 2), [3]);
+    assert_eq!(run_opcode(MOD, 7, 2), [1]);
+    assert_eq!(run_opcode(SLASH_MOD, 7, 2), [1, 3]);
+    assert_eq!(run_opcode(U_SLASH_MOD, 7, 2), [1, 3]);
+    assert_eq!(run_opcode(S_SLASH_REM, 7, 2), [1, 3]);
+    // Floored division rounds towards minus infinity.
+    assert_eq!(run_opcode(SLASH_MOD, m7, 2), [1, -4i32 as u32]);
+    assert_eq!(run_opcode(SLASH_MOD, 7, m2), [-1i32 as u32, -4i32 as u32]);
+    assert_eq!(run_opcode(SLASH_MOD, m7, m2), [-1i32 as u32, 3]);
+    assert_eq!(run_opcode(SLASH_MOD, -8i32 as u32, 2), [0, -4i32 as u32]);
+    assert_eq!(run_opcode(SLASH_MOD, 8, m2), [0, -4i32 as u32]);
+    // Symmetric division rounds towards zero.
+    assert_eq!(run_opcode(S_SLASH_REM, m7, 2), [-1i32 as u32, -3i32 as u32]);
+    assert_eq!(run_opcode(S_SLASH_REM, 7, m2), [1, -3i32 as u32]);
+    // Unsigned division.
+    assert_eq!(run_opcode(U_SLASH_MOD, m7, 2), [1, 0x7FFFFFFC]);
+}
+
+#[test]
+pub fn division_edge_cases() {
+    const U_SLASH_MOD: u32 = 0x29;
+    const S_SLASH_REM: u32 = 0x2A;
+    assert_eq!(run_opcode(U_SLASH_MOD, 0, 7), [0, 0]);
+    assert_eq!(run_opcode(S_SLASH_REM, 0, -7i32 as u32), [0, 0]);
+    assert_eq!(run_opcode(U_SLASH_MOD, 0xFFFFFFFF, 1), [0, 0xFFFFFFFF]);
+    assert_eq!(run_opcode(S_SLASH_REM, 0x7FFFFFFF, 1), [0, 0x7FFFFFFF]);
+    assert_eq!(run_opcode(S_SLASH_REM, 0x7FFFFFFF, 0x10), [0xF, 0x7FFFFFF]);
+    // Overflow wraps rather than trapping.
+    assert_eq!(run_opcode(S_SLASH_REM, 0x80000000, -1i32 as u32), [0, 0x80000000]);
+}
+
+#[test]
+pub fn throw() {
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    // Beetle assembler:
+    // $00: THROW
+    // $04: HALT
+    // $08: $04
+    vm.load_object(&[0x5E, 0x55, 0x04]);
+    vm.throw = 0x08;
+    vm.push(5);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(5));
+    assert_eq!(vm.bad, 0x04);
+}
+
+#[test]
+pub fn stack_underflow() {
+    let options = BeetleOptions {checked_stacks: true, ..BeetleOptions::default()};
+    let mut vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 0);
+    // Beetle assembler:
+    // $00: +
+    //      HALT
+    // $04: HALT
+    // $08: $04
+    vm.load_object(&[0x551E, 0x55, 0x04]);
+    vm.throw = 0x08;
+    let initial_sp = vm.sp;
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(-4i32 as u32));
+    assert_eq!(vm.bad, 0x04);
+    assert_eq!(vm.sp, initial_sp);
+    // Popping one item is fine.
+    vm.reset(false);
+    vm.throw = 0x08;
+    vm.push(1);
+    vm.push(2);
+    assert_eq!(unsafe { vm.run(0) }, BeetleExit::Halt(3));
+    assert_eq!(vm.sp, initial_sp);
+}
+
+/// Test that every instruction that reads `n` items from the data stack
+/// raises stack underflow if it holds fewer, without changing the stack.
+#[test]
+pub fn stack_underflow_depths() {
+    // (opcode, n), e.g. DUP, DROP, SWAP, OVER, ROT, -ROT, TUCK, NIP, <, 0<, +,
+    // 1+, /, ABS, MAX, INVERT, @, !, +!, MOVE, FILL, 2@, 2!, W@, W!, COMPARE.
+    const DEPTHS: [(u32, u32); 26] = [
+        (0x01, 1), (0x02, 1), (0x03, 2), (0x04, 2), (0x05, 3), (0x06, 3),
+        (0x07, 2), (0x08, 2), (0x0F, 2), (0x13, 1), (0x1E, 2), (0x21, 1),
+        (0x26, 2), (0x2D, 1), (0x2F, 2), (0x31, 1), (0x39, 1), (0x3A, 2),
+        (0x3D, 2), (0x63, 3), (0x66, 3), (0x67, 1), (0x68, 3), (0x69, 1),
+        (0x6A, 2), (0x6B, 4),
+    ];
+    let options = BeetleOptions {checked_stacks: true, ..BeetleOptions::default()};
+    let mut vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 0);
+    for (opcode, n) in DEPTHS {
+        // Beetle assembler:
+        // $00: opcode
+        //      HALT
+        // $04: HALT
+        // $08: $04
+        vm.reset(true);
+        vm.load_object(&[0x5500 | opcode, 0x55, 0x04]);
+        vm.throw = 0x08;
+        for _ in 1..n { vm.push(0x100); }
+        let sp = vm.sp;
+        let exit = unsafe { vm.run(0) };
+        assert_eq!(exit, BeetleExit::Halt(-4i32 as u32), "opcode {:#x}", opcode);
+        assert_eq!(vm.bad, 0x04, "opcode {:#x}", opcode);
+        assert_eq!(vm.sp, sp, "opcode {:#x}", opcode);
+        // With one more item, and a code for HALT, it succeeds.
+        vm.reset(true);
+        vm.load_object(&[0x5500 | opcode, 0x55, 0x04]);
+        vm.throw = 0x08;
+        vm.push(0);
+        for _ in 0..n { vm.push(0x100); }
+        let exit = unsafe { vm.run(0) };
+        assert_ne!(exit, BeetleExit::Halt(-4i32 as u32), "opcode {:#x}", opcode);
+    }
+}
+
+/// Test that the options can be combined.
+#[test]
+pub fn combined_options() {
+    let options = BeetleOptions {checked_stacks: true, profiling: true, ..BeetleOptions::default()};
+    let mut vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 0);
+    // Beetle assembler:
+    // $00: DUP
+    //      HALT
+    // $04: HALT
+    // $08: $04
+    vm.load_object(&[0x5501, 0x55, 0x04]);
+    vm.throw = 0x08;
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(-4i32 as u32));
+    let profile = vm.beetle().jit.profile(vm.beetle().root).expect("Not profiled");
+    assert!(profile.count > 0);
+}
+
+#[test]
+pub fn return_stack_underflow() {
+    let options = BeetleOptions {checked_stacks: true, ..BeetleOptions::default()};
+    let mut vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 0);
+    // Beetle assembler:
+    // $00: EXIT
+    // $04: HALT
+    // $08: $04
+    vm.load_object(&[0x4A, 0x55, 0x04]);
+    vm.throw = 0x08;
+    let initial_rp = vm.rp;
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(-6i32 as u32));
+    assert_eq!(vm.rp, initial_rp);
+}
+
+#[test]
+pub fn invalid_address() {
+    const BAD: u32 = 0xFFFFFFF0;
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    // Beetle assembler:
+    // $00: @
+    //      HALT
+    // $04: HALT
+    // $08: $04
+    vm.load_object(&[0x5539, 0x55, 0x04]);
+    vm.throw = 0x08;
+    vm.push(BAD);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(-9i32 as u32));
+    assert_eq!(vm.not_address, BAD);
+    assert_eq!(vm.bad, 0x04);
+    assert_eq!(vm.pop(), BAD);
+}
+
+/// Test the memory accesses at the top of memory, where any overrun would
+/// reach beyond the host buffer, and the unaligned accesses.
+#[test]
+pub fn memory_edges() {
+    let memory_size = MEMORY_CELLS * CELL as u32;
+    // `2@` and `2!` access an odd cell one cell at a time. `2@` reads the
+    // second cell first; `2!` writes the first cell first.
+    for (opcode, operands, addr, expected) in [
+        (0x39, 0, memory_size - 1, Some((-23, memory_size - 1))), // @
+        (0x39, 0, memory_size - 4, None),
+        (0x39, 0, memory_size - 8, None),
+        (0x39, 0, memory_size, Some((-9, memory_size))),
+        (0x3A, 1, memory_size - 1, Some((-23, memory_size - 1))), // !
+        (0x3A, 1, memory_size - 4, None),
+        (0x3A, 1, memory_size - 8, None),
+        (0x3A, 1, memory_size, Some((-9, memory_size))),
+        (0x67, 0, memory_size - 1, Some((-23, memory_size + 3))), // 2@
+        (0x67, 0, memory_size - 4, Some((-9, memory_size))),
+        (0x67, 0, memory_size - 8, None),
+        (0x68, 2, memory_size - 1, Some((-23, memory_size - 1))), // 2!
+        (0x68, 2, memory_size - 4, Some((-9, memory_size))),
+        (0x67, 0, memory_size, Some((-9, memory_size))),
+        (0x68, 2, memory_size - 8, None),
+        (0x68, 2, memory_size, Some((-9, memory_size))),
+        (0x69, 0, memory_size - 1, Some((-23, memory_size - 1))), // W@
+        (0x69, 0, memory_size - 2, None),
+        (0x69, 0, memory_size, Some((-9, memory_size))),
+        (0x6A, 1, memory_size - 1, Some((-23, memory_size - 1))), // W!
+        (0x6A, 1, memory_size - 2, None),
+        (0x6A, 1, memory_size, Some((-9, memory_size))),
+    ] {
+        let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+        // Beetle assembler:
+        // $00: opcode
+        //      HALT
+        // $04: HALT
+        // $08: $04
+        vm.load_object(&[0x5500 | opcode, 0x55, 0x04]);
+        vm.throw = 0x08;
+        // Something for `HALT` to pop.
+        vm.push(0);
+        for i in 0..operands { vm.push(i); }
+        vm.push(addr);
+        let exit = unsafe { vm.run(0) };
+        if let Some((code, not_address)) = expected {
+            assert_eq!(exit, BeetleExit::Halt(code as u32), "{:#x} {:#x}", opcode, addr);
+            assert_eq!(vm.bad, 0x04);
+            assert_eq!(vm.not_address, not_address, "{:#x} {:#x}", opcode, addr);
+        } else {
+            assert!(matches!(exit, BeetleExit::Halt(_)), "{:#x} {:#x}", opcode, addr);
+            assert_eq!(vm.bad, 0, "{:#x} {:#x}", opcode, addr);
+        }
+    }
+}
+
+/// Test that an exception that cannot be passed to the handler returns to
+/// the host instead of accessing memory out of range.
+#[test]
+pub fn bad_throw() {
+    const BAD: u32 = 0xFFFFFFF0;
+    let memory_size = MEMORY_CELLS * CELL as u32;
+    // Beetle assembler:
+    // $00: @
+    //      HALT
+    // $04: HALT
+    // $08: $04
+    // $0C: $02
+    let object = [0x5539, 0x55, 0x04, 0x02];
+    // The stack pointer is out of range, e.g. after too many `DROP`s.
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(&object);
+    vm.throw = 0x08;
+    vm.sp = memory_size + 4;
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::BadThrow);
+    assert_eq!(vm.not_address, memory_size);
+    // `'THROW` is out of range.
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(&object);
+    vm.throw = BAD;
+    vm.push(BAD);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::BadThrow);
+    assert_eq!(vm.not_address, BAD);
+    // The handler address is unaligned.
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(&object);
+    vm.throw = 0x0C;
+    vm.push(BAD);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::BadThrow);
+    assert_eq!(vm.not_address, 0x02);
+}
+
+#[test]
+pub fn division_by_zero() {
+    for opcode in 0x26..=0x2A {
+        let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+        // Beetle assembler:
+        // $00: opcode
+        //      HALT
+        // $04: HALT
+        // $08: $04
+        vm.load_object(&[0x5500 | opcode, 0x55, 0x04]);
+        vm.throw = 0x08;
+        let initial_sp = vm.sp;
+        vm.push(7);
+        vm.push(0);
+        let exit = unsafe { vm.run(0) };
+        assert_eq!(exit, BeetleExit::Halt(-10i32 as u32));
+        assert_eq!(vm.bad, 0x04);
+        // The operands are untouched.
+        assert_eq!(vm.pop(), 0);
+        assert_eq!(vm.pop(), 7);
+        assert_eq!(vm.sp, initial_sp);
+    }
+}
+
+/// Test that arbitrary object images stop only by returning a
+/// [`BeetleExit`]. The NEXT-time hook and `'THROW` point outside the memory,
+/// and guest code cannot change them, so every run stops within `PERIOD`
+/// NEXTs: the hook raises an exception, which cannot be passed to the
+/// handler.
+#[test]
+pub fn random_images() {
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::{Pcg64};
+    const MEMORY_CELLS: u32 = 1 << 10;
+    const STACK_CELLS: u32 = 1 << 6;
+    const PERIOD: u32 = 1000;
+    const NUM_IMAGES: u64 = 2000;
+    let options = BeetleOptions {next_hook: true, ..BeetleOptions::default()};
+    let mut vm = VM::with_options(MEMORY_CELLS, STACK_CELLS, STACK_CELLS, options, PERIOD);
+    vm.set_clock(ClockMode::Virtual {start: Duration::ZERO, instruction_time: Duration::ZERO});
+    let memory_size = MEMORY_CELLS * CELL as u32;
+    let (mut halts, mut bad_throws) = (0, 0);
+    for seed in 0..NUM_IMAGES {
+        let rng = &mut Pcg64::seed_from_u64(seed);
+        // Mostly instructions, some of which are not implemented, with some
+        // addresses.
+        let object: Vec<u32> = (0..MEMORY_CELLS - 2 * STACK_CELLS - 1).map(|_| {
+            if rng.gen_bool(0.25) {
+                rng.gen_range(0..memory_size)
+            } else {
+                u32::from_le_bytes([(); 4].map(|_| rng.gen_range(0..0x6D)))
+            }
+        }).collect();
+        vm.reset(true);
+        vm.load_object(&object);
+        vm.next_hook = memory_size;
+        vm.throw = memory_size;
+        match unsafe { vm.run(0) } {
+            BeetleExit::Halt(_) => { halts += 1; },
+            BeetleExit::NotImplemented(_) | BeetleExit::StackOverflow => {},
+            BeetleExit::BadThrow => { bad_throws += 1; },
+        }
+    }
+    assert!(halts > 0);
+    assert!(bad_throws > 0);
+}
+
+/// Test that `HALT` and `LIB` do not access the data stack out of range.
+#[test]
+pub fn host_stack_checks() {
+    let memory_size = MEMORY_CELLS * CELL as u32;
+    for (sp, opcode, routine, expected) in [
+        (memory_size, 0x55, None, BeetleExit::NotImplemented(0x55)),
+        (memory_size + 4, 0x55, None, BeetleExit::NotImplemented(0x55)),
+        (2, 0x55, None, BeetleExit::NotImplemented(0x55)),
+        (memory_size, 0x57, None, BeetleExit::NotImplemented(0x57)),
+        (memory_size, 0x57, Some(LIB_SLEEP), BeetleExit::NotImplemented(0x57)),
+        (20, 0x57, Some(LIB_TIME_DATE), BeetleExit::StackOverflow),
+    ] {
+        let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+        vm.load_object(&[opcode]);
+        vm.s_limit = 0;
+        vm.sp = sp;
+        if let Some(routine) = routine { vm.push(routine); }
+        let initial_sp = vm.sp;
+        let exit = unsafe { vm.run(0) };
+        assert_eq!(exit, expected, "{:#x} {:#x}", sp, opcode);
+        assert_eq!(vm.sp, initial_sp);
+        assert_eq!(vm.a, opcode);
+    }
+}
+
+/// Returns a hook word that increments the cell at `counter`, followed by a
+/// loop that counts down from the top of the stack to zero, executing one
+/// NEXT per iteration. The hook word is at address zero, and the loop at
+/// address eight.
+pub fn countdown_object(counter: u32) -> Vec<u32> {
+    // Beetle assembler:
+    // $00: 1
+    //      (LITERAL)I counter
+    // $04: +!
+    //      EXIT
+    // $08: 1-
+    //      DUP
+    //      0=
+    // $0C: ?BRANCHI $08
+    // $10: 0
+    //      HALT
+    assert!(counter < 0x8000);
+    vec![
+        0x0000531A | (counter << 16), 0x00004A3D,
+        0x00150122, 0xFFFFFE45,
+        0x00005519,
+    ]
+}
+
+#[test]
+pub fn next_hook() {
+    const PERIOD: u32 = 7;
+    const ITERATIONS: u32 = 100;
+    let options = BeetleOptions {next_hook: true, ..BeetleOptions::default()};
+    let mut vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, PERIOD);
+    vm.load_object(countdown_object(0x100).as_ref());
+    vm.store(0x100, 0);
+    let initial_sp = vm.sp;
+    let initial_rp = vm.rp;
+    vm.push(ITERATIONS);
+    let exit = unsafe { vm.run(8) };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    assert_eq!(vm.pop(), 0);
+    assert_eq!(vm.sp, initial_sp);
+    assert_eq!(vm.rp, initial_rp);
+    // One NEXT to start, then one per iteration.
+    assert_eq!(vm.load(0x100), (ITERATIONS + 1) / PERIOD);
+    assert_eq!(vm.next_count, PERIOD - (ITERATIONS + 1) % PERIOD);
+}
+
+#[test]
+pub fn no_next_hook() {
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(countdown_object(0x100).as_ref());
+    vm.store(0x100, 0);
+    vm.next_count = 1;
+    vm.next_period = 1;
+    vm.push(100);
+    let exit = unsafe { vm.run(8) };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    assert_eq!(vm.pop(), 0);
+    assert_eq!(vm.load(0x100), 0);
+    assert_eq!(vm.next_count, 1);
+}
+
+/// Test that `check_addresses()` accepts the memory accesses of the helper
+/// functions, including on cold paths.
+#[test]
+pub fn addresses_from_m0() {
+    let [exit, range, alignment] = [1, 2, 3].map(|i| EntryId::new(i).unwrap());
+    let bad_address = BadAddress {range, alignment};
+    check_addresses(&build(|mut b| {
+        load(&mut b, R1, BSP, bad_address);
+        store_byte(&mut b, R1, R2, bad_address);
+        b.store(R1, register!(a));
+        b.if_(R1,
+            build(|mut b| {
+                pop(&mut b, R3, BRP, None, bad_address);
+                b.jump(exit)
+            }),
+            build(|b| b.jump(exit)),
+        )
+    }));
+}
+
+/// Test that `check_addresses()` rejects a memory access whose address is
+/// not computed from `M0`.
+#[test]
+#[should_panic(expected = "Memory access not computed from M0")]
+pub fn address_not_from_m0() {
+    let exit = EntryId::new(1).unwrap();
+    check_addresses(&build(|mut b| {
+        b.binary64(Add, BI, M0, R1);
+        b.load(R2, (BI, 0, Four));
+        b.binary64(Add, BI, R3, R1);
+        b.load(R2, (BI, 0, Four));
+        b.jump(exit)
+    }));
+}
+
+/// Test that without the NEXT-time hook, NEXT is compiled as if the hook did
+/// not exist.
+#[test]
+pub fn next_hook_off() {
+    let [next, stack_overflow, range, alignment] = [1, 2, 3, 4].map(|i| EntryId::new(i).unwrap());
+    let bad_address = BadAddress {range, alignment};
+    let unhooked = build(|mut b| {
+        pop(&mut b, BA, BEP, None, bad_address);
+        b.jump(next)
+    });
+    let debug = |ebb: EBB<EntryId>| format!("{:?}", ebb);
+    assert_eq!(debug(next_instruction(false, next, stack_overflow, bad_address)), debug(unhooked.clone()));
+    assert_ne!(debug(next_instruction(true, next, stack_overflow, bad_address)), debug(unhooked));
+}
+
+/// Returns a hook word that yields to the host, followed by a task that
+/// increments the cell at the second item on the stack as many times as the
+/// top item says. The hook word is at address zero, the code to resume a task
+/// at address four, and the task at address eight.
+pub fn round_robin_object() -> Vec<u32> {
+    // Beetle assembler:
+    // $00: 1
+    //      HALT
+    // $04: EXIT
+    // $08: OVER
+    //      1
+    //      SWAP
+    //      +!
+    // $0C: 1-
+    //      DUP
+    //      0=
+    // $10: ?BRANCHI $08
+    // $14: DROP
+    //      DROP
+    //      0
+    //      HALT
+    vec![
+        0x0000551A, 0x0000004A,
+        0x3D031A04, 0x00150122,
+        0xFFFFFD45, 0x55190202,
+    ]
+}
+
+/// Test that the NEXT-time hook can share the VM between two tasks, each
+/// with its own stacks, taking turns.
+#[test]
+pub fn round_robin() {
+    const PERIOD: u32 = 5;
+    const ITERATIONS: u32 = 50;
+    const STACK_CELLS: u32 = 16;
+    const COUNTERS: [u32; 2] = [0x100, 0x104];
+    /// The registers that differ between tasks.
+    #[derive(Debug, PartialEq)]
+    struct Task {sp: u32, rp: u32, s_limit: u32, r_limit: u32, s0: u32, r0: u32}
+    fn save(vm: &VM) -> Task {
+        Task {sp: vm.sp, rp: vm.rp, s_limit: vm.s_limit, r_limit: vm.r_limit, s0: vm.s0, r0: vm.r0}
+    }
+    fn restore(vm: &mut VM, task: &Task) {
+        (vm.sp, vm.rp, vm.s_limit, vm.r_limit, vm.s0, vm.r0) =
+            (task.sp, task.rp, task.s_limit, task.r_limit, task.s0, task.r0);
+    }
+    let options = BeetleOptions {next_hook: true, ..BeetleOptions::default()};
+    let mut vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, PERIOD);
+    vm.load_object(round_robin_object().as_ref());
+    // Give each task its own stacks, and start it.
+    let mut tasks: Vec<Task> = COUNTERS.iter().map(|&counter| {
+        let (r_limit, r0) = vm.allocate(STACK_CELLS);
+        let (s_limit, s0) = vm.allocate(STACK_CELLS);
+        restore(&mut vm, &Task {sp: s0, rp: r0, s_limit, r_limit, s0, r0});
+        vm.push(counter);
+        vm.push(ITERATIONS);
+        vm.rpush(8);
+        save(&vm)
+    }).collect();
+    let initial: Vec<Task> = tasks.iter().map(|task| Task {sp: task.s0, rp: task.r0, ..*task}).collect();
+    // Take turns until both finish. Each turn resumes a task with EXIT.
+    let mut order = Vec::new();
+    let mut finished = [false; 2];
+    let mut current = 0;
+    while finished != [true; 2] {
+        assert!(order.len() < 2 * ITERATIONS as usize);
+        order.push(current);
+        restore(&mut vm, &tasks[current]);
+        let exit = unsafe { vm.run(4) };
+        tasks[current] = save(&vm);
+        match exit {
+            BeetleExit::Halt(1) => {},
+            BeetleExit::Halt(0) => { finished[current] = true; },
+            _ => panic!("Unexpected exit {:?}", exit),
+        }
+        if !finished[1 - current] { current = 1 - current; }
+    }
+    // Each task ran to completion on its own stacks.
+    for &counter in &COUNTERS { assert_eq!(vm.load(counter), ITERATIONS); }
+    assert_eq!(tasks, initial);
+    // The tasks alternated, and each had several turns.
+    let turns = |task| order.iter().filter(|&&t| t == task).count();
+    assert!(turns(0) > 2);
+    assert!((turns(0) as i64 - turns(1) as i64).abs() <= 1);
+    for pair in order.windows(2) { assert_ne!(pair[0], pair[1]); }
+}
+
+#[test]
+pub fn date_fields_() {
+    assert_eq!(date_fields(0), [0, 0, 0, 1, 1, 1970]);
+    assert_eq!(date_fields(951_782_400), [0, 0, 0, 29, 2, 2000]);
+    assert_eq!(date_fields(1_000_000_000), [40, 46, 1, 9, 9, 2001]);
+    assert_eq!(date_fields(4_102_444_799), [59, 59, 23, 31, 12, 2099]);
+}
+
+#[test]
+pub fn time_and_date() {
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.set_clock(ClockMode::Virtual {
+        start: Duration::from_secs(1_000_000_000),
+        instruction_time: Duration::ZERO,
+    });
+    vm.advance_clock(2500);
+    // Beetle assembler:
+    // $00: 1
+    //      LIB
+    //      0
+    //      HALT
+    vm.load_object(&[0x5519571A]);
+    let initial_sp = vm.sp;
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    let mut stack = Vec::new();
+    while vm.sp < initial_sp {
+        stack.insert(0, vm.pop());
+    }
+    assert_eq!(stack, [42, 46, 1, 9, 9, 2001]);
+}
+
+#[test]
+pub fn virtual_clock() {
+    // Beetle assembler:
+    // $00: 1
+    //      HALT
+    // $04: EXIT
+    // $08: 0
+    //      LIB
+    //      NEXT
+    // $0C: (LITERAL)I 100
+    // $10: U<
+    //      0=
+    //      ?BRANCHI $08
+    // $14: 0
+    //      HALT
+    let object = [
+        0x0000551A, 0x0000004A,
+        0x00005719, 0x00006453,
+        0xFD451517, 0x00005519,
+    ];
+    let options = BeetleOptions {next_hook: true, ..BeetleOptions::default()};
+    let mut vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 3);
+    vm.load_object(&object);
+    vm.set_clock(ClockMode::Virtual {start: Duration::ZERO, instruction_time: Duration::ZERO});
+    let initial_sp = vm.sp;
+    // The guest busy-waits, and the NEXT-time hook yields to the host.
+    let mut exit = unsafe { vm.run(0x08) };
+    let mut yields = 0;
+    while exit == BeetleExit::Halt(1) {
+        assert!(yields < 100);
+        yields += 1;
+        vm.advance_clock(10);
+        exit = unsafe { vm.run(0x04) };
+    }
+    assert_eq!(exit, BeetleExit::Halt(0));
+    assert_eq!(yields, 10);
+    assert_eq!(vm.sp, initial_sp);
+}
+
+#[test]
+pub fn sleep() {
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.set_clock(ClockMode::Virtual {start: Duration::ZERO, instruction_time: Duration::ZERO});
+    vm.advance_clock(100);
+    // The guest can also advance the virtual clock.
+    // Beetle assembler:
+    // $00: (LITERAL)I 200
+    // $04: (LITERAL)I LIB_SLEEP
+    // $08: LIB
+    //      0
+    //      LIB
+    //      0
+    // $0C: HALT
+    vm.load_object(&[0x0000C853, 0x00000253, 0x19571957, 0x00000055]);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    assert_eq!(vm.pop(), 300);
+}
+
+#[test]
+pub fn instruction_time() {
+    // The busy-wait loop of `virtual_clock()`, but with no NEXT-time hook.
+    let object = [
+        0x0000551A, 0x0000004A,
+        0x00005719, 0x00006453,
+        0xFD451517, 0x00005519,
+    ];
+    let options = BeetleOptions {profiling: true, ..BeetleOptions::default()};
+    let mut vm = VM::with_options(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS, options, 0);
+    vm.load_object(&object);
+    vm.set_clock(ClockMode::Virtual {
+        start: Duration::ZERO,
+        instruction_time: Duration::from_millis(1),
+    });
+    let initial_sp = vm.sp;
+    // The virtual clock advances without help from the host.
+    let exit = unsafe { vm.run(0x08) };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    assert_eq!(vm.sp, initial_sp);
+    // Resetting the virtual clock starts the count again.
+    // Beetle assembler:
+    // $00: 0
+    //      LIB
+    //      HALT
+    //      NEXT
+    vm.reset(false);
+    vm.load_object(&[0x00555719]);
+    let exit = unsafe { vm.run(0) };
+    assert!(matches!(exit, BeetleExit::Halt(ms) if ms < 100));
+}
+
+#[test]
+#[should_panic(expected = "Not compiled with profiling")]
+pub fn instruction_time_without_profiling() {
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.set_clock(ClockMode::Virtual {
+        start: Duration::ZERO,
+        instruction_time: Duration::from_millis(1),
+    });
+}
+
+#[test]
+pub fn real_clock() {
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    // Beetle assembler:
+    // $00: 0
+    //      LIB
+    //      0
+    //      LIB
+    // $04: 0
+    //      HALT
+    vm.load_object(&[0x57195719, 0x00005519]);
+    let mut previous = 0;
+    for _ in 0..100 {
+        let exit = unsafe { vm.run(0) };
+        assert_eq!(exit, BeetleExit::Halt(0));
+        let second = vm.pop();
+        let first = vm.pop();
+        assert!(previous <= first);
+        assert!(first <= second);
+        previous = second;
+    }
+}
+
+#[test]
+pub fn reset() {
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    let version = vm.beetle().jit.entry_version(vm.beetle().root);
+    let initial_sp = vm.sp;
+    let initial_rp = vm.rp;
+    // Run one program, and leave some mess behind.
+    vm.load_object(ackermann_object().as_ref());
+    vm.push(2);
+    vm.push(3);
+    vm.rpush(vm.halt_addr());
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    assert_eq!(vm.pop(), 9);
+    vm.push(0xDEAD);
+    vm.throw = 0x08;
+    vm.reset(true);
+    assert_eq!(vm.sp, initial_sp);
+    assert_eq!(vm.rp, initial_rp);
+    assert_eq!(vm.throw, 0);
+    assert!(vm.memory()[..13].iter().all(|&cell| cell == 0));
+    // Run a different program.
+    // Beetle assembler:
+    // $00: THROW
+    // $04: HALT
+    // $08: $04
+    vm.load_object(&[0x5E, 0x55, 0x04]);
+    vm.throw = 0x08;
+    vm.push(5);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(5));
+    // Nothing was recompiled.
+    assert!(vm.beetle().jit.is_current(vm.beetle().root, version));
+    // Resetting without clearing the memory keeps the program.
+    vm.reset(false);
+    vm.throw = 0x08;
+    vm.push(6);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(6));
+}
+
+#[test]
+pub fn save_and_restore_state() {
+    // Beetle assembler:
+    // $00: 1+ 0 HALT
+    // $04: 1+ 0 HALT
+    let object = [0x00551921, 0x00551921];
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(&object);
+    vm.push(7);
+    assert_eq!(unsafe { vm.run(0) }, BeetleExit::Halt(0));
+    let state = vm.save_state();
+    // Finish the program.
+    assert_eq!(unsafe { vm.run(vm.ep) }, BeetleExit::Halt(0));
+    assert_eq!(vm.pop(), 9);
+    // Finish it again in a fresh `VM`.
+    let mut vm2 = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm2.restore_state(state);
+    assert_eq!(unsafe { vm2.run(vm2.ep) }, BeetleExit::Halt(0));
+    assert_eq!(vm2.pop(), 9);
+    assert_eq!(vm2.sp, vm.sp);
+}
+
+#[test]
+pub fn save_and_restore_bytes() {
+    // Beetle assembler:
+    // $00: 1+ 0 HALT
+    // $04: 1+ 0 HALT
+    let object = [0x00551921, 0x00551921];
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(&object);
+    vm.push(7);
+    assert_eq!(unsafe { vm.run(0) }, BeetleExit::Halt(0));
+    let bytes = vm.save_state().to_bytes();
+    // Finish the program.
+    assert_eq!(unsafe { vm.run(vm.ep) }, BeetleExit::Halt(0));
+    assert_eq!(vm.pop(), 9);
+    // Finish it again in a fresh `VM`.
+    let mut vm2 = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm2.try_restore_state(VMState::from_bytes(&bytes).unwrap()).unwrap();
+    assert_eq!(unsafe { vm2.run(vm2.ep) }, BeetleExit::Halt(0));
+    assert_eq!(vm2.pop(), 9);
+    assert_eq!(vm2.sp, vm.sp);
+    // A `VM` with a different memory size refuses the state.
+    let mut vm3 = VM::new(MEMORY_CELLS * 2, DATA_CELLS, RETURN_CELLS);
+    assert_eq!(
+        vm3.try_restore_state(VMState::from_bytes(&bytes).unwrap()).unwrap_err(),
+        StateError::WrongMemorySize(MEMORY_CELLS as usize, (MEMORY_CELLS * 2) as usize),
+    );
+    // Malformed bytes are refused.
+    assert_eq!(VMState::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err(), StateError::Truncated);
+    assert_eq!(VMState::from_bytes(&bytes[..bytes.len() - 4]).unwrap_err(), StateError::Truncated);
+    assert_eq!(VMState::from_bytes(&[bytes.as_slice(), &[0; 4]].concat()).unwrap_err(), StateError::TrailingBytes);
+    assert_eq!(VMState::from_bytes(&[2, 0, 0, 0]).unwrap_err(), StateError::BadVersion(2));
+    // Registers that disagree with the memory are refused.
+    let tamper = |index: usize, value: u32| {
+        let mut bytes = bytes.clone();
+        let offset = 4 + index * 4;
+        bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        VMState::from_bytes(&bytes)
+    };
+    let cell = CELL as u32;
+    let memory_size = MEMORY_CELLS * cell;
+    assert!(tamper(10, memory_size).is_ok());
+    assert_eq!(tamper(10, memory_size + cell).unwrap_err(), StateError::BadRegisters);
+    assert_eq!(tamper(10, memory_size - cell).unwrap_err(), StateError::BadRegisters);
+    for index in [3, 4, 12, 13, 14, 15] {
+        assert!(tamper(index, memory_size).is_ok());
+        assert_eq!(tamper(index, memory_size + cell).unwrap_err(), StateError::BadRegisters);
+        assert_eq!(tamper(index, cell + 1).unwrap_err(), StateError::BadRegisters);
+    }
+}
+
+#[test]
+pub fn unaligned_ep() {
+    const ODD: u32 = 0x05;
+    const HIGH: u32 = 0xFFFFFFF0;
+    // Beetle assembler:
+    // $00: CALL
+    // $04: ODD
+    // $08: HALT
+    // $0C: $08
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(&[0x48, ODD, 0x55, 0x08]);
+    vm.throw = 0x0C;
+    let initial_rp = vm.rp;
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(-23i32 as u32));
+    assert_eq!(vm.not_address, ODD);
+    assert_eq!(vm.bad, ODD);
+    assert_eq!(vm.load(vm.rp), 0x08);
+    assert_eq!(vm.rp + CELL as u32, initial_rp);
+    // Beetle assembler:
+    // $00: BRANCH
+    // $04: ODD
+    // $08: HALT
+    // $0C: $08
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(&[0x42, ODD, 0x55, 0x08]);
+    vm.throw = 0x0C;
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(-23i32 as u32));
+    assert_eq!(vm.not_address, ODD);
+    assert_eq!(vm.bad, ODD);
+    // Beetle assembler:
+    // $00: ?BRANCH
+    // $04: ODD
+    // $08: HALT
+    // $0C: $08
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(&[0x44, ODD, 0x55, 0x08]);
+    vm.throw = 0x0C;
+    vm.push(0);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(-23i32 as u32));
+    assert_eq!(vm.not_address, ODD);
+    // Beetle assembler:
+    // $00: EXIT
+    // $04: HALT
+    // $08: $04
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(&[0x4A, 0x55, 0x04]);
+    vm.throw = 0x08;
+    vm.rpush(ODD);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(-23i32 as u32));
+    assert_eq!(vm.not_address, ODD);
+    // Beetle assembler:
+    // $00: EXECUTE
+    // $04: HALT
+    // $08: $04
+    for (address, code) in [(ODD, -23i32), (HIGH, -9)] {
+        let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+        vm.load_object(&[0x4B, 0x55, 0x04]);
+        vm.throw = 0x08;
+        vm.push(address);
+        let exit = unsafe { vm.run(0) };
+        assert_eq!(exit, BeetleExit::Halt(code as u32));
+        assert_eq!(vm.not_address, address);
+    }
+}
+
+#[test]
+pub fn call_execute() {
+    // Beetle assembler:
+    // $00: CALL
+    // $04: $14
+    // $08: (LITERAL)I $14
+    // $0C: EXECUTE
+    // $10: 0
+    //      HALT
+    // $14: 1+
+    //      EXIT
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(&[0x48, 0x14, 0x1453, 0x4B, 0x5519, 0x4A21]);
+    let initial_rp = vm.rp;
+    vm.push(5);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    assert_eq!(vm.pop(), 7);
+    assert_eq!(vm.rp, initial_rp);
+}
+
+#[test]
+pub fn branch() {
+    // Beetle assembler:
+    // $00: BRANCH
+    // $04: $0C
+    // $08: 1
+    //      HALT
+    // $0C: ?BRANCH
+    // $10: $08
+    // $14: 0
+    //      HALT
+    for (flag, code) in [(0, 1), (1, 0)] {
+        let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+        vm.load_object(&[0x42, 0x0C, 0x551A, 0x44, 0x08, 0x5519]);
+        vm.push(flag);
+        let exit = unsafe { vm.run(0) };
+        assert_eq!(exit, BeetleExit::Halt(code));
+    }
+}
+
+#[test]
+pub fn stack_aliasing() {
+    // Beetle assembler:
+    // $00: !
+    //      DUP
+    //      0
+    //      HALT
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    vm.load_object(&[0x5519013A]);
+    let initial_sp = vm.sp;
+    vm.push(7);
+    let addr = vm.sp;
+    vm.push(42);
+    vm.push(addr);
+    let exit = unsafe { vm.run(0) };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    // `!` overwrote the 7, and `DUP` read it back from the stack.
+    assert_eq!(vm.pop(), 42);
+    assert_eq!(vm.pop(), 42);
+    assert_eq!(vm.sp, initial_sp);
+}
+
+/// Runs `opcode` on the stack `[n1, n2, n3]`, which it is expected to
+/// consume, in `vm`, whose memory starts with `bytes`. Returns the first
+/// `bytes.len()` bytes of memory afterwards.
+fn run_block_opcode(opcode: u32, n1: u32, n2: u32, n3: u32, bytes: &[u8]) -> Vec<u8> {
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    assert_eq!(bytes.len() % CELL as usize, 0);
+    for (i, cell) in bytes.chunks(CELL as usize).enumerate() {
+        vm.store(i as u32 * CELL as u32, u32::from_ne_bytes(cell.try_into().unwrap()));
+    }
+    // Beetle assembler:
+    // $00: opcode
+    //      0
+    //      HALT
+    let code_addr = bytes.len() as u32;
+    vm.store(code_addr, 0x00551900 | opcode);
+    let initial_sp = vm.sp;
+    vm.push(n1);
+    vm.push(n2);
+    vm.push(n3);
+    let exit = unsafe { vm.run(code_addr) };
+    assert_eq!(exit, BeetleExit::Halt(0));
+    assert_eq!(vm.sp, initial_sp);
+    vm.memory()[..bytes.len() / CELL as usize].iter()
+        .flat_map(|cell| cell.to_ne_bytes())
+        .collect()
+}
+
+#[test]
+pub fn move_block() {
+    const MOVE: u32 = 0x63;
+    const CMOVE: u32 = 0x64;
+    const CMOVE_BACKWARDS: u32 = 0x65;
+    let bytes: Vec<u8> = (0..200).map(|i| (i * 7) as u8).collect();
+    for (src, dest) in [(0, 100), (10, 40), (40, 10), (20, 21), (21, 20), (30, 30)] {
+        let mut expected = bytes.clone();
+        expected.copy_within(src..src + 100, dest);
+        for opcode in [MOVE, CMOVE, CMOVE_BACKWARDS] {
+            let observed = run_block_opcode(opcode, src as u32, dest as u32, 100, &bytes);
+            // `CMOVE` and `CMOVE>` only behave like `MOVE` if they copy in
+            // the right direction.
+            let is_like_move = match opcode {
+                CMOVE => dest <= src || dest >= src + 100,
+                CMOVE_BACKWARDS => dest >= src || dest + 100 <= src,
+                _ => true,
+            };
+            if is_like_move {
+                assert_eq!(observed, expected, "opcode {:#x}, {} to {}", opcode, src, dest);
+            } else {
+                assert_ne!(observed, expected, "opcode {:#x}, {} to {}", opcode, src, dest);
+            }
+        }
+    }
+    // `CMOVE` propagates a byte forwards through an overlapping region.
+    let observed = run_block_opcode(CMOVE, 20, 21, 100, &bytes);
+    assert!(observed[20..121].iter().all(|&b| b == bytes[20]));
+    assert_eq!(observed[121..], bytes[121..]);
+    // Copying zero bytes does nothing.
+    for opcode in [MOVE, CMOVE, CMOVE_BACKWARDS] {
+        assert_eq!(run_block_opcode(opcode, 10, 40, 0, &bytes), bytes);
+    }
+}
+
+#[test]
+pub fn fill() {
+    const FILL: u32 = 0x66;
+    let bytes: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    let mut expected = bytes.clone();
+    expected[13..113].fill(0xA5);
+    // Only the low byte of `char` is significant.
+    assert_eq!(run_block_opcode(FILL, 13, 100, 0x123A5, &bytes), expected);
+    assert_eq!(run_block_opcode(FILL, 13, 0, 0xA5, &bytes), bytes);
+}
+
+#[test]
+pub fn compare() {
+    const COMPARE: u32 = 0x6B;
+    const CODE_ADDR: u32 = 0x100;
+    let bytes = b"abcdefghabcdxyzz\xFF\0\0\0";
+    let mut vm = VM::new(MEMORY_CELLS, DATA_CELLS, RETURN_CELLS);
+    for (i, cell) in bytes.chunks(CELL as usize).enumerate() {
+        vm.store(i as u32 * CELL as u32, u32::from_ne_bytes(cell.try_into().unwrap()));
+    }
+    // Beetle assembler:
+    // $00: COMPARE
+    //      0
+    //      HALT
+    vm.store(CODE_ADDR, 0x00551900 | COMPARE);
+    let initial_sp = vm.sp;
+    for (addr1, u1, addr2, u2, expected) in [
+        (0, 4, 8, 4, 0),
+        (0, 5, 8, 5, -1),
+        (8, 5, 0, 5, 1),
+        (13, 2, 14, 2, -1),
+        // A proper prefix is less.
+        (0, 3, 8, 4, -1),
+        (0, 4, 8, 3, 1),
+        (0, 0, 8, 0, 0),
+        (0, 0, 8, 1, -1),
+        // The characters are unsigned.
+        (16, 1, 0, 1, 1),
+        (0, 1, 16, 1, -1),
+    ] {
+        vm.push(addr1);
+        vm.push(u1);
+        vm.push(addr2);
+        vm.push(u2);
+        let exit = unsafe { vm.run(CODE_ADDR) };
+        assert_eq!(exit, BeetleExit::Halt(0));
+        assert_eq!(vm.pop(), expected as u32, "{} {} {} {}", addr1, u1, addr2, u2);
+        assert_eq!(vm.sp, initial_sp);
+    }
+}
@@ -0,0 +1,630 @@
+use super::super::target::{Native, native};
+use super::super::jit::{Jit, Interpreter, SharedJit, Run};
+
+use super::{Registers, M0Registers, CELL, Beetle, BeetleOptions, Stop};
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The suggested size of the Beetle memory, in cells.
+pub const MEMORY_CELLS: u32 = 1 << 20;
+/// The suggested size of the Beetle data stack, in cells.
+pub const DATA_CELLS: u32 = 1 << 18;
+/// The suggested size of the Beetle return stack, in cells.
+pub const RETURN_CELLS: u32 = 1 << 18;
+
+/// The `HALT` opcode.
+const HALT: u32 = 0x55;
+/// The `LIB` opcode.
+const LIB: u32 = 0x57;
+
+/// `LIB` routine `( -- ms )`: reads the clock, in milliseconds. Guest code
+/// may busy-wait on this.
+pub const LIB_MS: u32 = 0;
+/// `LIB` routine `( -- sec min hour day month year )`: reads the date.
+pub const LIB_TIME_DATE: u32 = 1;
+/// `LIB` routine `( ms -- )`: waits for `ms` milliseconds. In
+/// [`ClockMode::Virtual`] this advances the clock instead of sleeping.
+pub const LIB_SLEEP: u32 = 2;
+
+/// Returns the number of bytes in `cells` cells.
+///
+/// Panics if the result does not fit in a Beetle address.
+fn cell_bytes(cells: u32) -> u32 {
+    cells.checked_mul(CELL as u32).expect("Address out of range")
+}
+
+/// Selects how the clock words tell the time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockMode {
+    /// Use the host clock.
+    Real,
+    /// Use a clock that only moves when the guest calls [`LIB_SLEEP`], when
+    /// the host calls [`VM::advance_clock()`], and by `instruction_time` for
+    /// each instruction executed. `start` is the date at which it starts,
+    /// measured from the Unix epoch. A non-zero `instruction_time` requires
+    /// the `VM` to be constructed with profiling, in order to count the
+    /// instructions; see [`Beetle::instructions()`].
+    Virtual {start: Duration, instruction_time: Duration},
+}
+
+/// Why [`VM::run()`] returned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BeetleExit {
+    /// The program executed `HALT`. The payload is the code that was on top
+    /// of the data stack, which has been popped.
+    Halt(u32),
+    /// The program executed an instruction that Beetle does not implement,
+    /// or a `LIB` routine that the `VM` does not provide. The payload is
+    /// the opcode. This also happens if `HALT` or a `LIB` routine would
+    /// access the data stack out of range. The VM state is as it was before
+    /// the instruction, so the caller may perform it and call [`VM::run()`]
+    /// again.
+    ///
+    /// Exceptions do not appear here: `THROW` passes control to the guest's
+    /// handler at `'THROW`.
+    NotImplemented(u32),
+    /// A push would have moved `sp` below `s_limit` or `rp` below `r_limit`.
+    /// The stack pointer is unchanged, but the instruction may have been
+    /// partly executed. `LIB` routines check their pushes before doing
+    /// anything.
+    StackOverflow,
+    /// An exception was raised, but `sp`, [`Registers::throw`] or the
+    /// address of the handler was invalid. The invalid address is in
+    /// [`Registers::not_address`].
+    BadThrow,
+}
+
+/// A snapshot of a [`VM`], made by [`VM::save_state()`]. It comprises the
+/// registers and the memory, but not the compiled code.
+#[derive(Debug, Clone)]
+pub struct VMState {
+    registers: Registers,
+    memory: Vec<u32>,
+}
+
+/// The version number written by [`VMState::to_bytes()`].
+const STATE_VERSION: u32 = 1;
+
+/// The ways in which [`VMState::from_bytes()`] or [`VM::try_restore_state()`]
+/// can fail.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// The bytes end before the end of the state.
+    Truncated,
+    /// The bytes continue after the end of the state.
+    TrailingBytes,
+    /// The state was written by an incompatible version. The payload is
+    /// the version number found.
+    BadVersion(u32),
+    /// The state has the first number of memory cells, but the `VM` has the
+    /// second number.
+    WrongMemorySize(usize, usize),
+    /// [`Registers::memory_size`] does not match the memory, or a stack
+    /// pointer or limit is unaligned or beyond the memory. Running such a
+    /// state could access host memory.
+    BadRegisters,
+}
+
+impl VMState {
+    /// Serializes the state. The layout is a sequence of little-endian
+    /// `u32`s: the version number, the registers in the order they are
+    /// declared in [`Registers`], the number of memory cells, and the
+    /// memory. It does not depend on the compiled code, so it can be
+    /// restored into a `VM` in another process.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut words = vec![STATE_VERSION];
+        words.extend(self.registers.to_array());
+        words.push(u32::try_from(self.memory.len()).expect("Memory too big"));
+        words.extend(&self.memory);
+        words.into_iter().flat_map(u32::to_le_bytes).collect()
+    }
+
+    /// The inverse of `to_bytes()`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StateError> {
+        let mut words = bytes.chunks(4).map(|chunk| {
+            <[u8; 4]>::try_from(chunk).map(u32::from_le_bytes)
+                .map_err(|_| StateError::Truncated)
+        });
+        let mut next = || words.next().unwrap_or(Err(StateError::Truncated));
+        let version = next()?;
+        if version != STATE_VERSION { return Err(StateError::BadVersion(version)); }
+        let mut array = [0; Registers::COUNT];
+        for r in &mut array { *r = next()?; }
+        let memory_cells = next()? as usize;
+        let memory = (0..memory_cells).map(|_| next()).collect::<Result<_, _>>()?;
+        if words.next().is_some() { return Err(StateError::TrailingBytes); }
+        let state = VMState {registers: Registers::from_array(array), memory};
+        state.check()?;
+        Ok(state)
+    }
+
+    /// Checks that the registers are consistent with the memory.
+    fn check(&self) -> Result<(), StateError> {
+        let r = &self.registers;
+        if r.memory_size as usize != self.memory.len() * CELL as usize {
+            return Err(StateError::BadRegisters);
+        }
+        let is_aligned = |addr: u32| addr & (CELL as u32 - 1) == 0;
+        for p in [r.sp, r.rp, r.s_limit, r.r_limit, r.s0, r.r0] {
+            if p > r.memory_size || !is_aligned(p) {
+                return Err(StateError::BadRegisters);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A Beetle virtual machine, comprising the compiled code, the registers and
+/// the memory.
+pub struct VM<J: Run = Jit<Native>> {
+    /// The compiled code.
+    beetle: Beetle<J>,
+    /// The Beetle state (other than the memory).
+    state: M0Registers,
+    /// The Beetle memory.
+    memory: Vec<u32>,
+    /// The amount of unallocated memory, in cells.
+    free_cells: u32,
+    /// The address of a HALT instruction.
+    halt_addr: u32,
+    /// The registers as they were after construction. See `reset()`.
+    initial: Registers,
+    /// How the clock words tell the time.
+    clock: ClockMode,
+    /// The host time at which the VM was constructed. [`LIB_MS`] counts
+    /// from here in [`ClockMode::Real`].
+    epoch: Instant,
+    /// The time elapsed on the virtual clock, not counting instructions.
+    elapsed: Duration,
+    /// The value of [`Beetle::instructions()`] when the virtual clock was
+    /// last reset.
+    instructions: u64,
+    /// The opcode executed by the most recent call to [`VM::step()`].
+    last_opcode: Option<u8>,
+}
+
+impl VM {
+    /// Constructs a Beetle virtual machine with the specified parameters.
+    ///
+    /// The memory is `memory_cells` cells. The data stack occupies the last
+    /// `data_cells` cells of the memory, and the return stack occupies
+    /// the last `return_cells` cells before that. The cells before that
+    /// are free for the program's use.
+    ///
+    /// Panics if the memory is too big to address, or if the stacks do not
+    /// fit in it.
+    pub fn new(
+        memory_cells: u32,
+        data_cells: u32,
+        return_cells: u32,
+    ) -> Self {
+        Self::with_options(memory_cells, data_cells, return_cells, BeetleOptions::default(), 0)
+    }
+
+    /// Like `new()` but compiles Beetle with `options`. See
+    /// [`Beetle::with_options()`]. If `options.next_hook` is set, the hook is
+    /// initially set to call a word at address zero every `period` NEXTs.
+    pub fn with_options(
+        memory_cells: u32,
+        data_cells: u32,
+        return_cells: u32,
+        options: BeetleOptions,
+        period: u32,
+    ) -> Self {
+        let beetle = Beetle::with_options(native(), options);
+        Self::with_beetle(beetle, memory_cells, data_cells, return_cells, period)
+    }
+
+    /// Calls [`Beetle::specialize()`].
+    pub fn specialize(&mut self, threshold: u64, budget: usize) -> usize {
+        self.beetle.specialize(threshold, budget)
+    }
+}
+
+impl VM<Interpreter> {
+    /// Like [`VM::new()`] but interprets Beetle instead of compiling it. See
+    /// [`Beetle::interpreted()`].
+    pub fn interpreted(
+        memory_cells: u32,
+        data_cells: u32,
+        return_cells: u32,
+    ) -> Self {
+        Self::with_beetle(Beetle::interpreted(), memory_cells, data_cells, return_cells, 0)
+    }
+
+    /// Like [`VM::interpreted()`] but in single-step mode, so that
+    /// [`step()`] can be used. See [`Beetle::interpreted_with_single_step()`].
+    ///
+    /// [`step()`]: VM::step
+    pub fn interpreted_with_single_step(
+        memory_cells: u32,
+        data_cells: u32,
+        return_cells: u32,
+    ) -> Self {
+        let beetle = Beetle::interpreted_with_single_step();
+        Self::with_beetle(beetle, memory_cells, data_cells, return_cells, 0)
+    }
+}
+
+impl VM<SharedJit<Native>> {
+    /// Like [`VM::new()`] but runs the code compiled by `beetle`, which may
+    /// be shared with `VM`s on other threads. See [`Beetle::shared()`].
+    pub fn with_shared(
+        beetle: &Beetle<SharedJit<Native>>,
+        memory_cells: u32,
+        data_cells: u32,
+        return_cells: u32,
+    ) -> Self {
+        Self::with_beetle(beetle.clone(), memory_cells, data_cells, return_cells, 0)
+    }
+}
+
+impl<J: Run> VM<J> {
+    fn with_beetle(
+        beetle: Beetle<J>,
+        memory_cells: u32,
+        data_cells: u32,
+        return_cells: u32,
+        period: u32,
+    ) -> Self {
+        let memory_size = cell_bytes(memory_cells);
+        let mut vm = VM {
+            beetle,
+            state: M0Registers {
+                m0: std::ptr::null_mut(),
+                registers: Registers::default(),
+            },
+            memory: vec![0; memory_cells as usize],
+            free_cells: memory_cells,
+            halt_addr: 0,
+            initial: Registers::default(),
+            clock: ClockMode::Real,
+            epoch: Instant::now(),
+            elapsed: Duration::ZERO,
+            instructions: 0,
+            last_opcode: None,
+        };
+        vm.memory_size = memory_size;
+        // Allocate the return stack.
+        (vm.r_limit, vm.rp) = vm.allocate(return_cells);
+        vm.r0 = vm.rp;
+        // Allocate the data stack.
+        (vm.s_limit, vm.sp) = vm.allocate(data_cells);
+        vm.s0 = vm.sp;
+        // Allocate a word to hold a HALT instruction.
+        vm.halt_addr = vm.allocate(1).0;
+        vm.store(vm.halt_addr, 0x5519);
+        vm.next_count = period;
+        vm.next_period = period;
+        vm.initial = vm.registers.clone();
+        vm
+    }
+
+    /// Restores the registers to the state in which the constructor left
+    /// them, and resets the virtual clock. If `clear_memory` is `true`, also
+    /// zeroes the memory, except for the HALT instruction allocated by the
+    /// constructor. The compiled code is retained, so that the `VM` can run
+    /// another program without recompiling anything.
+    pub fn reset(&mut self, clear_memory: bool) {
+        self.state.registers = self.initial.clone();
+        self.reset_clock();
+        if clear_memory {
+            self.memory.fill(0);
+            self.store(self.halt_addr, 0x5519);
+        }
+    }
+
+    /// Read the memory.
+    pub fn memory(&self) -> &[u32] { &self.memory }
+
+    /// Returns a copy of the registers and the memory.
+    pub fn save_state(&self) -> VMState {
+        VMState {registers: self.registers.clone(), memory: self.memory.clone()}
+    }
+
+    /// Replaces the registers and the memory with those in `state`. The
+    /// compiled code is retained, so `state` may come from a different `VM`.
+    ///
+    /// Panics if `state` has a different memory size, or if its registers
+    /// are inconsistent with its memory (see [`StateError::BadRegisters`]).
+    pub fn restore_state(&mut self, state: VMState) {
+        self.try_restore_state(state).expect("Cannot restore state");
+    }
+
+    /// Like `restore_state()` but returns an error instead of panicking.
+    pub fn try_restore_state(&mut self, state: VMState) -> Result<(), StateError> {
+        if state.memory.len() != self.memory.len() {
+            return Err(StateError::WrongMemorySize(state.memory.len(), self.memory.len()));
+        }
+        state.check()?;
+        self.state.registers = state.registers;
+        self.memory = state.memory;
+        Ok(())
+    }
+
+    /// Allocate `cells` cells and return a (start, end) Beetle pointer pair.
+    /// Allocation starts at the top of memory and is permanent.
+    ///
+    /// Panics if there are fewer than `cells` free cells.
+    pub fn allocate(&mut self, cells: u32) -> (u32, u32) {
+        assert!(cells <= self.free_cells);
+        let end = cell_bytes(self.free_cells);
+        self.free_cells = self.free_cells.checked_sub(cells)
+            .expect("Out of memory");
+        let start = cell_bytes(self.free_cells);
+        (start, end)
+    }
+
+    /// Load `object` at address zero, i.e. in the unallocated memory.
+    pub fn load_object(&mut self, object: &[u32]) {
+        assert!(object.len() <= self.free_cells as usize);
+        for (i, &cell) in object.iter().enumerate() {
+            self.memory[i] = cell;
+        }
+    }
+
+    /// Return the value of the word at address `addr`.
+    pub fn load(&self, addr: u32) -> u32 {
+        assert_eq!(addr & 0x3, 0);
+        self.memory[(addr >> 2) as usize]
+    }
+
+    /// Set the word at address `addr` to `value`.
+    pub fn store(&mut self, addr: u32, value: u32) {
+        assert_eq!(addr & 0x3, 0);
+        self.memory[(addr >> 2) as usize] = value;
+    }
+
+    /// Push `item` onto the data stack.
+    pub fn push(&mut self, item: u32) {
+        self.sp -= CELL as u32;
+        self.store(self.sp, item);
+    }
+
+    /// Pop an item from the data stack.
+    pub fn pop(&mut self) -> u32 {
+        let item = self.load(self.sp);
+        self.sp += CELL as u32;
+        item
+    }
+
+    /// Push `item` onto the return stack.
+    pub fn rpush(&mut self, item: u32) {
+        self.rp -= CELL as u32;
+        self.store(self.rp, item);
+    }
+
+    /// Pop an item from the return stack.
+    pub fn rpop(&mut self) -> u32 {
+        let item = self.load(self.rp);
+        self.rp += CELL as u32;
+        item
+    }
+
+    /// Returns the address of a cell containing a `HALT` instruction.
+    /// Pushing it onto the return stack makes `EXIT` stop the VM.
+    pub fn halt_addr(&self) -> u32 { self.halt_addr }
+
+    /// Returns the compiled code.
+    pub fn beetle(&self) -> &Beetle<J> { &self.beetle }
+
+    /// Run the code at address `ep` until it stops, and say why.
+    /// `LIB` calls are serviced without returning.
+    ///
+    /// # Safety
+    ///
+    /// See [`Beetle::run()`]. The `VM` sets `m0` correctly, and sets
+    /// `memory_size` correctly unless the caller changes it. Pushes are
+    /// checked against the stack limits.
+    pub unsafe fn run(&mut self, ep: u32) -> BeetleExit {
+        assert!(Self::is_aligned(ep));
+        self.ep = ep;
+        loop {
+            if let Some(exit) = self.run_one() { return exit; }
+        }
+    }
+
+    /// Executes one instruction, starting with the opcode in the low byte of
+    /// [`Registers::a`], or NEXT if it is zero. Returns `None` if execution
+    /// can continue, otherwise says why it can't. `LIB` calls are serviced
+    /// without returning.
+    ///
+    /// Panics if Beetle was not compiled with [`BeetleOptions::single_step`].
+    ///
+    /// # Safety
+    ///
+    /// See [`run()`].
+    ///
+    /// [`run()`]: Self::run
+    pub unsafe fn step(&mut self) -> Option<BeetleExit> {
+        assert!(self.beetle.is_single_step(), "Not compiled for single-step mode");
+        assert!(Self::is_aligned(self.ep));
+        self.last_opcode = Some((self.a & 0xFF) as u8);
+        self.run_one()
+    }
+
+    /// Returns the opcode executed by the most recent call to [`step()`], if
+    /// any.
+    ///
+    /// [`step()`]: Self::step
+    pub fn last_opcode(&self) -> Option<u8> { self.last_opcode }
+
+    /// Calls [`Beetle::run()`] once, and services the instruction that made
+    /// it stop, if possible. Returns `None` if execution can continue.
+    unsafe fn run_one(&mut self) -> Option<BeetleExit> {
+        self.state.m0 = self.memory.as_mut_ptr();
+        match self.beetle.run(&mut self.state) {
+            Stop::Step => return None,
+            Stop::StackOverflow => return Some(BeetleExit::StackOverflow),
+            Stop::BadThrow => return Some(BeetleExit::BadThrow),
+            Stop::NotImplemented => {},
+        }
+        match self.a & 0xFF {
+            HALT => {
+                if !self.can_pop(1) { return Some(BeetleExit::NotImplemented(HALT)); }
+                self.a >>= 8;
+                Some(BeetleExit::Halt(self.pop()))
+            },
+            LIB => {
+                if !self.can_pop(1) { return Some(BeetleExit::NotImplemented(LIB)); }
+                let routine = self.load(self.sp);
+                let (pops, pushes) = match routine {
+                    LIB_MS => (1, 1),
+                    LIB_TIME_DATE => (1, 6),
+                    LIB_SLEEP => (2, 0),
+                    _ => return Some(BeetleExit::NotImplemented(LIB)),
+                };
+                if !self.can_pop(pops) { return Some(BeetleExit::NotImplemented(LIB)); }
+                if pushes > pops && !self.can_push(pushes - pops) { return Some(BeetleExit::StackOverflow); }
+                self.a >>= 8;
+                self.pop();
+                self.lib(routine);
+                None
+            },
+            opcode => Some(BeetleExit::NotImplemented(opcode)),
+        }
+    }
+
+    /// Returns `true` if the data stack pointer is cell-aligned, and the
+    /// `cells` cells starting at it are memory.
+    fn can_pop(&self, cells: u32) -> bool {
+        let end = (cells as usize * CELL as usize).checked_add(self.sp as usize);
+        Self::is_aligned(self.sp) && end.map_or(false, |end| {
+            end <= self.memory_size as usize && end <= self.memory.len() * CELL as usize
+        })
+    }
+
+    /// Returns `true` if `cells` cells can be pushed onto the data stack
+    /// without moving the data stack pointer below [`Registers::s_limit`] or
+    /// out of the memory.
+    fn can_push(&self, cells: u32) -> bool {
+        self.can_pop(0) && self.sp.checked_sub(cells * CELL as u32).map_or(false, |sp| sp >= self.s_limit)
+    }
+
+    /// Performs `LIB` routine `routine`, which must exist.
+    fn lib(&mut self, routine: u32) {
+        match routine {
+            LIB_MS => {
+                #[allow(clippy::cast_possible_truncation)]
+                let ms = match self.clock {
+                    ClockMode::Real => self.epoch.elapsed(),
+                    ClockMode::Virtual {..} => self.virtual_elapsed(),
+                }.as_millis() as u32;
+                self.push(ms);
+            },
+            LIB_TIME_DATE => {
+                for field in date_fields(self.date().as_secs()) {
+                    self.push(field);
+                }
+            },
+            LIB_SLEEP => {
+                let ms = self.pop();
+                match self.clock {
+                    ClockMode::Real => std::thread::sleep(Duration::from_millis(ms.into())),
+                    ClockMode::Virtual {..} => self.advance_clock(ms),
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Selects how the clock words tell the time. Selecting
+    /// [`ClockMode::Virtual`] resets the virtual clock.
+    ///
+    /// Panics if `clock` has a non-zero `instruction_time` but the `VM` was
+    /// not constructed with profiling.
+    pub fn set_clock(&mut self, clock: ClockMode) {
+        if let ClockMode::Virtual {instruction_time, ..} = clock {
+            assert!(
+                instruction_time.is_zero() || self.beetle.instructions().is_some(),
+                "Not compiled with profiling",
+            );
+        }
+        self.clock = clock;
+        self.reset_clock();
+    }
+
+    /// Resets the virtual clock to its start.
+    fn reset_clock(&mut self) {
+        self.elapsed = Duration::ZERO;
+        self.instructions = self.beetle.instructions().unwrap_or(0);
+    }
+
+    /// Returns the time elapsed on the virtual clock.
+    fn virtual_elapsed(&self) -> Duration {
+        let instruction_time = match self.clock {
+            ClockMode::Virtual {instruction_time, ..} => instruction_time,
+            ClockMode::Real => Duration::ZERO,
+        };
+        if instruction_time.is_zero() { return self.elapsed; }
+        let instructions = self.beetle.instructions().unwrap_or(0).saturating_sub(self.instructions);
+        let nanos = u64::try_from(instruction_time.as_nanos()).unwrap_or(u64::MAX);
+        self.elapsed.saturating_add(Duration::from_nanos(nanos.saturating_mul(instructions)))
+    }
+
+    /// Advances the virtual clock by `ms` milliseconds.
+    pub fn advance_clock(&mut self, ms: u32) {
+        self.elapsed += Duration::from_millis(ms.into());
+    }
+
+    /// Returns the current date, measured from the Unix epoch.
+    fn date(&self) -> Duration {
+        match self.clock {
+            ClockMode::Real => SystemTime::now().duration_since(UNIX_EPOCH)
+                .expect("Clock is before 1970"),
+            ClockMode::Virtual {start, ..} => start + self.virtual_elapsed(),
+        }
+    }
+
+    /// Indicate whether an address is cell-aligned.
+    pub fn is_aligned(addr: u32) -> bool {
+        addr & 0x3 == 0
+    }
+}
+
+impl<J: Run> std::fmt::Debug for VM<J> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        f.debug_struct("VM")
+            .field("state", &self.state)
+            .field("m0", &format!("{:#x}", self.memory().as_ptr() as u64))
+            .finish()
+    }
+}
+
+impl<J: Run> std::ops::Deref for VM<J> {
+    type Target = M0Registers;
+    fn deref(&self) -> &Self::Target { &self.state }
+}
+
+impl<J: Run> std::ops::DerefMut for VM<J> {
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.state }
+}
+
+/// Splits `secs` since the Unix epoch into the UTC date fields returned by
+/// `TIME&DATE`, in the order they are pushed: second, minute, hour, day,
+/// month, year.
+#[allow(clippy::cast_possible_truncation)]
+pub(super) fn date_fields(secs: u64) -> [u32; 6] {
+    let days = secs / 86400;
+    let secs = secs % 86400;
+    // Convert `days` to a date in the proleptic Gregorian calendar.
+    // The calendar repeats every 400 years, which is 146097 days. The
+    // year starts on 1st March, so that leap days come at the end.
+    let days = days + 719468; // 0000-03-01 to 1970-01-01.
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153; // March is zero.
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (month, year) = if month < 10 { (month + 3, 0) } else { (month - 9, 1) };
+    let year = era * 400 + year_of_era + year;
+    [
+        (secs % 60) as u32,
+        (secs / 60 % 60) as u32,
+        (secs / 3600) as u32,
+        day as u32,
+        month as u32,
+        year as u32,
+    ]
+}
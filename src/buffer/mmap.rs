@@ -1,16 +1,46 @@
+use std::io::{Result};
+use std::marker::{PhantomData};
 use std::ops::{Deref, DerefMut};
 use memmap::{MmapMut, Mmap as MmapExec};
 use super::{Buffer};
 
+/// Changes the permissions of the memory of an [`Mmap`].
+pub trait Protect {
+    /// Makes `memory` read-execute.
+    fn make_exec(memory: MmapMut) -> Result<MmapExec>;
+
+    /// Makes `memory` read-write.
+    fn make_mut(memory: MmapExec) -> Result<MmapMut>;
+}
+
+/// Changes the permissions of memory using `mprotect()`.
+#[derive(Debug)]
+pub struct Mprotect;
+
+impl Protect for Mprotect {
+    fn make_exec(memory: MmapMut) -> Result<MmapExec> { memory.make_exec() }
+
+    fn make_mut(memory: MmapExec) -> Result<MmapMut> { memory.make_mut() }
+}
+
+//-----------------------------------------------------------------------------
+
 /// Represents a block of memory claimed from the operating system using
 /// `mmap()`. Memory allocated in this way can be made executable.
-pub enum Mmap {
+///
+/// The memory is never writable and executable at the same time. It is made
+/// read-write by any mutable access, and read-execute by [`execute()`],
+/// using `P` if its current permissions are wrong. Code can therefore be
+/// executed, extended, and executed again.
+///
+/// [`execute()`]: Self::execute
+pub enum Mmap<P: Protect = Mprotect> {
     Mut(MmapMut),
     Exec(MmapExec),
-    Poisoned,
+    Poisoned(PhantomData<P>),
 }
 
-impl Mmap {
+impl<P: Protect> Mmap<P> {
     /// Make this [`Mmap`] executable if necessary, and pass it to `callback`.
     ///
     /// Panics if it can't change the buffer permissions. In this case the
@@ -19,14 +49,24 @@ impl Mmap {
         let m: &mut MmapExec = self.as_mut();
         callback(&*m)
     }
+
+    /// Returns the contents of this [`Mmap`] if they are executable, i.e.
+    /// if it has not been modified since the last call to [`execute()`].
+    /// Unlike `execute()`, this does not need exclusive access, so several
+    /// threads can execute the code at once.
+    ///
+    /// [`execute()`]: Self::execute
+    pub fn executable(&self) -> Option<&[u8]> {
+        if let Self::Exec(ref m) = self { Some(m) } else { None }
+    }
 }
 
-impl AsMut<MmapMut> for Mmap {
+impl<P: Protect> AsMut<MmapMut> for Mmap<P> {
     fn as_mut(&mut self) -> &mut MmapMut {
-        let mut new_self = Self::Poisoned;
+        let mut new_self = Self::Poisoned(PhantomData);
         std::mem::swap(self, &mut new_self);
         *self = match new_self {
-            Self::Exec(m) => Self::Mut(m.make_mut().expect("mprotect failed")),
+            Self::Exec(m) => Self::Mut(P::make_mut(m).expect("mprotect failed")),
             x => x,
         };
         match self {
@@ -36,12 +76,12 @@ impl AsMut<MmapMut> for Mmap {
     }
 }
 
-impl AsMut<MmapExec> for Mmap {
+impl<P: Protect> AsMut<MmapExec> for Mmap<P> {
     fn as_mut(&mut self) -> &mut MmapExec {
-        let mut new_self = Self::Poisoned;
+        let mut new_self = Self::Poisoned(PhantomData);
         std::mem::swap(self, &mut new_self);
         *self = match new_self {
-            Self::Mut(m) => Self::Exec(m.make_exec().expect("mprotect failed")),
+            Self::Mut(m) => Self::Exec(P::make_exec(m).expect("mprotect failed")),
             x => x,
         };
         match self {
@@ -51,26 +91,26 @@ impl AsMut<MmapExec> for Mmap {
     }
 }
 
-impl Deref for Mmap {
+impl<P: Protect> Deref for Mmap<P> {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
         match self {
             Self::Mut(ref m) => m,
             Self::Exec(ref m) => m,
-            Self::Poisoned => panic!("Poisoned by an earlier error"),
+            Self::Poisoned(_) => panic!("Poisoned by an earlier error"),
         }
     }
 }
 
-impl DerefMut for Mmap {
+impl<P: Protect> DerefMut for Mmap<P> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         let m: &mut MmapMut = self.as_mut();
         &mut *m
     }
 }
 
-impl Buffer for Mmap {
+impl<P: Protect> Buffer for Mmap<P> {
     fn new() -> Self {
         let memory = MmapMut::map_anon(0x1000).expect("Out of memory");
         Self::Mut(memory)
@@ -91,17 +131,81 @@ impl Buffer for Mmap {
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use std::cell::{RefCell};
+
+    /// The permissions that [`Mmap`] can give its memory.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Permissions {ReadWrite, ReadExecute}
+    use Permissions::*;
+
+    thread_local! {
+        /// The permissions set by [`Recorder`] on this thread, in order.
+        static RECORD: RefCell<Vec<Permissions>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// A [`Protect`] that records what it does, then calls [`Mprotect`].
+    #[derive(Debug)]
+    pub struct Recorder;
+
+    impl Recorder {
+        /// Returns and forgets the permissions set on this thread so far.
+        pub fn take() -> Vec<Permissions> {
+            RECORD.with(|record| record.take())
+        }
+    }
+
+    impl Protect for Recorder {
+        fn make_exec(memory: MmapMut) -> Result<MmapExec> {
+            RECORD.with(|record| record.borrow_mut().push(ReadExecute));
+            Mprotect::make_exec(memory)
+        }
+
+        fn make_mut(memory: MmapExec) -> Result<MmapMut> {
+            RECORD.with(|record| record.borrow_mut().push(ReadWrite));
+            Mprotect::make_mut(memory)
+        }
+    }
 
     #[test]
     fn api() {
-        let buffer = Mmap::new();
+        let buffer: Mmap = Mmap::new();
         super::super::tests::api(buffer)
     }
 
     #[test]
     fn execute() {
-        let mut buffer = Mmap::new();
+        let mut buffer: Mmap = Mmap::new();
         let result = buffer.execute(|_bytes| 42);
         assert_eq!(result, 42);
     }
+
+    /// Test that writing and executing alternate the permissions, and that
+    /// the contents survive.
+    #[test]
+    fn write_xor_execute() {
+        let mut buffer = Mmap::<Recorder>::new();
+        buffer.write(0, 0x12345678, 4);
+        assert!(matches!(buffer, Mmap::Mut(_)));
+        assert!(buffer.executable().is_none());
+        buffer.execute(|bytes| assert_eq!(bytes[0], 0x78));
+        assert!(matches!(buffer, Mmap::Exec(_)));
+        assert_eq!(buffer.executable().map(|bytes| bytes[0]), Some(0x78));
+        assert_eq!(Recorder::take(), [ReadExecute]);
+        // Reading does not change the permissions.
+        assert_eq!(buffer.read(0, 4), 0x12345678);
+        assert!(matches!(buffer, Mmap::Exec(_)));
+        assert_eq!(Recorder::take(), []);
+        // Writing does, even if the buffer grows.
+        buffer.write(0x2000, 0x9A, 1);
+        assert!(matches!(buffer, Mmap::Mut(_)));
+        assert_eq!(Recorder::take(), [ReadWrite]);
+        buffer.execute(|bytes| {
+            assert_eq!(bytes[0], 0x78);
+            assert_eq!(bytes[0x2000], 0x9A);
+        });
+        assert!(matches!(buffer, Mmap::Exec(_)));
+        // Executing again does not change the permissions.
+        buffer.execute(|_| ());
+        assert_eq!(Recorder::take(), [ReadExecute]);
+    }
 }
@@ -1,7 +1,7 @@
 use std::ops::{DerefMut};
 
 mod mmap;
-pub use mmap::{Mmap};
+pub use mmap::{Protect, Mprotect, Mmap};
 
 /// An auto-growing array of bytes. In addition to the usual slice API, methods
 /// are provided for reading or writing up to 8 bytes at a time using a `u64`.
@@ -9,6 +9,8 @@ pub use mmap::{Mmap};
 /// [`Vec<u8>`] implements this trait, which is useful for testing.
 ///
 /// [`Mmap`] implements this trait and allows the bytes to be executed as code.
+/// Resizing a `Buffer` may move it, so code written into it must not depend
+/// on its own address.
 pub trait Buffer: Sized + DerefMut<Target=[u8]> {
     /// Allocates a fresh `Buffer` with a default (small) length.
     fn new() -> Self;
@@ -76,6 +78,8 @@ impl Buffer for Vec<u8> {
 pub mod tests {
     use super::*;
 
+    pub use super::mmap::tests::{Permissions, Recorder};
+
     /// Any tests of the [`Buffer`] API, for use by submodule tests.
     pub fn api(mut buffer: impl Buffer) {
         buffer.resize(16);
@@ -1,4 +1,4 @@
-use super::{Register, Variable, Precision, UnaryOp, BinaryOp, Width};
+use super::{Register, Slot, Variable, Precision, UnaryOp, BinaryOp, Width, FenceOrder};
 
 /// Called by [`Action::Debug`].
 #[no_mangle]
@@ -6,10 +6,40 @@ pub extern fn debug_word(x: u64) {
     println!("Debug: {:#018x}", x);
 }
 
-/// A memory operand. This is used by [`Load`] and [`Store`] actions.
+/// A native function that [`Action::Call`] can call. It uses the platform's C
+/// calling convention, and it must not unwind.
+///
+/// `NativeFunction`s compare equal if they have the same address.
+#[derive(Copy, Clone)]
+pub struct NativeFunction(pub extern "C" fn(u64, u64) -> u64);
+
+impl NativeFunction {
+    /// Returns the address of the function.
+    pub fn address(self) -> usize { self.0 as usize }
+}
+
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &Self) -> bool { self.address() == other.address() }
+}
+
+impl Eq for NativeFunction {}
+
+impl std::hash::Hash for NativeFunction {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) { self.address().hash(state) }
+}
+
+impl std::fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:#x}", self.address())
+    }
+}
+
+/// A memory operand. This is used by [`Load`], [`Store`] and [`AtomicCas`]
+/// actions.
 ///
 /// [`Load`]: `Action::Load`
 /// [`Store`]: `Action::Store`
+/// [`AtomicCas`]: `Action::AtomicCas`
 #[derive(Copy, Clone, Hash, PartialEq, Eq)]
 pub struct Address {
     /// The base address.
@@ -47,7 +77,14 @@ pub enum Action {
     /// dest <- op(src1, src2)
     Binary(BinaryOp, Precision, Register, Variable, Variable),
 
-    /// dest <- \[addr]
+    /// dest <- if cond != 0 { src1 } else { src2 }
+    ///
+    /// Only the low `Precision` bits of `cond` are tested, and the result is
+    /// zero-extended from the `Precision`, as for `Binary`.
+    Select(Precision, Register, Variable, Variable, Variable),
+
+    /// dest <- \[addr], zero-extended. For a sign-extending load, follow
+    /// it with [`UnaryOp::Sxt`].
     Load(Register, Address),
 
     /// dest <- addr.base; \[addr] <- \[src]
@@ -79,7 +116,134 @@ pub enum Action {
     Drop(usize),
 
     /// Pass `src` to [`debug_word()`].
+    ///
+    /// The optimizer removes `Debug`s in release builds, unless the "debug"
+    /// feature is enabled.
     Debug(Variable),
+
+    /// dest <- function(src1, src2)
+    ///
+    /// Memory accesses via `src1` and `src2` happen before the call. All
+    /// later memory accesses happen after the call. All [`Variable`]s other
+    /// than `dest` are preserved.
+    ///
+    /// As for `Send`, if you later `Load` or `Store` via `src1` or `src2`,
+    /// the behaviour is undefined.
+    Call(Register, NativeFunction, Variable, Variable),
+
+    /// Orders memory accesses as specified by the [`FenceOrder`], even as
+    /// observed by other threads. Unlike `Send`, this applies to memory
+    /// accesses via any address.
+    Fence(FenceOrder),
+
+    /// dest <- \[addr], zero-extended; if dest = expected then \[addr] <- new
+    ///
+    /// The comparison uses only the low `addr.width` bytes of `expected`.
+    /// The whole operation is atomic, even as observed by other threads,
+    /// and it also acts as a `Fence(FenceOrder::Full)`. Unlike `Store`, it
+    /// is fine to later `Load` or `Store` via `addr`.
+    AtomicCas(Register, Variable, Variable, Address),
+
+    /// dest <- the address of `slot`
+    ///
+    /// The address remains valid until `slot` is dropped. Memory accesses
+    /// via `dest` read and write the value of `slot`.
+    ///
+    /// The optimizer does not support `SlotAddress`, because it does not
+    /// keep values in any particular `Slot`. It may only be passed directly
+    /// to a [`Lower`], and [`Action::check()`] rejects it.
+    ///
+    /// [`Lower`]: crate::target::Lower
+    SlotAddress(Register, Slot),
+}
+
+/// The reasons why an [`Action`] might have undefined behaviour regardless of
+/// the values it computes. See [`Action::check()`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Undefined {
+    /// Accesses a [`Slot`] that does not exist, i.e. whose index is not less
+    /// than the number of `Slot`s in use.
+    NoSuchSlot(Slot),
+    /// `Drop(n)` when fewer than `2*n` [`Slot`]s are in use.
+    DropTooMany(usize),
+    /// An [`UnaryOp::Extract`] whose bit-field does not fit in the
+    /// [`Precision`].
+    BadExtract(Precision, u8, u8),
+    /// Reaches a leaf of an [`EBB`] with the first number of [`Slot`]s in
+    /// use, when the leaf expects the second number. See [`EBB::check()`].
+    ///
+    /// [`EBB`]: super::EBB
+    /// [`EBB::check()`]: super::EBB::check
+    Unbalanced(usize, usize),
+    /// An [`Action::SlotAddress`], which may only be passed directly to a
+    /// [`Lower`].
+    ///
+    /// [`Lower`]: crate::target::Lower
+    SlotAddress(Slot),
+}
+
+/// Checks that `v` exists when `slots_used` [`Slot`]s are in use.
+pub(super) fn check_variable(v: Variable, slots_used: usize) -> Result<(), Undefined> {
+    match v {
+        Variable::Slot(s) if s.0 >= slots_used => Err(Undefined::NoSuchSlot(s)),
+        _ => Ok(()),
+    }
+}
+
+impl Action {
+    /// Checks that `self` has defined behaviour when `slots_used` [`Slot`]s
+    /// are in use, and returns the number of `Slot`s in use afterwards.
+    ///
+    /// This is the definitive list of the forms of `Action` that Mijit
+    /// accepts. Behaviour that depends on the values computed, such as the
+    /// result of shifting by more than the word size, is not checked.
+    pub fn check(&self, slots_used: usize) -> Result<usize, Undefined> {
+        let check = |v| check_variable(v, slots_used);
+        match *self {
+            // Any `Variable` to any `Variable`, including itself.
+            Action::Move(dest, src) => { check(dest)?; check(src)?; },
+            // Any value. `P32` truncates it to 32 bits.
+            Action::Constant(_, _, _) => {},
+            // Any operation at any precision. `Uxt` and `Sxt` to a `Width`
+            // at least as wide as the `Precision` only truncate. `Extract`
+            // must select a non-empty bit-field within the `Precision`.
+            Action::Unary(op, prec, _, src) => {
+                if let UnaryOp::Extract(shift, bits) = op {
+                    if bits == 0 || shift as usize + bits as usize > prec.bits() {
+                        return Err(Undefined::BadExtract(prec, shift, bits));
+                    }
+                }
+                check(src)?;
+            },
+            // Any operation at any precision, with any operands.
+            Action::Binary(_, _, _, src1, src2) => { check(src1)?; check(src2)?; },
+            // Any precision, with any operands.
+            Action::Select(_, _, cond, src1, src2) => { check(cond)?; check(src1)?; check(src2)?; },
+            // `dest` may be `addr.base`, since `addr` is read first.
+            Action::Load(_, addr) => { check(addr.base)?; },
+            // `dest` may be `src` or `addr.base`, since they are read first.
+            Action::Store(_, src, addr) => { check(src)?; check(addr.base)?; },
+            Action::Send(_, src1, src2) => { check(src1)?; check(src2)?; },
+            // Either operand may be a `Slot`, including one of the same value.
+            Action::Push(src1, src2) => {
+                for src in [src1, src2].into_iter().flatten() { check(src)?; }
+                return Ok(slots_used + 2);
+            },
+            Action::Drop(n) => {
+                return slots_used.checked_sub(2 * n).ok_or(Undefined::DropTooMany(n));
+            },
+            Action::Debug(src) => { check(src)?; },
+            Action::Call(_, _, src1, src2) => { check(src1)?; check(src2)?; },
+            Action::Fence(_) => {},
+            Action::AtomicCas(_, expected, new, addr) => {
+                check(expected)?;
+                check(new)?;
+                check(addr.base)?;
+            },
+            Action::SlotAddress(_, slot) => { return Err(Undefined::SlotAddress(slot)); },
+        }
+        Ok(slots_used)
+    }
 }
 
 impl std::fmt::Debug for Action {
@@ -93,6 +257,8 @@ impl std::fmt::Debug for Action {
                 write!(f, "{:?}_{:?} {:?}, {:?}", op, prec, dest, src),
             Action::Binary(op, prec, dest, src1, src2) =>
                 write!(f, "{:?}_{:?} {:?}, {:?}, {:?}", op, prec, dest, src1, src2),
+            Action::Select(prec, dest, cond, src1, src2) =>
+                write!(f, "Select_{:?} {:?}, {:?}, {:?}, {:?}", prec, dest, cond, src1, src2),
             Action::Load(dest, addr) =>
                 write!(f, "Load {:?}, {:?}", dest, addr),
             Action::Store(dest, src, addr) =>
@@ -105,6 +271,14 @@ impl std::fmt::Debug for Action {
                 write!(f, "Drop 2*{:?}", n),
             Action::Debug(src) =>
                 write!(f, "Debug {:?}", src),
+            Action::Call(dest, function, src1, src2) =>
+                write!(f, "Call {:?}, {:?}({:?}, {:?})", dest, function, src1, src2),
+            Action::Fence(order) =>
+                write!(f, "Fence {:?}", order),
+            Action::AtomicCas(dest, expected, new, addr) =>
+                write!(f, "AtomicCas {:?}, {:?}, {:?}, {:?}", dest, expected, new, addr),
+            Action::SlotAddress(dest, slot) =>
+                write!(f, "SlotAddress {:?}, {:?}", dest, slot),
         }
     }
 }
@@ -115,9 +289,55 @@ impl std::fmt::Debug for Action {
 pub mod tests {
     use super::*;
 
+    /// A [`NativeFunction`] for tests.
+    pub extern "C" fn add(x: u64, y: u64) -> u64 { x.wrapping_add(y) }
+
     /// Ensure the linker symbol `debug_word` is included in the binary.
     #[test]
     fn not_really_a_test() {
         debug_word(0);
     }
+
+    /// Each rejected form of [`Action`] is rejected for the documented reason.
+    #[test]
+    fn check() {
+        use super::super::REGISTERS;
+        let r = REGISTERS[1];
+        let s: Variable = Slot(1).into();
+        let addr = |base| Address {base, offset: 0, width: Width::Eight};
+        let uses_s = [
+            Action::Move(s, r.into()),
+            Action::Move(r.into(), s),
+            Action::Unary(UnaryOp::Not, Precision::P64, r, s),
+            Action::Binary(BinaryOp::Add, Precision::P64, r, r.into(), s),
+            Action::Select(Precision::P64, r, s, r.into(), r.into()),
+            Action::Select(Precision::P64, r, r.into(), s, r.into()),
+            Action::Select(Precision::P64, r, r.into(), r.into(), s),
+            Action::Load(r, addr(s)),
+            Action::Store(r, s, addr(r.into())),
+            Action::Store(r, r.into(), addr(s)),
+            Action::Send(r, r.into(), s),
+            Action::Push(None, Some(s)),
+            Action::Debug(s),
+            Action::Call(r, NativeFunction(add), r.into(), s),
+            Action::AtomicCas(r, s, r.into(), addr(r.into())),
+            Action::AtomicCas(r, r.into(), s, addr(r.into())),
+            Action::AtomicCas(r, r.into(), r.into(), addr(s)),
+        ];
+        for action in uses_s {
+            assert_eq!(action.check(1), Err(Undefined::NoSuchSlot(Slot(1))));
+            assert!(action.check(2).is_ok());
+        }
+        assert_eq!(Action::Push(Some(s), None).check(2), Ok(4));
+        assert_eq!(Action::SlotAddress(r, Slot(1)).check(2), Err(Undefined::SlotAddress(Slot(1))));
+        assert_eq!(Action::Constant(Precision::P32, r, -1 << 40).check(0), Ok(0));
+        assert_eq!(Action::Drop(1).check(1), Err(Undefined::DropTooMany(1)));
+        assert_eq!(Action::Drop(1).check(3), Ok(1));
+        assert_eq!(Action::Drop(0).check(0), Ok(0));
+        let extract = |prec, shift, bits| Action::Unary(UnaryOp::Extract(shift, bits), prec, r, r.into());
+        assert_eq!(extract(Precision::P32, 24, 8).check(0), Ok(0));
+        assert_eq!(extract(Precision::P32, 24, 9).check(0), Err(Undefined::BadExtract(Precision::P32, 24, 9)));
+        assert_eq!(extract(Precision::P64, 24, 9).check(0), Ok(0));
+        assert_eq!(extract(Precision::P64, 0, 0).check(0), Err(Undefined::BadExtract(Precision::P64, 0, 0)));
+    }
 }
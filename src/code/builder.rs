@@ -4,9 +4,9 @@
 //! be useful.
 
 use super::{
-    UnaryOp, BinaryOp, Precision, Width,
+    UnaryOp, BinaryOp, Precision, Width, FenceOrder,
     Register, REGISTERS, Variable, IntoVariable,
-    Address, Action, Switch, EBB, Ending,
+    NativeFunction, Address, Action, Switch, EBB, Ending,
 };
 use Precision::*;
 use BinaryOp::*;
@@ -112,6 +112,30 @@ impl<T> Builder<T> {
         self.actions.push(Action::Binary(op, P32, dest.into(), src1.into(), src2.into()));
     }
 
+    /// Assembles an `Action` to select `src1` if `cond` is non-zero,
+    /// otherwise `src2`, into `dest`.
+    pub fn select64(
+        &mut self,
+        dest: Register,
+        cond: impl IntoVariable,
+        src1: impl IntoVariable,
+        src2: impl IntoVariable,
+    ) {
+        self.actions.push(Action::Select(P64, dest, cond.into(), src1.into(), src2.into()));
+    }
+
+    /// Assembles an `Action` to select `src1` if `cond` is non-zero,
+    /// otherwise `src2`, into `dest`.
+    pub fn select32(
+        &mut self,
+        dest: Register,
+        cond: impl IntoVariable,
+        src1: impl IntoVariable,
+        src2: impl IntoVariable,
+    ) {
+        self.actions.push(Action::Select(P32, dest, cond.into(), src1.into(), src2.into()));
+    }
+
     /// Assembles `Action`s to compute `op(src, value)` into `dest`.
     /// [`TEMP`] is corrupted.
     pub fn const_binary64(
@@ -164,6 +188,18 @@ impl<T> Builder<T> {
         self.actions.push(Action::Load(dest.into(), Address {base, offset, width}));
     }
 
+    /// Assembles `Action`s to load `dest` from address `addr.0 + addr.1`,
+    /// sign-extending from the `Width` to 64 bits.
+    pub fn load_signed(
+        &mut self,
+        dest: Register,
+        addr: (impl IntoVariable, i32, Width),
+    ) {
+        let width = addr.2;
+        self.load(dest, addr);
+        self.unary64(UnaryOp::Sxt(width), dest, dest);
+    }
+
     /// Assembles `Action`s to store `src` at `addr.0 + addr.1`.
     /// [`TEMP`] is corrupted.
     pub fn store(
@@ -211,6 +247,39 @@ impl<T> Builder<T> {
         self.actions.push(Action::Debug(src.into()));
     }
 
+    /// Assembles an action that calls `function` with arguments `src1` and
+    /// `src2`, and puts the result in `dest`.
+    pub fn call(
+        &mut self,
+        dest: Register,
+        function: NativeFunction,
+        src1: impl IntoVariable,
+        src2: impl IntoVariable,
+    ) {
+        self.actions.push(Action::Call(dest, function, src1.into(), src2.into()));
+    }
+
+    /// Assembles an action that orders memory accesses as specified by
+    /// `order`.
+    pub fn fence(&mut self, order: FenceOrder) {
+        self.actions.push(Action::Fence(order));
+    }
+
+    /// Assembles an action that atomically replaces the value at
+    /// `addr.0 + addr.1` with `new` if it is equal to `expected`, and puts
+    /// the old value in `dest`.
+    pub fn atomic_cas(
+        &mut self,
+        dest: Register,
+        expected: impl IntoVariable,
+        new: impl IntoVariable,
+        addr: (impl IntoVariable, i32, Width),
+    ) {
+        let (base, offset, width) = addr;
+        let addr = Address {base: base.into(), offset, width};
+        self.actions.push(Action::AtomicCas(dest, expected.into(), new.into(), addr));
+    }
+
     /// Assemble code to check that `condition` is `expected`, and if not, to
     /// abort by running `if_fail`.
     /// See also [`Self::if_()`] which is more symmetrical.
@@ -267,6 +336,26 @@ impl<T> Builder<T> {
         self.switch(discriminant, Switch::new(cases, default_))
     }
 
+    /// Assembles code to compute the bit-field `(value >> shift) & mask` into
+    /// `dest`, and to select one of `cases` based on it, as for
+    /// [`Self::index()`]. This is useful for decoding packed instruction
+    /// formats. `dest` must not be [`TEMP`], which is corrupted.
+    pub fn bit_field(
+        mut self,
+        dest: Register,
+        value: impl IntoVariable,
+        shift: usize,
+        mask: u64,
+        cases: Box<[EBB<T>]>,
+        default_: EBB<T>,
+    ) -> EBB<T> {
+        assert!(shift < 64);
+        assert_ne!(dest, TEMP, "dest cannot be TEMP");
+        self.const_binary64(Lsr, dest, value, shift as i64);
+        self.const_binary64(And, dest, dest, mask as i64);
+        self.index(dest, cases, default_)
+    }
+
     /// Assembles code to select `if_true` if `condition` is non-zero,
     /// otherwise `if_false`.
     /// Equivalent to `switch(Switch::new(condition, if_true, if_false))`.
@@ -284,3 +373,61 @@ impl<T> Builder<T> {
 impl<T> Default for Builder<T> {
     fn default() -> Self { Self::new() }
 }
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jit::{Jit, EntryId};
+    use crate::jit::tests::{marshal};
+    use crate::target::{native, Word};
+
+    /// Test that `Builder::bit_field()` selects the case named by the field.
+    #[test]
+    fn bit_field() {
+        const X: Register = REGISTERS[1];
+        const FIELD: Register = REGISTERS[2];
+        const P: Register = REGISTERS[3];
+        let mut jit = Jit::new(native());
+        let marshal = marshal(P, &[X]);
+        let start = jit.new_entry(&marshal, 0);
+        let exits: Vec<EntryId> = (0..6).map(|i| jit.new_entry(&marshal, 10 + i)).collect();
+        jit.define(start, &build(|b| b.bit_field(
+            FIELD, X, 4, 0x7,
+            exits[..5].iter().map(|&exit| build(|b| b.jump(exit))).collect(),
+            build(|b| b.jump(exits[5])),
+        )));
+        for field in 0..8 {
+            // Put garbage on both sides of the field.
+            let mut x: u64 = 0xFEDC_BA98_7654_3A8F | (field << 4);
+            let expected = std::cmp::min(field, 5) + 10;
+            assert_eq!(unsafe {jit.run(start, &mut x)}, Word {u: expected});
+            assert_eq!(x & 0x70, field << 4);
+        }
+    }
+
+    /// Test that `Builder::load_signed()` sign-extends every `Width`.
+    #[test]
+    fn load_signed() {
+        const X: Register = REGISTERS[1];
+        const P: Register = REGISTERS[2];
+        let mut jit = Jit::new(native());
+        let marshal = marshal(P, &[]);
+        let halt = jit.new_entry(&marshal, 0);
+        let start = jit.new_entry(&marshal, 1);
+        jit.define(start, &build(|mut b| {
+            for (i, width) in [Width::One, Width::Two, Width::Four, Width::Eight].into_iter().enumerate() {
+                let offset = 8 * i as i32;
+                b.const_(X, 1 << (width.bits() - 1));
+                b.store(X, (P, offset, width));
+                b.load_signed(X, (P, offset, width));
+                b.store(X, (P, offset, Width::Eight));
+            }
+            b.jump(halt)
+        }));
+        let mut state = [0u64; 4];
+        assert_eq!(unsafe {jit.run(start, &mut state)}, Word {s: 0});
+        assert_eq!(state.map(|x| x as i64), [-0x80, -0x8000, -0x8000_0000, i64::MIN]);
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::{HashSet};
 
-use super::{GLOBAL, Variable, IntoVariable, Action, Switch};
+use super::{GLOBAL, Slot, Variable, IntoVariable, Action, Switch};
 
 /// Represents the convention by which code passes values to a label. The
 /// concept is similar to a calling convention, but it's for a jump, not a
@@ -23,6 +23,24 @@ impl Convention {
         let old_lives: HashSet<Variable> = old.lives.iter().copied().collect();
         self.lives.iter().all(|v| old_lives.contains(v)) && self.slots_used == old.slots_used
     }
+
+    /// Returns a `Convention` for a point where code using `a` and code using
+    /// `b` merge, i.e. the most general `Convention` that [refines] both.
+    /// `lives` is treated as a set: the result keeps the [`Variable`]s that
+    /// are live in both `a` and `b`, in sorted order.
+    ///
+    /// Panics if `a` and `b` have different `slots_used`, because then no
+    /// `Convention` refines both.
+    ///
+    /// [refines]: Self::refines
+    pub fn merge(a: &Convention, b: &Convention) -> Convention {
+        assert_eq!(a.slots_used, b.slots_used, "Cannot merge Conventions with different slots_used");
+        let b_lives: HashSet<Variable> = b.lives.iter().copied().collect();
+        let mut lives: Vec<Variable> = a.lives.iter().copied().filter(|v| b_lives.contains(v)).collect();
+        lives.sort();
+        lives.dedup();
+        Convention {lives: lives.into(), slots_used: a.slots_used}
+    }
 }
 
 impl Default for Convention {
@@ -118,6 +136,12 @@ impl Propagator {
                 self.insert(src1);
                 self.insert(src2);
             },
+            Select(_, dest, cond, src1, src2) => {
+                self.remove(dest);
+                self.insert(cond);
+                self.insert(src1);
+                self.insert(src2);
+            },
             Load(dest, addr) => {
                 self.remove(dest);
                 self.insert(addr.base);
@@ -133,28 +157,80 @@ impl Propagator {
                 self.insert(src2);
             },
             Push(src1, src2) => {
+                self.slots_used -= 2;
+                self.remove(Slot(self.slots_used + 1));
+                self.remove(Slot(self.slots_used));
                 if let Some(src) = src1 {
                     self.insert(src);
                 }
                 if let Some(src) = src2 {
                     self.insert(src);
                 }
-                self.slots_used -= 2;
             },
             Drop(n) => {
                 self.slots_used += 2 * n;
             },
+            Call(dest, _, src1, src2) => {
+                self.remove(dest);
+                self.insert(src1);
+                self.insert(src2);
+            },
             Debug(src) => {
                 self.insert(src);
             },
+            Fence(_) => {},
+            AtomicCas(dest, expected, new, addr) => {
+                self.remove(dest);
+                self.insert(expected);
+                self.insert(new);
+                self.insert(addr.base);
+            },
+            SlotAddress(dest, slot) => {
+                self.remove(dest);
+                self.insert(slot);
+            },
         }
     }
 
-    /// Returns the [`Convention`] before the code.
+    /// Returns the [`Convention`] before the code. The [`Variable`]s are
+    /// sorted, so that the result does not depend on the hasher.
     pub fn before(&self) -> Convention {
+        let mut lives: Vec<Variable> = self.lives.iter().copied().collect();
+        lives.sort();
         Convention {
-            lives: self.lives.iter().copied().collect(),
+            lives: lives.into(),
             slots_used: self.slots_used,
         }
     }
 }
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{REGISTERS};
+
+    #[test]
+    fn merge() {
+        let [r1, r2, r3, r4] = [1, 2, 3, 4].map(|i| Variable::from(REGISTERS[i]));
+        let s0 = Variable::from(Slot(0));
+        let a = Convention {lives: Box::new([r1, r2, s0]), slots_used: 1};
+        let b = Convention {lives: Box::new([s0, r4, r1]), slots_used: 1};
+        let c = Convention::merge(&a, &b);
+        assert_eq!(&*c.lives, &[r1, s0]);
+        assert_eq!(c.slots_used, 1);
+        assert!(c.refines(&a) && c.refines(&b));
+        // Order doesn't matter, and nothing extra is live.
+        let d = Convention {lives: Box::new([r3]), slots_used: 1};
+        assert_eq!(&*Convention::merge(&b, &a).lives, &*c.lives);
+        assert_eq!(&*Convention::merge(&a, &d).lives, &[]);
+    }
+
+    #[test]
+    #[should_panic(expected = "different slots_used")]
+    fn merge_slots_used() {
+        let a = Convention {lives: Box::new([GLOBAL.into()]), slots_used: 1};
+        Convention::merge(&a, &Convention::default());
+    }
+}
@@ -1,4 +1,5 @@
-use super::{Variable, Action};
+use super::{Variable, Action, Undefined};
+use super::action::{check_variable};
 
 /// Represents a control-flow decision. `C` is the thing being chosen.
 /// If the discriminant is `i` and `i < cases.len()` choose `cases[i]`.
@@ -38,6 +39,34 @@ pub struct EBB<L> {
     pub ending: Ending<L>,
 }
 
+impl<L> EBB<L> {
+    /// Applies [`Action::check()`] to every [`Action`] of `self`, checks
+    /// the discriminant of every [`Switch`], and checks that every leaf is
+    /// reached with the number of [`Slot`]s it expects, given by `after`.
+    /// `slots_used` is the number of `Slot`s in use on entry.
+    ///
+    /// [`Slot`]: super::Slot
+    pub fn check(&self, mut slots_used: usize, after: &dyn Fn(&L) -> usize) -> Result<(), Undefined> {
+        for action in &*self.actions {
+            slots_used = action.check(slots_used)?;
+        }
+        match self.ending {
+            Ending::Leaf(ref leaf) => {
+                let expected = after(leaf);
+                if slots_used != expected { return Err(Undefined::Unbalanced(slots_used, expected)); }
+                Ok(())
+            },
+            Ending::Switch(discriminant, ref switch) => {
+                check_variable(discriminant, slots_used)?;
+                for ebb in switch.cases.iter().chain(std::iter::once(&*switch.default_)) {
+                    ebb.check(slots_used, after)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Ending<L> {
     /// Control-flow merges with pre-existing code.
@@ -59,7 +88,7 @@ pub mod tests {
     use super::*;
     use super::super::{
         Register, REGISTERS, Variable, IntoVariable, Convention,
-        Precision, BinaryOp, UnaryOp, builder,
+        BinaryOp, builder,
     };
     use BinaryOp::*;
 
@@ -131,28 +160,28 @@ pub mod tests {
                     let x = self.get(src);
                     self.set(dest, x);
                 },
-                &Action::Constant(Precision::P64, dest, imm) => {
-                    self.set(dest, imm);
+                &Action::Constant(prec, dest, imm) => {
+                    self.set(dest, prec.truncate(imm));
                 },
-                &Action::Unary(op, Precision::P64, dest, src) => {
+                &Action::Unary(op, prec, dest, src) => {
                     let x = self.get(src);
-                    let result = match op {
-                        UnaryOp::Not => !x,
-                        _ => panic!("Don't know how to execute {:#?}", op),
-                    };
-                    self.set(dest, result);
+                    self.set(dest, op.apply(prec, x));
+                },
+                &Action::Binary(op, prec, dest, src1, src2) => {
+                    let x = self.get(src1);
+                    let y = self.get(src2);
+                    self.set(dest, op.apply(prec, x, y));
+                },
+                &Action::Select(prec, dest, cond, src1, src2) => {
+                    let x = if prec.truncate(self.get(cond)) != 0 { self.get(src1) } else { self.get(src2) };
+                    self.set(dest, prec.truncate(x));
                 },
-                &Action::Binary(op, Precision::P64, dest, src1, src2) => {
+                &Action::Call(dest, function, src1, src2) => {
                     let x = self.get(src1);
                     let y = self.get(src2);
-                    let result = match op {
-                        BinaryOp::Add => x.wrapping_add(y),
-                        BinaryOp::Xor => x ^ y,
-                        BinaryOp::Lt => if x < y { !0 } else { 0 },
-                        _ => panic!("Don't know how to execute {:#?}", op),
-                    };
-                    self.set(dest, result);
+                    self.set(dest, (function.0)(x as u64, y as u64) as i64);
                 },
+                &Action::Fence(_) => {},
                 _ => panic!("Don't know how to execute {:#?}", action),
             }
         }
@@ -1,3 +1,5 @@
+use crate::util::{bits};
+
 /// Represents the precision of an arithmetic operation.
 /// With `P32`, the arithmetic is performed with 32-bit precision, and written
 /// into the bottom 32 bits of the destination. The top 32 bits are 0.
@@ -10,6 +12,16 @@ pub enum Precision {
 
 impl Precision {
     pub fn bits(self) -> usize { 32 << (self as usize) }
+
+    /// Zero-extends the low `self` bits of `x`.
+    pub fn truncate(self, x: i64) -> i64 {
+        match self { Precision::P32 => x as u32 as i64, Precision::P64 => x }
+    }
+
+    /// Sign-extends the low `self` bits of `x`.
+    fn signed(self, x: i64) -> i64 {
+        match self { Precision::P32 => x as i32 as i64, Precision::P64 => x }
+    }
 }
 
 //-----------------------------------------------------------------------------
@@ -21,7 +33,57 @@ pub enum UnaryOp {
     Abs,
     Negate,
     Not,
-    // TODO: Uxt, Sxt (#12).
+    /// Zero-extend from the given [`Width`].
+    Uxt(Width),
+    /// Sign-extend from the given [`Width`].
+    Sxt(Width),
+    /// Count leading zeros. Zero has as many leading zeros as the
+    /// [`Precision`] has bits.
+    Clz,
+    /// Count trailing zeros. Zero has as many trailing zeros as the
+    /// [`Precision`] has bits.
+    Ctz,
+    /// Count the bits that are set.
+    Popcount,
+    /// `Extract(shift, bits)` shifts right by `shift`, then zero-extends
+    /// from `bits` bits. `bits` must be positive, and `shift + bits` must not
+    /// exceed the [`Precision`].
+    Extract(u8, u8),
+}
+
+impl UnaryOp {
+    /// Computes `self(x)` with precision `prec`. The result is zero-extended
+    /// from `prec`.
+    pub fn apply(self, prec: Precision, x: i64) -> i64 {
+        prec.truncate(match self {
+            UnaryOp::Abs => prec.signed(x).wrapping_abs(),
+            UnaryOp::Negate => x.wrapping_neg(),
+            UnaryOp::Not => !x,
+            UnaryOp::Uxt(width) => {
+                let shift = 64 - width.bits();
+                (((x as u64) << shift) >> shift) as i64
+            },
+            UnaryOp::Sxt(width) => {
+                let shift = 64 - width.bits();
+                (x << shift) >> shift
+            },
+            UnaryOp::Clz => match prec {
+                Precision::P32 => (x as u32).leading_zeros() as i64,
+                Precision::P64 => x.leading_zeros() as i64,
+            },
+            UnaryOp::Ctz => match prec {
+                Precision::P32 => (x as u32).trailing_zeros() as i64,
+                Precision::P64 => x.trailing_zeros() as i64,
+            },
+            UnaryOp::Popcount => match prec {
+                Precision::P32 => (x as u32).count_ones() as i64,
+                Precision::P64 => x.count_ones() as i64,
+            },
+            UnaryOp::Extract(shift, bits) => {
+                bits::extract(x as u64, shift.into(), bits.into()) as i64
+            },
+        })
+    }
 }
 
 /// Binary arithmetic operations.
@@ -31,19 +93,97 @@ pub enum BinaryOp {
     Add,
     Sub,
     Mul,
+    /// The high half of the signed double-width product.
+    MulHigh,
+    /// The high half of the unsigned double-width product.
+    UMulHigh,
+    /// Unsigned division, rounding towards zero. Dividing by zero gives zero.
     UDiv,
+    /// Signed division, rounding towards zero. Dividing by zero gives zero.
+    /// Dividing the most negative number by `-1` wraps.
     SDiv,
+    /// Unsigned remainder, matching `UDiv`. The remainder on dividing by zero
+    /// is the dividend.
+    URem,
+    /// Signed remainder, matching `SDiv`. It has the same sign as the
+    /// dividend. The remainder on dividing by zero is the dividend.
+    SRem,
     Lsl,
     Lsr,
     Asr,
+    /// Rotate left. The amount is reduced modulo the word size.
+    RotL,
+    /// Rotate right. The amount is reduced modulo the word size.
+    RotR,
     And,
     Or,
     Xor,
     Lt,
     Ult,
     Eq,
-    Max, // TODO: Unsigned too?
-    Min, // TODO: Unsigned too?
+    /// Signed maximum.
+    Max,
+    /// Signed minimum.
+    Min,
+    /// Unsigned maximum.
+    UMax,
+    /// Unsigned minimum.
+    UMin,
+}
+
+impl BinaryOp {
+    /// Computes `self(x, y)` with precision `prec`. The result is
+    /// zero-extended from `prec`.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn apply(self, prec: Precision, x: i64, y: i64) -> i64 {
+        let (ux, uy) = (prec.truncate(x) as u64, prec.truncate(y) as u64);
+        let (sx, sy) = (prec.signed(x), prec.signed(y));
+        let bool_ = |b: bool| if b { !0 } else { 0 };
+        prec.truncate(match self {
+            BinaryOp::Add => x.wrapping_add(y),
+            BinaryOp::Sub => x.wrapping_sub(y),
+            BinaryOp::Mul => x.wrapping_mul(y),
+            BinaryOp::MulHigh => match prec {
+                Precision::P32 => (sx * sy) >> 32,
+                Precision::P64 => ((sx as i128 * sy as i128) >> 64) as i64,
+            },
+            BinaryOp::UMulHigh => match prec {
+                Precision::P32 => ((ux * uy) >> 32) as i64,
+                Precision::P64 => ((ux as u128 * uy as u128) >> 64) as i64,
+            },
+            BinaryOp::UDiv => ux.checked_div(uy).unwrap_or(0) as i64,
+            BinaryOp::SDiv => if sy == 0 { 0 } else { sx.wrapping_div(sy) },
+            BinaryOp::URem => ux.checked_rem(uy).unwrap_or(ux) as i64,
+            BinaryOp::SRem => if sy == 0 { sx } else { sx.wrapping_rem(sy) },
+            BinaryOp::Lsl => match prec {
+                Precision::P32 => (x as u32).wrapping_shl(y as u32) as i64,
+                Precision::P64 => x.wrapping_shl(y as u32),
+            },
+            BinaryOp::Lsr => match prec {
+                Precision::P32 => (x as u32).wrapping_shr(y as u32) as i64,
+                Precision::P64 => (x as u64).wrapping_shr(y as u32) as i64,
+            },
+            BinaryOp::Asr => sx.wrapping_shr(y as u32 % prec.bits() as u32),
+            BinaryOp::RotL => match prec {
+                Precision::P32 => (x as u32).rotate_left(y as u32) as i64,
+                Precision::P64 => x.rotate_left(y as u32),
+            },
+            BinaryOp::RotR => match prec {
+                Precision::P32 => (x as u32).rotate_right(y as u32) as i64,
+                Precision::P64 => x.rotate_right(y as u32),
+            },
+            BinaryOp::And => x & y,
+            BinaryOp::Or => x | y,
+            BinaryOp::Xor => x ^ y,
+            BinaryOp::Lt => bool_(sx < sy),
+            BinaryOp::Ult => bool_(ux < uy),
+            BinaryOp::Eq => bool_(ux == uy),
+            BinaryOp::Max => std::cmp::max(sx, sy),
+            BinaryOp::Min => std::cmp::min(sx, sy),
+            BinaryOp::UMax => std::cmp::max(ux, uy) as i64,
+            BinaryOp::UMin => std::cmp::min(ux, uy) as i64,
+        })
+    }
 }
 
 /// The number of bytes transferred by a memory access.
@@ -55,3 +195,24 @@ pub enum Width {
     Four = 2,
     Eight = 3,
 }
+
+impl Width {
+    pub fn bits(self) -> usize { 8 << (self as usize) }
+}
+
+//-----------------------------------------------------------------------------
+
+/// Which memory accesses are ordered by an [`Action::Fence`].
+///
+/// [`Action::Fence`]: super::Action::Fence
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FenceOrder {
+    /// Loads before the fence happen before loads after it.
+    Load = 0,
+    /// Stores before the fence happen before stores after it.
+    Store = 1,
+    /// All memory accesses before the fence happen before all memory accesses
+    /// after it.
+    Full = 2,
+}
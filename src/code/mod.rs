@@ -80,10 +80,10 @@ mod variable;
 pub use variable::{Register, REGISTERS, GLOBAL, Slot, Variable, IntoVariable};
 
 mod enums;
-pub use enums::{Precision, UnaryOp, BinaryOp, Width};
+pub use enums::{Precision, UnaryOp, BinaryOp, Width, FenceOrder};
 
 mod action;
-pub use action::{Address, Action, debug_word};
+pub use action::{NativeFunction, Address, Action, Undefined, debug_word};
 
 mod ebb;
 pub use ebb::{Switch, EBB, Ending};
@@ -114,5 +114,6 @@ pub struct Marshal {
 pub mod tests {
     use super::*;
 
+    pub use action::tests::{add};
     pub use ebb::tests::{Emulator, EmulatorResult, emulate, random_ebb, random_ebb_convention};
 }
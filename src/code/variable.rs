@@ -13,7 +13,7 @@ array_index! {
     /// particular [`Target`].
     ///
     /// [`Target`]: crate::target::Target
-    #[derive(Copy, Clone, PartialEq, Eq, Hash)]
+    #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct Register(std::num::NonZeroU8) {
         debug_name: "Register",
         UInt: u8,
@@ -34,9 +34,22 @@ pub const REGISTERS: [Register; 12] = unsafe {[
 pub const GLOBAL: Register = REGISTERS[0];
 
 /// A stack-allocated spill slot.
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+///
+/// Every `Slot` is 8 bytes. [`Push`] allocates `Slot`s in pairs, so each pair
+/// occupies a 16-byte-aligned block of stack.
+///
+/// [`Push`]: super::Action::Push
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Slot(pub usize);
 
+impl Slot {
+    /// Returns `Slot(index)` if it exists when `slots_used` `Slot`s are in
+    /// use, otherwise `None`.
+    pub fn try_new(index: usize, slots_used: usize) -> Option<Self> {
+        if index < slots_used { Some(Slot(index)) } else { None }
+    }
+}
+
 impl Debug for Slot {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
         write!(f, "Slot({})", self.0)
@@ -45,7 +58,7 @@ impl Debug for Slot {
 
 /// A [`Register`] or [`Slot`].
 /// Used for source operands of Mijit instructions.
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Variable {
     Register(Register),
     Slot(Slot),
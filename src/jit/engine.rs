@@ -1,11 +1,13 @@
+use std::collections::{HashSet};
 use std::fmt::{Debug};
-use std::ops::{Index, IndexMut};
+use std::ops::{Index, IndexMut, Range};
 use std::marker::{PhantomData};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::util::{AsUsize};
 use super::target::{Label, Word, Lower, Execute, Target, RESULT};
 use super::code::{Precision, Variable, Switch, Action, Convention, Marshal, Propagator, EBB, Ending};
-use super::optimizer::{LookupLeaf, optimize};
+use super::optimizer::{LookupLeaf, Options, Stats, Trace, NoTrace, optimize};
 use Precision::*;
 
 // CaseId.
@@ -19,7 +21,7 @@ array_index! {
 }
 
 /// A branch that merges with a [`Case`] that is less specialized.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Retire {
     /// The code to run.
     actions: Box<[Action]>,
@@ -55,6 +57,21 @@ struct Case {
     retire: Option<Retire>,
     /// The `Fetch`, if any.
     fetch: Option<Fetch>,
+    /// If this `Case` was defined by [`Engine::build()`], statistics about
+    /// the optimization of its code.
+    stats: Option<Stats>,
+    /// If this `Case` was defined by [`Engine::build()`], the addresses of
+    /// the code assembled for it.
+    code: Option<Range<usize>>,
+    /// If this `Case` was defined by [`Engine::build()`], the indices in
+    /// [`Lower::relocations()`] of the absolute addresses in its code.
+    relocations: Option<Range<usize>>,
+    /// If this `Case` was constructed by [`Engine::new_entry()`], a copy of
+    /// its original `Retire`, which exits to the root.
+    exit: Option<Retire>,
+    /// If this `Case` was constructed while profiling, counts the times its
+    /// code has been executed.
+    counter: Option<Box<AtomicU64>>,
 }
 
 impl Case {
@@ -72,10 +89,69 @@ impl Case {
             self.before = Some(new);
         }
     }
+
+    /// Assembles code to increment `self.counter`, if any.
+    fn count(&self, lo: &mut impl Lower) {
+        if let Some(counter) = &self.counter { lo.count(counter); }
+    }
+
+    /// Returns the value of `self.counter`, if any.
+    fn count_value(&self) -> Option<u64> {
+        self.counter.as_ref().map(|counter| counter.load(Ordering::Relaxed))
+    }
+}
+
+/// The execution counts of an entry of an [`Engine`] and of the ways out of
+/// it, collected while profiling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileData {
+    /// The number of times the entry's code has been executed.
+    pub count: u64,
+    /// For each way out of the entry's code, the number of times it has been
+    /// taken. Each is identified by the index of the case chosen at each
+    /// [`Switch`] of the compiled code on the way to it, with `cases.len()`
+    /// meaning `default_`. Ways out constructed without profiling are
+    /// omitted.
+    pub exits: Vec<(Box<[usize]>, u64)>,
 }
 
 //-----------------------------------------------------------------------------
 
+/// Whether to reject [`EBB`]s with undefined behaviour. See [`EBB::check()`].
+const STRICT: bool = cfg!(any(debug_assertions, feature = "strict"));
+
+/// In debug builds, checks the relocations recorded by `lo` since index
+/// `first_relocation`, which are those of the code assembled since buffer
+/// address `start`. See [`check_relocations()`].
+fn check_new_relocations(lo: &impl Lower, start: usize, first_relocation: usize) {
+    if cfg!(debug_assertions) {
+        check_relocations(lo.code(), &lo.relocations()[first_relocation..], start);
+    }
+}
+
+/// Panics unless every position in `relocations` is in the buffer at or
+/// after `start`, i.e. where the new code or its constant pool can be.
+/// Relocations are the addresses of things outside the buffer, so those in
+/// `code` must not hold an address in `code`: the buffer can move, so the
+/// code must refer to itself using relative addresses only.
+///
+/// Only the recorded relocations are checked. Other bytes may happen to
+/// look like an address in the buffer, e.g. an immediate constant.
+fn check_relocations(code: &[u8], relocations: &[usize], start: usize) {
+    let base = code.as_ptr() as u64;
+    let buffer = base..(base + code.len() as u64);
+    for &pos in relocations {
+        assert!(pos >= start, "Relocation at {:#x} is before the new code at {:#x}", pos, start);
+        if pos < code.len() {
+            let bytes = code.get(pos..pos + 8).expect("Relocation beyond the end of the code");
+            let value = u64::from_le_bytes(bytes.try_into().unwrap());
+            if buffer.contains(&value) {
+                panic!("Absolute address {:#x} of the code at {:#x}", value - base, pos);
+            }
+        }
+    }
+}
+
 /// This only exists to keep the borrow checker happy.
 /// We might need to borrow these fields while generating code.
 #[derive(Debug)]
@@ -85,6 +161,8 @@ struct Internals {
     /// The [`Case`]s in the order they were compiled, excluding the root.
     /// Indexed by [`CaseId`].
     cases: Vec<Case>,
+    /// Whether new [`Case`]s count the times their code is executed.
+    profiling: bool,
 }
 
 impl Internals {
@@ -102,15 +180,67 @@ impl Internals {
             label: Label::new(None),
             retire: None,
             fetch: None,
+            stats: None,
+            code: None,
+            relocations: None,
+            exit: None,
+            counter: if self.profiling { Some(Box::default()) } else { None },
         });
         id
     }
 
+    /// Appends to `exits` the [`Retire`]s reachable from `id`, as described
+    /// by [`ProfileData::exits`], and their [`Case`]s. `path` identifies
+    /// `id`.
+    fn collect_exits(
+        &self,
+        id: CaseId,
+        path: &mut Vec<usize>,
+        exits: &mut Vec<(Box<[usize]>, CaseId, u64)>,
+    ) {
+        if let Some(fetch) = &self[id].fetch {
+            let Switch {ref cases, ref default_} = fetch.switch;
+            for (index, &child) in cases.iter().chain(std::iter::once(&**default_)).enumerate() {
+                path.push(index);
+                self.collect_exits(child, path, exits);
+                path.pop();
+            }
+        } else if let Some(count) = self[id].count_value() {
+            exits.push((path.clone().into(), id, count));
+        }
+    }
+
+    /// Appends to `built` `id` and the [`Case`]s reachable from it through
+    /// [`Fetch`]es that were defined by [`Engine::build()`], i.e. `id` and
+    /// its specializations.
+    fn collect_built(&self, id: CaseId, built: &mut Vec<CaseId>) {
+        if self[id].code.is_some() {
+            built.push(id);
+        }
+        if let Some(fetch) = &self[id].fetch {
+            let Switch {ref cases, ref default_} = fetch.switch;
+            for &child in cases.iter().chain(std::iter::once(&**default_)) {
+                self.collect_built(child, built);
+            }
+        }
+    }
+
     /// Find the [`Convention`] for a [`CaseId`] allowing for `None`.
     fn convention(&self, id: impl Into<Option<CaseId>>) -> &Convention {
         id.into().map_or(&self.convention, |id| self[id].convention())
     }
 
+    /// In strict mode, panics if `ebb` has undefined behaviour on entry to
+    /// `id`, including if it does not leave the stack as `to_case` expects.
+    fn check<L: Clone>(&self, id: CaseId, ebb: &EBB<L>, to_case: &impl Fn(L) -> CaseId) {
+        if STRICT {
+            let after = |leaf: &L| self.convention(to_case(leaf.clone())).slots_used;
+            if let Err(e) = ebb.check(self.convention(id).slots_used, &after) {
+                panic!("Undefined behaviour: {:?}", e);
+            }
+        }
+    }
+
     /// Add a [`Retire`] to a [`Case`] that doesn't have a [`Fetch`].
     fn add_retire(&mut self, lo: &mut impl Lower, id: CaseId, retire: Retire) {
         assert!(self[id].fetch.is_none());
@@ -125,6 +255,7 @@ impl Internals {
         let mut here = lo.here();
         lo.steal(&mut self[id].label, &mut here);
         self[id].label = here;
+        self[id].count(lo);
         // Compile `retire`.
         lo.actions(&retire.actions);
         let slots_used = *lo.slots_used_mut();
@@ -163,6 +294,7 @@ impl Internals {
         let mut here = lo.here();
         lo.steal(&mut self[id].label, &mut here);
         self[id].label = here;
+        self[id].count(lo);
         // Compile `fetch`.
         lo.actions(&fetch.actions);
         let slots_used = *lo.slots_used_mut();
@@ -171,12 +303,15 @@ impl Internals {
             assert_eq!(child.fetch_parent, Some(id));
         };
         let Switch {ref cases, ref default_} = fetch.switch;
-        for (index, &case) in cases.iter().enumerate() {
+        let mut labels: Vec<Label> = cases.iter().map(|_| Label::new(None)).collect();
+        let mut default_label = Label::new(None);
+        lo.jump_table(fetch.discriminant, &mut labels, &mut default_label);
+        for (label, &case) in labels.iter_mut().zip(cases.iter()) {
             check_child(&self[case]);
-            lo.if_eq((fetch.discriminant, index as u64), &mut self[case].label);
+            lo.steal(label, &mut self[case].label);
         }
         check_child(&self[**default_]);
-        lo.jump(&mut self[**default_].label);
+        lo.steal(&mut default_label, &mut self[**default_].label);
         self[id].set_convention(before);
         self[id].fetch = Some(fetch);
     }
@@ -204,6 +339,10 @@ impl IndexMut<CaseId> for Internals {
 pub struct Engine<T: Target> {
     /// The compilation target.
     _target: T,
+    /// Passed to [`optimize()`].
+    options: Options,
+    /// Notified of the progress of [`optimize()`].
+    trace: Box<dyn Trace>,
     /// The code compiled so far.
     lowerer: T::Lowerer,
     /// This nested struct can be borrowed independently of `lowerer`.
@@ -219,14 +358,150 @@ impl<T: Target> std::fmt::Debug for Engine<T> {
 }
 
 impl<T: Target> Engine<T> {
-    /// Constructs an `Engine`, initially with no entries.
+    /// Constructs an `Engine`, initially with no entries, that enables all
+    /// optimizations.
     pub fn new(target: T) -> Self {
+        Self::with_options(target, Options::new(T::NUM_REGISTERS))
+    }
+
+    /// Constructs an `Engine`, initially with no entries, that passes
+    /// `options` to the optimizer.
+    pub fn with_options(target: T, options: Options) -> Self {
+        assert!(options.num_registers <= T::NUM_REGISTERS);
         let lowerer = target.lowerer();
         let i = Internals {
             convention: Convention::default(),
             cases: Vec::new(),
+            profiling: false,
         };
-        Engine {_target: target, lowerer, i}
+        let trace = Box::new(NoTrace);
+        Engine {_target: target, options, trace, lowerer, i}
+    }
+
+    /// Replaces the [`Trace`] that is notified of the progress of the
+    /// optimizer. By default, nothing is traced.
+    pub fn set_trace(&mut self, trace: Box<dyn Trace>) {
+        self.trace = trace;
+    }
+
+    /// Sets whether [`Case`]s constructed from now on count the times their
+    /// code is executed. By default, they do not. Counting makes the code
+    /// slower. See [`Self::profile()`].
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.i.profiling = profiling;
+    }
+
+    /// Returns the execution counts of case `id` and of the ways out of it,
+    /// or `None` if `id` was constructed without profiling.
+    pub fn profile(&self, id: CaseId) -> Option<ProfileData> {
+        let count = self.i[id].count_value()?;
+        let mut exits = Vec::new();
+        self.i.collect_exits(id, &mut Vec::new(), &mut exits);
+        let exits = exits.into_iter().map(|(path, _, count)| (path, count)).collect();
+        Some(ProfileData {count, exits})
+    }
+
+    /// Specializes the hottest ways out of case `id`. Does nothing unless `id`
+    /// was constructed with profiling. Of the ways out listed by
+    /// [`Self::profile()`], up to `budget` that have been taken at least
+    /// `threshold` times are chosen, most frequent first. The code of each
+    /// is extended with a copy of the code it jumps to, up to and including
+    /// the next [`Switch`], so that it no longer merges with other code
+    /// before making that control-flow decision. For example, if `id`
+    /// dispatches an instruction, and its ways out are the instructions,
+    /// this compiles pairs of instructions into superinstructions without
+    /// returning to `id` between them.
+    ///
+    /// The new ways out are profiled, and are counted from zero, so calling
+    /// this repeatedly between runs grows traces of hot code.
+    ///
+    /// Returns the number of ways out that were specialized.
+    pub fn specialize_hot(&mut self, id: CaseId, threshold: u64, budget: usize) -> usize {
+        let mut exits = Vec::new();
+        self.i.collect_exits(id, &mut Vec::new(), &mut exits);
+        exits.retain(|&(_, _, count)| count > 0 && count >= threshold);
+        exits.sort_by_key(|&(_, _, count)| std::cmp::Reverse(count));
+        let mut done = 0;
+        for (_, exit, _) in exits {
+            if done == budget { break; }
+            if self.specialize(exit) { done += 1; }
+        }
+        done
+    }
+
+    /// Returns the [`Convention`] in effect on entry to case `id`.
+    pub fn convention(&self, id: CaseId) -> &Convention {
+        self.i[id].convention()
+    }
+
+    /// Returns statistics about the optimization of the code most recently
+    /// defined for case `id`, if any.
+    pub fn stats(&self, id: CaseId) -> Option<&Stats> {
+        self.i[id].stats.as_ref()
+    }
+
+    /// Returns `id` and its specializations, in order of address, or `None`
+    /// if `id` was not defined by [`Self::build()`].
+    fn built(&self, id: CaseId) -> Option<Vec<CaseId>> {
+        self.i[id].code.as_ref()?;
+        let mut built = Vec::new();
+        self.i.collect_built(id, &mut built);
+        built.sort_by_key(|&c| self.i[c].code.as_ref().unwrap().start);
+        Some(built)
+    }
+
+    /// Returns the code most recently assembled by [`Self::build()`] for case
+    /// `id`, if any. This includes the code of the `Case`s constructed to
+    /// represent the control-flow of the [`EBB`], followed by the code later
+    /// assembled for them by [`Self::specialize_hot()`], in order of address.
+    pub fn code_bytes(&self, id: CaseId) -> Option<Vec<u8>> {
+        self.built(id).map(|built| built.iter().flat_map(|&c| {
+            self.lowerer.code()[self.i[c].code.clone().unwrap()].iter().copied()
+        }).collect())
+    }
+
+    /// Returns the positions in the code buffer of the absolute addresses in
+    /// the code of case `id`, i.e. in [`Self::code_bytes()`] or in its
+    /// constant pool, if any. All other addresses in the code are relative.
+    /// See [`Lower::relocations()`].
+    pub fn relocations(&self, id: CaseId) -> Option<Vec<usize>> {
+        self.built(id).map(|built| built.iter().flat_map(|&c| {
+            self.lowerer.relocations()[self.i[c].relocations.clone().unwrap()].iter().copied()
+        }).collect())
+    }
+
+    /// Returns the [`Lower`] that holds the compiled code.
+    #[cfg(test)]
+    pub fn lowerer(&self) -> &T::Lowerer { &self.lowerer }
+
+    /// Writes to `w` a disassembly of [`Self::code_bytes()`] for case `id`,
+    /// if any. The address of each instruction is given relative to the
+    /// beginning of the code buffer. The addresses of `Case`s, including the
+    /// targets of the guards, are labelled with their [`CaseId`]s.
+    #[cfg(feature = "disassemble")]
+    pub fn dump(&self, id: CaseId, w: &mut impl std::fmt::Write) -> std::fmt::Result {
+        for c in self.built(id).unwrap_or_default() {
+            let code = self.i[c].code.clone().unwrap();
+            let labels: Vec<(usize, CaseId)> = self.i.cases.iter().enumerate().filter_map(|(index, case)| {
+                let target = case.label.target()?;
+                if code.contains(&target) { Some((target, CaseId::new(index).unwrap())) } else { None }
+            }).collect();
+            for (address, assembly) in self.lowerer.disassemble(code.start, code.end) {
+                for &(_, label) in labels.iter().filter(|&&(target, _)| target == address) {
+                    writeln!(w, "{:?}:", label)?;
+                }
+                writeln!(w, "{:08x}  {}", address, assembly)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes to `w` a disassembly of [`Self::code_bytes()`] for case `id`.
+    ///
+    /// Panics, because this was compiled without the "disassemble" feature.
+    #[cfg(not(feature = "disassemble"))]
+    pub fn dump(&self, _id: CaseId, _w: &mut impl std::fmt::Write) -> std::fmt::Result {
+        panic!("compile with feature disassemble");
     }
 
     /// Define the code for case `id`.
@@ -241,9 +516,74 @@ impl<T: Target> Engine<T> {
         ebb: &EBB<L>,
         to_case: &impl Fn(L) -> CaseId,
     ) {
-        let engine_wrapper = EngineWrapper {engine: &*self, to_case, _l: PhantomData};
-        let ebb = optimize(self.i.convention(id), ebb, &engine_wrapper);
-        self.build_inner(id, &ebb, to_case)
+        self.i.check(id, ebb, to_case);
+        let engine_wrapper = EngineWrapper {i: &self.i, to_case, _l: PhantomData};
+        let (ebb, stats) = optimize(&self.options, &*self.trace, self.i.convention(id), ebb, &engine_wrapper);
+        self.i.check(id, &ebb, to_case);
+        self.compile(id, &ebb, stats, to_case);
+    }
+
+    /// Define the code for several cases. This is like calling `build()` for
+    /// each of `definitions` in turn, except that all the [`EBB`]s are
+    /// optimized before any of them are compiled, so that the optimization
+    /// can be shared between up to `num_threads` threads. The compiled code
+    /// is laid out in the order of `definitions` regardless of `num_threads`.
+    ///
+    /// With `num_threads == 1`, no threads are spawned.
+    pub fn build_all<L: Debug + Clone + Send + Sync>(
+        &mut self,
+        definitions: &[(CaseId, &EBB<L>)],
+        num_threads: usize,
+        to_case: &(impl Fn(L) -> CaseId + Sync),
+    ) {
+        assert!(num_threads > 0);
+        for &(id, ebb) in definitions {
+            self.i.check(id, ebb, to_case);
+        }
+        let i = &self.i;
+        let options = &self.options;
+        let trace = &*self.trace;
+        let optimize_all = |chunk: &[(CaseId, &EBB<L>)]| -> Vec<(EBB<L>, Stats)> {
+            let engine_wrapper = EngineWrapper {i, to_case, _l: PhantomData};
+            chunk.iter().map(|&(id, ebb)| {
+                optimize(options, trace, i.convention(id), ebb, &engine_wrapper)
+            }).collect()
+        };
+        let ebbs = if num_threads == 1 {
+            optimize_all(definitions)
+        } else {
+            let chunk_size = std::cmp::max((definitions.len() + num_threads - 1) / num_threads, 1);
+            std::thread::scope(|scope| {
+                let threads: Vec<_> = definitions.chunks(chunk_size).map(|chunk| {
+                    scope.spawn(move || optimize_all(chunk))
+                }).collect();
+                threads.into_iter().flat_map(|thread| {
+                    thread.join().expect("Optimizer panicked")
+                }).collect()
+            })
+        };
+        for (&(id, _), (ebb, stats)) in definitions.iter().zip(ebbs) {
+            self.i.check(id, &ebb, to_case);
+            self.compile(id, &ebb, stats, to_case);
+        }
+    }
+
+    /// Assembles the optimized `ebb` as the code of case `id`, and records
+    /// where it is.
+    fn compile<L: Clone>(
+        &mut self,
+        id: CaseId,
+        ebb: &EBB<L>,
+        stats: Stats,
+        to_case: &impl Fn(L) -> CaseId,
+    ) {
+        let start = self.lowerer.code().len();
+        let first_relocation = self.lowerer.relocations().len();
+        self.build_inner(id, ebb, to_case);
+        check_new_relocations(&self.lowerer, start, first_relocation);
+        self.i[id].stats = Some(stats);
+        self.i[id].code = Some(start..self.lowerer.code().len());
+        self.i[id].relocations = Some(first_relocation..self.lowerer.relocations().len());
     }
 
     fn build_inner<L: Clone>(
@@ -284,12 +624,16 @@ impl<T: Target> Engine<T> {
     ///  - id - the `CaseId` corresponding to the entry.
     pub fn new_entry(&mut self, marshal: &Marshal, exit_value: i64) -> (Label, CaseId) {
         assert!(exit_value >= 0);
+        let start = self.lowerer.code().len();
+        let first_relocation = self.lowerer.relocations().len();
         let id = self.i.new_case(None);
         // Compile the epilogue.
         let mut actions = Vec::new();
         actions.extend(marshal.epilogue.iter().copied());
         actions.push(Action::Constant(P64, RESULT, exit_value));
-        self.i.add_retire(&mut self.lowerer, id, Retire {actions: actions.into(), jump: None});
+        let exit = Retire {actions: actions.into(), jump: None};
+        self.i[id].exit = Some(exit.clone());
+        self.i.add_retire(&mut self.lowerer, id, exit);
         // Compile the prologue.
         let lo = &mut self.lowerer;
         *lo.slots_used_mut() = 0;
@@ -298,16 +642,46 @@ impl<T: Target> Engine<T> {
         lo.actions(&marshal.prologue);
         assert_eq!(*lo.slots_used_mut(), self.i[id].convention().slots_used);
         lo.jump(&mut self.i[id].label);
+        check_new_relocations(lo, start, first_relocation);
         // Return.
         (label, id)
     }
 
+    /// Discard the code defined for entry `id`, so that it once again
+    /// immediately returns its `exit_value`. All jumps to `id`, including
+    /// those in code compiled for other `Case`s, are redirected to the new
+    /// code. Afterwards, `id` can be defined again using [`Self::build()`].
+    ///
+    /// The discarded code is not reclaimed.
+    pub fn invalidate(&mut self, id: CaseId) {
+        let exit = self.i[id].exit.clone().expect("Not an entry");
+        self.i[id].fetch = None;
+        self.i[id].stats = None;
+        self.i[id].code = None;
+        self.i[id].relocations = None;
+        let start = self.lowerer.code().len();
+        let first_relocation = self.lowerer.relocations().len();
+        self.i.add_retire(&mut self.lowerer, id, exit);
+        check_new_relocations(&self.lowerer, start, first_relocation);
+    }
+
     /// Returns a copy of the hot path starting at `id` up to the next
     /// [`Switch`]. Returns `None` if the hot path exits Mijit without reaching
-    /// a `Switch`.
+    /// a `Switch`, or if it returns to a [`Case`] whose [`Retire`] it has
+    /// already followed. In the latter case the path is a cycle of `Retire`s
+    /// that never exits, and there is no control-flow decision to specialize.
+    ///
+    /// This only stops `hot_path()` from following such a cycle forever. It
+    /// does not form loops: a back-edge exists only if the code passed to
+    /// [`Self::build()`] jumps to an earlier `Case`.
     fn hot_path(&self, mut id: CaseId) -> Option<EBB<CaseId>> {
         let mut actions = Vec::new();
+        let mut retired = HashSet::new();
         loop {
+            if !retired.insert(id) {
+                // Fail.
+                return None;
+            }
             if let Some(fetch) = &self.i[id].fetch {
                 actions.extend(fetch.actions.iter().copied());
                 // Succeed.
@@ -339,14 +713,30 @@ impl<T: Target> Engine<T> {
 
     /// Find the hot path starting at `id`, which must be a [`Retire`].
     /// Clone it, optimize it, and replace `id` with a [`Fetch`].
-    #[allow(unused)] // TODO.
-    fn specialize(&mut self, id: CaseId) {
+    /// Returns `false` if there is no hot path to specialize.
+    fn specialize(&mut self, id: CaseId) -> bool {
         assert!(self.i[id].fetch.is_none());
         if let Some(ebb) = self.hot_path(id) {
             self.build(id, &ebb, &|c| c);
+            true
+        } else {
+            false
         }
     }
 
+    /// Summarises the compiled code: the control-flow structure, and the
+    /// [`Convention`] and [`Action`]s of every [`Case`]. The addresses of
+    /// the code are omitted.
+    #[cfg(test)]
+    pub fn fingerprint(&self) -> String {
+        self.i.cases.iter().map(|case| format!("{:?} {:?} {:?} {:?}\n",
+            case.fetch_parent,
+            case.before,
+            case.retire.as_ref().map(|retire| (&retire.actions, retire.jump)),
+            case.fetch.as_ref().map(|fetch| (&fetch.actions, fetch.discriminant, &fetch.switch)),
+        )).collect()
+    }
+
     /// Call the compiled code starting at `label`.
     /// - global - the value to pass in [`GLOBAL`].
     ///
@@ -360,20 +750,37 @@ impl<T: Target> Engine<T> {
             f(global)
         })
     }
+
+    /// Makes the compiled code executable, so that `run_shared()` can run
+    /// it.
+    pub fn make_executable(&mut self) {
+        self.lowerer.make_executable();
+    }
+
+    /// Like `run()`, but can be called by several threads at once. Returns
+    /// `None` if code has been compiled since the last call to `run()` or
+    /// `make_executable()`.
+    ///
+    /// # Safety
+    ///
+    /// As for `run()`.
+    pub unsafe fn run_shared(&self, label: &Label, global: *mut ()) -> Option<Word> {
+        self.lowerer.execute_shared(label, |f| f(global))
+    }
 }
 
-struct EngineWrapper<'a, T: Target, L: Debug + Clone, F: Fn(L) -> CaseId> {
-    engine: &'a Engine<T>,
+struct EngineWrapper<'a, L: Debug + Clone, F: Fn(L) -> CaseId> {
+    i: &'a Internals,
     to_case: &'a F,
     _l: PhantomData<L>,
 }
 
-impl<'a, T: Target, L: Debug + Clone, F: Fn(L) -> CaseId> LookupLeaf for EngineWrapper<'a, T, L, F> {
+impl<'a, L: Debug + Clone, F: Fn(L) -> CaseId> LookupLeaf for EngineWrapper<'a, L, F> {
     type Leaf = L;
 
     /// Return the convention in effect at `leaf`.
     fn after(&self, leaf: &L) -> &Convention {
-        self.engine.i.convention((self.to_case)(leaf.clone()))
+        self.i.convention((self.to_case)(leaf.clone()))
     }
 
     /// Return the estimated relative frequency of `leaf`.
@@ -381,3 +788,142 @@ impl<'a, T: Target, L: Debug + Clone, F: Fn(L) -> CaseId> LookupLeaf for EngineW
         1  // FIXME
     }
 }
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::target::{native};
+    use super::super::code::{Register, GLOBAL, REGISTERS, BinaryOp, Width};
+    use super::super::code::builder::{build, build_block};
+    use super::super::tests::{marshal};
+
+    /// A [`Case`] built to transition back to itself should compile to a
+    /// backwards jump, not a return to the caller.
+    #[test]
+    fn back_edge() {
+        const COUNTER: Register = REGISTERS[1];
+        const POINTER: Register = REGISTERS[2];
+        let mut engine = Engine::new(native());
+        let marshal = marshal(POINTER, &[COUNTER]);
+        let (label, id) = engine.new_entry(&marshal, 1);
+        let (_, exit) = engine.new_entry(&marshal, 2);
+        engine.build(id, &build(|b| b.if_(
+            COUNTER,
+            build(|mut b| {
+                b.const_binary64(BinaryOp::Sub, COUNTER, COUNTER, 1);
+                b.jump(true)
+            }),
+            build(|b| b.jump(false)),
+        )), &|is_loop| if is_loop { id } else { exit });
+        // Find the back-edge. It jumps to `id`, not to the root.
+        let back_edges: Vec<&Case> = engine.i.cases.iter().filter(|case| {
+            matches!(case.retire, Some(Retire {jump: Some(jump), ..}) if jump == id)
+        }).collect();
+        assert_eq!(back_edges.len(), 1);
+        assert!(engine.hot_path(id).is_some()); // `id` has a `Fetch`.
+        // Run it.
+        let mut counter: u64 = 10;
+        let result = unsafe { engine.run(&label, &mut counter as *mut u64 as *mut ()) };
+        assert_eq!(result, Word {s: 2});
+        assert_eq!(counter, 0);
+        // A `Case` that only retires to itself has no hot path.
+        let (_, spin) = engine.new_entry(&marshal, 3);
+        engine.build(spin, &build(|b| b.jump(())), &|()| spin);
+        assert!(engine.hot_path(spin).is_none());
+    }
+
+    /// `code_bytes()` should return exactly the code assembled by `build()`.
+    #[test]
+    fn code_bytes() {
+        const COUNTER: Register = REGISTERS[1];
+        let mut engine = Engine::new(native());
+        let marshal = Marshal {
+            prologue: build_block(|b| { b.load(COUNTER, (GLOBAL, 0, Width::Eight)); }),
+            epilogue: build_block(|b| { b.store(COUNTER, (GLOBAL, 0, Width::Eight)); }),
+        };
+        let (_, id) = engine.new_entry(&marshal, 1);
+        let (_, exit) = engine.new_entry(&marshal, 2);
+        assert!(engine.code_bytes(id).is_none());
+        let start = engine.lowerer.code().len();
+        engine.build(id, &build(|b| b.if_(
+            COUNTER,
+            build(|b| b.jump(())),
+            build(|b| b.jump(())),
+        )), &|()| exit);
+        let end = engine.lowerer.code().len();
+        assert!(start < end);
+        assert_eq!(engine.code_bytes(id).as_deref(), Some(&engine.lowerer.code()[start..end]));
+        engine.invalidate(id);
+        assert!(engine.code_bytes(id).is_none());
+    }
+
+    /// Test that a constant that happens to be the absolute address of the
+    /// code buffer is not mistaken for a relocation.
+    #[test]
+    fn buffer_address_constant() {
+        const X: Register = REGISTERS[1];
+        let mut engine = Engine::new(native());
+        let marshal = Marshal {
+            prologue: build_block(|b| { b.load(X, (GLOBAL, 0, Width::Eight)); }),
+            epilogue: build_block(|b| { b.store(X, (GLOBAL, 0, Width::Eight)); }),
+        };
+        let (_, id) = engine.new_entry(&marshal, 0);
+        let (_, exit) = engine.new_entry(&marshal, 1);
+        let address = engine.lowerer.code().as_ptr() as i64 + 0x10;
+        engine.build(id, &build(|mut b| {
+            b.const_(X, address);
+            b.jump(())
+        }), &|()| exit);
+        assert!(engine.code_bytes(id).is_some());
+    }
+
+    /// Test that a relocation may not hold an address in the code buffer.
+    #[test]
+    #[should_panic(expected = "Absolute address 0x10 of the code at 0x8")]
+    fn position_dependent() {
+        let mut code = vec![0u8; 32];
+        let address = code.as_ptr() as u64 + 0x10;
+        code[8..16].copy_from_slice(&address.to_le_bytes());
+        check_relocations(&code, &[0, 8], 0);
+    }
+
+    /// Test that a relocation must be part of the new code.
+    #[test]
+    #[should_panic(expected = "Relocation at 0x8 is before the new code at 0x10")]
+    fn stale_relocation() {
+        check_relocations(&[0; 32], &[8], 0x10);
+    }
+
+    /// Test that a relocation must fit in the code.
+    #[test]
+    #[should_panic(expected = "Relocation beyond the end of the code")]
+    fn truncated_relocation() {
+        check_relocations(&[0; 32], &[28], 0);
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "strict"))]
+    #[should_panic(expected = "Undefined behaviour: DropTooMany(1)")]
+    fn strict() {
+        let mut engine = Engine::new(native());
+        let marshal = Marshal {prologue: Box::new([]), epilogue: Box::new([])};
+        let (_, id) = engine.new_entry(&marshal, 0);
+        let ebb = EBB {actions: Box::new([Action::Drop(1)]), ending: Ending::Leaf(())};
+        engine.build(id, &ebb, &|()| id);
+    }
+
+    /// Test that strict mode rejects an `EBB` that leaves `Slot`s on the
+    /// stack.
+    #[test]
+    #[cfg(any(debug_assertions, feature = "strict"))]
+    #[should_panic(expected = "Undefined behaviour: Unbalanced(2, 0)")]
+    fn strict_unbalanced() {
+        let mut engine = Engine::new(native());
+        let marshal = Marshal {prologue: Box::new([]), epilogue: Box::new([])};
+        let (_, id) = engine.new_entry(&marshal, 0);
+        let ebb = EBB {actions: Box::new([Action::Push(None, None)]), ending: Ending::Leaf(())};
+        engine.build(id, &ebb, &|()| id);
+    }
+}
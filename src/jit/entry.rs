@@ -1,7 +1,8 @@
 use crate::util::{AsUsize};
-use super::{code, Engine, CaseId};
+use super::{code, optimizer, Engine, CaseId, ProfileData};
 use super::target::{Label, Word, Target};
-use code::{Marshal, EBB};
+use code::{Marshal, Convention, EBB};
+use optimizer::{Options, Stats, Trace};
 
 // EntryId.
 array_index! {
@@ -19,10 +20,19 @@ struct Entry {
     label: Label,
     case: CaseId,
     is_defined: bool,
+    /// Incremented whenever the behaviour of the entry point changes.
+    version: u32,
 }
 
 //-----------------------------------------------------------------------------
 
+/// A buffer of compiled code, divided into entry points.
+///
+/// Each entry point has its own [`Marshal`], so a single `Jit` can host
+/// several independent virtual machines with different state layouts. They
+/// share one code buffer, and code compiled for one virtual machine can jump
+/// to an entry point of another, after converting its state using the
+/// `Marshal`s.
 #[derive(Debug)]
 pub struct Jit<T: Target> {
     engine: Engine<T>,
@@ -43,6 +53,27 @@ impl<T: Target> Jit<T> {
         }
     }
 
+    /// Like `new()` but passes `options` to the optimizer.
+    pub fn with_options(target: T, options: Options) -> Self {
+        Self {
+            engine: Engine::with_options(target, options),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Replaces the [`Trace`] that is notified of the progress of the
+    /// optimizer. By default, nothing is traced.
+    pub fn set_trace(&mut self, trace: Box<dyn Trace>) {
+        self.engine.set_trace(trace);
+    }
+
+    /// Sets whether entries constructed from now on count the times their
+    /// code is executed, and the ways out of it. By default, they do not.
+    /// Counting makes the code slower. See [`Self::profile()`].
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.engine.set_profiling(profiling);
+    }
+
     /// Constructs a new entry/exit point. Initially, the code at the entry
     /// point will immediately exit, returning `exit_value`. Use `define()` to
     /// change its behaviour.
@@ -53,11 +84,12 @@ impl<T: Target> Jit<T> {
     pub fn new_entry(&mut self, marshal: &Marshal, exit_value: i64) -> EntryId {
         let (label, case) = self.engine.new_entry(marshal, exit_value);
         let id = EntryId::new(self.entries.len()).unwrap();
-        self.entries.push(Entry {label, case, is_defined: false});
+        self.entries.push(Entry {label, case, is_defined: false, version: 0});
         id
     }
 
-    /// Replace the code at `entry`. Each `EntryId` may only be defined once.
+    /// Replace the code at `entry`. Each `EntryId` may only be defined once,
+    /// unless it is first passed to `invalidate()`.
     ///
     ///  - entry - the entry point to modify.
     ///  - ebb - the extended basic block defining the desired behaviour.
@@ -65,11 +97,132 @@ impl<T: Target> Jit<T> {
         assert!(!get!(self, entry).is_defined);
         self.engine.build(get!(self, entry).case, ebb, &|e| get!(self, e).case);
         get!(self, entry).is_defined = true;
+        get!(self, entry).version += 1;
+    }
+
+    /// Replace the code at several entries. This is like calling `define()`
+    /// for each of `definitions` in turn, except that the work of optimizing
+    /// them is shared between up to `num_threads` threads. The compiled code
+    /// is laid out in the order of `definitions` regardless of `num_threads`.
+    ///
+    /// All of `definitions` are optimized before any of them are compiled.
+    /// The optimizer only reads the [`Convention`]s of the entries, which
+    /// are fixed by `new_entry()`, so the code is the same as that compiled
+    /// by `define()`.
+    pub fn define_all(&mut self, definitions: &[(EntryId, &EBB<EntryId>)], num_threads: usize) {
+        let mut cases = Vec::new();
+        for &(entry, ebb) in definitions {
+            assert!(!get!(self, entry).is_defined);
+            get!(self, entry).is_defined = true;
+            get!(self, entry).version += 1;
+            cases.push((get!(self, entry).case, ebb));
+        }
+        let entry_cases: Vec<CaseId> = self.entries.iter().map(|e| e.case).collect();
+        self.engine.build_all(&cases, num_threads, &|e: EntryId| entry_cases[e.as_usize()]);
+    }
+
+    /// Discard the code at `entry`, so that it once again immediately exits,
+    /// returning its `exit_value`. Code that jumps to `entry`, including
+    /// code defined for other entry points, will run whatever `entry` is
+    /// next defined to do. This is useful when the guest code that `entry`
+    /// was compiled from has changed.
+    pub fn invalidate(&mut self, entry: EntryId) {
+        self.engine.invalidate(get!(self, entry).case);
+        get!(self, entry).is_defined = false;
+        get!(self, entry).version += 1;
+    }
+
+    /// Returns the version number of `entry`. This increases whenever the
+    /// behaviour of `entry` changes, e.g. when it is defined. Callers that
+    /// cache information about `entry` can record its version and later use
+    /// `is_current()` to find out whether the information is stale.
+    pub fn entry_version(&self, entry: EntryId) -> u32 {
+        get!(self, entry).version
+    }
+
+    /// Tests whether `version` is the current version number of `entry`.
+    pub fn is_current(&self, entry: EntryId, version: u32) -> bool {
+        self.entry_version(entry) == version
+    }
+
+    /// Returns the [`Convention`] by which code that jumps to `entry` passes
+    /// values to it. Every [`Variable`] that the epilogue of `entry` reads
+    /// is live, so the [`Register`]s it restores stay in registers from
+    /// entry to entry, and are only saved on exit.
+    ///
+    /// [`Variable`]: code::Variable
+    /// [`Register`]: code::Register
+    pub fn convention(&self, entry: EntryId) -> &Convention {
+        self.engine.convention(get!(self, entry).case)
+    }
+
+    /// Returns statistics about the optimization of the code at `entry`, or
+    /// `None` if `entry` has not been defined.
+    pub fn stats(&self, entry: EntryId) -> Option<&Stats> {
+        self.engine.stats(get!(self, entry).case)
+    }
+
+    /// Summarises the compiled code, ignoring its addresses. Two `Jit`s with
+    /// the same fingerprint have compiled the same code.
+    #[cfg(test)]
+    pub fn fingerprint(&self) -> String { self.engine.fingerprint() }
+
+    /// Returns the machine code most recently compiled by `define()` for
+    /// `entry`, followed by any code since compiled for it by
+    /// `specialize_hot()`, or `None` if `entry` has not been defined.
+    pub fn code_bytes(&self, entry: EntryId) -> Option<Vec<u8>> {
+        self.engine.code_bytes(get!(self, entry).case)
+    }
+
+    /// Returns the positions of the absolute addresses in
+    /// [`Self::code_bytes()`], or `None` if `entry` has not been defined. The
+    /// positions are relative to the beginning of the code buffer, as in
+    /// [`Self::dump()`]. The addresses are of things outside the code, such
+    /// as host functions; everything else is position-independent.
+    pub fn relocations(&self, entry: EntryId) -> Option<Vec<usize>> {
+        self.engine.relocations(get!(self, entry).case)
+    }
+
+    /// Writes to `w` a disassembly of [`Self::code_bytes()`], with the entry
+    /// point and the targets of its guards labelled. Writes nothing if
+    /// `entry` has not been defined.
+    ///
+    /// Panics unless compiled with the "disassemble" feature.
+    pub fn dump(&self, entry: EntryId, w: &mut impl std::fmt::Write) -> std::fmt::Result {
+        self.engine.dump(get!(self, entry).case, w)
+    }
+
+    /// Returns the execution counts of `entry` and of the ways out of it, or
+    /// `None` if `entry` was constructed without profiling.
+    pub fn profile(&self, entry: EntryId) -> Option<ProfileData> {
+        self.engine.profile(get!(self, entry).case)
+    }
+
+    /// Specializes up to `budget` of the ways out of `entry` that have been
+    /// taken at least `threshold` times, most frequent first (see
+    /// [`Self::profile()`]). The code of each is extended with a copy of the
+    /// code it jumps to, up to the next control-flow decision, so that it no
+    /// longer merges with other code before making that decision. The new
+    /// ways out are counted from zero, so calling this repeatedly between
+    /// runs grows traces of hot code. Does nothing unless `entry` was
+    /// constructed with profiling.
+    ///
+    /// Returns the number of ways out that were specialized. If it is not
+    /// zero, the version number of `entry` increases.
+    pub fn specialize_hot(&mut self, entry: EntryId, threshold: u64, budget: usize) -> usize {
+        let done = self.engine.specialize_hot(get!(self, entry).case, threshold, budget);
+        if done > 0 { get!(self, entry).version += 1; }
+        done
     }
 
     /// Call the compiled code starting at `entry`.
     /// - global - the value to pass in [`GLOBAL`].
     ///
+    /// Execution continues until it reaches an entry point that has not been
+    /// defined, and returns that entry point's `exit_value`. This is the only
+    /// way to exit, so choose distinct exit values to find out where
+    /// execution stopped.
+    ///
     /// # Safety
     ///
     /// This will crash if the code is compiled for the wrong [`Target`] or if
@@ -78,20 +231,597 @@ impl<T: Target> Jit<T> {
         let label = &get!(self, entry).label;
         self.engine.run(label, global as *mut G as *mut ())
     }
+
+    /// Makes the compiled code executable without running it. See
+    /// [`Self::run_shared()`].
+    pub fn make_executable(&mut self) {
+        self.engine.make_executable();
+    }
+
+    /// Like [`Self::run()`], but does not need exclusive access to the `Jit`,
+    /// so several threads can run the compiled code at once, each with its
+    /// own `global`. Returns `None` if any code has been compiled since the
+    /// last call to `run()` or [`Self::make_executable()`].
+    ///
+    /// # Safety
+    ///
+    /// As for [`Self::run()`].
+    pub unsafe fn run_shared<G>(&self, entry: EntryId, global: &mut G) -> Option<Word> {
+        let label = &get!(self, entry).label;
+        self.engine.run_shared(label, global as *mut G as *mut ())
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// The features common to [`Jit`] and [`Interpreter`], so that a client can
+/// be written once and run either way.
+///
+/// [`Interpreter`]: super::Interpreter
+pub trait Run {
+    /// See [`Jit::new_entry()`].
+    fn new_entry(&mut self, marshal: &Marshal, exit_value: i64) -> EntryId;
+
+    /// See [`Jit::define()`].
+    fn define(&mut self, entry: EntryId, ebb: &EBB<EntryId>);
+
+    /// See [`Jit::define_all()`]. By default, this calls `define()` for each
+    /// of `definitions` in turn, ignoring `num_threads`.
+    fn define_all(&mut self, definitions: &[(EntryId, &EBB<EntryId>)], num_threads: usize) {
+        let _ = num_threads;
+        for &(entry, ebb) in definitions { self.define(entry, ebb); }
+    }
+
+    /// See [`Jit::run()`].
+    ///
+    /// # Safety
+    ///
+    /// This will crash if the code is invalid.
+    unsafe fn run<G>(&mut self, entry: EntryId, global: &mut G) -> Word;
+
+    /// See [`Jit::profile()`]. By default, this returns `None`.
+    fn profile(&self, entry: EntryId) -> Option<ProfileData> {
+        let _ = entry;
+        None
+    }
+}
+
+impl<T: Target> Run for Jit<T> {
+    fn new_entry(&mut self, marshal: &Marshal, exit_value: i64) -> EntryId {
+        Jit::new_entry(self, marshal, exit_value)
+    }
+
+    fn define(&mut self, entry: EntryId, ebb: &EBB<EntryId>) {
+        Jit::define(self, entry, ebb);
+    }
+
+    fn define_all(&mut self, definitions: &[(EntryId, &EBB<EntryId>)], num_threads: usize) {
+        Jit::define_all(self, definitions, num_threads);
+    }
+
+    unsafe fn run<G>(&mut self, entry: EntryId, global: &mut G) -> Word {
+        Jit::run(self, entry, global)
+    }
+
+    fn profile(&self, entry: EntryId) -> Option<ProfileData> {
+        Jit::profile(self, entry)
+    }
 }
 
 //-----------------------------------------------------------------------------
 
 #[cfg(test)]
 pub mod tests {
-    use super::super::target::{native};
+    use super::*;
+    use super::super::target::{native, RESULT};
+    use super::super::target::tests::{VM, TEST_VALUES};
+    use code::{Action, Ending, Switch, REGISTERS, GLOBAL, Width};
+    use code::builder::{build, build_block};
 
     use super::super::factorial::*;
 
+    /// Returns a [`Marshal`] that keeps the pointer to the state in
+    /// `pointer`, and keeps each of `cells` in a register. `cells[i]` is
+    /// saved in the `i`th word of the state.
+    pub fn marshal(pointer: code::Register, cells: &[code::Register]) -> Marshal {
+        Marshal {
+            prologue: build_block(|b| {
+                b.move_(pointer, GLOBAL);
+                for (i, &cell) in cells.iter().enumerate() {
+                    b.load(cell, (pointer, 8 * i as i32, Width::Eight));
+                }
+            }),
+            epilogue: build_block(|b| {
+                for (i, &cell) in cells.iter().enumerate() {
+                    b.store(cell, (pointer, 8 * i as i32, Width::Eight));
+                }
+                b.move_(GLOBAL, pointer);
+            }),
+        }
+    }
+
     #[test]
     pub fn factorial() {
         let mut jit = Factorial::new(native());
         let result = jit.run(5);
         assert_eq!(result, 120);
     }
+
+    /// Test that the compiled code still works if moved to a different
+    /// address, after applying the relocations. The code is profiled, so
+    /// that it contains the absolute addresses of the counters. On aarch64
+    /// the constant pool is not all in `code()`, so this test is specific to
+    /// x86_64.
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    pub fn relocate() {
+        use crate::buffer::{Buffer, Mmap};
+        use super::super::target::{Lower, ExecuteFn};
+        let mut jit = Jit::new(native());
+        jit.set_profiling(true);
+        let mut factorial = Factorial::with_jit(jit, 1);
+        assert_eq!(factorial.run(5), 120);
+        let Factorial {jit, start, loop_} = factorial;
+        let count = jit.profile(loop_).expect("Not profiled").count;
+        let lowerer = jit.engine.lowerer();
+        for entry in [start, loop_] {
+            let relocations = jit.relocations(entry).expect("Not defined");
+            assert!(!relocations.is_empty());
+            assert!(relocations.iter().all(|pos| lowerer.relocations().contains(pos)));
+        }
+        // Copy the code to a buffer with a different base address.
+        const OFFSET: usize = 0x1230;
+        let code = lowerer.code();
+        let mut moved: Mmap = Mmap::new();
+        moved.resize(OFFSET + code.len());
+        moved[OFFSET..][..code.len()].copy_from_slice(code);
+        for &pos in lowerer.relocations() {
+            // The counters have not moved, so rewrite their old addresses.
+            let address = u64::from_le_bytes(code[pos..pos + 8].try_into().unwrap());
+            moved.write(OFFSET + pos, address, 8);
+        }
+        // Run it.
+        let target = get!(jit, start).label.target().expect("Undefined label");
+        let mut registers = [5u64, 0];
+        let result = moved.execute(|bytes| unsafe {
+            let f: ExecuteFn = std::mem::transmute(&bytes[OFFSET + target]);
+            f(registers.as_mut_ptr() as *mut ())
+        });
+        assert_eq!(result, Word {s: 2});
+        assert_eq!(registers, [0, 120]);
+        assert_eq!(jit.profile(loop_).expect("Not profiled").count, 2 * count);
+    }
+
+    /// Test that `dump()` disassembles the loop, and labels the cases it
+    /// chooses between.
+    #[test]
+    #[cfg(feature = "disassemble")]
+    pub fn dump() {
+        let jit = Factorial::new(native());
+        let mut dump = String::new();
+        jit.jit.dump(jit.loop_, &mut dump).unwrap();
+        let instructions: Vec<&str> = dump.lines().filter_map(|line| line.split_once("  ")).map(|(_, i)| i).collect();
+        assert!(instructions.iter().any(|i| i.contains("mul")));
+        assert!(instructions.iter().any(|i| {
+            (i.starts_with('j') && !i.starts_with("jmp")) || i.starts_with("b.") || i.starts_with("cb")
+        }));
+        assert!(dump.matches("CaseId").count() >= 2);
+    }
+
+    #[test]
+    #[cfg(not(feature = "disassemble"))]
+    #[should_panic(expected = "compile with feature disassemble")]
+    pub fn dump() {
+        let jit = Factorial::new(native());
+        let _ = jit.jit.dump(jit.loop_, &mut String::new());
+    }
+
+    /// Test that the structure of the compiled code does not depend on the
+    /// number of threads.
+    #[test]
+    pub fn define_all() {
+        let mut jit1 = Factorial::with_threads(native(), 1);
+        let mut jit4 = Factorial::with_threads(native(), 4);
+        assert_eq!(jit1.jit.engine.fingerprint(), jit4.jit.engine.fingerprint());
+        assert_eq!(jit1.run(5), 120);
+        assert_eq!(jit4.run(5), 120);
+    }
+
+    /// Test that reaching an entry point with no code returns its exit value,
+    /// even if it was not meant to be an exit.
+    #[test]
+    pub fn stall() {
+        const X: code::Register = REGISTERS[1];
+        const P: code::Register = REGISTERS[2];
+        let mut jit = Jit::new(native());
+        let marshal = marshal(P, &[X]);
+        let start = jit.new_entry(&marshal, 0);
+        let halt = jit.new_entry(&marshal, 1);
+        let stuck = jit.new_entry(&marshal, 2);
+        jit.define(start, &EBB {
+            actions: Box::new([]),
+            ending: Ending::Switch(X.into(), Switch::if_(
+                EBB {actions: Box::new([]), ending: Ending::Leaf(stuck)},
+                EBB {actions: Box::new([]), ending: Ending::Leaf(halt)},
+            )),
+        });
+        let mut x: u64 = 0;
+        assert_eq!(unsafe {jit.run(start, &mut x)}, Word {s: 1});
+        let mut x: u64 = 5;
+        assert_eq!(unsafe {jit.run(start, &mut x)}, Word {s: 2});
+        assert_eq!(x, 5);
+    }
+
+    /// Test that `Options` reach the optimizer and that `Stats` are kept
+    /// for each defined entry.
+    #[test]
+    pub fn stats() {
+        use super::super::target::{Native};
+        const X: code::Register = REGISTERS[1];
+        const P: code::Register = REGISTERS[2];
+        let marshal = marshal(P, &[]);
+        let nodes_created = |options| {
+            let mut jit = Jit::with_options(native(), options);
+            let halt = jit.new_entry(&marshal, 0);
+            let start = jit.new_entry(&marshal, 1);
+            jit.define(start, &build(|mut b| {
+                b.load(X, (P, 0, Width::Eight));
+                b.const_binary64(code::BinaryOp::Add, X, X, 0);
+                b.const_binary64(code::BinaryOp::Mul, X, X, 1);
+                b.store(X, (P, 8, Width::Eight));
+                b.debug(X);
+                b.jump(halt)
+            }));
+            let mut state = [42u64, 0];
+            assert_eq!(unsafe {jit.run(start, &mut state)}, Word {s: 0});
+            assert_eq!(state, [42, 42]);
+            assert!(jit.stats(halt).is_none());
+            jit.stats(start).expect("Missing stats").nodes_created
+        };
+        let options = Options::new(<Native as Target>::NUM_REGISTERS);
+        let folded = nodes_created(options);
+        let unfolded = nodes_created(Options {enable_constant_folding: false, ..options});
+        assert!(folded < unfolded);
+        let stripped = nodes_created(Options {keep_debug: false, ..options});
+        let kept = nodes_created(Options {keep_debug: true, ..options});
+        assert_eq!(stripped + 1, kept);
+    }
+
+    /// Test that an installed `Trace` is notified of register allocation.
+    #[test]
+    pub fn trace() {
+        use std::sync::{Arc};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        struct CountAllocations(Arc<AtomicUsize>);
+        impl Trace for CountAllocations {
+            fn on_allocation(&self, _node: usize, _register: code::Register) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        const X: code::Register = REGISTERS[1];
+        const P: code::Register = REGISTERS[2];
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut jit = Jit::new(native());
+        jit.set_trace(Box::new(CountAllocations(count.clone())));
+        let marshal = marshal(P, &[X]);
+        let halt = jit.new_entry(&marshal, 0);
+        let start = jit.new_entry(&marshal, 1);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        jit.define(start, &build(|mut b| {
+            b.binary64(code::BinaryOp::Mul, X, X, X);
+            b.jump(halt)
+        }));
+        assert!(count.load(Ordering::Relaxed) > 0);
+        let mut x: u64 = 7;
+        assert_eq!(unsafe {jit.run(start, &mut x)}, Word {s: 0});
+        assert_eq!(x, 49);
+    }
+
+    /// Test that a `Switch` with many cases dispatches correctly.
+    #[test]
+    pub fn many_cases() {
+        const X: code::Register = REGISTERS[1];
+        const P: code::Register = REGISTERS[2];
+        const NUM_CASES: u64 = 200;
+        let mut jit = Jit::new(native());
+        let marshal = marshal(P, &[X]);
+        let start = jit.new_entry(&marshal, 0);
+        let exits: Vec<EntryId> = (0..=NUM_CASES).map(|i| jit.new_entry(&marshal, 1 + i as i64)).collect();
+        jit.define(start, &EBB {
+            actions: Box::new([]),
+            ending: Ending::Switch(X.into(), Switch::new(
+                exits[..NUM_CASES as usize].iter().map(
+                    |&exit| EBB {actions: Box::new([]), ending: Ending::Leaf(exit)}
+                ).collect(),
+                EBB {actions: Box::new([]), ending: Ending::Leaf(exits[NUM_CASES as usize])},
+            )),
+        });
+        for mut x in (0..NUM_CASES + 10).chain([!0, 1 << 32]) {
+            let expected = std::cmp::min(x, NUM_CASES) + 1;
+            assert_eq!(unsafe {jit.run(start, &mut x)}, Word {u: expected});
+        }
+    }
+
+    /// Test that defining an entry point changes its version number and no
+    /// other, and that running code does not.
+    #[test]
+    pub fn versions() {
+        let mut jit = Jit::new(native());
+        let marshal = Marshal {prologue: Box::new([]), epilogue: Box::new([])};
+        let start = jit.new_entry(&marshal, 0);
+        let halt = jit.new_entry(&marshal, 1);
+        let start_version = jit.entry_version(start);
+        let halt_version = jit.entry_version(halt);
+        assert!(jit.is_current(start, start_version));
+        jit.define(start, &EBB {actions: Box::new([]), ending: Ending::Leaf(halt)});
+        assert!(!jit.is_current(start, start_version));
+        assert!(jit.is_current(halt, halt_version));
+        let start_version = jit.entry_version(start);
+        assert_eq!(unsafe {jit.run(start, &mut ())}, Word {s: 1});
+        assert!(jit.is_current(start, start_version));
+        assert!(jit.is_current(halt, halt_version));
+    }
+
+    /// Test that an invalidated entry point can be redefined, and that code
+    /// that jumps to it sees the new definition.
+    #[test]
+    pub fn invalidate() {
+        const X: code::Register = REGISTERS[1];
+        const P: code::Register = REGISTERS[2];
+        let mut jit = Jit::new(native());
+        let marshal = marshal(P, &[X]);
+        let start = jit.new_entry(&marshal, 0);
+        let step = jit.new_entry(&marshal, 1);
+        let halt = jit.new_entry(&marshal, 2);
+        jit.define(start, &build(|mut b| {
+            b.const_binary64(code::BinaryOp::Add, X, X, 1);
+            b.jump(step)
+        }));
+        jit.define(step, &build(|mut b| {
+            b.const_binary64(code::BinaryOp::Mul, X, X, 2);
+            b.jump(halt)
+        }));
+        let mut x: u64 = 5;
+        assert_eq!(unsafe {jit.run(start, &mut x)}, Word {s: 2});
+        assert_eq!(x, 12);
+        let version = jit.entry_version(step);
+        jit.invalidate(step);
+        assert!(!jit.is_current(step, version));
+        assert!(jit.stats(step).is_none());
+        // `step` now exits.
+        let mut x: u64 = 5;
+        assert_eq!(unsafe {jit.run(start, &mut x)}, Word {s: 1});
+        assert_eq!(x, 6);
+        // Redefine `step`.
+        jit.define(step, &build(|mut b| {
+            b.const_binary64(code::BinaryOp::Mul, X, X, 3);
+            b.jump(halt)
+        }));
+        let mut x: u64 = 5;
+        assert_eq!(unsafe {jit.run(start, &mut x)}, Word {s: 2});
+        assert_eq!(x, 18);
+    }
+
+    /// Adds `amount` to the counter at `counter` and returns its new value.
+    extern "C" fn increment(counter: u64, amount: u64) -> u64 {
+        let counter = counter as *mut u64;
+        unsafe {
+            *counter += amount;
+            *counter
+        }
+    }
+
+    /// Test that compiled code can call a native function, and that memory
+    /// accesses are correctly ordered with respect to the call.
+    #[test]
+    pub fn call() {
+        const X: code::Register = REGISTERS[1];
+        const Y: code::Register = REGISTERS[2];
+        const P: code::Register = REGISTERS[3];
+        const COUNTER: code::Register = REGISTERS[4];
+        let mut jit = Jit::new(native());
+        let marshal = marshal(P, &[]);
+        let start = jit.new_entry(&marshal, 0);
+        let halt = jit.new_entry(&marshal, 1);
+        jit.define(start, &build(|mut b| {
+            b.load(COUNTER, (P, 0, Width::Eight));
+            b.const_(Y, 4);
+            b.store(Y, (COUNTER, 0, Width::Eight));
+            b.const_(Y, 3);
+            b.call(X, code::NativeFunction(increment), COUNTER, Y);
+            b.call(X, code::NativeFunction(increment), COUNTER, X);
+            b.store(X, (P, 8, Width::Eight));
+            b.jump(halt)
+        }));
+        let mut counter: u64 = 0;
+        let mut state: [u64; 2] = [&mut counter as *mut u64 as u64, 0];
+        assert_eq!(unsafe {jit.run(start, &mut state)}, Word {s: 1});
+        // 4 + 3 = 7, then 7 + 7 = 14.
+        assert_eq!(counter, 14);
+        assert_eq!(state[1], 14);
+    }
+
+    /// Test that two virtual machines with different state layouts can share
+    /// a `Jit`, and that one can jump into the other.
+    #[test]
+    pub fn two_machines() {
+        let mut jit = Jit::new(native());
+        // Machine `a` keeps a word of its state in `REGISTERS[1]`.
+        const AX: code::Register = REGISTERS[1];
+        const AP: code::Register = REGISTERS[2];
+        let marshal_a = marshal(AP, &[AX]);
+        // Machine `b` keeps a different word in `REGISTERS[3]`.
+        const BX: code::Register = REGISTERS[3];
+        const BP: code::Register = REGISTERS[4];
+        let marshal_b = Marshal {
+            prologue: build_block(|b| {
+                b.move_(BP, GLOBAL);
+                b.load(BX, (BP, 8, Width::Eight));
+            }),
+            epilogue: build_block(|b| {
+                b.store(BX, (BP, 8, Width::Eight));
+                b.move_(GLOBAL, BP);
+            }),
+        };
+        let start_a = jit.new_entry(&marshal_a, 0);
+        let halt_a = jit.new_entry(&marshal_a, 1);
+        let call_b = jit.new_entry(&marshal_a, 2);
+        let start_b = jit.new_entry(&marshal_b, 3);
+        let halt_b = jit.new_entry(&marshal_b, 4);
+        jit.define(start_a, &build(|mut b| {
+            b.const_binary64(code::BinaryOp::Mul, AX, AX, 2);
+            b.jump(halt_a)
+        }));
+        jit.define(start_b, &build(|mut b| {
+            b.const_binary64(code::BinaryOp::Add, BX, BX, 1);
+            b.jump(halt_b)
+        }));
+        // To jump from one machine to another, convert between their state
+        // layouts by running the epilogue of one and the prologue of the
+        // other.
+        jit.define(call_b, &build(|mut b| {
+            b.const_binary64(code::BinaryOp::Mul, AX, AX, 3);
+            b.actions.extend(marshal_a.epilogue.iter().copied());
+            b.actions.extend(marshal_b.prologue.iter().copied());
+            b.jump(start_b)
+        }));
+        let mut state: [u64; 2] = [10, 20];
+        assert_eq!(unsafe {jit.run(start_a, &mut state)}, Word {s: 1});
+        assert_eq!(state, [20, 20]);
+        assert_eq!(unsafe {jit.run(start_b, &mut state)}, Word {s: 4});
+        assert_eq!(state, [20, 21]);
+        assert_eq!(unsafe {jit.run(call_b, &mut state)}, Word {s: 4});
+        assert_eq!(state, [60, 22]);
+    }
+
+    /// Test that when two threads race to `AtomicCas` the same location,
+    /// exactly one of them wins.
+    #[test]
+    pub fn atomic_cas() {
+        use std::sync::{Barrier};
+        use std::sync::atomic::{AtomicU64, Ordering};
+        const X: code::Register = REGISTERS[1];
+        const Y: code::Register = REGISTERS[2];
+        const P: code::Register = REGISTERS[3];
+        const LOCK: code::Register = REGISTERS[4];
+        const NUM_ROUNDS: u64 = 100;
+        let lock = AtomicU64::new(0);
+        let barrier = Barrier::new(2);
+        let race = || {
+            let mut jit = Jit::new(native());
+            let marshal = marshal(P, &[]);
+            let halt = jit.new_entry(&marshal, 0);
+            let start = jit.new_entry(&marshal, 1);
+            // Try to increment the value at `LOCK` from `X` to `X + 1`.
+            jit.define(start, &build(|mut b| {
+                b.load(LOCK, (P, 0, Width::Eight));
+                b.load(X, (P, 8, Width::Eight));
+                b.const_binary64(code::BinaryOp::Add, Y, X, 1);
+                b.atomic_cas(X, X, Y, (LOCK, 0, Width::Eight));
+                b.store(X, (P, 16, Width::Eight));
+                b.jump(halt)
+            }));
+            (0..NUM_ROUNDS).map(|round| {
+                barrier.wait();
+                let mut state = [&lock as *const AtomicU64 as u64, round, 0];
+                assert_eq!(unsafe {jit.run(start, &mut state)}, Word {s: 0});
+                state[2] == round
+            }).collect::<Vec<bool>>()
+        };
+        let (wins1, wins2) = std::thread::scope(|scope| {
+            let thread1 = scope.spawn(race);
+            let thread2 = scope.spawn(race);
+            (thread1.join().unwrap(), thread2.join().unwrap())
+        });
+        assert_eq!(lock.load(Ordering::SeqCst), NUM_ROUNDS);
+        for (win1, win2) in wins1.into_iter().zip(wins2) {
+            assert_ne!(win1, win2);
+        }
+    }
+
+    /// Returns examples of every defined form of [`Action`] that writes to
+    /// `dest` and reads only `REGISTERS[1]` and `REGISTERS[2]`.
+    fn defined_forms(dest: code::Register) -> Vec<Action> {
+        use code::{Precision, UnaryOp, BinaryOp};
+        let (src1, src2) = (REGISTERS[1].into(), REGISTERS[2].into());
+        let mut forms = vec![Action::Move(dest.into(), src1)];
+        for prec in [Precision::P32, Precision::P64] {
+            for c in TEST_VALUES {
+                forms.push(Action::Constant(prec, dest, c as i64));
+            }
+            for width in [Width::One, Width::Two, Width::Four, Width::Eight] {
+                forms.push(Action::Unary(UnaryOp::Uxt(width), prec, dest, src1));
+                forms.push(Action::Unary(UnaryOp::Sxt(width), prec, dest, src1));
+            }
+            for op in [UnaryOp::Abs, UnaryOp::Negate, UnaryOp::Not, UnaryOp::Clz, UnaryOp::Ctz, UnaryOp::Popcount] {
+                forms.push(Action::Unary(op, prec, dest, src1));
+            }
+            for (shift, bits) in [(0, 8), (3, 29), (0, 32)] {
+                forms.push(Action::Unary(UnaryOp::Extract(shift, bits), prec, dest, src1));
+            }
+            for op in [
+                BinaryOp::Add, BinaryOp::Sub, BinaryOp::Mul,
+                BinaryOp::MulHigh, BinaryOp::UMulHigh,
+                BinaryOp::UDiv, BinaryOp::SDiv, BinaryOp::URem, BinaryOp::SRem,
+                BinaryOp::Lsl, BinaryOp::Lsr, BinaryOp::Asr,
+                BinaryOp::RotL, BinaryOp::RotR,
+                BinaryOp::And, BinaryOp::Or, BinaryOp::Xor,
+                BinaryOp::Lt, BinaryOp::Ult, BinaryOp::Eq,
+                BinaryOp::Max, BinaryOp::Min, BinaryOp::UMax, BinaryOp::UMin,
+            ] {
+                forms.push(Action::Binary(op, prec, dest, src1, src2));
+            }
+            forms.push(Action::Select(prec, dest, src1, src1, src2));
+            forms.push(Action::Select(prec, dest, src2, src1, src2));
+        }
+        forms.push(Action::Call(dest, code::NativeFunction(code::tests::add), src1, src2));
+        forms
+    }
+
+    /// Test that the emulator, the optimized pipeline and direct lowering
+    /// agree on the result of every defined form of [`Action`].
+    #[test]
+    pub fn agreement() {
+        use std::collections::{HashMap};
+        use code::{BinaryOp, tests::{Emulator}};
+        const X: code::Register = REGISTERS[1];
+        const Y: code::Register = REGISTERS[2];
+        const DEST: code::Register = REGISTERS[3];
+        const P: code::Register = REGISTERS[4];
+        let marshal = marshal(P, &[X, Y, DEST]);
+        let jit_forms = defined_forms(DEST);
+        let vm_forms = defined_forms(RESULT);
+        assert_eq!(jit_forms.len(), vm_forms.len());
+        for (&jit_form, &vm_form) in jit_forms.iter().zip(&vm_forms) {
+            let mut jit = Jit::new(native());
+            let start = jit.new_entry(&marshal, 1);
+            let halt = jit.new_entry(&marshal, 0);
+            jit.define(start, &build(|mut b| {
+                b.actions.push(jit_form);
+                b.jump(halt)
+            }));
+            let mut vm = VM::new(&[X, Y], |lo| lo.action(vm_form));
+            for x in TEST_VALUES {
+                for y in TEST_VALUES {
+                    let y = match jit_form {
+                        // Shifts are only defined for amounts less than the
+                        // word size.
+                        Action::Binary(BinaryOp::Lsl | BinaryOp::Lsr | BinaryOp::Asr, prec, _, _, _) =>
+                            y % prec.bits() as u64,
+                        _ => y,
+                    };
+                    let mut emulator = Emulator::new(HashMap::from([
+                        (X.into(), x as i64),
+                        (Y.into(), y as i64),
+                    ]), vec![]);
+                    emulator.action(&jit_form);
+                    let expected = emulator.variables[&DEST.into()] as u64;
+                    let mut memory = [x, y, 0];
+                    assert_eq!(unsafe {jit.run(start, &mut memory)}, Word {s: 0});
+                    assert_eq!(memory, [x, y, expected], "{:?}", jit_form);
+                    vm = unsafe {vm.run(&mut [Word {u: x}, Word {u: y}], Word {u: expected})};
+                }
+            }
+        }
+    }
 }
@@ -24,6 +24,8 @@ pub struct Factorial<T: Target> {
     pub jit: Jit<T>,
     /// The entry point.
     pub start: EntryId,
+    /// The entry point of the loop.
+    pub loop_: EntryId,
 }
 
 const START: i64 = 0;
@@ -32,7 +34,18 @@ const HALT: i64 = 2;
 
 impl<T: Target> Factorial<T> {
     pub fn new(target: T) -> Factorial<T> {
-        let mut jit = Jit::new(target);
+        Self::with_threads(target, 1)
+    }
+
+    /// Like `new()` but shares the work of optimizing between up to
+    /// `num_threads` threads.
+    pub fn with_threads(target: T, num_threads: usize) -> Factorial<T> {
+        Self::with_jit(Jit::new(target), num_threads)
+    }
+
+    /// Like `with_threads()` but compiles the code into `jit`, which must
+    /// have no entries. This allows `jit` to be configured first.
+    pub fn with_jit(mut jit: Jit<T>, num_threads: usize) -> Factorial<T> {
         let marshal = Marshal {
             prologue: Box::new([
                 // Restore `N`.
@@ -54,13 +67,13 @@ impl<T: Target> Factorial<T> {
         let start = jit.new_entry(&marshal, START);
         let loop_ = jit.new_entry(&marshal, LOOP);
         let halt = jit.new_entry(&marshal, HALT);
-        jit.define(start, &EBB {
+        let start_ebb = EBB {
             actions: Box::new([
                 Constant(P32, RESULT, 1),
             ]),
             ending: Ending::Leaf(loop_),
-        });
-        jit.define(loop_, &EBB {
+        };
+        let loop_ebb = EBB {
             actions: Box::new([]),
             ending: Ending::Switch(N.into(), Switch::if_(
                 EBB {
@@ -75,8 +88,9 @@ impl<T: Target> Factorial<T> {
                     ending: Ending::Leaf(halt),
                 },
             )),
-        });
-        Factorial {jit, start}
+        };
+        jit.define_all(&[(start, &start_ebb), (loop_, &loop_ebb)], num_threads);
+        Factorial {jit, start, loop_}
     }
 
     pub fn run(&mut self, n: u64) -> u64 {
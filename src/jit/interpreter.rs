@@ -0,0 +1,271 @@
+use std::sync::atomic::{self, AtomicU8, AtomicU16, AtomicU32, AtomicU64, Ordering};
+
+use crate::util::{AsUsize, ArrayMap};
+use super::{code, EntryId, Run};
+use super::target::{Word};
+use code::{Register, REGISTERS, Variable, Width, Address, Action, Marshal, Switch, EBB, Ending, GLOBAL, debug_word};
+
+/// An entry point of an [`Interpreter`].
+#[derive(Debug)]
+struct Entry {
+    marshal: Marshal,
+    exit_value: i64,
+    /// `None` until the entry is defined.
+    ebb: Option<EBB<EntryId>>,
+}
+
+//-----------------------------------------------------------------------------
+
+/// Executes the code at the entry points of an [`Interpreter`].
+#[derive(Debug)]
+struct State {
+    registers: ArrayMap<Register, i64>,
+    /// Indexed by `Slot`. The most recently pushed `Slot` is last.
+    slots: Vec<i64>,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {registers: ArrayMap::new(REGISTERS.len()), slots: Vec::new()}
+    }
+
+    fn get(&self, v: impl Into<Variable>) -> i64 {
+        match v.into() {
+            Variable::Register(r) => self.registers[r],
+            Variable::Slot(s) => self.slots[s.0],
+        }
+    }
+
+    fn set(&mut self, v: impl Into<Variable>, x: i64) {
+        match v.into() {
+            Variable::Register(r) => { self.registers[r] = x; },
+            Variable::Slot(s) => { self.slots[s.0] = x; },
+        }
+    }
+
+    fn address(&self, addr: Address) -> *mut u8 {
+        (self.get(addr.base) as *mut u8).wrapping_offset(addr.offset as isize)
+    }
+
+    /// Reads `addr`, zero-extended.
+    unsafe fn load(&self, addr: Address) -> i64 {
+        let p = self.address(addr);
+        match addr.width {
+            Width::One => i64::from(p.read()),
+            Width::Two => i64::from((p as *const u16).read_unaligned()),
+            Width::Four => i64::from((p as *const u32).read_unaligned()),
+            Width::Eight => (p as *const u64).read_unaligned() as i64,
+        }
+    }
+
+    /// Writes the low bytes of `x` to `addr`.
+    #[allow(clippy::cast_possible_truncation)]
+    unsafe fn store(&self, addr: Address, x: i64) {
+        let p = self.address(addr);
+        match addr.width {
+            Width::One => p.write(x as u8),
+            Width::Two => (p as *mut u16).write_unaligned(x as u16),
+            Width::Four => (p as *mut u32).write_unaligned(x as u32),
+            Width::Eight => (p as *mut u64).write_unaligned(x as u64),
+        }
+    }
+
+    /// Atomically replaces `expected` by `new` at `addr`, and returns the
+    /// old value, zero-extended.
+    #[allow(clippy::cast_possible_truncation)]
+    unsafe fn cas(&self, addr: Address, expected: i64, new: i64) -> i64 {
+        let p = self.address(addr);
+        let (s, f) = (Ordering::SeqCst, Ordering::SeqCst);
+        match addr.width {
+            Width::One => i64::from((*(p as *const AtomicU8))
+                .compare_exchange(expected as u8, new as u8, s, f).unwrap_or_else(|x| x)),
+            Width::Two => i64::from((*(p as *const AtomicU16))
+                .compare_exchange(expected as u16, new as u16, s, f).unwrap_or_else(|x| x)),
+            Width::Four => i64::from((*(p as *const AtomicU32))
+                .compare_exchange(expected as u32, new as u32, s, f).unwrap_or_else(|x| x)),
+            Width::Eight => (*(p as *const AtomicU64))
+                .compare_exchange(expected as u64, new as u64, s, f).unwrap_or_else(|x| x) as i64,
+        }
+    }
+
+    unsafe fn action(&mut self, action: &Action) {
+        match *action {
+            Action::Move(dest, src) => {
+                let x = self.get(src);
+                self.set(dest, x);
+            },
+            Action::Constant(prec, dest, imm) => {
+                self.set(dest, prec.truncate(imm));
+            },
+            Action::Unary(op, prec, dest, src) => {
+                let x = self.get(src);
+                self.set(dest, op.apply(prec, x));
+            },
+            Action::Binary(op, prec, dest, src1, src2) => {
+                let x = self.get(src1);
+                let y = self.get(src2);
+                self.set(dest, op.apply(prec, x, y));
+            },
+            Action::Select(prec, dest, cond, src1, src2) => {
+                let x = if prec.truncate(self.get(cond)) != 0 { self.get(src1) } else { self.get(src2) };
+                self.set(dest, prec.truncate(x));
+            },
+            Action::Load(dest, addr) => {
+                let x = self.load(addr);
+                self.set(dest, x);
+            },
+            Action::Store(dest, src, addr) => {
+                self.store(addr, self.get(src));
+                self.set(dest, self.get(addr.base));
+            },
+            Action::Send(dest, src1, _) => {
+                self.set(dest, self.get(src1));
+            },
+            Action::Push(src1, src2) => {
+                let x = src1.map_or(0, |v| self.get(v));
+                let y = src2.map_or(0, |v| self.get(v));
+                self.slots.push(y);
+                self.slots.push(x);
+            },
+            Action::Drop(n) => {
+                assert!(2 * n <= self.slots.len());
+                self.slots.truncate(self.slots.len() - 2 * n);
+            },
+            Action::Debug(src) => {
+                debug_word(self.get(src) as u64);
+            },
+            Action::Call(dest, function, src1, src2) => {
+                let x = self.get(src1);
+                let y = self.get(src2);
+                self.set(dest, (function.0)(x as u64, y as u64) as i64);
+            },
+            Action::Fence(_) => {
+                atomic::fence(Ordering::SeqCst);
+            },
+            Action::AtomicCas(dest, expected, new, addr) => {
+                let x = self.cas(addr, self.get(expected), self.get(new));
+                self.set(dest, x);
+            },
+            Action::SlotAddress(_, _) => {
+                panic!("The interpreter does not support SlotAddress; see Action::check()");
+            },
+        }
+    }
+
+    unsafe fn actions(&mut self, actions: &[Action]) {
+        for action in actions {
+            self.action(action);
+        }
+    }
+
+    /// Executes `ebb` and returns the leaf it reaches.
+    unsafe fn ebb(&mut self, mut ebb: &EBB<EntryId>) -> EntryId {
+        loop {
+            self.actions(&ebb.actions);
+            match ebb.ending {
+                Ending::Leaf(leaf) => return leaf,
+                Ending::Switch(discriminant, Switch {ref cases, ref default_}) => {
+                    let i = self.get(discriminant) as u64;
+                    ebb = if i < cases.len() as u64 { &cases[i as usize] } else { default_ };
+                },
+            }
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// A drop-in replacement for [`Jit`] that executes the code at its entry
+/// points without compiling it. This is slow, but it works where generating
+/// machine code is forbidden, and it is useful for finding out whether a bug
+/// is a miscompilation.
+///
+/// Reading a [`Register`] that has not been written gives zero.
+///
+/// [`Jit`]: super::Jit
+#[derive(Debug, Default)]
+pub struct Interpreter {
+    /// Indexed by `EntryId`.
+    entries: Vec<Entry>,
+}
+
+impl Interpreter {
+    pub fn new() -> Self { Self::default() }
+}
+
+impl Run for Interpreter {
+    fn new_entry(&mut self, marshal: &Marshal, exit_value: i64) -> EntryId {
+        assert!(exit_value >= 0);
+        let id = EntryId::new(self.entries.len()).unwrap();
+        self.entries.push(Entry {marshal: marshal.clone(), exit_value, ebb: None});
+        id
+    }
+
+    fn define(&mut self, entry: EntryId, ebb: &EBB<EntryId>) {
+        let entry = &mut self.entries[entry.as_usize()];
+        assert!(entry.ebb.is_none());
+        entry.ebb = Some(ebb.clone());
+    }
+
+    unsafe fn run<G>(&mut self, entry: EntryId, global: &mut G) -> Word {
+        let mut state = State::new();
+        state.set(GLOBAL, global as *mut G as i64);
+        let mut entry = &self.entries[entry.as_usize()];
+        state.actions(&entry.marshal.prologue);
+        while let Some(ebb) = &entry.ebb {
+            entry = &self.entries[state.ebb(ebb).as_usize()];
+        }
+        state.actions(&entry.marshal.epilogue);
+        Word {s: entry.exit_value}
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use code::{Slot, BinaryOp, Precision};
+    use code::builder::{build};
+
+    /// Test that the `Interpreter` follows control flow from entry to entry,
+    /// keeps `Slot`s across entries, and exits at an undefined entry.
+    #[test]
+    pub fn factorial() {
+        const N: Register = REGISTERS[1];
+        const RESULT: Register = REGISTERS[2];
+        const P: Register = REGISTERS[3];
+        let mut interpreter = Interpreter::new();
+        const ACC: Slot = Slot(1);
+        let marshal = Marshal {
+            prologue: Box::new([
+                Action::Move(P.into(), GLOBAL.into()),
+                Action::Load(N, Address {base: P.into(), offset: 0, width: Width::Eight}),
+                Action::Constant(Precision::P64, RESULT, 1),
+                Action::Push(Some(RESULT.into()), None),
+            ]),
+            epilogue: Box::new([
+                Action::Move(RESULT.into(), ACC.into()),
+                Action::Drop(1),
+                Action::Store(GLOBAL, RESULT.into(), Address {base: P.into(), offset: 0, width: Width::Eight}),
+            ]),
+        };
+        let halt = interpreter.new_entry(&marshal, 1);
+        let start = interpreter.new_entry(&marshal, 0);
+        interpreter.define(start, &build(|b| {
+            b.if_(N,
+                build(|mut b| {
+                    b.move_(RESULT, ACC);
+                    b.binary64(BinaryOp::Mul, RESULT, RESULT, N);
+                    b.move_(ACC, RESULT);
+                    b.const_binary64(BinaryOp::Sub, N, N, 1);
+                    b.jump(start)
+                }),
+                build(|b| b.jump(halt)),
+            )
+        }));
+        let mut x: u64 = 5;
+        assert_eq!(unsafe {interpreter.run(start, &mut x)}, Word {s: 1});
+        assert_eq!(x, 120);
+    }
+}
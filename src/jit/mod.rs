@@ -2,9 +2,21 @@ use super::{code, target, optimizer};
 
 mod engine;
 use engine::{Engine, CaseId};
+pub use engine::{ProfileData};
 
 mod entry;
-pub use entry::{Jit, EntryId};
+pub use entry::{Jit, EntryId, Run};
+
+mod interpreter;
+pub use interpreter::{Interpreter};
+
+mod shared;
+pub use shared::{SharedJit};
 
 #[cfg(test)]
 pub mod factorial;
+
+#[cfg(test)]
+pub mod tests {
+    pub use super::entry::tests::{marshal};
+}
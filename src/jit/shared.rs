@@ -0,0 +1,103 @@
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{code, Jit, EntryId, Run, ProfileData};
+use super::target::{Word, Target};
+use code::{Marshal, EBB};
+
+/// A [`Jit`] that can be shared between threads. Clones of a `SharedJit`
+/// share the same compiled code.
+///
+/// Compiling code takes exclusive access to the `Jit`, but running it does
+/// not, so several threads can run the compiled code at once. Each thread
+/// passes its own `global`, so the threads' states are independent.
+///
+/// Profiling counts are not updated atomically, so they are approximate if
+/// several threads run the same profiled code at once.
+#[derive(Debug)]
+pub struct SharedJit<T: Target>(Arc<RwLock<Jit<T>>>);
+
+impl<T: Target> SharedJit<T> {
+    pub fn new(target: T) -> Self { Self::from(Jit::new(target)) }
+
+    /// Returns shared access to the `Jit`. Panics if another thread panicked
+    /// while compiling.
+    pub fn read(&self) -> RwLockReadGuard<'_, Jit<T>> {
+        self.0.read().expect("Poisoned by an earlier panic")
+    }
+
+    /// Returns exclusive access to the `Jit`. This waits until no other
+    /// thread is running the compiled code. Panics if another thread
+    /// panicked while compiling.
+    pub fn write(&self) -> RwLockWriteGuard<'_, Jit<T>> {
+        self.0.write().expect("Poisoned by an earlier panic")
+    }
+}
+
+impl<T: Target> From<Jit<T>> for SharedJit<T> {
+    fn from(jit: Jit<T>) -> Self { Self(Arc::new(RwLock::new(jit))) }
+}
+
+impl<T: Target> Clone for SharedJit<T> {
+    fn clone(&self) -> Self { Self(Arc::clone(&self.0)) }
+}
+
+impl<T: Target> Run for SharedJit<T> {
+    fn new_entry(&mut self, marshal: &Marshal, exit_value: i64) -> EntryId {
+        self.write().new_entry(marshal, exit_value)
+    }
+
+    fn define(&mut self, entry: EntryId, ebb: &EBB<EntryId>) {
+        self.write().define(entry, ebb);
+    }
+
+    fn define_all(&mut self, definitions: &[(EntryId, &EBB<EntryId>)], num_threads: usize) {
+        self.write().define_all(definitions, num_threads);
+    }
+
+    unsafe fn run<G>(&mut self, entry: EntryId, global: &mut G) -> Word {
+        loop {
+            if let Some(result) = self.read().run_shared(entry, global) {
+                return result;
+            }
+            // Code has been compiled since it was last run.
+            self.write().make_executable();
+        }
+    }
+
+    fn profile(&self, entry: EntryId) -> Option<ProfileData> {
+        self.read().profile(entry)
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::factorial::{Factorial};
+    use super::super::target::{native};
+
+    /// Test that several threads can run the same compiled code at once,
+    /// each with its own state.
+    #[test]
+    pub fn factorial() {
+        let Factorial {jit, start, ..} = Factorial::new(native());
+        let jit = SharedJit::from(jit);
+        let threads: Vec<_> = (0..4).map(|i| {
+            let mut jit = jit.clone();
+            std::thread::spawn(move || {
+                (0..100).map(|j| {
+                    let mut registers = [(i + j) % 10, 0];
+                    let exit = unsafe { jit.run(start, &mut registers) };
+                    (unsafe { exit.s }, registers[1])
+                }).collect::<Vec<_>>()
+            })
+        }).collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            for (j, observed) in thread.join().unwrap().into_iter().enumerate() {
+                let expected = (1..=((i + j) % 10) as u64).product::<u64>();
+                assert_eq!(observed, (2, expected));
+            }
+        }
+    }
+}
@@ -1,10 +1,10 @@
 use std::collections::{HashMap};
 use std::fmt::{self, Debug, Formatter};
 
-use super::{NUM_REGISTERS, all_registers, Resources, Dataflow, Node, Exit, Frontier};
+use super::{all_registers, Resources, Dataflow, Node, Exit, Frontier, Trace};
 use super::cost::{BUDGET, SPILL_COST, SLOT_COST};
 use super::code::{Register, Variable};
-use crate::util::{ArrayMap, map_filter_max, Usage};
+use crate::util::{ArrayMap, AsUsize, map_filter_max, Usage};
 
 mod pool;
 use pool::{RegisterPool};
@@ -83,14 +83,15 @@ impl<'a> Allocator<'a> {
     /// - usage - The concatenation of the `input` lists of all [`Node`]s that
     ///   will be processed.
     pub fn new(
+        num_registers: usize,
         variables: &HashMap<Node, Variable>,
         dataflow: &'a Dataflow,
         usage: Usage<Node, Input>,
     ) -> Self {
         // Initialize the data structures with the live registers of `variables`.
-        let mut dirty = ArrayMap::new(NUM_REGISTERS);
+        let mut dirty = ArrayMap::new(num_registers);
         let mut allocation: HashMap<Node, Register> = HashMap::new();
-        let mut regs: ArrayMap<Register, Option<Node>> = ArrayMap::new(NUM_REGISTERS);
+        let mut regs: ArrayMap<Register, Option<Node>> = ArrayMap::new(num_registers);
         for (&node, &value) in variables.iter() {
             if usage.topmost(&node).is_some() {
                 // `node` is alive on entry.
@@ -122,7 +123,8 @@ impl<'a> Allocator<'a> {
         let (node, input) = self.usage.pop().expect("Incorrect usage information");
         if self.usage.topmost(&node).is_none() {
             if let Some(reg) = self.current_reg(node) {
-                self.pool.free(reg);
+                // If `node` was spilled, `reg` is already clean.
+                if !self.pool.is_clean(reg) { self.pool.free(reg); }
             }
         }
         (node, input)
@@ -135,7 +137,7 @@ impl<'a> Allocator<'a> {
 
     /// Select a `Register` to spill and free it.
     fn free_a_register(&mut self) -> Register {
-        let i = map_filter_max(all_registers(), |reg| {
+        let i = map_filter_max(all_registers(self.regs.len()), |reg| {
             self.regs[reg]
                 .filter(|_| !self.pool.is_clean(reg))
                 .map(|node| std::cmp::Reverse(
@@ -180,7 +182,9 @@ impl<'a> Allocator<'a> {
     /// - `num_inputs` - The number of items to pop from `self.usage`.
     ///   These are often just the inputs of `node`, but can also include e.g.
     ///   values needed by `node`'s cold paths.
-    pub fn add_node(&mut self, node: Node, num_inputs: usize) {
+    ///
+    /// Returns the [`Time`] at which `node` is placed.
+    pub fn add_node(&mut self, node: Node, num_inputs: usize) -> Time {
         let df: &'a Dataflow = self.dataflow;
         let mut time = EARLY; // Earliest time (in cycles) when we can place `node`.
         // Read inputs.
@@ -234,6 +238,7 @@ impl<'a> Allocator<'a> {
         if df.has_out(node) {
             self.access(node, time);
         }
+        time
     }
 
     /// Read the [`Node`]s that are live on exit, and the sequence `Node`.
@@ -241,11 +246,59 @@ impl<'a> Allocator<'a> {
         for _ in 0..num_outputs { let _ = self.pop_use(); }
         let _ = self.pop_use();
         assert_eq!(self.usage.len(), 0);
-        assert!(all_registers().all(|reg| self.pool.is_clean(reg)));
+        assert!(all_registers(self.regs.len()).all(|reg| self.pool.is_clean(reg)));
         (self.placer.iter().cloned().collect(), self.allocation)
     }
 }
 
+/// Removes the [`Spill`]s in `instructions` that turn out to be unnecessary.
+///
+/// A `Spill` is unnecessary if neither of its `Node`s is read after its
+/// [`Register`] is overwritten, e.g. because register pressure eased before
+/// the `Register` was reused. Such `Node`s can be read from their `Register`s
+/// instead of from the stack. Reads include value inputs of later `Node`s,
+/// the cold paths of later [`Guard`]s, and `exit`.
+///
+/// [`Guard`]: super::Op::Guard
+fn coalesce_spills<'a>(
+    instructions: &mut Vec<Instruction>,
+    dataflow: &Dataflow,
+    allocation: &HashMap<Node, Register>,
+    get_frontier: impl Fn(Node) -> Option<&'a Frontier>,
+    exit: &Exit,
+) {
+    // Returns `true` if `spilled` is read after its `Register` is overwritten.
+    let is_needed = |rest: &[Instruction], spilled: Node| {
+        let reg = allocation[&spilled];
+        let mut is_clobbered = false;
+        for &instruction in rest {
+            if let Node(node) = instruction {
+                let mut is_read = false;
+                dataflow.each_input(node, |in_, dep| {
+                    is_read |= in_ == spilled && dep.is_value();
+                });
+                if let Some(f) = get_frontier(node) {
+                    is_read |= f.0.get(&spilled).map_or(false, |v| v.is_value());
+                }
+                if is_read && is_clobbered { return true; }
+                is_clobbered |= dataflow.has_out(node) && allocation[&node] == reg;
+            }
+        }
+        is_clobbered && exit.outputs.contains(&spilled)
+    };
+    let mut i = 0;
+    while i < instructions.len() {
+        if let Spill(x, y) = instructions[i] {
+            let rest = &instructions[i + 1..];
+            if !is_needed(rest, x) && !is_needed(rest, y) {
+                instructions.remove(i);
+                continue;
+            }
+        }
+        i += 1;
+    }
+}
+
 /// Accumulates memory accesses and `Send`s that wait for them.
 #[derive(Debug, Default)]
 struct Address {
@@ -291,6 +344,7 @@ impl Queue {
 
 /// Choose the execution order and allocate [`Register`]s.
 ///
+/// - num_registers - the number of [`Register`]s available for allocation.
 /// - effects - [`Node`]s representing side-effects that have already occurred.
 /// - variables - the [`Variable`]s passed on entry to the hot path.
 /// - dataflow - the dataflow graph.
@@ -306,11 +360,13 @@ impl Queue {
 /// [`Guard`]: super::Op::Guard
 // FIXME: Place `Send(x, y)` and `Store(y)` after all `Load(y)`s.
 pub fn allocate<'a>(
+    num_registers: usize,
     variables: &HashMap<Node, Variable>,
     dataflow: &Dataflow,
     nodes: &[Node],
     get_frontier: impl Fn(Node) -> Option<&'a Frontier>,
     exit: &Exit,
+    trace: &dyn Trace,
 ) -> (
     Vec<Instruction>,
     HashMap<Node, Register>
@@ -333,7 +389,7 @@ pub fn allocate<'a>(
         if let Some(f) = get_frontier(node) {
             for (&in_, &v) in &f.0 {
                 if v.is_address() {
-                    println!("Cold path memory instruction {:?}", in_);
+                    trace.on_cold_address(in_.as_usize());
                     addresses.entry(in_).or_default().mems.push(node);
                 }
             }
@@ -394,9 +450,58 @@ pub fn allocate<'a>(
     assert_eq!(nodes_rev.len(), nodes.len());
 
     // Schedule and allocate registers for every `Node`.
-    let mut a = Allocator::new(variables, dataflow, usage);
+    let mut a = Allocator::new(num_registers, variables, dataflow, usage);
     while let Some((node, num_inputs)) = nodes_rev.pop() {
-        a.add_node(node, num_inputs);
+        let time = a.add_node(node, num_inputs);
+        trace.on_schedule(node.as_usize(), time.as_usize());
+    }
+    let (mut instructions, allocation) = a.finish(exit.outputs.len());
+    coalesce_spills(&mut instructions, dataflow, &allocation, get_frontier, exit);
+    (instructions, allocation)
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::{Op};
+    use super::super::code::{REGISTERS, Precision::*, BinaryOp::*};
+
+    /// Test that `coalesce_spills()` removes a `Spill` if and only if a
+    /// spilled value is not read after its `Register` is overwritten.
+    #[test]
+    fn coalesce() {
+        let mut df = Dataflow::new(2);
+        let x = df.inputs()[0];
+        let y = df.inputs()[1];
+        let sum = df.add_node(Op::Binary(P64, Add), &[x, y]);
+        let product = df.add_node(Op::Binary(P64, Mul), &[sum, x]);
+        let exit = Exit {sequence: df.undefined(), outputs: Box::new([product])};
+        let schedule = vec![Spill(x, y), Node(sum), Node(product)];
+        let coalesce = |allocation: &[(Node, Register)]| {
+            let allocation = allocation.iter().copied().collect();
+            let mut instructions = schedule.clone();
+            coalesce_spills(&mut instructions, &df, &allocation, |_| None, &exit);
+            instructions
+        };
+        // `sum` does not overwrite `x`: the `Spill` is unnecessary.
+        let observed = coalesce(&[
+            (x, REGISTERS[0]), (y, REGISTERS[1]),
+            (sum, REGISTERS[2]), (product, REGISTERS[2]),
+        ]);
+        assert_eq!(observed, vec![Node(sum), Node(product)]);
+        // `sum` overwrites `y`, which is not read again.
+        let observed = coalesce(&[
+            (x, REGISTERS[0]), (y, REGISTERS[1]),
+            (sum, REGISTERS[1]), (product, REGISTERS[1]),
+        ]);
+        assert_eq!(observed, vec![Node(sum), Node(product)]);
+        // `sum` overwrites `x`, which `product` reads.
+        let observed = coalesce(&[
+            (x, REGISTERS[0]), (y, REGISTERS[1]),
+            (sum, REGISTERS[0]), (product, REGISTERS[0]),
+        ]);
+        assert_eq!(observed, schedule);
     }
-    a.finish(exit.outputs.len())
 }
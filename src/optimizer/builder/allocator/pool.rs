@@ -1,5 +1,5 @@
 use crate::util::{ArrayMap};
-use super::{NUM_REGISTERS, all_registers};
+use super::{all_registers};
 use super::{Register};
 
 /// A pool of allocatable [`Register`]s.
@@ -20,8 +20,8 @@ impl RegisterPool {
     /// Initialise a `RegisterPool` with specified dirty bits.
     pub fn new(dirty: ArrayMap<Register, bool>) -> Self {
         // Enumerate the clean registers.
-        let mut clean = Vec::with_capacity(NUM_REGISTERS);
-        for reg in all_registers() {
+        let mut clean = Vec::with_capacity(dirty.len());
+        for reg in all_registers(dirty.len()) {
             if !dirty[reg] {
                 clean.push(reg);
             }
@@ -1,7 +1,7 @@
 use std::collections::{HashMap};
 
 use super::{
-    code, NUM_REGISTERS,
+    code,
     Dataflow, Node, Op, LookupLeaf, Cold, Exit,
     moves, all_registers,
 };
@@ -43,13 +43,14 @@ pub struct CodeGen<'a, L: LookupLeaf> {
 
 impl<'a, L: LookupLeaf> CodeGen<'a, L> {
     pub fn new(
+        num_registers: usize,
         dataflow: &'a Dataflow,
         lookup_leaf: &'a L,
         allocation: HashMap<Node, Register>,
         slots_used: usize,
         variables: HashMap<Node, Variable>,
     ) -> Self {
-        let mut registers = ArrayMap::new(NUM_REGISTERS);
+        let mut registers = ArrayMap::new(num_registers);
         for (&node, &v) in variables.iter() {
             if let Variable::Register(r) = v {
                 registers[r] = Some(node);
@@ -96,6 +97,10 @@ impl<'a, L: LookupLeaf> CodeGen<'a, L> {
     }
 
     /// Returns `node`'s [`Variable`].
+    ///
+    /// If `node` has been spilled but its [`Register`] has not yet been
+    /// overwritten, this returns the `Register`, which is cheaper to read than
+    /// the [`Slot`]. Cold paths use this to find their inputs.
     pub fn read(&self, node: Node) -> Variable {
         if let Some(&r) = self.allocation.get(&node) {
             if self.registers[r] == Some(node) {
@@ -115,7 +120,8 @@ impl<'a, L: LookupLeaf> CodeGen<'a, L> {
     pub fn add_spill(&mut self, node1: Node, node2: Node) {
         let r1 = self.spill(node1);
         let r2 = self.spill(node2);
-        self.actions.push(Action::Push(Some(r1.into()), Some(r2.into())));
+        // `node1` is in the lower-numbered `Slot`, which is `Push`'s `src2`.
+        self.actions.push(Action::Push(Some(r2.into()), Some(r1.into())));
     }
 
     /// Generate an [`Action`] to execute `n`.
@@ -162,40 +168,35 @@ impl<'a, L: LookupLeaf> CodeGen<'a, L> {
         // We need a temporary `Register`: the least used in `dest_to_src`.
         // `uses[r] & 1` indicates that `r` is used as a destination.
         // `uses[r] >> 1` counts uses of `r` as a source.
-        let mut uses: ArrayMap<Register, usize> = ArrayMap::new(NUM_REGISTERS);
+        let mut uses: ArrayMap<Register, usize> = ArrayMap::new(self.registers.len());
         for (&dest, &src) in &dest_to_src {
             if let Variable::Register(r) = dest { uses[r] |= 1; }
             if let Variable::Register(r) = src { uses[r] += 2; }
         }
-        let temp = all_registers().min_by_key(|&r| uses[r]).unwrap();
-        let mut temp_replacement = Variable::from(Slot(self.slots_used));
+        let temp = all_registers(self.registers.len()).min_by_key(|&r| uses[r]).unwrap();
 
-        // If `temp` is used, spill it and replace all mentions of it.
-        if uses[temp] == 1 {
-            // `temp` is a destination only.
-            self.actions.push(Action::Push(None, None));
-            self.slots_used += 2;
-        } else if uses[temp] >= 2 {
-            // `temp` is used as a source.
-            self.actions.push(Action::Push(None, Some(temp.into())));
+        // If `temp` is used, spill the value it holds and the value destined
+        // for it, so that it is free to break cycles. Read the former from its
+        // `Slot` instead of from `temp`.
+        if uses[temp] != 0 {
+            let src1 = dest_to_src.remove(&temp.into());
+            let src2 = if uses[temp] >= 2 { Some(temp.into()) } else { None };
+            self.actions.push(Action::Push(src1, src2));
+            for src in dest_to_src.values_mut() {
+                if *src == temp.into() { *src = Slot(self.slots_used).into(); }
+            }
             self.slots_used += 2;
-        } else {
-            temp_replacement = Variable::from(temp);
         }
 
         // Move all live values into the expected `Variable`s.
         // TODO: Find a way to schedule these `Move`s properly or to eliminate them.
         self.actions.extend(moves(dest_to_src, &temp.into()).map(
-            |(mut dest, mut src)| {
-                if src == temp.into() { src = temp_replacement; }
-                if dest == temp.into() { dest = temp_replacement; }
-                Action::Move(dest, src)
-            }
+            |(dest, src)| Action::Move(dest, src)
         ));
 
         if uses[temp] & 1 != 0 {
             // `temp` is a destination.
-            self.actions.push(Action::Move(temp.into(), temp_replacement));
+            self.actions.push(Action::Move(temp.into(), Slot(self.slots_used - 1).into()));
         }
 
         // Drop now-unused slots.
@@ -213,3 +214,103 @@ impl<'a, L: LookupLeaf> CodeGen<'a, L> {
         ebb
     }
 }
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use code::{REGISTERS, Precision, BinaryOp, Convention};
+    use crate::target::{Native, Target};
+
+    const NUM_REGISTERS: usize = <Native as Target>::NUM_REGISTERS;
+
+    /// A spilled value should be read from its `Register` until it is
+    /// overwritten, and only then from its `Slot`.
+    #[test]
+    fn read_prefers_register() {
+        let convention = Convention {slots_used: 0, lives: Box::new([REGISTERS[0].into()])};
+        let mut df = Dataflow::new(1);
+        let in_ = df.inputs()[0];
+        let x = df.add_node(Op::Binary(Precision::P64, BinaryOp::Add), &[in_, in_]);
+        let y = df.add_node(Op::Binary(Precision::P64, BinaryOp::Mul), &[in_, in_]);
+        let z = df.add_node(Op::Binary(Precision::P64, BinaryOp::Sub), &[in_, in_]);
+        let allocation: HashMap<Node, Register> = [
+            (in_, REGISTERS[0]),
+            (x, REGISTERS[1]),
+            (y, REGISTERS[2]),
+            (z, REGISTERS[1]),
+        ].into_iter().collect();
+        let variables: HashMap<Node, Variable> = [(in_, REGISTERS[0].into())].into_iter().collect();
+        let mut cg = CodeGen::new(NUM_REGISTERS, &df, &convention, allocation, 0, variables);
+        cg.add_node(x);
+        cg.add_node(y);
+        cg.add_spill(x, y);
+        assert_eq!(cg.slots_used(), 2);
+        assert_eq!(cg.read(x), REGISTERS[1].into());
+        assert_eq!(cg.read(y), REGISTERS[2].into());
+        cg.add_node(z);
+        assert_eq!(cg.read(x), Slot(0).into());
+        assert_eq!(cg.read(y), REGISTERS[2].into());
+    }
+
+    /// Run `finish()` with every `Register` live, such that output `i` is
+    /// input `f(i)`, emulate the code, and check the result.
+    fn check_finish(f: impl Fn(usize) -> usize) {
+        let lives: Box<[Variable]> = all_registers(NUM_REGISTERS).map(Variable::from).collect();
+        let convention = Convention {slots_used: 0, lives};
+        let df = Dataflow::new(NUM_REGISTERS);
+        let inputs = df.inputs();
+        let allocation: HashMap<Node, Register> = inputs.iter().copied().zip(all_registers(NUM_REGISTERS)).collect();
+        let variables = allocation.iter().map(|(&node, &r)| (node, r.into())).collect();
+        let cg = CodeGen::new(NUM_REGISTERS, &df, &convention, allocation, 0, variables);
+        let outputs = (0..NUM_REGISTERS).map(|i| inputs[f(i)]).collect();
+        let ebb = cg.finish(&Exit {sequence: df.undefined(), outputs}, 0);
+        // Emulate the code.
+        let mut registers: ArrayMap<Register, usize> = ArrayMap::new(NUM_REGISTERS);
+        for (i, r) in all_registers(NUM_REGISTERS).enumerate() { registers[r] = i; }
+        let mut slots: Vec<usize> = Vec::new();
+        let get = |registers: &ArrayMap<Register, usize>, slots: &[usize], v: Variable| match v {
+            Variable::Register(r) => registers[r],
+            Variable::Slot(s) => slots[s.0],
+        };
+        for action in &*ebb.actions {
+            match *action {
+                Action::Move(dest, src) => {
+                    let x = get(&registers, &slots, src);
+                    match dest {
+                        Variable::Register(r) => { registers[r] = x; },
+                        Variable::Slot(s) => { slots[s.0] = x; },
+                    }
+                },
+                Action::Push(src1, src2) => {
+                    let x = src1.map_or(usize::MAX, |v| get(&registers, &slots, v));
+                    let y = src2.map_or(usize::MAX, |v| get(&registers, &slots, v));
+                    slots.push(y);
+                    slots.push(x);
+                },
+                Action::Drop(n) => { slots.truncate(slots.len() - 2 * n); },
+                _ => panic!("Unexpected {:?}", action),
+            }
+        }
+        assert_eq!(slots, []);
+        let expected: Vec<usize> = (0..NUM_REGISTERS).map(f).collect();
+        let observed: Vec<usize> = all_registers(NUM_REGISTERS).map(|r| registers[r]).collect();
+        assert_eq!(observed, expected);
+    }
+
+    /// If every `Register` is a source, `finish()` must spill one of them.
+    #[test]
+    fn finish_all_registers() {
+        // Rotate the values.
+        check_finish(|i| (i + 1) % NUM_REGISTERS);
+    }
+
+    /// If the temporary `Register` is a destination, breaking a cycle must
+    /// not overwrite the value destined for it.
+    #[test]
+    fn finish_temp_is_destination() {
+        // The last `Register` is only a destination. Swap two other values.
+        check_finish(|i| match i {0 => 1, 1 => 0, i if i == NUM_REGISTERS - 1 => 3, _ => i});
+    }
+}
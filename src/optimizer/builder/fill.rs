@@ -1,3 +1,4 @@
+use std::collections::{BTreeMap};
 use std::collections::hash_map::{HashMap, Entry};
 
 use super::{dep, Dataflow, Node, Exit};
@@ -6,8 +7,10 @@ use super::{dep, Dataflow, Node, Exit};
 ///
 /// A mapping from `node` to `dep` indicates that one or more `Node`s in the
 /// `Fill` depend on a boundary `Node` `node` in the manner described by `dep`.
+///
+/// The map is ordered so that iterating over it is deterministic.
 #[derive(Debug, Clone, Default)]
-pub struct Frontier(pub HashMap<Node, dep::Value>);
+pub struct Frontier(pub BTreeMap<Node, dep::Value>);
 
 /// The state of a flood fill through a [`Dataflow`] graph.
 pub struct Fill<'a> {
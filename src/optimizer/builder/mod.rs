@@ -1,7 +1,8 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, BTreeSet};
 use std::fmt::{Debug};
 
-use super::{code, target, dep, cost, Dataflow, Node, Op, Resources, LookupLeaf, Cold, Exit, CFT};
+use crate::util::{AsUsize};
+use super::{code, dep, cost, Dataflow, Node, Op, Resources, LookupLeaf, Cold, Exit, CFT, Stats, Trace};
 use code::{Register, Variable, Convention, EBB};
 
 mod fill;
@@ -18,10 +19,9 @@ use codegen::{CodeGen};
 
 //-----------------------------------------------------------------------------
 
-const NUM_REGISTERS: usize = target::x86_64::ALLOCATABLE_REGISTERS.len();
-
-fn all_registers() -> impl Iterator<Item=Register> {
-    (0..NUM_REGISTERS).map(|i| Register::new(i as u8).unwrap())
+/// Returns the first `num_registers` [`Register`]s.
+fn all_registers(num_registers: usize) -> impl Iterator<Item=Register> {
+    (0..num_registers).map(|i| Register::new(i as u8).unwrap())
 }
 
 //-----------------------------------------------------------------------------
@@ -47,12 +47,23 @@ impl<'a, L: Debug + Clone> Debug for GuardFailure<'a, L> {
 //-----------------------------------------------------------------------------
 
 struct Builder<'a, L: LookupLeaf> {
+    num_registers: usize,
+    trace: &'a dyn Trace,
     lookup_leaf: &'a L,
+    /// The [`Node`]s scheduled so far, on any path.
+    scheduled: HashSet<Node>,
+    /// The [`Register`]s allocated so far, on any path.
+    registers: HashSet<Register>,
+    /// Counts `Spill`s and [`EBB`]s.
+    stats: Stats,
 }
 
 impl<'a, L: LookupLeaf> Builder<'a, L> {
-    fn new(lookup_leaf: &'a L) -> Self {
-        Builder {lookup_leaf}
+    fn new(num_registers: usize, trace: &'a dyn Trace, lookup_leaf: &'a L) -> Self {
+        let scheduled = HashSet::new();
+        let registers = HashSet::new();
+        let stats = Stats::default();
+        Builder {num_registers, trace, lookup_leaf, scheduled, registers, stats}
     }
 
     /// Converts a [`CFT`] into an [`EBB`]. Optimises the hot path in
@@ -64,7 +75,9 @@ impl<'a, L: LookupLeaf> Builder<'a, L> {
     ///   entry and exit all [`Node`]s must be unmarked.
     /// - `cft` - the code to optimise.
     /// - `slots_used` - the number of [`Slot`]s on entry to the code.
-    /// - `lookup_input` - Returns a [`Variable`] that is live on entry.
+    /// - `lookup_input` - Returns a [`Variable`] that is live on entry. For
+    ///   a cold path this is [`CodeGen::read()`] at the guard, which returns
+    ///   a [`Register`] in preference to a [`Slot`].
     /// - `lookup_guard` - returns the `GuardFailure` for an [`Op::Guard`]
     ///
     /// [`Slot`]: code::Slot
@@ -76,8 +89,10 @@ impl<'a, L: LookupLeaf> Builder<'a, L> {
         lookup_input: &'w dyn Fn(Node) -> Variable,
         lookup_guard: &'w dyn Fn(Node) -> &'w GuardFailure<'a, L::Leaf>,
     ) -> EBB<L::Leaf> {
+        self.trace.on_walk_enter(slots_used);
         let df = fill.dataflow();
         let is_guard = |node| matches!(df.op(node), Op::Guard);
+        let is_constant = |node| matches!(df.op(node), Op::Constant(_));
 
         // Find nodes on the hot path.
         let (colds, exit, leaf) = cft.hot_path();
@@ -87,7 +102,11 @@ impl<'a, L: LookupLeaf> Builder<'a, L> {
         let guard_failures = colds.into_iter().map(|(guard, cold)| {
             let mut fill2 = fill.nested();
             cold.map(|&child| child.exits().for_each(|e| fill2.exit(e)));
-            (guard, GuardFailure {cold, fontier: fill2.drain().1})
+            let mut fontier = fill2.drain().1;
+            // The cold paths will recompute constants, so there is no need
+            // to keep them alive.
+            fontier.0.retain(|&node, _| !is_constant(node));
+            (guard, GuardFailure {cold, fontier})
         }).collect::<HashMap<Node, GuardFailure<_>>>();
         let lookup_guard = |guard| guard_failures.get(&guard)
             .unwrap_or_else(|| lookup_guard(guard));
@@ -97,23 +116,40 @@ impl<'a, L: LookupLeaf> Builder<'a, L> {
         for node in guards { fill.resume(&lookup_guard(node).fontier); }
 
         // Build an instruction schedule and allocate registers.
-        let (nodes, frontier) = fill.drain();
+        // Recompute any constants that were not kept alive for us.
+        let (mut nodes, frontier) = fill.drain();
+        let constants = frontier.0.keys().copied()
+            .filter(|&node| is_constant(node))
+            .collect::<BTreeSet<Node>>();
+        nodes.extend(&constants);
         let variables = frontier.0.iter()
-            .filter(|(_, dep)| dep.is_value())
+            .filter(|(node, dep)| dep.is_value() && !constants.contains(node))
             .map(|(&node, _)| (node, lookup_input(node)))
             .collect::<HashMap<Node, Variable>>();
         let distinct_variables: HashSet<Variable> = variables.values().copied().collect();
         assert_eq!(variables.len(), distinct_variables.len());
         let (instructions, allocation) = allocate(
+            self.num_registers,
             &variables,
             df,
             &nodes,
             |node| if is_guard(node) { Some(&lookup_guard(node).fontier) } else { None },
             &exit,
+            self.trace,
         );
+        let mut allocated: Vec<(Node, Register)> = allocation.iter()
+            .map(|(&node, &register)| (node, register))
+            .collect();
+        allocated.sort();
+        for (node, register) in allocated {
+            self.trace.on_allocation(node.as_usize(), register);
+        }
+        self.registers.extend(allocation.values().copied());
+        self.stats.num_ebbs += 1;
 
         // Build the EBB.
         let mut cg = CodeGen::new(
+            self.num_registers,
             df,
             self.lookup_leaf,
             allocation,
@@ -123,10 +159,15 @@ impl<'a, L: LookupLeaf> Builder<'a, L> {
         for &instruction in &instructions {
             match instruction {
                 Instruction::Spill(x, y) => {
+                    self.trace.on_instruction(None);
                     cg.add_spill(x, y);
+                    self.stats.spills += 1;
                 },
                 Instruction::Node(node) => {
-                    fill.mark(node);
+                    self.trace.on_instruction(Some(node.as_usize()));
+                    self.scheduled.insert(node);
+                    // Recomputed constants are already marked.
+                    if !constants.contains(&node) { fill.mark(node); }
                     if is_guard(node) {
                         // Recurse on cold paths.
                         let mut fill2 = fill.nested();
@@ -149,18 +190,23 @@ impl<'a, L: LookupLeaf> Builder<'a, L> {
     }
 }
 
-/// Convert `cft` into an [`EBB`].
+/// Convert `cft` into an [`EBB`]. Also returns [`Stats`] about it.
 ///
+/// - `num_registers` - the number of [`Register`]s available for allocation.
+/// - `trace` - notified of the progress of the algorithm.
 /// - `before` - the [`Convention`] on entry to `cft`.
 /// - `dataflow` - the [`Dataflow`] dependencies of `cft`.
 /// - `cft` - the control-flow tree to convert.
 /// - `lookup_leaf` - looks up properties of the leaves of `cft`.
 pub fn build<L: LookupLeaf>(
+    num_registers: usize,
+    trace: &dyn Trace,
     before: &Convention,
     dataflow: &Dataflow,
     cft: &CFT<L::Leaf>,
     lookup_leaf: &L,
-) -> EBB<L::Leaf> {
+) -> (EBB<L::Leaf>, Stats) {
+    debug_assert_eq!(dataflow.verify(), Ok(()));
     // Work out what is where.
     let input_map: HashMap<Node, Variable> =
         dataflow.inputs().iter()
@@ -168,14 +214,22 @@ pub fn build<L: LookupLeaf>(
         .map(|(&node, &variable)| (node, variable))
         .collect();
     // Build the new `EBB`.
-    let mut builder = Builder::new(lookup_leaf);
-    with_fill(dataflow, |mut fill| builder.walk(
+    let mut builder = Builder::new(num_registers, trace, lookup_leaf);
+    let ebb = with_fill(dataflow, |mut fill| builder.walk(
         &mut fill,
         cft,
         before.slots_used,
         &|node| *input_map.get(&node).unwrap(),
         &|guard| panic!("Unknown guard {:?}", guard),
-    ))
+    ));
+    // Summarise what happened.
+    let nodes_created = dataflow.all_nodes()
+        .filter(|&node| !matches!(dataflow.op(node), Op::Input))
+        .count();
+    let nodes_eliminated = nodes_created - builder.scheduled.len();
+    let registers_used = builder.registers.len();
+    let stats = Stats {nodes_created, nodes_eliminated, registers_used, ..builder.stats};
+    (ebb, stats)
 }
 
 //-----------------------------------------------------------------------------
@@ -183,11 +237,16 @@ pub fn build<L: LookupLeaf>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use code::{Register, REGISTERS, Slot, Precision, BinaryOp, Width, builder};
+    use code::{Register, REGISTERS, Slot, Precision, BinaryOp, Width, Action, Ending, Switch, builder};
     use BinaryOp::*;
     use Precision::*;
     use Width::*;
-    use crate::util::{ArrayMap, AsUsize};
+    use crate::util::{ArrayMap};
+    use crate::target::{Native, Target};
+
+    const NUM_REGISTERS: usize = <Native as Target>::NUM_REGISTERS;
+
+    fn options() -> super::super::Options { super::super::Options::new(NUM_REGISTERS) }
 
     const R0: Register = REGISTERS[0];
     const R1: Register = REGISTERS[1];
@@ -238,7 +297,7 @@ mod tests {
         cft = CFT::switch(g_2, [cft], CFT::Merge {exit: e_2, leaf: R2}, 0);
         cft = CFT::switch(g_1, [cft], CFT::Merge {exit: e_1, leaf: R1}, 0);
         // Call `build()`.
-        let _observed = build(&before, &df, &cft, &afters);
+        let _observed = build(NUM_REGISTERS, &super::super::NoTrace, &before, &df, &cft, &afters);
         // TODO: Expected output.
     }
 
@@ -264,9 +323,9 @@ mod tests {
             )
         });
         // Optimize it.
-        // inline let _observed = super::super::optimize(&convention, &ebb, &convention);
-        let (dataflow, cft) = super::super::simulate(&convention, &ebb, &convention);
-        let _observed = build(&convention, &dataflow, &cft, &convention);
+        // inline let _observed = super::super::optimize(&options(), &convention, &ebb, &convention);
+        let (dataflow, cft) = super::super::simulate(&options(), &convention, &ebb, &convention);
+        let _observed = build(NUM_REGISTERS, &super::super::NoTrace, &convention, &dataflow, &cft, &convention);
         // TODO: Expected output.
     }
 
@@ -286,9 +345,9 @@ mod tests {
             b.jump(1)
         });
         // Optimize it.
-        // inline let _observed = super::super::optimize(&convention, &ebb, &convention);
-        let (dataflow, cft) = super::super::simulate(&convention, &ebb, &convention);
-        let _observed = build(&convention, &dataflow, &cft, &convention);
+        // inline let _observed = super::super::optimize(&options(), &convention, &ebb, &convention);
+        let (dataflow, cft) = super::super::simulate(&options(), &convention, &ebb, &convention);
+        let _observed = build(NUM_REGISTERS, &super::super::NoTrace, &convention, &dataflow, &cft, &convention);
         // TODO: Expected output.
     }
 
@@ -317,10 +376,78 @@ mod tests {
         });
         // Optimize it.
         println!("input = {:#?}", input);
-        // inline let _observed = super::super::optimize(&convention, &ebb, &convention);
-        let (dataflow, cft) = super::super::simulate(&convention, &input, &convention);
-        let output = build(&convention, &dataflow, &cft, &convention);
+        // inline let _observed = super::super::optimize(&options(), &convention, &ebb, &convention);
+        let (dataflow, cft) = super::super::simulate(&options(), &convention, &input, &convention);
+        let (output, _) = build(NUM_REGISTERS, &super::super::NoTrace, &convention, &dataflow, &cft, &convention);
         // TODO: Expected output.
         println!("output = {:#?}", output);
     }
+
+    /// Test that a cold path recomputes constants instead of requiring the
+    /// hot path to keep them alive.
+    #[test]
+    fn recompute_constants() {
+        let convention = Convention {slots_used: 0, lives: REGISTERS.iter().map(|&r| r.into()).collect()};
+        // Make an `EBB`. The hot path stores some constants and overwrites
+        // them with a load. The cold path adds them up.
+        let input = builder::build(|mut b| {
+            for (i, &r) in (1..).zip(&REGISTERS[1..]) {
+                b.const_(r, 1000 + i);
+                b.store(r, (R0, 8 * i as i32, Eight));
+            }
+            b.guard(R0, true, builder::build(|mut b| {
+                for &r in &REGISTERS[1..] { b.binary64(Add, R0, R0, r); }
+                b.jump(0)
+            }));
+            b.load(R1, (R0, 0, Eight));
+            for &r in &REGISTERS[2..] { b.move_(r, R1); }
+            b.jump(1)
+        });
+        let (dataflow, cft) = super::super::simulate(&options(), &convention, &input, &convention);
+        let (output, _) = build(NUM_REGISTERS, &super::super::NoTrace, &convention, &dataflow, &cft, &convention);
+        // Find the cold path.
+        let cold = match output.ending {
+            Ending::Switch(_, Switch {ref cases, ..}) => &cases[0],
+            _ => panic!("Expected a Switch"),
+        };
+        let num_constants = cold.actions.iter()
+            .filter(|a| matches!(a, Action::Constant(..)))
+            .count();
+        assert_eq!(num_constants, REGISTERS.len() - 1);
+    }
+
+    /// Test that a `Trace` sees when each `Node` is scheduled.
+    #[test]
+    fn schedule_times() {
+        use std::sync::{Mutex};
+        #[derive(Default)]
+        struct RecordSchedule(Mutex<HashMap<usize, usize>>);
+        impl Trace for RecordSchedule {
+            fn on_schedule(&self, node: usize, cycle: usize) {
+                self.0.lock().unwrap().insert(node, cycle);
+            }
+        }
+        let convention = Convention {lives: Box::new([R0.into(), R1.into()]), slots_used: 0};
+        // A chain of three `Mul`s, and two independent `Add`s.
+        let mut df = Dataflow::new(2);
+        let (x, y) = (df.inputs()[0], df.inputs()[1]);
+        let m_1 = df.add_node(Op::Binary(P64, Mul), &[x, x]);
+        let m_2 = df.add_node(Op::Binary(P64, Mul), &[m_1, m_1]);
+        let m_3 = df.add_node(Op::Binary(P64, Mul), &[m_2, m_2]);
+        let a_1 = df.add_node(Op::Binary(P64, Add), &[y, y]);
+        let a_2 = df.add_node(Op::Binary(P64, Add), &[x, y]);
+        let a_3 = df.add_node(Op::Binary(P64, Add), &[a_1, a_2]);
+        let a_4 = df.add_node(Op::Binary(P64, Add), &[m_3, a_3]);
+        let exit = Exit {sequence: df.undefined(), outputs: Box::new([a_4, y])};
+        let cft = CFT::Merge {exit, leaf: 0};
+        let trace = RecordSchedule::default();
+        let _ = build(NUM_REGISTERS, &trace, &convention, &df, &cft, &convention);
+        let cycles = trace.0.into_inner().unwrap();
+        let cycle = |node: Node| cycles[&node.as_usize()];
+        assert!(cycle(m_1) < cycle(m_2));
+        assert!(cycle(m_2) < cycle(m_3));
+        assert!(cycle(m_3) < cycle(a_4));
+        assert_eq!(cycle(a_1), cycle(a_2));
+        assert!(cycle(a_2) < cycle(a_3));
+    }
 }
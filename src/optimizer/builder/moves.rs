@@ -8,15 +8,17 @@ use std::hash::{Hash};
 ///  - dest_to_src - for each destination V, the corresponding source V.
 ///  - temp - a temporary location used to break cycles.
 #[allow(clippy::implicit_hasher)]
-pub fn moves<V: Debug + Clone + Hash + Eq>(
+pub fn moves<V: Debug + Clone + Hash + Ord>(
     mut dest_to_src: HashMap<V, V>,
     temp: &V,
 ) -> impl Iterator<Item=(V, V)> {
     // Make a work list that won't change as we remove elements from the map.
-    let dests: Vec<V> = dest_to_src.iter().map(|(dest, src)| {
+    // Sort it so that the result does not depend on the hasher.
+    let mut dests: Vec<V> = dest_to_src.iter().map(|(dest, src)| {
         assert_ne!(src, temp);
         dest.clone()
     }).collect();
+    dests.sort();
     // Loop through the work list.
     let mut moves: Vec<(V, V)> = Vec::new(); // In reverse order.
     let mut chain: Vec<V> = Vec::new(); // In forwards order.
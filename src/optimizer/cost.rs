@@ -75,24 +75,42 @@ pub const CONDITIONAL_COST: Cost = Cost {
     resources: Resources::new(0x0200013),
 };
 
+/// The cost of a bit-counting operation such as `Clz`.
+pub const COUNT_COST: Cost = Cost {
+    latency: 3,
+    resources: Resources::new(0x1100001),
+};
+
 /// The cost of a `Mul` operation.
 pub const MUL_COST: Cost = Cost {
     latency: 3,
     resources: Resources::new(0x1100001),
 };
 
-/// The cost of a `UDiv` or `SDiv` operation.
+/// The cost of a `UDiv`, `SDiv`, `URem` or `SRem` operation.
 pub const DIV_COST: Cost = Cost {
     latency: 30,
     resources: Resources::new(0x1012306),
 };
 
+/// The cost of a `Select` operation.
+pub const SELECT_COST: Cost = Cost {
+    latency: 1,
+    resources: Resources::new(0x0200013),
+};
+
 /// The cost of a typical shift operation such as `Lsl`.
 pub const SHIFT_COST: Cost = Cost {
     latency: 1,
     resources: Resources::new(0x0100002),
 };
 
+/// The cost of an `Extract` operation, which is typically a shift and a mask.
+pub const EXTRACT_COST: Cost = Cost {
+    latency: 2,
+    resources: Resources::new(0x0200002),
+};
+
 /// The cost of a `Load` operation.
 pub const LOAD_COST: Cost = Cost {
     latency: 3,
@@ -111,6 +129,25 @@ pub const SEND_COST: Cost = Cost {
     resources: Resources::new(0x0000000),
 };
 
+/// The cost of a `Call` operation, excluding the callee. It saves and
+/// restores all the caller-saved registers.
+pub const CALL_COST: Cost = Cost {
+    latency: 20,
+    resources: Resources::new(0x1412316),
+};
+
+/// The cost of a `Fence` operation.
+pub const FENCE_COST: Cost = Cost {
+    latency: 0xFF,
+    resources: Resources::new(0x0011003),
+};
+
+/// The cost of an `AtomicCas` operation. It is also a full memory barrier.
+pub const CAS_COST: Cost = Cost {
+    latency: 20,
+    resources: Resources::new(0x0111103),
+};
+
 /// A cost used for Debug operations. This won't affect other instructions.
 pub const DEBUG_COST: Cost = Cost {
     latency: 0xFF,
@@ -130,18 +167,24 @@ pub fn op_cost(op: Op) -> &'static Cost {
         Constant(_) => &CONST_COST, // TODO: Make the cost depend on `n`.
         Unary(_, op) => match op {
             Abs => &ABS_COST,
-            Negate | Not => &UNARY_COST,
+            Negate | Not | Uxt(_) | Sxt(_) => &UNARY_COST,
+            Clz | Ctz | Popcount => &COUNT_COST,
+            Extract(_, _) => &EXTRACT_COST,
         },
         Binary(_, op) => match op {
             Add | Sub | And| Or| Xor => &BINARY_COST,
-            Mul => &MUL_COST,
-            UDiv | SDiv => &DIV_COST,
-            Lsl | Lsr | Asr => &SHIFT_COST,
-            Lt | Ult | Eq | Max | Min => &CONDITIONAL_COST,
+            Mul | MulHigh | UMulHigh => &MUL_COST,
+            UDiv | SDiv | URem | SRem => &DIV_COST,
+            Lsl | Lsr | Asr | RotL | RotR => &SHIFT_COST,
+            Lt | Ult | Eq | Max | Min | UMax | UMin => &CONDITIONAL_COST,
         },
+        Select(_) => &SELECT_COST,
         Load(_, _) => &LOAD_COST,
         Store(_, _) => &STORE_COST,
         Send => &SEND_COST,
         Debug => &DEBUG_COST,
+        Call(_) => &CALL_COST,
+        Fence(_, _) => &FENCE_COST,
+        AtomicCas(_, _, _) => &CAS_COST,
     }
 }
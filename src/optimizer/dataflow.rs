@@ -6,7 +6,7 @@ use super::{Dep, Op, Cost, op_cost};
 //-----------------------------------------------------------------------------
 
 /// A node in a Dataflow graph. Also represents the value it computes.
-#[derive(Copy, Clone, Hash, PartialEq, Eq)]
+#[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Node(usize);
 
 impl Debug for Node {
@@ -39,6 +39,29 @@ impl<'a> Debug for NodeAdapter<'a> {
 
 //-----------------------------------------------------------------------------
 
+/// The ways in which a [`Dataflow`] can be inconsistent. See
+/// [`Dataflow::verify()`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DataflowError {
+    /// An input of the first [`Node`] is the second `Node`, which does not
+    /// exist.
+    NoSuchNode(Node, Node),
+    /// An input of the first [`Node`] is the second `Node`, which was not
+    /// added before it.
+    NotTopological(Node, Node),
+    /// A value input of the first [`Node`] is the second `Node`, which does
+    /// not compute a result.
+    NoResult(Node, Node),
+    /// The [`Node`] has the first number of inputs, but its [`Op`] expects
+    /// the second number.
+    WrongArity(Node, usize, usize),
+    /// The [`Node`] is the undefined value or is live on entry, but it does
+    /// not exist or is not an [`Op::Input`].
+    BadInput(Node),
+}
+
+//-----------------------------------------------------------------------------
+
 /// The information remembered about a [`Node`].
 #[derive(Clone)]
 struct Info {
@@ -151,10 +174,79 @@ impl Dataflow {
         node
     }
 
+    /// Checks that every [`Node`] has the inputs that its [`Op`] expects, that
+    /// they are `Node`s added before it, and that every value input computes
+    /// a result. Also checks that [`Self::undefined()`] and
+    /// [`Self::inputs()`] are [`Op::Input`]s. Returns all the problems found.
+    pub fn verify(&self) -> Result<(), Vec<DataflowError>> {
+        let mut errors = Vec::new();
+        for &input in &*self.inputs {
+            if input.0 >= self.nodes.len() || self.op(input) != Op::Input {
+                errors.push(DataflowError::BadInput(input));
+            }
+        }
+        for node in self.all_nodes() {
+            let info = self.info(node);
+            let end_in = self.nodes.get(node.0 + 1).map_or(self.ins.len(), |next| next.start_in);
+            let ins = self.ins.get(info.start_in..end_in).unwrap_or(&[]);
+            if ins.len() != info.op.expected_arity() {
+                errors.push(DataflowError::WrongArity(node, ins.len(), info.op.expected_arity()));
+            }
+            for (&in_, &dep) in ins.iter().zip(info.op.deps()) {
+                if in_.0 >= self.nodes.len() {
+                    errors.push(DataflowError::NoSuchNode(node, in_));
+                } else if in_ >= node {
+                    errors.push(DataflowError::NotTopological(node, in_));
+                } else if dep.is_value() && !self.has_out(in_) {
+                    errors.push(DataflowError::NoResult(node, in_));
+                }
+            }
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
     /// Returns all [`Node`]s in the order they were added.
     pub fn all_nodes(&self) -> impl Iterator<Item=Node> {
         (0..self.nodes.len()).map(|i| Node(i))
     }
+
+    /// Writes `self` to `w` in the DOT language of [Graphviz]. Each [`Node`]
+    /// is labelled with its index and [`Op`]. Value inputs are solid arrows
+    /// labelled with the operand index. Other dependencies are dashed
+    /// arrows.
+    ///
+    /// [Graphviz]: https://graphviz.org/
+    #[cfg(feature = "graphviz")]
+    #[allow(dead_code)]
+    pub fn graphviz(&self, w: &mut impl std::io::Write) -> std::io::Result<()> {
+        writeln!(w, "digraph dataflow {{")?;
+        for node in self.all_nodes() {
+            let label = format!("{}: {:?}", node.0, self.op(node)).replace('"', "\\\"");
+            writeln!(w, "    n{} [label=\"{}\"];", node.0, label)?;
+        }
+        for node in self.all_nodes() {
+            let deps = self.info(node).deps;
+            for (index, (&dep, &in_)) in deps.iter().zip(self.ins(node)).enumerate() {
+                if dep.is_value() {
+                    writeln!(w, "    n{} -> n{} [label=\"{}\"];", in_.0, node.0, index)?;
+                } else {
+                    writeln!(w, "    n{} -> n{} [style=dashed];", in_.0, node.0)?;
+                }
+            }
+        }
+        writeln!(w, "}}")
+    }
+
+    /// Writes `self` to `w` in the DOT language of [Graphviz].
+    ///
+    /// Panics, because this was compiled without the "graphviz" feature.
+    ///
+    /// [Graphviz]: https://graphviz.org/
+    #[cfg(not(feature = "graphviz"))]
+    #[allow(dead_code)]
+    pub fn graphviz(&self, _w: &mut impl std::io::Write) -> std::io::Result<()> {
+        panic!("compile with feature graphviz");
+    }
 }
 
 impl Debug for Dataflow {
@@ -165,3 +257,71 @@ impl Debug for Dataflow {
         )).finish()
     }
 }
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::code::{Precision, UnaryOp, BinaryOp};
+    use Precision::*;
+    use BinaryOp::*;
+
+    /// Returns a `Dataflow` with two inputs `a` and `b` that computes
+    /// `(a + b) * a`.
+    fn small_graph() -> Dataflow {
+        let mut dataflow = Dataflow::new(2);
+        let (a, b) = (dataflow.inputs()[0], dataflow.inputs()[1]);
+        let sum = dataflow.add_node(Op::Binary(P64, Add), &[a, b]);
+        let _ = dataflow.add_node(Op::Binary(P64, Mul), &[sum, a]);
+        dataflow
+    }
+
+    #[test]
+    fn verify() {
+        assert_eq!(small_graph().verify(), Ok(()));
+        // An input that does not exist.
+        let mut dataflow = small_graph();
+        dataflow.ins[0] = Node(9);
+        assert_eq!(dataflow.verify(), Err(vec![DataflowError::NoSuchNode(Node(3), Node(9))]));
+        // An input that is added later.
+        let mut dataflow = small_graph();
+        dataflow.ins[1] = Node(4);
+        assert_eq!(dataflow.verify(), Err(vec![DataflowError::NotTopological(Node(3), Node(4))]));
+        // A value input that computes nothing.
+        let mut dataflow = small_graph();
+        let guard = dataflow.add_node(Op::Guard, &[dataflow.undefined(), Node(4)]);
+        let _ = dataflow.add_node(Op::Unary(P64, UnaryOp::Not), &[Node(1)]);
+        *dataflow.ins.last_mut().unwrap() = guard;
+        assert_eq!(dataflow.verify(), Err(vec![DataflowError::NoResult(Node(6), guard)]));
+        // An `Op` that expects fewer inputs.
+        let mut dataflow = small_graph();
+        dataflow.nodes[4].op = Op::Unary(P64, UnaryOp::Not);
+        assert_eq!(dataflow.verify(), Err(vec![DataflowError::WrongArity(Node(4), 2, 1)]));
+        // An input on entry that computes something.
+        let mut dataflow = small_graph();
+        dataflow.inputs[1] = Node(3);
+        assert_eq!(dataflow.verify(), Err(vec![DataflowError::BadInput(Node(3))]));
+    }
+
+    #[test]
+    #[cfg(feature = "graphviz")]
+    fn graphviz() {
+        let mut dot = Vec::new();
+        small_graph().graphviz(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph"));
+        assert!(dot.contains("n3 [label=\"3: Binary(P64, Add)\"];"));
+        assert!(dot.contains("n4 [label=\"4: Binary(P64, Mul)\"];"));
+        assert!(dot.contains("n3 -> n4 [label=\"0\"];"));
+        assert!(dot.contains("n1 -> n4 [label=\"1\"];"));
+        assert_eq!(dot.matches(" -> ").count(), 4);
+    }
+
+    #[test]
+    #[cfg(not(feature = "graphviz"))]
+    #[should_panic(expected = "compile with feature graphviz")]
+    fn graphviz() {
+        let _ = small_graph().graphviz(&mut std::io::sink());
+    }
+}
@@ -1,6 +1,6 @@
 use std::fmt::{Debug};
 
-use super::{code, target};
+use super::{code};
 use code::{Switch, EBB, Convention};
 
 //-----------------------------------------------------------------------------
@@ -10,7 +10,7 @@ mod dep;
 use dep::{Dep};
 
 mod op;
-use op::{Op};
+use op::{Op, MAX_FENCE_ACCESSES};
 
 mod resources;
 use resources::{Resources};
@@ -30,6 +30,9 @@ use simulation::{simulate};
 mod builder;
 use builder::{build};
 
+mod trace;
+pub use trace::{Trace, NoTrace, StderrTrace};
+
 /// Look up information about a control-flow merge point.
 pub trait LookupLeaf {
     // A control-flow merge point.
@@ -40,13 +43,75 @@ pub trait LookupLeaf {
     fn weight(&self, leaf: &Self::Leaf) -> usize;
 }
 
-/// Optimizes an [`EBB`].
-pub fn optimize<L: LookupLeaf>(before: &Convention, input: &EBB<L::Leaf>, lookup_leaf: &L)
--> EBB<L::Leaf> {
+/// Whether to keep [`Action::Debug`]s by default. If `false`, they are
+/// removed.
+///
+/// [`Action::Debug`]: code::Action::Debug
+const KEEP_DEBUG: bool = cfg!(any(debug_assertions, feature = "debug"));
+
+/// Controls what [`optimize()`] is allowed to do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// The number of [`Register`]s available for allocation.
+    ///
+    /// [`Register`]: code::Register
+    pub num_registers: usize,
+    /// Whether a pure operation may share a [`Node`] with an identical one.
+    pub enable_cse: bool,
+    /// Whether operations on constants are evaluated, and identity
+    /// operations are replaced by their inputs.
+    pub enable_constant_folding: bool,
+    /// Whether [`Action::Debug`]s are compiled. If `false`, they are
+    /// removed. The default is `true` in debug builds or with the `debug`
+    /// feature.
+    ///
+    /// [`Action::Debug`]: code::Action::Debug
+    pub keep_debug: bool,
+}
+
+impl Options {
+    /// Returns `Options` that use up to `num_registers` [`Register`]s and
+    /// enable all optimizations.
+    ///
+    /// [`Register`]: code::Register
+    pub fn new(num_registers: usize) -> Self {
+        Options {num_registers, enable_cse: true, enable_constant_folding: true, keep_debug: KEEP_DEBUG}
+    }
+}
+
+/// Statistics about one call to [`optimize()`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of [`Node`]s in the [`Dataflow`] graph, excluding inputs.
+    pub nodes_created: usize,
+    /// The number of those [`Node`]s that are not in the output, e.g.
+    /// because nothing uses their results.
+    pub nodes_eliminated: usize,
+    /// The number of [`Action::Push`]es inserted to spill values.
+    ///
+    /// [`Action::Push`]: code::Action::Push
+    pub spills: usize,
+    /// The number of distinct [`Register`]s allocated to [`Node`]s.
+    ///
+    /// [`Register`]: code::Register
+    pub registers_used: usize,
+    /// The number of [`EBB`]s in the output, including cold paths.
+    pub num_ebbs: usize,
+}
+
+/// Optimizes an [`EBB`] according to `options`, reporting progress to
+/// `trace`.
+pub fn optimize<L: LookupLeaf>(
+    options: &Options,
+    trace: &dyn Trace,
+    before: &Convention,
+    input: &EBB<L::Leaf>,
+    lookup_leaf: &L,
+) -> (EBB<L::Leaf>, Stats) {
     // Generate the [`Dataflow`] graph.
-    let (dataflow, cft) = simulate(before, input, lookup_leaf);
+    let (dataflow, cft) = simulate(options, before, input, lookup_leaf);
     // Turn it back into an EBB.
-    build(before, &dataflow, &cft, lookup_leaf)
+    build(options.num_registers, trace, before, &dataflow, &cft, lookup_leaf)
 }
 
 //-----------------------------------------------------------------------------
@@ -57,6 +122,9 @@ mod tests {
     use crate::code::{REGISTERS as R, BinaryOp, builder as cb};
     use BinaryOp::*;
     use crate::code::tests::{emulate, random_ebb, random_ebb_convention};
+    use crate::target::{Native, Target};
+
+    const NUM_REGISTERS: usize = <Native as Target>::NUM_REGISTERS;
 
     // Several tests represent leaves as integers.
     impl LookupLeaf for Convention {
@@ -73,7 +141,7 @@ mod tests {
     /// panic with diagnostics if they behave differently.
     pub fn optimize_and_compare(input_ebb: EBB<usize>, convention: Convention) {
         let expected = emulate(&input_ebb, &convention);
-        let output_ebb = optimize(&convention, &input_ebb, &convention);
+        let (output_ebb, _) = optimize(&Options::new(NUM_REGISTERS), &NoTrace, &convention, &input_ebb, &convention);
         let observed = emulate(&output_ebb, &convention);
         if expected != observed {
             println!("input_ebb: {:#x?}", input_ebb);
@@ -130,6 +198,115 @@ mod tests {
         optimize_and_compare(ebb, random_ebb_convention());
     }
 
+    /// Test that computations whose results are not used are not in the
+    /// output. The [`Dataflow`] graph keeps them, but [`build()`] schedules
+    /// only the `Node`s on which a guard or an exit depends.
+    #[test]
+    fn dead_code() {
+        use code::{Action, Ending};
+        let convention = Convention {lives: Box::new([R[1].into(), R[2].into()]), slots_used: 0};
+        let ebb = cb::build(|mut b| {
+            b.binary64(Mul, R[3], R[1], R[2]);
+            b.binary64(Add, R[4], R[3], R[1]);
+            b.binary64(Xor, R[2], R[1], R[2]);
+            b.jump(0)
+        });
+        let (output_ebb, stats) = optimize(&Options::new(NUM_REGISTERS), &NoTrace, &convention, &ebb, &convention);
+        assert_eq!(stats.nodes_eliminated, 2);
+        assert!(matches!(output_ebb.ending, Ending::Leaf(0)));
+        assert!(output_ebb.actions.iter().all(|action| !matches!(action, Action::Binary(Mul | Add, _, _, _, _))));
+        optimize_and_compare(ebb, convention);
+    }
+
+    /// Test that [`Stats`] report more spills when fewer registers are
+    /// available.
+    #[test]
+    fn stats_spills() {
+        let convention = Convention {lives: Box::new([R[1].into()]), slots_used: 0};
+        // Compute many values, then combine them forwards and backwards, so
+        // that they are all live at once.
+        let ebb = cb::build(|mut b| {
+            for (i, &r) in (2..).zip(&R[2..11]) {
+                b.const_binary64(Mul, r, R[1], i);
+            }
+            b.move_(R[11], R[2]);
+            for &r in &R[3..11] { b.binary64(Add, R[11], R[11], r); }
+            for &r in R[2..11].iter().rev() { b.binary64(Xor, R[11], R[11], r); }
+            b.move_(R[1], R[11]);
+            b.jump(0)
+        });
+        let convention = &convention;
+        let (_, default_stats) = optimize(&Options::new(NUM_REGISTERS), &NoTrace, convention, &ebb, convention);
+        assert_eq!(default_stats.spills, 0);
+        assert_eq!(default_stats.num_ebbs, 1);
+        assert!(default_stats.registers_used > 4);
+        let options = Options {num_registers: 4, ..Options::new(NUM_REGISTERS)};
+        let (_, stats) = optimize(&options, &NoTrace, convention, &ebb, convention);
+        assert!(stats.spills > default_stats.spills);
+        assert!(stats.registers_used <= 4);
+        optimize_and_compare(ebb, convention.clone());
+    }
+
+    /// Test that a value pushed and then read back does not touch the stack.
+    #[test]
+    fn push_then_read() {
+        use code::{Action, Slot, Precision};
+        let convention = Convention {lives: Box::new([R[1].into()]), slots_used: 0};
+        let ebb = EBB {
+            actions: Box::new([
+                Action::Push(Some(R[1].into()), None),
+                Action::Binary(Add, Precision::P64, R[1], Slot(1).into(), Slot(1).into()),
+                Action::Drop(1),
+            ]),
+            ending: code::Ending::Leaf(0),
+        };
+        let (output_ebb, _) = optimize(&Options::new(NUM_REGISTERS), &NoTrace, &convention, &ebb, &convention);
+        assert!(output_ebb.actions.iter().all(|action| !matches!(action, Action::Push(..) | Action::Drop(_))));
+        assert!(output_ebb.actions.iter().any(|&action| action == Action::Binary(Add, Precision::P64, R[1], R[1].into(), R[1].into())));
+    }
+
+    /// Test that [`Options`] can disable common subexpression elimination.
+    #[test]
+    fn disable_cse() {
+        let convention = Convention {lives: Box::new([R[1].into()]), slots_used: 0};
+        let ebb = cb::build(|mut b| {
+            b.binary64(Mul, R[2], R[1], R[1]);
+            b.binary64(Mul, R[3], R[1], R[1]);
+            b.binary64(Add, R[1], R[2], R[3]);
+            b.jump(0)
+        });
+        let convention = &convention;
+        let (_, stats) = optimize(&Options::new(NUM_REGISTERS), &NoTrace, convention, &ebb, convention);
+        assert_eq!(stats.nodes_created, 2);
+        let options = Options {enable_cse: false, ..Options::new(NUM_REGISTERS)};
+        let (_, stats) = optimize(&options, &NoTrace, convention, &ebb, convention);
+        assert_eq!(stats.nodes_created, 3);
+    }
+
+    #[test]
+    fn deterministic() {
+        use crate::target::{x86_64, Lower};
+        // Returns the optimized `EBB` and the machine code of its hot path.
+        let compile = |input_ebb: &EBB<usize>| {
+            let convention = random_ebb_convention();
+            let (output_ebb, _) = optimize(&Options::new(NUM_REGISTERS), &NoTrace, &convention, input_ebb, &convention);
+            let mut lo = x86_64::Lowerer::<Vec<u8>>::new();
+            lo.actions(&output_ebb.actions);
+            let (_, code) = lo.use_assembler(|mut a| {
+                let code = a.use_buffer(|b| b.clone());
+                Ok((a, code))
+            }).unwrap();
+            (format!("{:?}", output_ebb), code)
+        };
+        for seed in 0..20 {
+            let input_ebb = random_ebb(seed, 4);
+            let expected = compile(&input_ebb);
+            for _ in 0..10 {
+                assert_eq!(compile(&input_ebb), expected);
+            }
+        }
+    }
+
     #[test]
     fn optimize_random_ebbs() {
         for seed in 0..1000 {
@@ -1,5 +1,23 @@
 use super::{Dep};
-use super::code::{Register, Variable, Precision, UnaryOp, BinaryOp, Width, Address, Action};
+use super::code::{Register, Variable, Precision, UnaryOp, BinaryOp, Width, FenceOrder, Address, Action, NativeFunction};
+
+/// The maximum number of memory access [`Node`]s that an [`Op::Fence`] or
+/// an [`Op::AtomicCas`] can depend on.
+///
+/// [`Node`]: super::Node
+pub const MAX_FENCE_ACCESSES: usize = 15;
+
+/// The `Dep`s of the largest [`Op::Fence`].
+const FENCE_DEPS: [Dep; MAX_FENCE_ACCESSES + 1] = [Dep::GUARD; MAX_FENCE_ACCESSES + 1];
+
+/// The `Dep`s of the largest [`Op::AtomicCas`].
+const CAS_DEPS: [Dep; MAX_FENCE_ACCESSES + 4] = {
+    let mut deps = [Dep::GUARD; MAX_FENCE_ACCESSES + 4];
+    deps[1] = Dep::VALUE;
+    deps[2] = Dep::VALUE;
+    deps[3] = Dep::STORE;
+    deps
+};
 
 /// Annotates a [`Node`] of a [`Dataflow`] graph.
 ///
@@ -15,10 +33,18 @@ pub enum Op {
     Constant(i64),
     Unary(Precision, UnaryOp),
     Binary(Precision, BinaryOp),
+    /// Selects the second input if the first is non-zero, otherwise the third.
+    Select(Precision),
     Load(i32, Width),
     Store(i32, Width),
     Send,
     Debug,
+    Call(NativeFunction),
+    /// Happens after the previous [`Op::Guard`], [`Op::Debug`], [`Op::Call`]
+    /// or `Op::Fence`, and after the specified number of memory accesses.
+    Fence(FenceOrder, u8),
+    /// Like `Fence(FenceOrder::Full, _)`, but also does a compare-and-swap.
+    AtomicCas(i32, Width, u8),
 }
 
 impl Op {
@@ -33,13 +59,23 @@ impl Op {
             Op::Constant(_) => &[],
             Op::Unary(_, _) => &[Dep::VALUE],
             Op::Binary(_, _) => &[Dep::VALUE, Dep::VALUE],
+            Op::Select(_) => &[Dep::VALUE, Dep::VALUE, Dep::VALUE],
             Op::Load(_, _) => &[Dep::GUARD, Dep::LOAD],
             Op::Store(_, _) => &[Dep::GUARD, Dep::VALUE, Dep::STORE],
             Op::Send => &[Dep::VALUE, Dep::SEND],
             Op::Debug => &[Dep::GUARD, Dep::VALUE],
+            Op::Call(_) => &[Dep::GUARD, Dep::SEND, Dep::SEND],
+            Op::Fence(_, n) => &FENCE_DEPS[..=(n as usize)],
+            Op::AtomicCas(_, _, n) => &CAS_DEPS[..(4 + n as usize)],
         }
     }
 
+    /// Returns the number of operands of a [`Node`] annotated with this
+    /// `Op`, i.e. the length of `self.deps()`.
+    ///
+    /// [`Node`]: super::Node
+    pub fn expected_arity(self) -> usize { self.deps().len() }
+
     /// Aggregates this [`Op`] with the specified outputs and inputs to make an
     /// [`Action`].
     /// Panics if the `Op` is a `Guard`.
@@ -59,6 +95,10 @@ impl Op {
                 assert_eq!(ins.len(), 2);
                 Action::Binary(op, prec, out.unwrap(), ins[0], ins[1])
             },
+            Op::Select(prec) => {
+                assert_eq!(ins.len(), 3);
+                Action::Select(prec, out.unwrap(), ins[0], ins[1], ins[2])
+            },
             Op::Load(offset, width) => {
                 assert_eq!(ins.len(), 1);
                 Action::Load(out.unwrap(), Address {base: ins[0], offset, width})
@@ -76,6 +116,19 @@ impl Op {
                 assert_eq!(ins.len(), 1);
                 Action::Debug(ins[0])
             },
+            Op::Call(function) => {
+                assert_eq!(ins.len(), 2);
+                Action::Call(out.unwrap(), function, ins[0], ins[1])
+            },
+            Op::Fence(order, _) => {
+                assert!(out.is_none());
+                assert_eq!(ins.len(), 0);
+                Action::Fence(order)
+            },
+            Op::AtomicCas(offset, width, _) => {
+                assert_eq!(ins.len(), 3);
+                Action::AtomicCas(out.unwrap(), ins[0], ins[1], Address {base: ins[2], offset, width})
+            },
         }
     }
 }
@@ -1,7 +1,132 @@
 use std::collections::{HashMap};
 use std::fmt::{Debug};
-use super::code::{Precision, Register, Slot, Variable, Convention, Action, Switch, EBB, Ending};
-use super::{Exit, CFT, Op, Dataflow, Node, LookupLeaf};
+
+use crate::util::{bits};
+use super::code::{Precision, UnaryOp, BinaryOp, FenceOrder, Register, Slot, Variable, Convention, Action, Switch, EBB, Ending};
+use super::{Options, Exit, CFT, Op, MAX_FENCE_ACCESSES, KEEP_DEBUG, Dataflow, Node, LookupLeaf};
+
+/// Returns the value of `node` if it is an [`Op::Constant`].
+fn constant(dataflow: &Dataflow, node: Node) -> Option<i64> {
+    if let Op::Constant(c) = dataflow.op(node) { Some(c) } else { None }
+}
+
+/// Returns whether the value of `node` is known to be zero-extended from
+/// `prec`.
+fn is_truncated(dataflow: &Dataflow, node: Node, prec: Precision) -> bool {
+    prec == Precision::P64 || match dataflow.op(node) {
+        Op::Unary(p, _) | Op::Binary(p, _) | Op::Select(p) => p == prec,
+        Op::Constant(c) => prec.truncate(c) == c,
+        _ => false,
+    }
+}
+
+/// If `op` is pure and its result does not depend on the unknown values
+/// among `in_nodes`, returns the value that `op` computes. This is the case
+/// if all of `in_nodes` are [`Op::Constant`]s, or for `x * 0`, `x & 0`,
+/// `x - x` and `x ^ x`.
+fn fold(dataflow: &Dataflow, op: Op, in_nodes: &[Node]) -> Option<i64> {
+    let value = |node| constant(dataflow, node);
+    match op {
+        Op::Unary(prec, op) => Some(op.apply(prec, value(in_nodes[0])?)),
+        Op::Binary(prec, op) => {
+            let (x, y) = (in_nodes[0], in_nodes[1]);
+            if let (Some(x), Some(y)) = (value(x), value(y)) {
+                return Some(op.apply(prec, x, y));
+            }
+            let is_zero = |node| value(node).map_or(false, |c| prec.truncate(c) == 0);
+            match op {
+                BinaryOp::Mul | BinaryOp::And if is_zero(x) || is_zero(y) => Some(0),
+                BinaryOp::Sub | BinaryOp::Xor if x == y => Some(0),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// If `op` is pure and computes the same value as one of `in_nodes`, returns
+/// that `Node`. This is the case for `x + 0`, `x - 0`, `x * 1`, `x | 0`,
+/// `x ^ 0`, `x & x` and for shifts by zero, provided that `x` is already
+/// truncated to the [`Precision`].
+fn identity(dataflow: &Dataflow, op: Op, in_nodes: &[Node]) -> Option<Node> {
+    if let Op::Binary(prec, op) = op {
+        let (x, y) = (in_nodes[0], in_nodes[1]);
+        let is = |node, value| constant(dataflow, node).map_or(false, |c| prec.truncate(c) == value);
+        let shift_is_zero = constant(dataflow, y).map_or(false, |c| c % (prec.bits() as i64) == 0);
+        let result = match op {
+            BinaryOp::Add | BinaryOp::Or | BinaryOp::Xor if is(y, 0) => x,
+            BinaryOp::Add | BinaryOp::Or | BinaryOp::Xor if is(x, 0) => y,
+            BinaryOp::Mul if is(y, 1) => x,
+            BinaryOp::Mul if is(x, 1) => y,
+            BinaryOp::Sub if is(y, 0) => x,
+            BinaryOp::And if x == y => x,
+            BinaryOp::Lsl | BinaryOp::Lsr | BinaryOp::Asr if shift_is_zero => x,
+            _ => return None,
+        };
+        if is_truncated(dataflow, result, prec) { return Some(result); }
+    }
+    None
+}
+
+/// Returns whether the value of `node` is known to be `0` or `-1` when
+/// truncated to `prec`, i.e. whether it is the result of a comparison.
+fn is_boolean(dataflow: &Dataflow, node: Node, prec: Precision) -> bool {
+    match dataflow.op(node) {
+        Op::Binary(p, BinaryOp::Lt | BinaryOp::Ult | BinaryOp::Eq) => p == prec || p == Precision::P64,
+        _ => false,
+    }
+}
+
+/// If `op` selects between a value and zero according to a condition that
+/// is known to be `0` or `-1`, returns the equivalent `And` of the condition
+/// and the value.
+fn select_and(dataflow: &Dataflow, op: Op, in_nodes: &[Node]) -> Option<(Op, Node, Node)> {
+    if let Op::Select(prec) = op {
+        let (c, x, y) = (in_nodes[0], in_nodes[1], in_nodes[2]);
+        let is_zero = constant(dataflow, y).map_or(false, |k| prec.truncate(k) == 0);
+        if is_zero && is_boolean(dataflow, c, prec) {
+            return Some((Op::Binary(prec, BinaryOp::And), c, x));
+        }
+    }
+    None
+}
+
+/// If `op` subtracts a constant from a non-constant, returns the equivalent
+/// `Add` and the negated constant. Targets can more often fuse an `Add` with
+/// its operands, and it gives common subexpression elimination more to find.
+fn sub_constant(dataflow: &Dataflow, op: Op, in_nodes: &[Node]) -> Option<(Op, i64)> {
+    if let Op::Binary(prec, BinaryOp::Sub) = op {
+        if constant(dataflow, in_nodes[0]).is_none() {
+            let c = constant(dataflow, in_nodes[1])?;
+            return Some((Op::Binary(prec, BinaryOp::Add), prec.truncate(c.wrapping_neg())));
+        }
+    }
+    None
+}
+
+/// If `op` applied to `in_nodes` masks the result of a logical right shift
+/// by a constant with a constant of the form `2^n - 1`, returns an equivalent
+/// [`UnaryOp::Extract`] and its input.
+fn extract(dataflow: &Dataflow, op: Op, in_nodes: &[Node]) -> Option<(Op, Node)> {
+    let prec = if let Op::Binary(prec, BinaryOp::And) = op { prec } else { return None; };
+    let prec_bits = prec.bits() as u64;
+    for (shifted, mask) in [(in_nodes[0], in_nodes[1]), (in_nodes[1], in_nodes[0])] {
+        if let (Op::Binary(p, BinaryOp::Lsr), Op::Constant(mask)) = (dataflow.op(shifted), dataflow.op(mask)) {
+            let (x, shift) = (dataflow.ins(shifted)[0], dataflow.ins(shifted)[1]);
+            if let Op::Constant(shift) = dataflow.op(shift) {
+                let mask = (mask as u64) & bits::mask(prec_bits as u32);
+                let shift = shift as u64;
+                if let Some(width) = bits::mask_width(mask) {
+                    if p == prec && shift < prec_bits && width > 0 {
+                        let width = std::cmp::min(u64::from(width), prec_bits - shift);
+                        return Some((Op::Unary(prec, UnaryOp::Extract(shift as u8, width as u8)), x));
+                    }
+                }
+            }
+        }
+    }
+    None
+}
 
 /// Represents the state of an abstract execution of some code which builds a
 /// [`Dataflow`] graph.
@@ -13,9 +138,22 @@ pub struct Simulation {
     slots_used: usize,
     /// Maps each [`Variable`] to the corresponding [`Node`].
     bindings: HashMap<Variable, Node>,
-    /// The most recent [`Op::Guard`] or [`Op::Debug`], if any, otherwise the
-    /// undefined `Node`.
+    /// The most recent [`Op::Guard`], [`Op::Debug`], [`Op::Call`],
+    /// [`Op::Fence`] or [`Op::AtomicCas`], if any, otherwise the undefined
+    /// `Node`.
     sequence: Node,
+    /// The [`Op::Load`] and [`Op::Store`] `Node`s since the most recent
+    /// [`Op::Fence`] or [`Op::AtomicCas`].
+    accesses: Vec<Node>,
+    /// If `false`, [`Action::Debug`]s are ignored.
+    keep_debug: bool,
+    /// Maps each pure [`Op`] and its inputs to an existing [`Node`] that
+    /// computes it, for common subexpression elimination.
+    pure_nodes: HashMap<(Op, Vec<Node>), Node>,
+    /// If `false`, `pure_nodes` is not used.
+    enable_cse: bool,
+    /// If `false`, constants are not folded and identities are not removed.
+    enable_constant_folding: bool,
 }
 
 impl Simulation {
@@ -31,6 +169,11 @@ impl Simulation {
             slots_used: before.slots_used,
             bindings: bindings,
             sequence: dataflow.undefined(),
+            accesses: Vec::new(),
+            keep_debug: KEEP_DEBUG,
+            pure_nodes: HashMap::new(),
+            enable_cse: true,
+            enable_constant_folding: true,
         }
     }
 
@@ -56,9 +199,26 @@ impl Simulation {
         self.bindings.remove(&dest);
     }
 
+    /// Returns a [`Node`] representing `op` applied to `in_nodes`. If `op` is
+    /// pure and an identical `Node` exists, returns it instead of making a new
+    /// one.
+    fn add_node(&mut self, dataflow: &mut Dataflow, op: Op, in_nodes: Vec<Node>) -> Node {
+        if self.enable_cse && matches!(op, Op::Constant(_) | Op::Unary(_, _) | Op::Binary(_, _) | Op::Select(_)) {
+            *self.pure_nodes.entry((op, in_nodes))
+                .or_insert_with_key(|(op, in_nodes)| dataflow.add_node(*op, in_nodes))
+        } else {
+            dataflow.add_node(op, &in_nodes)
+        }
+    }
+
     /// Returns a [`Node`] representing `op` applied to `ins`.
-    /// Side-effect dependencies are deduced from `op`.
-    /// Binds `out` to the `Node`'s output, if any.
+    /// Side-effect dependencies are deduced from `op`. Pure operations on
+    /// constants are folded, identity operations return their input,
+    /// subtracting a constant becomes adding its negation, a shift followed
+    /// by a mask is combined into an [`UnaryOp::Extract`], and selecting
+    /// between a value and zero according to a comparison becomes an `And`.
+    /// If `op` is pure and an identical `Node` exists, returns it instead of
+    /// making a new one. Binds `out` to the `Node`'s output, if any.
     fn op(
         &mut self,
         dataflow: &mut Dataflow,
@@ -67,15 +227,33 @@ impl Simulation {
         out: impl Into<Option<Register>>,
     ) -> Node {
         let mut in_nodes = Vec::new();
-        if matches!(op, Op::Guard | Op::Load(_, _) | Op::Store(_, _) | Op::Debug) {
+        if matches!(op, Op::Guard | Op::Load(_, _) | Op::Store(_, _) | Op::Debug | Op::Call(_)) {
             in_nodes.push(self.sequence);
         }
         for &in_ in ins {
             in_nodes.push(self.lookup(in_));
         }
-        // TODO: Common subexpression elimination.
-        // TODO: Peephole optimizations.
-        let node = dataflow.add_node(op, &in_nodes);
+        let folded = if self.enable_constant_folding { fold(dataflow, op, &in_nodes) } else { None };
+        let subtracted = if self.enable_constant_folding { sub_constant(dataflow, op, &in_nodes) } else { None };
+        let (op, in_nodes) = if let Some(c) = folded {
+            (Op::Constant(c), Vec::new())
+        } else if let Some((op, c)) = subtracted {
+            let c = self.add_node(dataflow, Op::Constant(c), Vec::new());
+            (op, vec![in_nodes[0], c])
+        } else if let Some((op, x)) = extract(dataflow, op, &in_nodes) {
+            (op, vec![x])
+        } else if let Some((op, c, x)) = select_and(dataflow, op, &in_nodes) {
+            (op, vec![c, x])
+        } else {
+            (op, in_nodes)
+        };
+        let identity = if self.enable_constant_folding { identity(dataflow, op, &in_nodes) } else { None };
+        let node = if let Some(node) = identity {
+            node
+        } else {
+            self.add_node(dataflow, op, in_nodes)
+        };
+        if matches!(op, Op::Load(_, _) | Op::Store(_, _)) { self.accesses.push(node); }
         if let Some(r) = out.into() { self.bindings.insert(r.into(), node); }
         node
     }
@@ -99,6 +277,9 @@ impl Simulation {
             Action::Binary(bin_op, prec, dest, src1, src2) => {
                 let _ = self.op(dataflow, Op::Binary(prec, bin_op), &[src1, src2], dest);
             },
+            Action::Select(prec, dest, cond, src1, src2) => {
+                let _ = self.op(dataflow, Op::Select(prec), &[cond, src1, src2], dest);
+            },
             Action::Load(dest, addr) => {
                 let _ = self.op(dataflow, Op::Load(addr.offset, addr.width), &[addr.base], dest);
             },
@@ -123,9 +304,41 @@ impl Simulation {
                 }
             },
             Action::Debug(src) => {
-                let node = self.op(dataflow, Op::Debug, &[src], None);
+                if self.keep_debug {
+                    let node = self.op(dataflow, Op::Debug, &[src], None);
+                    self.sequence = node;
+                }
+            },
+            Action::Call(dest, function, src1, src2) => {
+                let node = self.op(dataflow, Op::Call(function), &[src1, src2], dest);
+                self.sequence = node;
+            },
+            Action::Fence(order) => {
+                // Chain `Op::Fence`s if there are too many accesses for one.
+                let mut accesses = std::mem::take(&mut self.accesses);
+                loop {
+                    let n = std::cmp::min(accesses.len(), MAX_FENCE_ACCESSES);
+                    let mut in_nodes = vec![self.sequence];
+                    in_nodes.extend(accesses.drain(..n));
+                    self.sequence = dataflow.add_node(Op::Fence(order, n as u8), &in_nodes);
+                    if accesses.is_empty() { break; }
+                }
+            },
+            Action::AtomicCas(dest, expected, new, addr) => {
+                if self.accesses.len() > MAX_FENCE_ACCESSES {
+                    self.action(dataflow, &Action::Fence(FenceOrder::Full));
+                }
+                let n = self.accesses.len();
+                let mut in_nodes = vec![self.sequence];
+                in_nodes.extend([expected, new, addr.base].map(|v| self.lookup(v)));
+                in_nodes.append(&mut self.accesses);
+                let node = dataflow.add_node(Op::AtomicCas(addr.offset, addr.width, n as u8), &in_nodes);
+                self.bindings.insert(dest.into(), node);
                 self.sequence = node;
             },
+            Action::SlotAddress(_, _) => {
+                panic!("The optimizer does not support SlotAddress; see Action::check()");
+            },
         };
     }
 
@@ -177,11 +390,487 @@ impl Simulation {
 }
 
 /// Construct a [`Dataflow`] and a [`CFT`] that include all the operations in
-/// `input`.
-pub fn simulate<L: LookupLeaf>(before: &Convention, input: &EBB<L::Leaf>, lookup_leaf: &L)
+/// `input`, simplifying them as permitted by `options`.
+pub fn simulate<L: LookupLeaf>(options: &Options, before: &Convention, input: &EBB<L::Leaf>, lookup_leaf: &L)
 -> (Dataflow, CFT<L::Leaf>) {
     let mut dataflow = Dataflow::new(before.lives.len());
-    let simulation = Simulation::new(&dataflow, before);
+    let mut simulation = Simulation::new(&dataflow, before);
+    simulation.enable_cse = options.enable_cse;
+    simulation.enable_constant_folding = options.enable_constant_folding;
+    simulation.keep_debug = options.keep_debug;
     let (cft, _) = simulation.walk(&mut dataflow, input, lookup_leaf);
+    debug_assert_eq!(dataflow.verify(), Ok(()));
     (dataflow, cft)
 }
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashSet};
+
+    use rand::prelude::*;
+    use rand_pcg::{Pcg64};
+
+    use super::*;
+    use super::super::code::{REGISTERS, UnaryOp, BinaryOp, Width, Address, NativeFunction};
+    use super::super::code::tests::{add};
+    use Precision::*;
+
+    /// The [`Variable`]s that are live on entry.
+    fn convention() -> Convention {
+        Convention {
+            lives: Box::new([
+                REGISTERS[1].into(), REGISTERS[2].into(), REGISTERS[3].into(),
+                Slot(0).into(), Slot(1).into(),
+            ]),
+            slots_used: 2,
+        }
+    }
+
+    /// Return a random element of `choices`.
+    fn choose<R: Rng, T: Copy>(rng: &mut R, choices: &[T]) -> T {
+        choices[rng.gen_range(0..choices.len())]
+    }
+
+    /// Return a random [`Action`] that [`Simulation::action()`] converts to
+    /// an [`Op`]. It reads only [`Variable`]s in `convention()`.
+    fn random_action<R: Rng>(rng: &mut R) -> Action {
+        let lives = convention().lives;
+        let dests = [REGISTERS[1], REGISTERS[2], REGISTERS[4]];
+        let precs = [P32, P64];
+        let widths = [Width::One, Width::Two, Width::Four, Width::Eight];
+        let dest = choose(rng, &dests);
+        let src1 = choose(rng, &lives);
+        let src2 = choose(rng, &lives);
+        let addr = Address {base: src2, offset: rng.gen(), width: choose(rng, &widths)};
+        match rng.gen_range(0..11) {
+            0 => Action::Constant(choose(rng, &precs), dest, rng.gen()),
+            1 => {
+                use UnaryOp::*;
+                let width = choose(rng, &widths);
+                let op = choose(rng, &[Abs, Negate, Not, Uxt(width), Sxt(width), Clz, Ctz, Popcount, Extract(8, 8)]);
+                Action::Unary(op, choose(rng, &precs), dest, src1)
+            },
+            2 => {
+                use BinaryOp::*;
+                let op = choose(rng, &[
+                    Add, Sub, Mul, MulHigh, UMulHigh, UDiv, SDiv, URem, SRem, Lsl, Lsr, Asr, RotL, RotR,
+                    And, Or, Xor, Lt, Ult, Eq, Max, Min, UMax, UMin,
+                ]);
+                // Use distinct operands, so that `x - x` etc. are not folded.
+                let src2 = if src2 == src1 { *lives.iter().find(|&&v| v != src1).unwrap() } else { src2 };
+                Action::Binary(op, choose(rng, &precs), dest, src1, src2)
+            },
+            3 => Action::Load(dest, addr),
+            4 => Action::Store(dest, src1, addr),
+            5 => Action::Send(dest, src1, src2),
+            6 => Action::Call(dest, NativeFunction(add), src1, src2),
+            7 => Action::Fence(choose(rng, &[FenceOrder::Load, FenceOrder::Store, FenceOrder::Full])),
+            8 => Action::AtomicCas(dest, src1, src2, addr),
+            9 => Action::Select(choose(rng, &precs), dest, choose(rng, &lives), src1, src2),
+            _ => Action::Debug(src1),
+        }
+    }
+
+    /// Returns the [`Action`] that `Simulation::action()` should be equivalent
+    /// to.
+    fn normalize(action: Action) -> Action {
+        match action {
+            Action::Constant(P32, dest, value) => Action::Constant(P64, dest, value & 0xFFFFFFFF),
+            action => action,
+        }
+    }
+
+    /// Simulate `action` and convert the resulting [`Op`] back to an
+    /// [`Action`]. Also returns the `Op`.
+    fn round_trip(action: Action) -> (Action, Op) {
+        let before = convention();
+        let mut dataflow = Dataflow::new(before.lives.len());
+        let mut simulation = Simulation::new(&dataflow, &before);
+        // Keep `Debug` even in release builds, so that it has an `Op`.
+        simulation.keep_debug = true;
+        simulation.action(&mut dataflow, &action);
+        let (node, out) = match action {
+            Action::Debug(_) | Action::Fence(_) => (simulation.sequence, None),
+            Action::Constant(_, dest, _) |
+            Action::Unary(_, _, dest, _) |
+            Action::Binary(_, _, dest, _, _) |
+            Action::Select(_, dest, _, _, _) |
+            Action::Load(dest, _) |
+            Action::Store(dest, _, _) |
+            Action::Send(dest, _, _) |
+            Action::Call(dest, _, _, _) |
+            Action::AtomicCas(dest, _, _, _) => (simulation.lookup(dest.into()), Some(dest)),
+            _ => panic!("Not an Op: {:?}", action),
+        };
+        let ins: Vec<Variable> = dataflow.ins(node).iter()
+            .filter(|&&in_| in_ != dataflow.undefined())
+            .map(|&in_| {
+                let index = dataflow.inputs().iter().position(|&input| input == in_)
+                    .expect("Not an input");
+                before.lives[index]
+            })
+            .collect();
+        let op = dataflow.op(node);
+        (op.to_action(out, &ins), op)
+    }
+
+    #[test]
+    fn action_round_trip() {
+        let rng = &mut Pcg64::seed_from_u64(0);
+        let mut ops_seen = HashSet::new();
+        for _ in 0..1000 {
+            let action = random_action(rng);
+            let (observed, op) = round_trip(action);
+            assert_eq!(observed, normalize(action));
+            ops_seen.insert(std::mem::discriminant(&op));
+        }
+        // Every `Op` except `Guard` and `Input`.
+        assert_eq!(ops_seen.len(), 11);
+    }
+
+    /// Test that `Debug` is removed unless `keep_debug` is set.
+    #[test]
+    fn strip_debug() {
+        let before = convention();
+        for keep_debug in [false, true] {
+            let mut dataflow = Dataflow::new(before.lives.len());
+            let mut simulation = Simulation::new(&dataflow, &before);
+            simulation.keep_debug = keep_debug;
+            simulation.action(&mut dataflow, &Action::Debug(Slot(1).into()));
+            let num_debugs = dataflow.all_nodes()
+                .filter(|&node| dataflow.op(node) == Op::Debug)
+                .count();
+            assert_eq!(num_debugs, keep_debug as usize);
+            assert_eq!(simulation.sequence == dataflow.undefined(), !keep_debug);
+        }
+    }
+
+    /// Test that a `Fence` is ordered after every earlier memory access and
+    /// before every later one.
+    #[test]
+    fn fence() {
+        let before = convention();
+        let mut dataflow = Dataflow::new(before.lives.len());
+        let mut simulation = Simulation::new(&dataflow, &before);
+        let addr = Address {base: REGISTERS[1].into(), offset: 0, width: Width::Eight};
+        simulation.action(&mut dataflow, &Action::Load(REGISTERS[2], addr));
+        let load = simulation.lookup(REGISTERS[2].into());
+        simulation.action(&mut dataflow, &Action::Store(REGISTERS[4], REGISTERS[2].into(), addr));
+        let store = simulation.lookup(REGISTERS[4].into());
+        simulation.action(&mut dataflow, &Action::Fence(FenceOrder::Full));
+        let fence = simulation.sequence;
+        assert_eq!(dataflow.op(fence), Op::Fence(FenceOrder::Full, 2));
+        assert!(dataflow.ins(fence).contains(&load));
+        assert!(dataflow.ins(fence).contains(&store));
+        simulation.action(&mut dataflow, &Action::Load(REGISTERS[2], addr));
+        assert!(dataflow.ins(simulation.lookup(REGISTERS[2].into())).contains(&fence));
+        // Many accesses are split over a chain of `Fence`s.
+        for _ in 0..MAX_FENCE_ACCESSES {
+            simulation.action(&mut dataflow, &Action::Load(REGISTERS[2], addr));
+        }
+        simulation.action(&mut dataflow, &Action::Fence(FenceOrder::Load));
+        let fence2 = simulation.sequence;
+        assert_eq!(dataflow.op(fence2), Op::Fence(FenceOrder::Load, 1));
+        let fence1 = dataflow.ins(fence2)[0];
+        assert_eq!(dataflow.op(fence1), Op::Fence(FenceOrder::Load, MAX_FENCE_ACCESSES as u8));
+        assert_eq!(dataflow.ins(fence1)[0], fence);
+    }
+
+    /// Test that a `Load` depends only on the `Store` to its own address.
+    /// Memory accesses via different address `Variable`s are independent
+    /// unless joined by a `Send`, so there is no need for alias groups.
+    #[test]
+    fn disjoint_stores() {
+        let before = convention();
+        let mut dataflow = Dataflow::new(before.lives.len());
+        let mut simulation = Simulation::new(&dataflow, &before);
+        let addr1 = Address {base: REGISTERS[1].into(), offset: 0, width: Width::Eight};
+        let addr3 = Address {base: REGISTERS[3].into(), offset: 0, width: Width::Eight};
+        simulation.action(&mut dataflow, &Action::Store(REGISTERS[1], REGISTERS[2].into(), addr1));
+        let store1 = simulation.lookup(REGISTERS[1].into());
+        simulation.action(&mut dataflow, &Action::Store(REGISTERS[3], REGISTERS[2].into(), addr3));
+        let store3 = simulation.lookup(REGISTERS[3].into());
+        simulation.action(&mut dataflow, &Action::Load(REGISTERS[4], addr1));
+        let load = simulation.lookup(REGISTERS[4].into());
+        assert!(dataflow.ins(load).contains(&store1));
+        assert!(!dataflow.ins(load).contains(&store3));
+    }
+
+    /// Test that an `AtomicCas` is ordered after every earlier memory access
+    /// and before every later one.
+    #[test]
+    fn atomic_cas() {
+        let before = convention();
+        let mut dataflow = Dataflow::new(before.lives.len());
+        let mut simulation = Simulation::new(&dataflow, &before);
+        let addr = Address {base: REGISTERS[1].into(), offset: 8, width: Width::Four};
+        simulation.action(&mut dataflow, &Action::Store(REGISTERS[4], REGISTERS[2].into(), addr));
+        let store = simulation.lookup(REGISTERS[4].into());
+        simulation.action(&mut dataflow, &Action::AtomicCas(REGISTERS[2], REGISTERS[2].into(), REGISTERS[4].into(), addr));
+        let cas = simulation.lookup(REGISTERS[2].into());
+        assert_eq!(simulation.sequence, cas);
+        assert_eq!(dataflow.op(cas), Op::AtomicCas(8, Width::Four, 1));
+        assert!(dataflow.ins(cas).contains(&store));
+        simulation.action(&mut dataflow, &Action::Load(REGISTERS[2], addr));
+        assert!(dataflow.ins(simulation.lookup(REGISTERS[2].into())).contains(&cas));
+    }
+
+    /// Test that computing `x * x * x` twice makes only two `Mul` nodes.
+    #[test]
+    fn common_subexpressions() {
+        let before = convention();
+        let mut dataflow = Dataflow::new(before.lives.len());
+        let mut simulation = Simulation::new(&dataflow, &before);
+        let x = REGISTERS[1];
+        for dest in [REGISTERS[2], REGISTERS[4]] {
+            simulation.action(&mut dataflow, &Action::Binary(BinaryOp::Mul, P64, dest, x.into(), x.into()));
+            simulation.action(&mut dataflow, &Action::Binary(BinaryOp::Mul, P64, dest, dest.into(), x.into()));
+        }
+        let num_muls = dataflow.all_nodes()
+            .filter(|&node| dataflow.op(node) == Op::Binary(P64, BinaryOp::Mul))
+            .count();
+        assert_eq!(num_muls, 2);
+        assert_eq!(simulation.lookup(REGISTERS[2].into()), simulation.lookup(REGISTERS[4].into()));
+    }
+
+    /// Test that incrementing a constant makes a constant.
+    #[test]
+    fn constant_folding() {
+        let before = convention();
+        let mut dataflow = Dataflow::new(before.lives.len());
+        let mut simulation = Simulation::new(&dataflow, &before);
+        let (x, one) = (REGISTERS[2], REGISTERS[4]);
+        simulation.action(&mut dataflow, &Action::Constant(P32, x, 41));
+        simulation.action(&mut dataflow, &Action::Constant(P32, one, 1));
+        simulation.action(&mut dataflow, &Action::Binary(BinaryOp::Add, P32, x, x.into(), one.into()));
+        assert_eq!(dataflow.op(simulation.lookup(x.into())), Op::Constant(42));
+        assert!(dataflow.all_nodes().all(|node| !matches!(dataflow.op(node), Op::Binary(_, _))));
+        // The result is truncated to the `Precision`.
+        simulation.action(&mut dataflow, &Action::Unary(UnaryOp::Negate, P32, x, x.into()));
+        assert_eq!(dataflow.op(simulation.lookup(x.into())), Op::Constant(0xFFFF_FFD6));
+        // Dividing by zero gives zero.
+        simulation.action(&mut dataflow, &Action::Constant(P64, one, 0));
+        simulation.action(&mut dataflow, &Action::Binary(BinaryOp::SDiv, P64, x, x.into(), one.into()));
+        assert_eq!(dataflow.op(simulation.lookup(x.into())), Op::Constant(0));
+        // Non-constant inputs are not folded.
+        simulation.action(&mut dataflow, &Action::Constant(P64, x, 5));
+        simulation.action(&mut dataflow, &Action::Binary(BinaryOp::Add, P64, x, x.into(), REGISTERS[1].into()));
+        assert_eq!(dataflow.op(simulation.lookup(x.into())), Op::Binary(P64, BinaryOp::Add));
+    }
+
+    /// Test that folding agrees with the architectural semantics of shifts
+    /// and comparisons.
+    #[test]
+    fn constant_folding_edge_cases() {
+        use BinaryOp::*;
+        let before = convention();
+        let (x, y, dest) = (REGISTERS[1], REGISTERS[2], REGISTERS[4]);
+        for (op, prec, a, b, expected) in [
+            (Mul, P64, 2, 3, 6),
+            (Mul, P32, 0x10000, 0x10000, 0),
+            (Lsl, P32, 1, 33, 2),
+            (Lsl, P64, 1, 64, 1),
+            (Lsr, P32, -1, 28, 0xF),
+            (Asr, P32, -16, 34, 0xFFFF_FFFC),
+            (Lt, P64, -1, 0, -1),
+            (Lt, P32, 0, -1, 0),
+            (Ult, P32, 0, -1, 0xFFFF_FFFF),
+            (Eq, P64, 7, 7, -1),
+            (Eq, P32, 7, 8, 0),
+        ] {
+            let mut dataflow = Dataflow::new(before.lives.len());
+            let mut simulation = Simulation::new(&dataflow, &before);
+            simulation.action(&mut dataflow, &Action::Constant(P64, x, a));
+            simulation.action(&mut dataflow, &Action::Constant(P64, y, b));
+            simulation.action(&mut dataflow, &Action::Binary(op, prec, dest, x.into(), y.into()));
+            assert_eq!(dataflow.op(simulation.lookup(dest.into())), Op::Constant(expected));
+            assert!(dataflow.all_nodes().all(|node| !matches!(dataflow.op(node), Op::Binary(_, _))));
+        }
+    }
+
+    /// Test each identity and annihilator. `None` means `x`.
+    #[test]
+    fn identities() {
+        use BinaryOp::*;
+        let before = convention();
+        let (x, c1, c2, dest) = (REGISTERS[1], REGISTERS[2], REGISTERS[3], REGISTERS[4]);
+        for (op, a, b, expected) in [
+            (Add, None, Some(0), None),
+            (Add, Some(0), None, None),
+            (Sub, None, Some(0), None),
+            (Mul, None, Some(1), None),
+            (Mul, Some(1), None, None),
+            (Mul, None, Some(0), Some(0)),
+            (Mul, Some(0), None, Some(0)),
+            (Xor, None, Some(0), None),
+            (And, None, Some(0), Some(0)),
+            (Or, None, Some(0), None),
+            (Xor, None, None, Some(0)),
+            (And, None, None, None),
+            (Sub, None, None, Some(0)),
+            (Lsr, None, Some(0), None),
+            (Lsl, None, Some(0), None),
+            (Lsl, None, Some(64), None),
+            (Asr, None, Some(0), None),
+        ] {
+            let mut dataflow = Dataflow::new(before.lives.len());
+            let mut simulation = Simulation::new(&dataflow, &before);
+            let operand = |dataflow: &mut Dataflow, simulation: &mut Simulation, value, r: Register| match value {
+                Some(value) => {
+                    simulation.action(dataflow, &Action::Constant(P64, r, value));
+                    r
+                },
+                None => x,
+            };
+            let src1 = operand(&mut dataflow, &mut simulation, a, c1);
+            let src2 = operand(&mut dataflow, &mut simulation, b, c2);
+            simulation.action(&mut dataflow, &Action::Binary(op, P64, dest, src1.into(), src2.into()));
+            let result = simulation.lookup(dest.into());
+            match expected {
+                Some(value) => assert_eq!(dataflow.op(result), Op::Constant(value)),
+                None => assert_eq!(result, simulation.lookup(x.into())),
+            }
+            assert!(dataflow.all_nodes().all(|node| !matches!(dataflow.op(node), Op::Binary(_, _))));
+        }
+    }
+
+    /// Test that subtracting a constant becomes adding its negation.
+    #[test]
+    fn sub_constant() {
+        let before = convention();
+        let (x, c, dest) = (REGISTERS[1], REGISTERS[2], REGISTERS[3]);
+        for (prec, negated) in [(P64, -4), (P32, 0xFFFF_FFFC)] {
+            let mut dataflow = Dataflow::new(before.lives.len());
+            let mut simulation = Simulation::new(&dataflow, &before);
+            simulation.action(&mut dataflow, &Action::Constant(P64, c, 4));
+            simulation.action(&mut dataflow, &Action::Binary(BinaryOp::Sub, prec, dest, x.into(), c.into()));
+            let result = simulation.lookup(dest.into());
+            assert_eq!(dataflow.op(result), Op::Binary(prec, BinaryOp::Add));
+            let ins = dataflow.ins(result);
+            assert_eq!(ins[0], simulation.lookup(x.into()));
+            assert_eq!(dataflow.op(ins[1]), Op::Constant(negated));
+            // Subtracting from a constant is not rewritten.
+            simulation.action(&mut dataflow, &Action::Binary(BinaryOp::Sub, prec, dest, c.into(), x.into()));
+            assert_eq!(dataflow.op(simulation.lookup(dest.into())), Op::Binary(prec, BinaryOp::Sub));
+        }
+    }
+
+    /// Test that a `P32` identity is only applied to a truncated value.
+    #[test]
+    fn identities_p32() {
+        let before = convention();
+        let (x, zero, dest) = (REGISTERS[1], REGISTERS[2], REGISTERS[4]);
+        let mut dataflow = Dataflow::new(before.lives.len());
+        let mut simulation = Simulation::new(&dataflow, &before);
+        simulation.action(&mut dataflow, &Action::Constant(P32, zero, 0));
+        simulation.action(&mut dataflow, &Action::Binary(BinaryOp::Add, P32, dest, x.into(), zero.into()));
+        assert_eq!(dataflow.op(simulation.lookup(dest.into())), Op::Binary(P32, BinaryOp::Add));
+        simulation.action(&mut dataflow, &Action::Binary(BinaryOp::Or, P32, dest, dest.into(), zero.into()));
+        assert_eq!(dataflow.op(simulation.lookup(dest.into())), Op::Binary(P32, BinaryOp::Add));
+    }
+
+    /// Test that a shift followed by a mask becomes an `Extract`.
+    #[test]
+    fn extract() {
+        let before = convention();
+        let x = REGISTERS[1];
+        let (dest, shift, mask) = (REGISTERS[2], REGISTERS[4], REGISTERS[5]);
+        for (prec, amount, mask_value, expected) in [
+            (P64, 8, 0xFF, Some(UnaryOp::Extract(8, 8))),
+            (P32, 20, 0xFFFF, Some(UnaryOp::Extract(20, 12))),
+            (P32, 0, -1, Some(UnaryOp::Extract(0, 32))),
+            (P64, 60, 0x3, Some(UnaryOp::Extract(60, 2))),
+            (P64, 8, 0xF0, None),
+            (P32, 32, 0xFF, None),
+        ] {
+            let mut dataflow = Dataflow::new(before.lives.len());
+            let mut simulation = Simulation::new(&dataflow, &before);
+            for action in [
+                Action::Constant(P64, shift, amount),
+                Action::Binary(BinaryOp::Lsr, prec, dest, x.into(), shift.into()),
+                Action::Constant(P64, mask, mask_value),
+                Action::Binary(BinaryOp::And, prec, dest, mask.into(), dest.into()),
+            ] {
+                simulation.action(&mut dataflow, &action);
+            }
+            let node = simulation.lookup(dest.into());
+            if let Some(op) = expected {
+                assert_eq!(dataflow.op(node), Op::Unary(prec, op));
+                assert_eq!(dataflow.ins(node), &[simulation.lookup(x.into())]);
+            } else {
+                assert_eq!(dataflow.op(node), Op::Binary(prec, BinaryOp::And));
+            }
+        }
+    }
+
+    /// Test that selecting between a value and zero according to a
+    /// comparison becomes an `And`, but only if the comparison is at least
+    /// as wide as the `Select`.
+    #[test]
+    fn select_and() {
+        let before = convention();
+        let (x, y, c, zero, dest) = (REGISTERS[1], REGISTERS[2], REGISTERS[3], REGISTERS[4], REGISTERS[5]);
+        for (cmp_prec, prec, expected) in [
+            (P64, P64, true),
+            (P64, P32, true),
+            (P32, P32, true),
+            (P32, P64, false),
+        ] {
+            let mut dataflow = Dataflow::new(before.lives.len());
+            let mut simulation = Simulation::new(&dataflow, &before);
+            for action in [
+                Action::Binary(BinaryOp::Lt, cmp_prec, c, x.into(), y.into()),
+                Action::Constant(P64, zero, 0),
+                Action::Select(prec, dest, c.into(), x.into(), zero.into()),
+            ] {
+                simulation.action(&mut dataflow, &action);
+            }
+            let node = simulation.lookup(dest.into());
+            if expected {
+                assert_eq!(dataflow.op(node), Op::Binary(prec, BinaryOp::And));
+                assert_eq!(dataflow.ins(node), &[simulation.lookup(c.into()), simulation.lookup(x.into())]);
+            } else {
+                assert_eq!(dataflow.op(node), Op::Select(prec));
+            }
+        }
+        // The condition must be a comparison.
+        let mut dataflow = Dataflow::new(before.lives.len());
+        let mut simulation = Simulation::new(&dataflow, &before);
+        simulation.action(&mut dataflow, &Action::Constant(P64, zero, 0));
+        simulation.action(&mut dataflow, &Action::Select(P64, dest, c.into(), x.into(), zero.into()));
+        assert_eq!(dataflow.op(simulation.lookup(dest.into())), Op::Select(P64));
+    }
+
+    /// Test that `x + 1` twice makes one `Add`, but that two identical
+    /// `Load`s make two `Load`s.
+    #[test]
+    fn pure_and_impure() {
+        let before = convention();
+        let mut dataflow = Dataflow::new(before.lives.len());
+        let mut simulation = Simulation::new(&dataflow, &before);
+        let (x, one) = (REGISTERS[1], REGISTERS[4]);
+        let addr = Address {base: x.into(), offset: 8, width: Width::Eight};
+        for dest in [REGISTERS[2], REGISTERS[3]] {
+            simulation.action(&mut dataflow, &Action::Constant(P64, one, 1));
+            simulation.action(&mut dataflow, &Action::Binary(BinaryOp::Add, P64, dest, x.into(), one.into()));
+            simulation.action(&mut dataflow, &Action::Load(dest, addr));
+        }
+        let count = |op| dataflow.all_nodes().filter(|&node| dataflow.op(node) == op).count();
+        assert_eq!(count(Op::Binary(P64, BinaryOp::Add)), 1);
+        assert_eq!(count(Op::Load(8, Width::Eight)), 2);
+        assert_ne!(simulation.lookup(REGISTERS[2].into()), simulation.lookup(REGISTERS[3].into()));
+    }
+
+    #[test]
+    fn guard() {
+        let before = convention();
+        let mut dataflow = Dataflow::new(before.lives.len());
+        let mut simulation = Simulation::new(&dataflow, &before);
+        let guard = simulation.guard(&mut dataflow, Slot(1).into());
+        assert_eq!(dataflow.op(guard), Op::Guard);
+        assert_eq!(dataflow.ins(guard), &[dataflow.undefined(), dataflow.inputs()[4]]);
+        assert_eq!(simulation.sequence, guard);
+    }
+}
@@ -0,0 +1,69 @@
+use super::code::{Register};
+
+/// Receives notifications of the progress of [`optimize()`], for debugging.
+/// Every method does nothing by default.
+///
+/// [`Node`]s are identified by their index in the [`Dataflow`] graph.
+///
+/// [`optimize()`]: super::optimize
+/// [`Node`]: super::Node
+/// [`Dataflow`]: super::Dataflow
+pub trait Trace: Send + Sync {
+    /// Called when the optimizer starts to build the hot path or a cold
+    /// path, with `slots_used` [`Slot`]s on entry.
+    ///
+    /// [`Slot`]: super::code::Slot
+    fn on_walk_enter(&self, _slots_used: usize) {}
+
+    /// Called when the result of `node` is allocated to `register`.
+    fn on_allocation(&self, _node: usize, _register: Register) {}
+
+    /// Called for each instruction scheduled, in order. `node` is `None` for
+    /// an instruction that spills values.
+    fn on_instruction(&self, _node: Option<usize>) {}
+
+    /// Called when `node` is placed in a clock cycle. `cycle` is the
+    /// estimated number of cycles after the start of the hot path or cold
+    /// path at which `node` issues.
+    fn on_schedule(&self, _node: usize, _cycle: usize) {}
+
+    /// Called when a cold path accesses memory at an address computed by
+    /// `node`.
+    fn on_cold_address(&self, _node: usize) {}
+}
+
+/// A [`Trace`] that ignores everything.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoTrace;
+
+impl Trace for NoTrace {}
+
+/// A [`Trace`] that prints everything to stderr.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct StderrTrace;
+
+impl Trace for StderrTrace {
+    fn on_walk_enter(&self, slots_used: usize) {
+        eprintln!("Walk: slots_used = {}", slots_used);
+    }
+
+    fn on_allocation(&self, node: usize, register: Register) {
+        eprintln!("Allocate: Node({}) -> {:?}", node, register);
+    }
+
+    fn on_instruction(&self, node: Option<usize>) {
+        if let Some(node) = node {
+            eprintln!("Instruction: Node({})", node);
+        } else {
+            eprintln!("Instruction: Spill");
+        }
+    }
+
+    fn on_schedule(&self, node: usize, cycle: usize) {
+        eprintln!("Schedule: Node({}) at cycle {}", node, cycle);
+    }
+
+    fn on_cold_address(&self, node: usize) {
+        eprintln!("Cold path memory instruction Node({})", node);
+    }
+}
@@ -0,0 +1,1383 @@
+use super::{buffer, code};
+
+mod word;
+pub use word::{Word};
+
+mod label;
+pub use label::{Patch, Label};
+
+mod traits;
+pub use traits::{Lower, ExecuteFn, Execute, Target};
+
+pub mod x86_64;
+pub mod aarch64;
+
+/// The [`code::Register`] which holds the exit code on exit from Mijit.
+/// This is guaranteed to be [`code::REGISTERS`]`[[0]]`.
+pub const RESULT: code::Register = code::REGISTERS[0];
+
+#[cfg(target_arch="x86_64")]
+pub type Native = x86_64::Target;
+#[cfg(target_arch="aarch64")]
+pub type Native = aarch64::Target;
+
+/// Returns the current [`Target`]. Equivalent to [`Default::default()`].
+pub fn native() -> Native {
+    Default::default()
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use code::{Register, Variable, REGISTERS, GLOBAL, Slot, Precision, UnaryOp, BinaryOp, Width, Address, Action, NativeFunction};
+    use Precision::*;
+    use UnaryOp::*;
+    use BinaryOp::*;
+    use Width::*;
+    use Action::*;
+
+    /// A test harness for a `Native::Lowerer`.
+    pub struct VM {
+        pub lowerer: <Native as Target>::Lowerer,
+        pub entry: Label,
+    }
+
+    impl VM {
+        /// Constructs a `Native::Lowerer` and passes it to `compile()`.
+        /// The compiled code gets arguments in `inputs` and returns a result
+        /// in `RESULT`.
+        pub fn new(inputs: &[Register], compile: impl FnOnce(&mut dyn Lower)) -> Self {
+            let mut lowerer = native().lowerer();
+            let entry = lowerer.here();
+            lowerer.prologue();
+            for (i, &r) in inputs.iter().enumerate() {
+                // Load `GLOBAL[i]` into `r`.
+                assert_ne!(r, GLOBAL);
+                lowerer.action(Load(r, Address {base: GLOBAL.into(), offset: i as i32 * 8, width: Eight}));
+            }
+            compile(&mut lowerer);
+            lowerer.epilogue();
+            Self {lowerer, entry}
+        }
+
+        /// Calls the compiled code after setting the `inputs` to the specified
+        /// values, and checks that the return value is `expected_result`.
+        pub unsafe fn run(mut self, inputs: &mut [Word], expected_result: Word) -> Self {
+            let global = inputs.as_mut_ptr() as *mut ();
+            let observed_result = self.lowerer.execute(&self.entry, |f| {
+                f(global)
+            });
+            if observed_result != expected_result {
+                println!("inputs = {:?}", inputs);
+                println!("observed = {:?}", observed_result);
+                println!("expected = {:?}", expected_result);
+                panic!("observed != expected");
+            }
+            self
+        }
+    }
+
+    pub const R1: Register = REGISTERS[1];
+    pub const R2: Register = REGISTERS[2];
+    pub const R3: Register = REGISTERS[3];
+
+    pub const TEST_VALUES: [u64; 22] = [
+        0x0000000000000000,
+        0x0000000000000001,
+        0x0000000011111111,
+        0x000000007FFFFFFF,
+        0x0000000080000000,
+        0x00000000EEEEEEEE,
+        0x00000000FFFFFFFE,
+        0x00000000FFFFFFFF,
+        0x0123456789ABCDEF,
+        0x1111111111111111,
+        0x7FFFFFFFFFFFFFFF,
+        0x8000000000000000,
+        0xEEEEEEEEEEEEEEEE,
+        0xFEDCBA9876543210,
+        0xFFFFFFFF00000000,
+        0xFFFFFFFF00000001,
+        0xFFFFFFFF11111111,
+        0xFFFFFFFF7FFFFFFF,
+        0xFFFFFFFF80000000,
+        0xFFFFFFFFEEEEEEEE,
+        0xFFFFFFFFFFFFFFFE,
+        0xFFFFFFFFFFFFFFFF,
+    ];
+
+    /// Constructs a [`VM`], then calls it passing example values.
+    pub unsafe fn test_unary(compile: impl FnOnce(&mut dyn Lower), expected: impl Fn(u64) -> u64) {
+        let mut vm = VM::new(&[R1], |lo| compile(lo));
+        for x in TEST_VALUES {
+            vm = vm.run(
+                &mut [Word {u: x}],
+                Word {u: expected(x)},
+            );
+        }
+    }
+
+    /// Constructs a [`VM`], then calls it passing example pairs of values.
+    pub unsafe fn test_binary(compile: impl FnOnce(&mut dyn Lower), expected: impl Fn(u64, u64) -> u64) {
+        let mut vm = VM::new(&[R1, R2], |lo| compile(lo));
+        for x in TEST_VALUES {
+            for y in TEST_VALUES {
+                vm = vm.run(
+                    &mut [Word {u: x}, Word {u: y}],
+                    Word {u: expected(x, y)},
+                );
+            }
+        }
+    }
+
+    /// Constructs a [`VM`], then calls it passing a pointer.
+    pub unsafe fn test_mem(compile: impl FnOnce(&mut dyn Lower), expected: impl Fn(u64, &mut [u64; 2]) -> u64) {
+        let mut vm = VM::new(&[R1], |lo| compile(lo));
+        for x in TEST_VALUES {
+            let mut memory = [x, !x];
+            vm = vm.run(
+                &mut [Word {mp: memory.as_mut_ptr() as *mut ()}],
+                Word {u: expected(x, &mut memory)},
+            );
+        }
+    }
+
+    /// Test that the results of arithmetic operations do not depend on which
+    /// registers are used to compute them.
+    ///  - compile - Takes (lo, dest, src1, src2).
+    pub unsafe fn test_clobber(
+        compile: impl Fn(&mut dyn Lower, Register, Register, Register),
+    ) {
+        for dest in [R1, R2] {
+            let mut vm = VM::new(&[R1, R2], |lo| {
+                compile(lo, R3, R1, R2);
+                compile(lo, dest, R1, R2);
+                lo.action(Binary(Xor, P64, RESULT, dest.into(), R3.into()));
+            });
+            for x in TEST_VALUES {
+                for y in [1, 11, 31] {
+                    vm = vm.run(
+                        &mut [Word {u: x}, Word {u: y}],
+                        Word {u: 0},
+                    );
+                }
+            }
+        }
+    }
+
+    // Move, Constant, Push, Drop.
+
+    #[test]
+    fn push() {
+        unsafe {test_unary(
+            |lo| {
+                assert_eq!(*lo.slots_used_mut(), 0);
+                lo.action(Push(None, Some(R1.into())));
+                assert_eq!(*lo.slots_used_mut(), 2);
+                lo.action(Move(RESULT.into(), Slot(0).into()));
+                lo.action(Drop(1));
+                assert_eq!(*lo.slots_used_mut(), 0);
+            },
+            |x| x,
+        )};
+        unsafe {test_unary(
+            |lo| {
+                assert_eq!(*lo.slots_used_mut(), 0);
+                lo.action(Push(Some(R1.into()), None));
+                assert_eq!(*lo.slots_used_mut(), 2);
+                lo.action(Move(RESULT.into(), Slot(1).into()));
+                lo.action(Drop(1));
+                assert_eq!(*lo.slots_used_mut(), 0);
+            },
+            |x| x,
+        )};
+    }
+
+    #[test]
+    fn drop() {
+        unsafe {test_unary(
+            |lo| {
+                assert_eq!(*lo.slots_used_mut(), 0);
+                lo.action(Push(None, None));
+                assert_eq!(*lo.slots_used_mut(), 2);
+                lo.action(Push(None, Some(R1.into())));
+                assert_eq!(*lo.slots_used_mut(), 4);
+                lo.action(Push(None, None));
+                assert_eq!(*lo.slots_used_mut(), 6);
+                lo.action(Push(None, None));
+                assert_eq!(*lo.slots_used_mut(), 8);
+                lo.action(Drop(2));
+                assert_eq!(*lo.slots_used_mut(), 4);
+                lo.action(Move(RESULT.into(), Slot(2).into()));
+                assert_eq!(*lo.slots_used_mut(), 4);
+                lo.action(Drop(2));
+                assert_eq!(*lo.slots_used_mut(), 0);
+            },
+            |x| x,
+        )};
+    }
+
+    #[test]
+    fn move_() {
+        for variable in [R1.into(), Slot(0).into()] {
+            unsafe {test_unary(
+                |lo| {
+                    lo.action(Push(None, None));
+                    lo.action(Constant(P64, RESULT, 42));
+                    lo.action(Move(Slot(0).into(), RESULT.into()));
+                    lo.action(Move(variable, R1.into()));
+                    lo.action(Move(RESULT.into(), variable));
+                    lo.action(Drop(1));
+                },
+                |x| x,
+            )};
+        }
+    }
+
+    #[test]
+    fn constant() {
+        for x in TEST_VALUES {
+            let vm = VM::new(&[], |lo| {
+                lo.action(Constant(P32, RESULT, x as i64));
+            });
+            unsafe {vm.run(&mut [], Word {u: x as u32 as u64})};
+            let vm = VM::new(&[], |lo| {
+                lo.action(Constant(P64, RESULT, x as i64));
+            });
+            unsafe {vm.run(&mut [], Word {u: x})};
+        }
+    }
+
+    // UnaryOps.
+
+    #[test]
+    fn abs() {
+        unsafe {test_unary(
+            |lo| { lo.action(Unary(Abs, P32, RESULT, R1.into())); },
+            |x| (if x as i32 == i32::MIN { x as i32 } else { (x as i32).abs() }) as u32 as u64,
+        )};
+        unsafe {test_unary(
+            |lo| { lo.action(Unary(Abs, P64, RESULT, R1.into())); },
+            |x| (if x as i64 == i64::MIN { x as i64 } else { (x as i64).abs() }) as u64,
+        )};
+    }
+
+    #[test]
+    fn negate() {
+        unsafe {test_unary(
+            |lo| { lo.action(Unary(Negate, P32, RESULT, R1.into())); },
+            |x| (if x as i32 == i32::MIN { x as i32 } else { -(x as i32) }) as u32 as u64,
+        )};
+        unsafe {test_unary(
+            |lo| { lo.action(Unary(Negate, P64, RESULT, R1.into())); },
+            |x| (if x as i64 == i64::MIN {x as i64 } else { -(x as i64) }) as u64,
+        )};
+    }
+
+    #[test]
+    fn not() {
+        unsafe {test_unary(
+            |lo| { lo.action(Unary(Not, P32, RESULT, R1.into())); },
+            |x| !(x as u32) as u64,
+        )};
+        unsafe {test_unary(
+            |lo| { lo.action(Unary(Not, P64, RESULT, R1.into())); },
+            |x| !x,
+        )};
+    }
+
+    /// Returns the results of zero- and sign-extending `x` from `width` to
+    /// `prec`.
+    fn extend(prec: Precision, width: Width, x: u64) -> (u64, u64) {
+        let shift = 64 - width.bits();
+        let (u, s) = ((x << shift) >> shift, (((x << shift) as i64) >> shift) as u64);
+        match prec {
+            P32 => (u as u32 as u64, s as u32 as u64),
+            P64 => (u, s),
+        }
+    }
+
+    #[test]
+    fn uxt_sxt() {
+        // Every 16-bit input, with and without garbage in the high bits.
+        let inputs: Vec<u64> = (0..0x10000u64)
+            .flat_map(|x| [x, x | 0x9876_5432_1DB9_0000])
+            .chain(TEST_VALUES)
+            .collect();
+        for prec in [P32, P64] {
+            for width in [One, Two, Four, Eight] {
+                let mut uxt = VM::new(&[R1], |lo| {
+                    lo.action(Unary(Uxt(width), prec, RESULT, R1.into()));
+                });
+                let mut sxt = VM::new(&[R1], |lo| {
+                    lo.action(Unary(Sxt(width), prec, RESULT, R1.into()));
+                });
+                for &x in &inputs {
+                    let (u, s) = extend(prec, width, x);
+                    uxt = unsafe {uxt.run(&mut [Word {u: x}], Word {u})};
+                    sxt = unsafe {sxt.run(&mut [Word {u: x}], Word {u: s})};
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clz() {
+        // Pseudo-random inputs with every number of leading zeros.
+        let inputs: Vec<u64> = (0..0x10000u64)
+            .map(|i| i.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (i % 65).min(63) >> (i % 65 / 64))
+            .chain(TEST_VALUES)
+            .collect();
+        for prec in [P32, P64] {
+            let mut vm = VM::new(&[R1], |lo| {
+                lo.action(Unary(Clz, prec, RESULT, R1.into()));
+            });
+            for &x in &inputs {
+                let expected = match prec {
+                    P32 => (x as u32).leading_zeros(),
+                    P64 => x.leading_zeros(),
+                };
+                vm = unsafe {vm.run(&mut [Word {u: x}], Word {u: expected.into()})};
+            }
+        }
+    }
+
+    #[test]
+    fn ctz() {
+        // Pseudo-random inputs with every number of trailing zeros.
+        let inputs: Vec<u64> = (0..0x10000u64)
+            .map(|i| (i | 1).wrapping_mul(0x9E37_79B9_7F4A_7C15) << (i % 65).min(63) << (i % 65 / 64))
+            .chain(TEST_VALUES)
+            .collect();
+        for prec in [P32, P64] {
+            let mut vm = VM::new(&[R1], |lo| {
+                lo.action(Unary(Ctz, prec, RESULT, R1.into()));
+            });
+            for &x in &inputs {
+                let expected = match prec {
+                    P32 => (x as u32).trailing_zeros(),
+                    P64 => x.trailing_zeros(),
+                };
+                vm = unsafe {vm.run(&mut [Word {u: x}], Word {u: expected.into()})};
+            }
+        }
+    }
+
+    #[test]
+    fn popcount() {
+        // Pseudo-random inputs with every number of set bits.
+        let inputs: Vec<u64> = (0..0x10000u64)
+            .map(|i| i.wrapping_mul(0x9E37_79B9_7F4A_7C15) & (!0 >> (i % 64)))
+            .chain(TEST_VALUES)
+            .collect();
+        for prec in [P32, P64] {
+            let mut vm = VM::new(&[R1], |lo| {
+                lo.action(Unary(Popcount, prec, RESULT, R1.into()));
+            });
+            for &x in &inputs {
+                let expected = match prec {
+                    P32 => (x as u32).count_ones(),
+                    P64 => x.count_ones(),
+                };
+                vm = unsafe {vm.run(&mut [Word {u: x}], Word {u: expected.into()})};
+            }
+        }
+    }
+
+    /// Bit-fields `(shift, bits)` that fit in 32 or 64 bits.
+    const FIELDS: [(u8, u8); 14] = [
+        (0, 1), (0, 31), (0, 32), (1, 31), (8, 8), (24, 8), (31, 1),
+        (0, 33), (4, 40), (0, 63), (1, 63), (0, 64), (32, 32), (60, 4),
+    ];
+
+    #[test]
+    fn extract() {
+        for prec in [P32, P64] {
+            for (shift, bits) in FIELDS {
+                if shift as usize + bits as usize > prec.bits() { continue; }
+                unsafe {test_unary(
+                    |lo| { lo.action(Unary(Extract(shift, bits), prec, RESULT, R1.into())); },
+                    |x| crate::util::bits::extract(x, shift.into(), bits.into()),
+                )};
+            }
+        }
+    }
+
+    #[test]
+    fn clobber_unary() {
+        for op in [Abs, Negate, Not, Uxt(One), Sxt(Two), Clz, Ctz, Popcount, Extract(4, 20)] {
+            for prec in [P32, P64] {
+                unsafe {test_clobber(|lo, dest, src1, _| {
+                    lo.action(Unary(op, prec, dest, src1.into()));
+                })};
+            }
+        }
+    }
+
+    // BinaryOps.
+
+    #[test]
+    fn add() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Add, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| (x as u32).wrapping_add(y as u32) as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Add, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| x.wrapping_add(y),
+        )};
+    }
+
+    #[test]
+    fn sub() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Sub, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| (x as u32).wrapping_sub(y as u32) as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Sub, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| x.wrapping_sub(y),
+        )};
+    }
+
+    #[test]
+    fn mul() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Mul, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| (x as u32).wrapping_mul(y as u32) as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Mul, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| x.wrapping_mul(y),
+        )};
+    }
+
+    #[test]
+    fn mul_high() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(MulHigh, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| ((x as i32 as i64 * y as i32 as i64) >> 32) as u32 as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(MulHigh, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| ((x as i64 as i128 * y as i64 as i128) >> 64) as u64,
+        )};
+    }
+
+    #[test]
+    fn umul_high() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(UMulHigh, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| (x as u32 as u64 * y as u32 as u64) >> 32,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(UMulHigh, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| ((x as u128 * y as u128) >> 64) as u64,
+        )};
+    }
+
+    /// Test both halves of `0x1_0000_0001 * 0x1_0000_0001`, which is
+    /// `0x1_0000_0002_0000_0001`.
+    #[test]
+    fn mul_both_halves() {
+        const X: u64 = 0x1_0000_0001;
+        for (op, expected) in [(Mul, 0x0000_0002_0000_0001), (UMulHigh, 1), (MulHigh, 1)] {
+            let vm = VM::new(&[R1, R2], |lo| {
+                lo.action(Binary(op, P64, RESULT, R1.into(), R2.into()));
+            });
+            let _ = unsafe {vm.run(&mut [Word {u: X}, Word {u: X}], Word {u: expected})};
+        }
+    }
+
+    #[test]
+    fn udiv() {
+        // P32.
+        let mut vm = VM::new(&[R1, R2], |lo| {
+            lo.action(Binary(UDiv, P32, RESULT, R1.into(), R2.into()));
+        });
+        for x in TEST_VALUES {
+            let x2 = x as u32;
+            for y in TEST_VALUES {
+                let y2 = y as u32;
+                let expected = x2.checked_div(y2).unwrap_or(0);
+                vm = unsafe {vm.run(
+                    &mut [Word {u: x}, Word {u: y}],
+                    Word {u: expected as u64},
+                )};
+            }
+        }
+        // P64.
+        let mut vm = VM::new(&[R1, R2], |lo| {
+            lo.action(Binary(UDiv, P64, RESULT, R1.into(), R2.into()));
+        });
+        for x in TEST_VALUES {
+            let x2 = x as u64;
+            for y in TEST_VALUES {
+                let y2 = y as u64;
+                let expected = x2.checked_div(y2).unwrap_or(0);
+                vm = unsafe {vm.run(
+                    &mut [Word {u: x}, Word {u: y}],
+                    Word {u: expected},
+                )};
+            }
+        }
+    }
+
+    #[test]
+    fn sdiv() {
+        // P32.
+        let mut vm = VM::new(&[R1, R2], |lo| {
+            lo.action(Binary(SDiv, P32, RESULT, R1.into(), R2.into()));
+        });
+        for x in TEST_VALUES {
+            let x2 = x as i32;
+            for y in TEST_VALUES {
+                let y2 = y as i32;
+                let expected = if y2 == 0 { 0 } else { x2.wrapping_div(y2) };
+                vm = unsafe {vm.run(
+                    &mut [Word {u: x}, Word {u: y}],
+                    Word {u: expected as u32 as u64},
+                )};
+            }
+        }
+        // P64.
+        let mut vm = VM::new(&[R1, R2], |lo| {
+            lo.action(Binary(SDiv, P64, RESULT, R1.into(), R2.into()));
+        });
+        for x in TEST_VALUES {
+            let x2 = x as i64;
+            for y in TEST_VALUES {
+                let y2 = y as i64;
+                let expected = if y2 == 0 { 0 } else { x2.wrapping_div(y2) };
+                vm = unsafe {
+                    vm.run(&mut [Word {u: x}, Word {u: y}],
+                    Word {u: expected as u64},
+                )};
+            }
+        }
+    }
+
+    #[test]
+    fn urem() {
+        // P32.
+        let mut vm = VM::new(&[R1, R2], |lo| {
+            lo.action(Binary(URem, P32, RESULT, R1.into(), R2.into()));
+        });
+        for x in TEST_VALUES {
+            let x2 = x as u32;
+            for y in TEST_VALUES {
+                let y2 = y as u32;
+                let expected = x2.checked_rem(y2).unwrap_or(x2);
+                vm = unsafe {vm.run(
+                    &mut [Word {u: x}, Word {u: y}],
+                    Word {u: expected as u64},
+                )};
+            }
+        }
+        // P64.
+        let mut vm = VM::new(&[R1, R2], |lo| {
+            lo.action(Binary(URem, P64, RESULT, R1.into(), R2.into()));
+        });
+        for x in TEST_VALUES {
+            for y in TEST_VALUES {
+                let expected = x.checked_rem(y).unwrap_or(x);
+                vm = unsafe {vm.run(
+                    &mut [Word {u: x}, Word {u: y}],
+                    Word {u: expected},
+                )};
+            }
+        }
+    }
+
+    #[test]
+    fn srem() {
+        // P32.
+        let mut vm = VM::new(&[R1, R2], |lo| {
+            lo.action(Binary(SRem, P32, RESULT, R1.into(), R2.into()));
+        });
+        for x in TEST_VALUES {
+            let x2 = x as i32;
+            for y in TEST_VALUES {
+                let y2 = y as i32;
+                let expected = if y2 == 0 { x2 } else { x2.wrapping_rem(y2) };
+                vm = unsafe {vm.run(
+                    &mut [Word {u: x}, Word {u: y}],
+                    Word {u: expected as u32 as u64},
+                )};
+            }
+        }
+        // P64.
+        let mut vm = VM::new(&[R1, R2], |lo| {
+            lo.action(Binary(SRem, P64, RESULT, R1.into(), R2.into()));
+        });
+        for x in TEST_VALUES {
+            let x2 = x as i64;
+            for y in TEST_VALUES {
+                let y2 = y as i64;
+                let expected = if y2 == 0 { x2 } else { x2.wrapping_rem(y2) };
+                vm = unsafe {vm.run(
+                    &mut [Word {u: x}, Word {u: y}],
+                    Word {u: expected as u64},
+                )};
+            }
+        }
+    }
+
+    /// Representative shift amounts.
+    /// Shifts < 0 or >= word size are undefined.
+    const SHIFTS: [usize; 5] = [0, 1, 21, 31, 63];
+
+    #[test]
+    fn lsl() {
+        for shift in SHIFTS {
+            if shift < 32 {
+                unsafe {test_unary(
+                    |lo| {
+                        lo.action(Constant(P64, RESULT, shift as i64));
+                        lo.action(Binary(Lsl, P32, RESULT, R1.into(), RESULT.into()));
+                    },
+                    |x| ((x as u32) << shift) as u64,
+                )};
+            }
+            unsafe {test_unary(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, shift as i64));
+                    lo.action(Binary(Lsl, P64, RESULT, R1.into(), RESULT.into()));
+                },
+                |x| x << shift,
+            )};
+        }
+    }
+
+    #[test]
+    fn lsr() {
+        for shift in SHIFTS {
+            if shift < 32 {
+                unsafe {test_unary(
+                    |lo| {
+                        lo.action(Constant(P64, RESULT, shift as i64));
+                        lo.action(Binary(Lsr, P32, RESULT, R1.into(), RESULT.into()));
+                    },
+                    |x| ((x as u32) >> shift) as u64,
+                )};
+            }
+            unsafe {test_unary(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, shift as i64));
+                    lo.action(Binary(Lsr, P64, RESULT, R1.into(), RESULT.into()));
+                },
+                |x| x >> shift,
+            )};
+        }
+    }
+
+    #[test]
+    fn asr() {
+        for shift in SHIFTS {
+            if shift < 32 {
+                unsafe {test_unary(
+                    |lo| {
+                        lo.action(Constant(P64, RESULT, shift as i64));
+                        lo.action(Binary(Asr, P32, RESULT, R1.into(), RESULT.into()));
+                    },
+                    |x| ((x as i32) >> shift) as u32 as u64,
+                )};
+            }
+            unsafe {test_unary(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, shift as i64));
+                    lo.action(Binary(Asr, P64, RESULT, R1.into(), RESULT.into()));
+                },
+                |x| ((x as i64) >> shift) as u64,
+            )};
+        }
+    }
+
+    /// Representative rotation amounts.
+    /// Rotations are reduced modulo the word size.
+    const ROTATIONS: [usize; 7] = [0, 1, 21, 31, 32, 63, 100];
+
+    #[test]
+    fn rotl() {
+        for shift in ROTATIONS {
+            unsafe {test_unary(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, shift as i64));
+                    lo.action(Binary(RotL, P32, RESULT, R1.into(), RESULT.into()));
+                },
+                |x| (x as u32).rotate_left(shift as u32) as u64,
+            )};
+            unsafe {test_unary(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, shift as i64));
+                    lo.action(Binary(RotL, P64, RESULT, R1.into(), RESULT.into()));
+                },
+                |x| x.rotate_left(shift as u32),
+            )};
+        }
+    }
+
+    #[test]
+    fn rotr() {
+        for shift in ROTATIONS {
+            unsafe {test_unary(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, shift as i64));
+                    lo.action(Binary(RotR, P32, RESULT, R1.into(), RESULT.into()));
+                },
+                |x| (x as u32).rotate_right(shift as u32) as u64,
+            )};
+            unsafe {test_unary(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, shift as i64));
+                    lo.action(Binary(RotR, P64, RESULT, R1.into(), RESULT.into()));
+                },
+                |x| x.rotate_right(shift as u32),
+            )};
+        }
+    }
+
+    /// Test that `RotR` undoes `RotL`.
+    #[test]
+    fn rotate_round_trip() {
+        for prec in [P32, P64] {
+            unsafe {test_binary(
+                |lo| {
+                    lo.action(Binary(RotL, prec, RESULT, R1.into(), R2.into()));
+                    lo.action(Binary(RotR, prec, RESULT, RESULT.into(), R2.into()));
+                },
+                |x, _| match prec {
+                    P32 => x as u32 as u64,
+                    P64 => x,
+                },
+            )};
+        }
+    }
+
+    #[test]
+    fn and() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(And, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| ((x as u32) & (y as u32)) as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(And, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| x & y,
+        )};
+    }
+
+    #[test]
+    fn or() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Or, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| ((x as u32) | (y as u32)) as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Or, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| x | y,
+        )};
+    }
+
+    #[test]
+    fn xor() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Xor, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| ((x as u32) ^ (y as u32)) as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Xor, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| x ^ y,
+        )};
+    }
+
+    #[test]
+    fn lt() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Lt, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| (if (x as i32) < (y as i32) { !0 as u32 } else { 0 }) as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Lt, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| if (x as i64) < (y as i64) { !0 } else { 0 },
+        )};
+    }
+
+    #[test]
+    fn ult() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Ult, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| (if (x as u32) < (y as u32) { !0 as u32 } else { 0 }) as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Ult, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| if x < y { !0 } else { 0 },
+        )};
+    }
+
+    #[test]
+    fn eq() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Eq, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| (if (x as u32) == (y as u32) { !0 as u32 } else { 0 }) as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Eq, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| if x == y { !0 } else { 0 },
+        )};
+    }
+
+    #[test]
+    fn max() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Max, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| std::cmp::max(x as i32, y as i32) as u32 as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Max, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| std::cmp::max(x as i64, y as i64) as u64,
+        )};
+    }
+
+    #[test]
+    fn min() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Min, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| std::cmp::min(x as i32, y as i32) as u32 as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(Min, P64, RESULT, R1.into(), R2.into())); },
+            |x, y| std::cmp::min(x as i64, y as i64) as u64,
+        )};
+    }
+
+    #[test]
+    fn umax() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(UMax, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| std::cmp::max(x as u32, y as u32) as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(UMax, P64, RESULT, R1.into(), R2.into())); },
+            std::cmp::max,
+        )};
+    }
+
+    #[test]
+    fn umin() {
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(UMin, P32, RESULT, R1.into(), R2.into())); },
+            |x, y| std::cmp::min(x as u32, y as u32) as u64,
+        )};
+        unsafe {test_binary(
+            |lo| { lo.action(Binary(UMin, P64, RESULT, R1.into(), R2.into())); },
+            std::cmp::min,
+        )};
+    }
+
+    /// Unsigned and signed extrema disagree when the operands differ in sign.
+    #[test]
+    fn min_signedness() {
+        let x = Word {s: 0xFFFF_FFFE_u32 as i32 as i64};
+        let y = Word {u: 1};
+        for (op, expected) in [(Min, 0xFFFF_FFFE), (UMin, 1)] {
+            let vm = VM::new(&[R1, R2], |lo| {
+                lo.action(Binary(op, P32, RESULT, R1.into(), R2.into()));
+            });
+            unsafe { vm.run(&mut [x, y], Word {u: expected}) };
+        }
+    }
+
+    // Call.
+
+    /// A [`NativeFunction`] for tests.
+    extern "C" fn subtract(x: u64, y: u64) -> u64 { x.wrapping_sub(y) }
+
+    #[test]
+    fn call() {
+        unsafe {test_binary(
+            |lo| lo.action(Call(RESULT, NativeFunction(subtract), R1.into(), R2.into())),
+            |x, y| x.wrapping_sub(y),
+        )};
+        unsafe {test_binary(
+            |lo| {
+                lo.action(Call(R1, NativeFunction(subtract), R2.into(), R1.into()));
+                lo.action(Move(RESULT.into(), R1.into()));
+            },
+            |x, y| y.wrapping_sub(x),
+        )};
+        unsafe {test_clobber(|lo, dest, src1, src2| {
+            lo.action(Call(dest, NativeFunction(subtract), src1.into(), src2.into()));
+        })};
+    }
+
+    /// Test that `Call` can read its arguments from any [`Variable`], and
+    /// preserves all `Variable`s except `dest`.
+    #[test]
+    fn call_preserves() {
+        // Give every `Register` and `Slot` a different initial value.
+        let initial = |i: usize| 1u64 << (4 * i);
+        let vars: Vec<Variable> = REGISTERS.iter().map(|&r| r.into())
+            .chain([Slot(0).into(), Slot(1).into()])
+            .collect();
+        for (&dest, &src1) in REGISTERS.iter().zip(&vars) {
+            for &src2 in &vars {
+                let vm = VM::new(&[], |lo| {
+                    for (i, &r) in REGISTERS.iter().enumerate() {
+                        lo.action(Constant(P64, r, initial(i) as i64));
+                    }
+                    lo.action(Push(Some(R1.into()), Some(R2.into())));
+                    lo.action(Call(dest, NativeFunction(subtract), src1, src2));
+                    // Sum everything, giving `Slot(1)` twice the weight.
+                    for v in [Slot(0).into(), Slot(1).into(), Slot(1).into()] {
+                        lo.action(Binary(Add, P64, RESULT, RESULT.into(), v));
+                    }
+                    lo.action(Drop(1));
+                    for &r in &REGISTERS[1..] {
+                        lo.action(Binary(Add, P64, RESULT, RESULT.into(), r.into()));
+                    }
+                });
+                // Compute the expected result.
+                let mut values: Vec<u64> = (0..REGISTERS.len()).map(initial).collect();
+                let slots = [values[2], values[1]];
+                let read = |values: &[u64], v: Variable| match v {
+                    Variable::Register(r) => values[REGISTERS.iter().position(|&s| s == r).unwrap()],
+                    Variable::Slot(s) => slots[s.0],
+                };
+                let d = REGISTERS.iter().position(|&r| r == dest).unwrap();
+                values[d] = subtract(read(&values, src1), read(&values, src2));
+                let expected = values.iter().chain(&[slots[0], slots[1], slots[1]])
+                    .fold(0u64, |acc, &x| acc.wrapping_add(x));
+                let _ = unsafe {vm.run(&mut [], Word {u: expected})};
+            }
+        }
+    }
+
+    #[test]
+    fn clobber_binary() {
+        for op in [
+            Add, Sub, Mul, MulHigh, UMulHigh, UDiv, SDiv, URem, SRem,
+            Lsl, Lsr, Asr, RotL, RotR,
+            And, Or, Xor,
+            Lt, Ult, Eq,
+            Max, Min, UMax, UMin,
+        ] {
+            for prec in [P32, P64] {
+                unsafe {test_clobber(|lo, dest, src1, src2| {
+                    lo.action(Binary(op, prec, dest, src1.into(), src2.into()));
+                })};
+            }
+        }
+    }
+
+    // Select.
+
+    /// Test that `Select` chooses between two constants according to the
+    /// low `Precision` bits of the condition, with `Register` and `Slot`
+    /// operands.
+    #[test]
+    fn select() {
+        const X: i64 = 0x0123456789ABCDEF;
+        const Y: i64 = -2;
+        for prec in [P32, P64] {
+            let expected = |c: u64| prec.truncate(if prec.truncate(c as i64) != 0 { X } else { Y }) as u64;
+            unsafe {test_unary(
+                |lo| {
+                    lo.action(Constant(P64, R2, X));
+                    lo.action(Constant(P64, R3, Y));
+                    lo.action(Select(prec, RESULT, R1.into(), R2.into(), R3.into()));
+                },
+                expected,
+            )};
+            unsafe {test_unary(
+                |lo| {
+                    lo.action(Constant(P64, R2, X));
+                    lo.action(Constant(P64, R3, Y));
+                    lo.action(Push(Some(R2.into()), Some(R3.into())));
+                    lo.action(Push(Some(R1.into()), None));
+                    lo.action(Select(prec, RESULT, Slot(3).into(), Slot(1).into(), Slot(0).into()));
+                    lo.action(Drop(2));
+                },
+                expected,
+            )};
+        }
+    }
+
+    #[test]
+    fn clobber_select() {
+        for prec in [P32, P64] {
+            unsafe {test_clobber(|lo, dest, src1, src2| {
+                lo.action(Select(prec, dest, src1.into(), src1.into(), src2.into()));
+            })};
+            unsafe {test_clobber(|lo, dest, src1, src2| {
+                lo.action(Select(prec, dest, src2.into(), src1.into(), src2.into()));
+            })};
+        }
+    }
+
+    /// Test that `actions()` computes the same result as calling `action()`
+    /// for each `Action`, including when a target fuses them.
+    #[test]
+    fn fuse_constant() {
+        for op in [Add, Sub, And, Or, Xor] {
+            for const_prec in [P32, P64] {
+                for prec in [P32, P64] {
+                    for c in [0, 1, 0x7FFFFFFF, -0x80000000, -1, 0x123456789] {
+                        for constant_first in [false, true] {
+                            let operands = |k: Register| -> (Variable, Variable) {
+                                if constant_first { (k.into(), R1.into()) } else { (R1.into(), k.into()) }
+                            };
+                            let (src1, src2) = operands(R2);
+                            let (fused1, fused2) = operands(RESULT);
+                            let mut vm = VM::new(&[R1], |lo| {
+                                lo.action(Constant(const_prec, R2, c));
+                                lo.action(Binary(op, prec, R2, src1, src2));
+                                lo.actions(&[
+                                    Constant(const_prec, RESULT, c),
+                                    Binary(op, prec, RESULT, fused1, fused2),
+                                ]);
+                                lo.action(Binary(Xor, P64, RESULT, RESULT.into(), R2.into()));
+                            });
+                            for x in TEST_VALUES {
+                                vm = unsafe {vm.run(&mut [Word {u: x}], Word {u: 0})};
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // SlotAddress.
+
+    /// A [`NativeFunction`] that reads memory.
+    extern "C" fn read(address: u64, _: u64) -> u64 { unsafe {*(address as *const u64)} }
+
+    /// Test that the host can read a `Slot` via its address, and that the
+    /// compiled code can write one.
+    #[test]
+    fn slot_address() {
+        unsafe {test_binary(
+            |lo| {
+                lo.action(Push(Some(R1.into()), Some(R2.into())));
+                lo.action(SlotAddress(RESULT, Slot(0)));
+                lo.action(Call(RESULT, NativeFunction(read), RESULT.into(), RESULT.into()));
+                lo.action(SlotAddress(R3, Slot(1)));
+                lo.action(Store(R3, RESULT.into(), Address {base: R3.into(), offset: 0, width: Eight}));
+                lo.action(Binary(Sub, P64, RESULT, Slot(1).into(), R1.into()));
+                lo.action(Drop(1));
+            },
+            |x, y| y.wrapping_sub(x),
+        )};
+    }
+
+    // Load and Store.
+
+    #[test]
+    fn load() {
+        for (offset, mask) in [(0, 0), (8, !0)] {
+            unsafe {test_mem(
+                |lo| { lo.action(Load(RESULT, Address {base: R1.into(), offset, width: One})); },
+                |x, _| (x ^ mask) as u8 as u64,
+            )};
+            unsafe {test_mem(
+                |lo| { lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Two})); },
+                |x, _| (x ^ mask) as u16 as u64,
+            )};
+            unsafe {test_mem(
+                |lo| { lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Four})); },
+                |x, _| (x ^ mask) as u32 as u64,
+            )};
+            unsafe {test_mem(
+                |lo| { lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Eight})); },
+                |x, _| (x ^ mask),
+            )};
+        }
+    }
+
+    #[test]
+    fn store() {
+        const DATA: u64 = 0x5555555555555555;
+        for (offset, mask) in [(0, 0), (8, !0)] {
+            // Check returned address.
+            unsafe {test_mem(
+                |lo| {
+                    lo.action(Constant(P64, R2, DATA as i64));
+                    lo.action(Store(RESULT, R2.into(), Address {base: R1.into(), offset, width: Eight}));
+                },
+                |_, p| p.as_mut_ptr() as u64,
+            )};
+            // Check value stored at the returned address.
+            unsafe {test_mem(
+                |lo| {
+                    lo.action(Constant(P64, R2, DATA as i64));
+                    lo.action(Store(RESULT, R2.into(), Address {base: R1.into(), offset, width: Eight}));
+                    lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Eight}));
+                },
+                |_, _p| DATA,
+            )};
+            // Check value stored at the returned address when `dest == src`.
+            unsafe {test_mem(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, DATA as i64));
+                    lo.action(Store(RESULT, RESULT.into(), Address {base: R1.into(), offset, width: Eight}));
+                    lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Eight}));
+                },
+                |_, _p| DATA,
+            )};
+            // Check value stored at the returned address when `dest == addr`.
+            unsafe {test_mem(
+                |lo| {
+                    lo.action(Constant(P64, R2, DATA as i64));
+                    lo.action(Move(RESULT.into(), R1.into()));
+                    lo.action(Store(RESULT, R2.into(), Address {base: RESULT.into(), offset, width: Eight}));
+                    lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Eight}));
+                },
+                |_, _p| DATA,
+            )};
+            // Check all `Width`s.
+            unsafe {test_mem(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, DATA as i64));
+                    lo.action(Store(R1, RESULT.into(), Address {base: R1.into(), offset, width: One}));
+                    lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Eight}));
+                },
+                |x, _| {
+                    let y = x ^ mask;
+                    (y ^ DATA) as u8 as u64 ^ y
+                },
+            )};
+            unsafe {test_mem(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, DATA as i64));
+                    lo.action(Store(R1, RESULT.into(), Address {base: R1.into(), offset, width: Two}));
+                    lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Eight}));
+                },
+                |x, _| {
+                    let y = x ^ mask;
+                    (y ^ DATA) as u16 as u64 ^ y
+                },
+            )};
+            unsafe {test_mem(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, DATA as i64));
+                    lo.action(Store(R1, RESULT.into(), Address {base: R1.into(), offset, width: Four}));
+                    lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Eight}));
+                },
+                |x, _| {
+                    let y = x ^ mask;
+                    (y ^ DATA) as u32 as u64 ^ y
+                },
+            )};
+            unsafe {test_mem(
+                |lo| {
+                    lo.action(Constant(P64, RESULT, DATA as i64));
+                    lo.action(Store(R1, RESULT.into(), Address {base: R1.into(), offset, width: Eight}));
+                    lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Eight}));
+                },
+                |_, _| DATA,
+            )};
+        }
+    }
+
+    #[test]
+    fn atomic_cas() {
+        const DATA: u64 = 0x5555555555555555;
+        for (offset, mask) in [(0, 0), (8, !0)] {
+            for width in [One, Two, Four, Eight] {
+                let addr = Address {base: R1.into(), offset, width};
+                let truncate = |y: u64| y & (!0 >> (64 - width.bits()));
+                // Check the returned value, whether or not the swap happens.
+                for delta in [0, 1] {
+                    unsafe {test_mem(
+                        |lo| {
+                            lo.action(Load(R2, addr));
+                            lo.action(Constant(P64, R3, delta));
+                            lo.action(Binary(Add, P64, R2, R2.into(), R3.into()));
+                            lo.action(Constant(P64, R3, DATA as i64));
+                            lo.action(AtomicCas(RESULT, R2.into(), R3.into(), addr));
+                        },
+                        |x, _| truncate(x ^ mask),
+                    )};
+                }
+                // Check the value in memory when the swap happens.
+                unsafe {test_mem(
+                    |lo| {
+                        lo.action(Load(RESULT, addr));
+                        lo.action(Constant(P64, R2, DATA as i64));
+                        lo.action(AtomicCas(R2, RESULT.into(), R2.into(), addr));
+                        lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Eight}));
+                    },
+                    |x, _| {
+                        let y = x ^ mask;
+                        truncate(y ^ DATA) ^ y
+                    },
+                )};
+                // Check the value in memory when the swap does not happen.
+                unsafe {test_mem(
+                    |lo| {
+                        lo.action(Load(R2, addr));
+                        lo.action(Unary(UnaryOp::Not, P64, R2, R2.into()));
+                        lo.action(Constant(P64, RESULT, DATA as i64));
+                        lo.action(AtomicCas(R2, R2.into(), RESULT.into(), addr));
+                        lo.action(Load(RESULT, Address {base: R1.into(), offset, width: Eight}));
+                    },
+                    |x, _| x ^ mask,
+                )};
+            }
+        }
+    }
+
+    /// Test that code still runs after the buffer has been reallocated,
+    /// including code that reads constants from the buffer.
+    #[test]
+    fn grow() {
+        const N: u64 = 1000;
+        let mut vm = VM::new(&[R1], |lo| {
+            lo.action(Move(R3.into(), R1.into()));
+            for i in 0..N {
+                lo.action(Constant(P64, R2, i as i64));
+                lo.action(Binary(Add, P64, R1, R1.into(), R2.into()));
+            }
+            // `Lt` might read constants placed at the start of the buffer.
+            lo.action(Binary(Lt, P64, R2, R3.into(), R1.into()));
+            lo.action(Binary(Sub, P64, RESULT, R1.into(), R2.into()));
+            assert!(lo.here().target().unwrap() > 0x1000);
+        });
+        for x in [0, 1, 1000] {
+            vm = unsafe { vm.run(&mut [Word {u: x}], Word {u: x + N * (N - 1) / 2 + 1}) };
+        }
+    }
+
+    /// Test that code can be appended to a buffer after executing it.
+    #[test]
+    fn compile_after_execute() {
+        let mut lo = native().lowerer();
+        let compile = |lo: &mut <Native as Target>::Lowerer, value| {
+            let entry = lo.here();
+            lo.prologue();
+            lo.action(Constant(P64, RESULT, value));
+            lo.epilogue();
+            entry
+        };
+        let first = compile(&mut lo, 1);
+        assert_eq!(lo.execute(&first, |f| unsafe { f(std::ptr::null_mut()) }), Word {s: 1});
+        let second = compile(&mut lo, 2);
+        assert_eq!(lo.execute(&second, |f| unsafe { f(std::ptr::null_mut()) }), Word {s: 2});
+        assert_eq!(lo.execute(&first, |f| unsafe { f(std::ptr::null_mut()) }), Word {s: 1});
+    }
+
+    // TestOps.
+
+    const TRUE: u64 = !0;
+    const FALSE: u64 = 0;
+
+    #[test]
+    fn if_eq() {
+        for y in TEST_VALUES {
+            let mut vm = VM::new(&[R1], |lo| {
+                let mut else_ = Label::new(None);
+                let mut endif = Label::new(None);
+                lo.if_eq((R1.into(), y), &mut else_);
+                lo.action(Constant(P64, RESULT, FALSE as i64));
+                lo.jump(&mut endif);
+                lo.define(&mut else_);
+                lo.action(Constant(P64, RESULT, TRUE as i64));
+                lo.define(&mut endif);
+            });
+            for x in TEST_VALUES {
+                vm = unsafe {vm.run(
+                    &mut [Word {u: x}],
+                    Word {u: if x == y { TRUE } else { FALSE }},
+                )};
+            }
+        }
+    }
+
+    #[test]
+    fn if_ne() {
+        for y in TEST_VALUES {
+            let mut vm = VM::new(&[R1], |lo| {
+                let mut else_ = Label::new(None);
+                let mut endif = Label::new(None);
+                lo.if_ne((R1.into(), y), &mut else_);
+                lo.action(Constant(P64, RESULT, FALSE as i64));
+                lo.jump(&mut endif);
+                lo.define(&mut else_);
+                lo.action(Constant(P64, RESULT, TRUE as i64));
+                lo.define(&mut endif);
+            });
+            for x in TEST_VALUES {
+                vm = unsafe {vm.run(
+                    &mut [Word {u: x}],
+                    Word {u: if x != y { TRUE } else { FALSE }},
+                )};
+            }
+        }
+    }
+
+    // Test extremes.
+
+    /// Generate a pseudo-random permutation of size `size`.
+    fn permutation(size: usize) -> Vec<usize> {
+        let mut nats: Vec<usize> = (0..size).collect();
+        let mut seed: u32 = 1;
+        (0..size).map(|_| {
+            seed = seed.wrapping_mul(314159265).wrapping_add(271828183);
+            let index = ((nats.len() as u64) * (seed as u64)) >> 32;
+            nats.swap_remove(index as usize)
+        }).collect()
+    }
+
+    #[test]
+    fn long_jump() {
+        // Choose an order in which to visit a large number of blocks.
+        const SIZE: usize = 0x10000;
+        let permutation = permutation(SIZE);
+        // Work out which block each block jumps to.
+        let mut nexts: Vec<Option<usize>> = vec![None; SIZE];
+        let mut prev = permutation[0];
+        for &p in &permutation[1..SIZE] {
+            nexts[prev] = Some(p);
+            prev = p;
+        }
+        // Choose a pseudo-random constant per block.
+        let constants: Vec<u64> = permutation.iter().map(|&p|
+            (p as u64).wrapping_mul(39564853453457569)
+        ).collect();
+        // Compile all the blocks.
+        let vm = VM::new(&[], |lo| {
+            let mut labels: Vec<Label> = permutation.iter().map(|_| Label::new(None)).collect();
+            let mut exit = Label::new(None);
+            lo.action(Constant(P64, RESULT, 0));
+            lo.jump(&mut labels[permutation[0]]);
+            for i in 0..permutation.len() {
+                lo.define(&mut labels[i]);
+                // Rotate `RESULT` right by 21 places.
+                lo.action(Constant(P64, R2, 21));
+                lo.action(Binary(Lsr, P64, R1, RESULT.into(), R2.into()));
+                lo.action(Constant(P64, R2, 64 - 21));
+                lo.action(Binary(Lsl, P64, RESULT, RESULT.into(), R2.into()));
+                lo.action(Binary(Or, P64, RESULT, RESULT.into(), R1.into()));
+                // Add `constants[i]` to `RESULT`.
+                lo.action(Constant(P64, R2, constants[i] as i64));
+                lo.action(Binary(Add, P64, RESULT, RESULT.into(), R2.into()));
+                // Jump to next block.
+                if let Some(next) = nexts[i] {
+                    lo.jump(&mut labels[next]);
+                } else {
+                    lo.jump(&mut exit);
+                }
+            }
+            lo.define(&mut exit);
+        });
+        // Check against the expected result.
+        let mut expected: u64 = 0;
+        for p in permutation {
+            expected = crate::util::rotate_right(expected, 21).wrapping_add(constants[p]);
+        }
+        unsafe {vm.run(&mut [], Word {u: expected})};
+    }
+}
@@ -0,0 +1,37 @@
+/// Optional instruction set extensions that the [`Lowerer`] may use.
+///
+/// [`Lowerer`]: super::Lowerer
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Features {
+    /// Bit Manipulation Instruction Set 1 (e.g. `ANDN`, `TZCNT`).
+    pub bmi1: bool,
+    /// The `LZCNT` instruction.
+    pub lzcnt: bool,
+    /// The `POPCNT` instruction.
+    pub popcnt: bool,
+}
+
+impl Features {
+    /// No optional features. Code generated with these features will run on
+    /// any x86_64 CPU.
+    pub const NONE: Self = Features {bmi1: false, lzcnt: false, popcnt: false};
+
+    /// Query the CPU Mijit is running on, using `CPUID`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn detect() -> Self {
+        Features {
+            bmi1: is_x86_feature_detected!("bmi1"),
+            lzcnt: is_x86_feature_detected!("lzcnt"),
+            popcnt: is_x86_feature_detected!("popcnt"),
+        }
+    }
+
+    /// Returns `NONE`, because we are not running on an x86_64 CPU.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub fn detect() -> Self { Self::NONE }
+}
+
+impl Default for Features {
+    /// Equivalent to [`Features::detect()`].
+    fn default() -> Self { Self::detect() }
+}
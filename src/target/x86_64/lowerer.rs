@@ -0,0 +1,1760 @@
+use std::sync::atomic::{AtomicU64};
+
+use crate::util::{AsUsize, bits};
+use super::{
+    buffer, code,
+    Lower, Word, Patch, Label, RESULT,
+    Assembler, Features, Register, BinaryOp, ShiftOp, Condition, Width,
+    CALLEE_SAVES, CALLER_SAVES, ARGUMENTS, RESULTS,
+};
+use buffer::{Buffer, Protect, Mmap};
+use code::{Precision, Variable, Action, NativeFunction, GLOBAL, Slot};
+use Register::*;
+use Precision::*;
+use BinaryOp::*;
+use ShiftOp::*;
+
+//-----------------------------------------------------------------------------
+
+/// The constants that [`Lowerer`] requires to be in the [`Buffer`].
+pub const CONSTANTS: [Word; 8] = [
+    Word {u: 0},
+    Word {u: 0}, // unused
+    Word {u: 0}, // unused
+    Word {u: 0}, // unused
+    Word {u: 0}, // unused
+    Word {u: 0}, // unused
+    Word {u: 0}, // unused
+    Word {u: 0}, // unused
+];
+
+/// The address of zero.
+const ZERO_ADDRESS: usize = 0;
+
+/// The [`Register`] used as a temporary variable.
+const TEMP: Register = R12;
+
+/// The minimum number of cases for which `jump_table()` assembles a table.
+const MIN_JUMP_TABLE: usize = 4;
+
+/// The size in bytes of each entry of a jump table, i.e. of `const_jump()`.
+const JUMP_SIZE: usize = 6;
+
+/// The size in bytes of the code that `jump_table()` assembles between the
+/// `push` and the table.
+const DISPATCH_SIZE: usize = 15;
+
+/// The registers available for allocation. This omits:
+///  - `TEMP`, which is used as temporary workspace.
+// TODO: Write a test that compares this to `ALL_REGISTERS`.
+pub const ALLOCATABLE_REGISTERS: [Register; 14] =
+    [RA, RD, RC, RB, RBP, RSI, RDI, R8, R9, R10, R11, R13, R14, R15];
+
+impl From<code::Register> for Register {
+    fn from(r: code::Register) -> Self {
+        ALLOCATABLE_REGISTERS[r.as_usize()]
+    }
+}
+
+impl From<code::Width> for Width {
+    fn from(w: code::Width) -> Self {
+        use code::Width::*;
+        match w {
+            One => Width::U8,
+            Two => Width::U16,
+            Four => Width::U32,
+            Eight => Width::U64,
+        }
+    }
+}
+
+/// Returns the sign-extending [`Width`] corresponding to `w`.
+fn signed(w: code::Width) -> Width {
+    use code::Width::*;
+    match w {
+        One => Width::S8,
+        Two => Width::S16,
+        Four => Width::S32,
+        Eight => Width::S64,
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+/// A low-level analogue of `code::Variable`, which can hold unallocatable
+/// [`Register`]s.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+enum Value {
+    Register(Register),
+    Slot(Slot),
+}
+
+impl From<Register> for Value {
+    fn from(r: Register) -> Self {
+        Value::Register(r)
+    }
+}
+
+impl From<Slot> for Value {
+    fn from(s: Slot) -> Self {
+        Value::Slot(s)
+    }
+}
+
+impl From<code::Register> for Value {
+    fn from(r: code::Register) -> Self {
+        Value::Register(r.into())
+    }
+}
+
+impl From<code::Variable> for Value {
+    fn from(v: code::Variable) -> Self {
+        match v {
+            code::Variable::Register(reg) => reg.into(),
+            code::Variable::Slot(slot) => slot.into(),
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+pub struct Lowerer<B: Buffer> {
+    /// The underlying [`Assembler`].
+    a: Assembler<B>,
+    /// The number of stack-allocated spill [`Slot`]s.
+    slots_used: usize,
+    /// The instruction set extensions we may use.
+    features: Features,
+}
+
+impl<B: Buffer> Lowerer<B> {
+    /// Constructs a `Lowerer` that uses no optional instruction set
+    /// extensions.
+    pub fn new() -> Self {
+        Self::with_features(Features::NONE)
+    }
+
+    /// Constructs a `Lowerer` that uses the specified instruction set
+    /// extensions.
+    pub fn with_features(features: Features) -> Self {
+        let mut a = Assembler::new();
+        // Fill the first cache line with useful constants.
+        for &word in &CONSTANTS {
+            a.write_imm64(unsafe {word.s});
+        }
+        Self {a, slots_used: 0, features}
+    }
+
+    /// Apply `callback` to the contained [`Assembler`].
+    pub fn use_assembler<T>(
+        mut self,
+        callback: impl FnOnce(Assembler<B>) -> std::io::Result<(Assembler<B>, T)>,
+    ) -> std::io::Result<(Self, T)> {
+        let (a, ret) = callback(self.a)?;
+        self.a = a;
+        Ok((self, ret))
+    }
+
+    /// Put `value` in `dest`.
+    fn const_(&mut self, prec: Precision, dest: impl Into<Register>, value: i64) {
+        let dest = dest.into();
+        self.a.const_(prec, dest, value);
+    }
+
+    /// Apply `op` to `dest` and `value`.
+    fn const_op(&mut self, op: BinaryOp, prec: Precision, dest: impl Into<Register>, value: i32) {
+        let dest = dest.into();
+        self.a.const_op(op, prec, dest, value);
+    }
+
+    /// Conditional branch.
+    fn jump_if(&mut self, cc: Condition, target: &mut Label) {
+        let patch = self.a.jump_if(cc, target.target());
+        target.push(patch);
+    }
+
+    /// Unconditional jump to a constant.
+    fn const_jump(&mut self, target: &mut Label) {
+        let patch = self.a.const_jump(target.target());
+        target.push(patch);
+    }
+
+    /// Unconditional call to a constant.
+    #[allow(dead_code)]
+    fn const_call(&mut self, target: &mut Label) {
+        let patch = self.a.const_call(target.target());
+        target.push(patch);
+    }
+
+    /// Move `src` to `dest` if they are different.
+    fn move_(&mut self, dest: impl Into<Register>, src: impl Into<Register>) {
+        let dest = dest.into();
+        let src = src.into();
+        if dest != src {
+            self.a.move_(P64, dest, src);
+        }
+    }
+
+    /// Move `src` to `TEMP` if `src` is `dest`.
+    fn move_away_from(&mut self, src: impl Into<Value>, dest: impl Into<Register>) -> Value {
+        let src = src.into();
+        let dest = dest.into();
+        if src == dest.into() {
+            self.move_(TEMP, dest);
+            TEMP.into()
+        } else {
+            src
+        }
+    }
+
+    /// Returns the base and offset of `slot` in the stack-allocated data.
+    fn slot_address(&self, slot: Slot) -> (Register, i32) {
+        assert!(
+            Slot::try_new(slot.0, self.slots_used).is_some(),
+            "{:?} out of bounds (slots_used={})", slot, self.slots_used,
+        );
+        (RSP, (((self.slots_used - 1) - slot.0) * 8) as i32)
+    }
+
+    /// If `src` is a Register, returns it, otherwise loads it into `reg` and
+    /// returns `reg`.
+    fn src_to_register(&mut self, src: impl Into<Value>, reg: impl Into<Register>) -> Register {
+        let src = src.into();
+        let reg = reg.into();
+        match src {
+            Value::Register(src) => src,
+            Value::Slot(slot) => {
+                self.a.load(P64, reg, self.slot_address(slot));
+                reg
+            },
+        }
+    }
+
+    /// Calls `a.op()` or `a.load_op()` depending on whether `src` is a Register
+    /// or a Slot.
+    fn value_op(&mut self, op: BinaryOp, prec: Precision, dest: impl Into<Register>, src: impl Into<Value>) {
+        let dest = dest.into();
+        let src = src.into();
+        match src {
+            Value::Register(src) => {
+                self.a.op(op, prec, dest, src);
+            },
+            Value::Slot(slot) => {
+                self.a.load_op(op, prec, dest, self.slot_address(slot));
+            },
+        }
+    }
+
+    fn value_move_if(&mut self, cc: Condition, prec: Precision, dest: impl Into<Register>, src: impl Into<Value>) {
+        let dest = dest.into();
+        let src = src.into();
+        match src {
+            Value::Register(src) => {
+                self.a.move_if(cc, prec, dest, src);
+            },
+            Value::Slot(slot) => {
+                self.a.load_if(cc, prec, dest, self.slot_address(slot));
+            },
+        }
+    }
+
+    /// Sign- or zero-extend `src` according to `type_`.
+    fn value_move_narrow(&mut self, prec: Precision, type_: Width, dest: impl Into<Register>, src: impl Into<Value>) {
+        let dest = dest.into();
+        let src = src.into();
+        match src {
+            Value::Register(src) => {
+                self.a.move_narrow(prec, type_, dest, src);
+            },
+            Value::Slot(slot) => {
+                self.a.load_narrow(prec, type_, dest, self.slot_address(slot));
+            },
+        }
+    }
+
+    /// Select how to assemble an asymmetric `BinaryOp` such as `Sub`.
+    fn asymmetric_binary(
+        &mut self,
+        dest: impl Into<Register>,
+        src1: impl Into<Value>,
+        src2: impl Into<Value>,
+        callback: impl FnOnce(&mut Self, Register, Value),
+    ) {
+        let dest = dest.into();
+        let src2 = self.move_away_from(src2, dest);
+        let src1 = self.src_to_register(src1, dest);
+        self.move_(dest, src1);
+        callback(self, dest, src2);
+    }
+
+    /// Select how to assemble a symmetric `BinaryOp` such as `Add`.
+    fn symmetric_binary(
+        &mut self,
+        dest: impl Into<Register>,
+        src1: impl Into<Value>,
+        src2: impl Into<Value>,
+        callback: impl FnOnce(&mut Self, Register, Value),
+    ) {
+        let dest = dest.into();
+        let src1 = src1.into();
+        let src2 = src2.into();
+        #[allow(clippy::if_same_then_else)]
+        if !matches!(src1, Value::Register(_)) {
+            // We get better code if `src1` is a Register, so swap with `src2`.
+            self.asymmetric_binary(dest, src2, src1, callback);
+        } else if src2 == Value::Register(dest) {
+            // We get better code if `src1` is `dest`, so swap with `src2`.
+            self.asymmetric_binary(dest, src2, src1, callback);
+        } else {
+            self.asymmetric_binary(dest, src1, src2, callback);
+        }
+    }
+
+    /// Assembles a division operation, and moves the quotient or the
+    /// remainder to `dest`. If the denominator is zero, the quotient is zero
+    /// and the remainder is the numerator, as on AArch64. `RA` and `RD` are
+    /// preserved unless `dest` is one of them.
+    fn div(
+        &mut self,
+        prec: Precision,
+        dest: impl Into<Register>,
+        src1: impl Into<Value>,
+        src2: impl Into<Value>,
+        is_signed: bool,
+        is_remainder: bool,
+    ) {
+        self.a.push(RA);
+        self.a.push(RD);
+        self.slots_used += 2;
+        let src2 = self.src_to_register(src2, TEMP);
+        self.move_(TEMP, src2);
+        let src1 = self.src_to_register(src1, RA);
+        self.move_(RA, src1);
+        let mut zero = Label::new(None);
+        let mut done = Label::new(None);
+        self.const_op(Cmp, prec, TEMP, 0);
+        self.jump_if(Condition::Z, &mut zero);
+        if is_signed {
+            // Dividing the most negative number by `-1` would trap.
+            // Negate instead, which wraps, as on AArch64.
+            let mut divide = Label::new(None);
+            self.const_op(Cmp, prec, TEMP, -1);
+            self.jump_if(Condition::NZ, &mut divide);
+            self.const_(prec, RD, 0);
+            self.a.op(Sub, prec, RD, RA);
+            self.move_(RA, RD);
+            self.const_(prec, RD, 0);
+            self.const_jump(&mut done);
+            self.define(&mut divide);
+            self.move_(RD, RA);
+            self.a.const_shift(Sar, prec, RD, (prec.bits() - 1) as u8);
+            self.a.sdiv(prec, TEMP);
+        } else {
+            self.const_(prec, RD, 0);
+            self.a.udiv(prec, TEMP);
+        }
+        self.const_jump(&mut done);
+        self.define(&mut zero);
+        self.a.move_(prec, RD, RA);
+        self.const_(prec, RA, 0);
+        self.define(&mut done);
+        self.move_(TEMP, if is_remainder { RD } else { RA });
+        self.a.pop(RD);
+        self.a.pop(RA);
+        self.slots_used -= 2;
+        self.move_(dest, TEMP);
+    }
+
+    /// Assembles a long multiplication, and moves the high half of the
+    /// product to `dest`. `RA` and `RD` are preserved unless `dest` is one
+    /// of them.
+    fn mul_high(
+        &mut self,
+        prec: Precision,
+        dest: impl Into<Register>,
+        src1: impl Into<Value>,
+        src2: impl Into<Value>,
+        is_signed: bool,
+    ) {
+        self.a.push(RA);
+        self.a.push(RD);
+        self.slots_used += 2;
+        let src2 = self.src_to_register(src2, TEMP);
+        self.move_(TEMP, src2);
+        let src1 = self.src_to_register(src1, RA);
+        self.move_(RA, src1);
+        if is_signed {
+            self.a.smul_long(prec, TEMP);
+        } else {
+            self.a.umul_long(prec, TEMP);
+        }
+        self.move_(TEMP, RD);
+        self.a.pop(RD);
+        self.a.pop(RA);
+        self.slots_used -= 2;
+        self.move_(dest, TEMP);
+    }
+
+    /// Select how to assemble a shift `BinaryOp` such as `Shl`.
+    fn shift_binary(&mut self, op: ShiftOp, prec: Precision, dest: impl Into<Register>, src1: impl Into<Value>, src2: impl Into<Value>) {
+        let mut dest = dest.into();
+        let mut src1 = src1.into();
+        let src2 = src2.into();
+        let save_rc = src2 != Value::Register(RC) || (dest == RC && src1 != Value::Register(RC));
+        if save_rc {
+            if dest != RC || src1 == Value::Register(RC) {
+                self.move_(TEMP, RC);
+            }
+            if dest == RC {
+                dest = TEMP;
+            }
+            if src1 == Value::Register(RC) {
+                src1 = Value::Register(TEMP);
+            }
+        }
+        let src2 = self.src_to_register(src2, RC);
+        self.move_(RC, src2);
+        let src1 = self.src_to_register(src1, dest);
+        self.move_(dest, src1);
+        self.a.shift(op, prec, dest);
+        if save_rc {
+            self.move_(RC, TEMP);
+        }
+    }
+
+    /// Assembles code to compute `!src1 & src2` into `dest`.
+    /// Uses `ANDN` if BMI1 is available.
+    fn and_not(&mut self, prec: Precision, dest: impl Into<Register>, src1: impl Into<Value>, src2: impl Into<Value>) {
+        let dest = dest.into();
+        if self.features.bmi1 {
+            let src1 = self.src_to_register(src1, TEMP);
+            let src2 = self.src_to_register(src2, if src1 == dest { TEMP } else { dest });
+            self.a.andn(prec, dest, src1, src2);
+        } else {
+            self.asymmetric_binary(dest, src1, src2, |l, dest, src2| {
+                l.const_op(Xor, prec, dest, -1);
+                l.value_op(And, prec, dest, src2);
+            });
+        }
+    }
+
+    /// Assembles code to count the leading zeros of `src` into `dest`.
+    /// Uses `LZCNT` if available.
+    fn count_leading_zeros(&mut self, prec: Precision, dest: impl Into<Register>, src: impl Into<Value>) {
+        let dest = dest.into();
+        let src = self.src_to_register(src, dest);
+        if self.features.lzcnt {
+            self.a.lzcnt(prec, dest, src);
+        } else {
+            // `BSR` finds the most significant set bit, which `XOR` converts
+            // to a count of leading zeros. For zero, use `2 * bits - 1`,
+            // which `XOR` converts to `bits`.
+            let bits = prec.bits() as i32;
+            self.const_(P32, TEMP, (2 * bits - 1).into());
+            self.a.bsr(prec, dest, src);
+            self.a.move_if(Condition::Z, prec, dest, TEMP);
+            self.const_op(Xor, prec, dest, bits - 1);
+        }
+    }
+
+    /// Assemble code to count the trailing zeros of `src`.
+    fn count_trailing_zeros(&mut self, prec: Precision, dest: impl Into<Register>, src: impl Into<Value>) {
+        let dest = dest.into();
+        let src = self.src_to_register(src, dest);
+        if self.features.bmi1 {
+            self.a.tzcnt(prec, dest, src);
+        } else {
+            // `BSF` finds the least significant set bit, which is the count
+            // of trailing zeros, except for zero.
+            let bits = prec.bits() as i32;
+            self.const_(P32, TEMP, bits.into());
+            self.a.bsf(prec, dest, src);
+            self.a.move_if(Condition::Z, prec, dest, TEMP);
+        }
+    }
+
+    /// Assembles code to extract `bits` bits of `src` starting at bit
+    /// `shift` into `dest`. Uses `BEXTR` if BMI1 is available.
+    fn extract(&mut self, prec: Precision, dest: impl Into<Register>, src: impl Into<Value>, shift: u8, bits: u8) {
+        let dest = dest.into();
+        let src = self.src_to_register(src, dest);
+        if self.features.bmi1 {
+            self.const_(P32, TEMP, (i64::from(bits) << 8) | i64::from(shift));
+            self.a.bextr(prec, dest, src, TEMP);
+        } else {
+            self.move_(dest, src);
+            let top = shift as usize + bits as usize;
+            if bits > 32 && top < prec.bits() {
+                // The mask does not fit in 32 bits. Use two shifts.
+                self.a.const_shift(Shl, prec, dest, (prec.bits() - top) as u8);
+                self.a.const_shift(Shr, prec, dest, (prec.bits() - bits as usize) as u8);
+            } else {
+                if shift > 0 {
+                    self.a.const_shift(Shr, prec, dest, shift);
+                }
+                // `move_()` does not zero-extend, but a 32-bit `SHR` does.
+                if top < prec.bits() || (prec == P32 && shift == 0) {
+                    if bits == 32 {
+                        self.a.move_(P32, dest, dest);
+                    } else {
+                        self.const_op(And, prec, dest, bits::mask(bits.into()) as i32);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Assemble code to count the set bits of `src`.
+    fn count_ones(&mut self, prec: Precision, dest: impl Into<Register>, src: impl Into<Value>) {
+        let dest = dest.into();
+        let src = self.src_to_register(src, dest);
+        if self.features.popcnt {
+            self.a.popcnt(prec, dest, src);
+        } else {
+            // Add adjacent bit-fields in parallel, doubling their width each
+            // time. `TEMP` holds the even fields, and `dest` the odd ones.
+            self.move_(dest, src);
+            for (shift, mask) in [(1, 0x5555_5555_5555_5555), (2, 0x3333_3333_3333_3333)] {
+                self.const_(prec, TEMP, mask);
+                self.a.op(And, prec, TEMP, dest);
+                self.a.op(Xor, prec, dest, TEMP);
+                self.a.const_shift(Shr, prec, dest, shift);
+                self.a.op(Add, prec, dest, TEMP);
+            }
+            // Nybbles cannot overflow, so add before masking.
+            self.a.move_(prec, TEMP, dest);
+            self.a.const_shift(Shr, prec, TEMP, 4);
+            self.a.op(Add, prec, dest, TEMP);
+            self.const_(prec, TEMP, 0x0F0F_0F0F_0F0F_0F0F);
+            self.a.op(And, prec, dest, TEMP);
+            // Sum the bytes into the least significant byte.
+            let mut shift = 8;
+            while shift < prec.bits() {
+                self.a.move_(prec, TEMP, dest);
+                self.a.const_shift(Shr, prec, TEMP, shift as u8);
+                self.a.op(Add, prec, dest, TEMP);
+                shift *= 2;
+            }
+            self.const_op(And, prec, dest, 0x7F);
+        }
+    }
+
+    /// Assemble code to call `function` with arguments `src1` and `src2`,
+    /// putting the result in `dest` and preserving all other registers.
+    fn call(&mut self, dest: code::Register, function: NativeFunction, src1: Variable, src2: Variable) {
+        // Save the caller-saved registers, keeping `RSP` 16-byte aligned.
+        // While they are saved, treat them as extra `Slot`s, so that
+        // `CALLER_SAVES[i]` is in `Slot(first + i)`.
+        let pad = CALLER_SAVES.len() & 1;
+        if pad != 0 { self.a.const_op(BinaryOp::Sub, P64, RSP, 8); }
+        let first = self.slots_used + pad;
+        for &r in &CALLER_SAVES { self.a.push(r); }
+        self.slots_used = first + CALLER_SAVES.len();
+        let saved = |v: Value| match v {
+            Value::Register(r) => CALLER_SAVES.iter().position(|&s| s == r)
+                .map_or(v, |i| Slot(first + i).into()),
+            Value::Slot(_) => v,
+        };
+        // Read the arguments from the saved copies, which are not overwritten
+        // by passing the other arguments.
+        for (&arg, src) in ARGUMENTS.iter().zip([src1, src2]) {
+            let src = self.src_to_register(saved(src.into()), arg);
+            self.move_(arg, src);
+        }
+        self.a.const_address(RA, function.address());
+        self.a.call(RA);
+        // If `dest` was saved, overwrite the saved copy.
+        match saved(dest.into()) {
+            Value::Register(dest) => self.move_(dest, RESULTS[0]),
+            Value::Slot(slot) => self.a.store(P64, self.slot_address(slot), RESULTS[0]),
+        }
+        for &r in CALLER_SAVES.iter().rev() { self.a.pop(r); }
+        if pad != 0 { self.a.const_op(BinaryOp::Add, P64, RSP, 8); }
+        self.slots_used = first - pad;
+    }
+
+    /// Assemble code to atomically compare the value at `addr` to `expected`
+    /// and if they are equal replace it with `new`. The old value is put in
+    /// `dest`. All other registers are preserved.
+    fn atomic_cas(&mut self, dest: code::Register, expected: Variable, new: Variable, addr: code::Address) {
+        // `CMPXCHG` needs `expected` in `RA`. Save `RA` and `RC`, so that we
+        // can use them. While they are saved, treat them as extra `Slot`s,
+        // and read the operands from the saved copies.
+        let first = self.slots_used;
+        self.a.push(RA);
+        self.a.push(RC);
+        self.slots_used = first + 2;
+        let saved = |v: Value| match v {
+            Value::Register(RA) => Slot(first).into(),
+            Value::Register(RC) => Slot(first + 1).into(),
+            v => v,
+        };
+        let base = self.src_to_register(saved(addr.base.into()), TEMP);
+        let new = self.src_to_register(saved(new.into()), RC);
+        let expected = self.src_to_register(saved(expected.into()), RA);
+        self.move_(RA, expected);
+        let width = addr.width.into();
+        self.a.lock_cmpxchg(width, (base, addr.offset), new);
+        self.a.move_narrow(P64, width, TEMP, RA);
+        self.a.pop(RC);
+        self.a.pop(RA);
+        self.slots_used = first;
+        self.move_(dest, TEMP);
+    }
+
+    /// Select how to assemble a conditional `BinaryOp` such as `Lt` or `Max`.
+    fn compare_binary(
+        &mut self,
+        prec: Precision,
+        dest: impl Into<Register>,
+        src1: impl Into<Value>,
+        src2: impl Into<Value>,
+        callback: impl FnOnce(&mut Self, Register, Register),
+    ) {
+        let dest = dest.into();
+        let src1 = self.src_to_register(src1, TEMP);
+        let src2 = src2.into();
+        self.value_op(Cmp, prec, src1, src2);
+        callback(self, dest, src1);
+    }
+
+    /// Assembles code to move `src1` to `dest` if `cond` is non-zero,
+    /// otherwise `src2`.
+    fn select(
+        &mut self,
+        prec: Precision,
+        dest: impl Into<Register>,
+        cond: impl Into<Value>,
+        src1: impl Into<Value>,
+        src2: impl Into<Value>,
+    ) {
+        let dest = dest.into();
+        let (src1, src2) = (src1.into(), src2.into());
+        let cond = self.src_to_register(cond, TEMP);
+        self.const_op(Cmp, prec, cond, 0);
+        if Value::Register(dest) == src2 {
+            self.value_move_if(Condition::NZ, prec, dest, src1);
+        } else {
+            // Neither `move_()` nor `load()` affects the flags.
+            let src1 = self.src_to_register(src1, dest);
+            self.move_(dest, src1);
+            self.value_move_if(Condition::Z, prec, dest, src2);
+        }
+    }
+
+    /// Assemble code to perform the given `unary_op`.
+    fn unary_op(
+        &mut self,
+        unary_op: code::UnaryOp,
+        prec: Precision,
+        dest: code::Register,
+        src: code::Variable,
+    ) {
+        match unary_op {
+            code::UnaryOp::Abs => {
+                let src = self.move_away_from(src, dest);
+                self.const_(prec, dest, 0);
+                self.value_op(Sub, prec, dest, src);
+                self.value_move_if(Condition::L, prec, dest, src);
+            },
+            code::UnaryOp::Negate => {
+                let src = self.move_away_from(src, dest);
+                self.const_(prec, dest, 0);
+                self.value_op(Sub, prec, dest, src);
+            },
+            code::UnaryOp::Not => {
+                let src = self.src_to_register(src, dest);
+                self.move_(dest, src);
+                self.const_op(Xor, prec, dest, -1);
+            },
+            code::UnaryOp::Uxt(width) => {
+                self.value_move_narrow(prec, width.into(), dest, src);
+            },
+            code::UnaryOp::Sxt(width) => {
+                self.value_move_narrow(prec, signed(width), dest, src);
+            },
+            code::UnaryOp::Clz => {
+                self.count_leading_zeros(prec, dest, src);
+            },
+            code::UnaryOp::Ctz => {
+                self.count_trailing_zeros(prec, dest, src);
+            },
+            code::UnaryOp::Popcount => {
+                self.count_ones(prec, dest, src);
+            },
+            code::UnaryOp::Extract(shift, bits) => {
+                self.extract(prec, dest, src, shift, bits);
+            },
+        };
+    }
+
+    /// Assemble code to perform the given `binary_op`.
+    fn binary_op(
+        &mut self,
+        binary_op: code::BinaryOp,
+        prec: Precision,
+        dest: code::Register,
+        src1: code::Variable,
+        src2: code::Variable,
+    ) {
+        match binary_op {
+            code::BinaryOp::Add => {
+                self.symmetric_binary(dest, src1, src2, |l, dest, src| {
+                    l.value_op(Add, prec, dest, src);
+                });
+            },
+            code::BinaryOp::Sub => {
+                self.asymmetric_binary(dest, src1, src2, |l, dest, src| {
+                    l.value_op(Sub, prec, dest, src);
+                });
+            },
+            code::BinaryOp::Mul => {
+                self.symmetric_binary(dest, src1, src2, |l, dest, src| {
+                    match src {
+                        Value::Register(src) => {
+                            l.a.mul(prec, dest, src);
+                        },
+                        Value::Slot(slot) => {
+                            l.a.load_mul(prec, dest, l.slot_address(slot));
+                        },
+                    }
+                });
+            },
+            code::BinaryOp::MulHigh => {
+                self.mul_high(prec, dest, src1, src2, true);
+            },
+            code::BinaryOp::UMulHigh => {
+                self.mul_high(prec, dest, src1, src2, false);
+            },
+            code::BinaryOp::UDiv => {
+                self.div(prec, dest, src1, src2, false, false);
+            },
+            code::BinaryOp::SDiv => {
+                self.div(prec, dest, src1, src2, true, false);
+            },
+            code::BinaryOp::URem => {
+                self.div(prec, dest, src1, src2, false, true);
+            },
+            code::BinaryOp::SRem => {
+                self.div(prec, dest, src1, src2, true, true);
+            },
+            // TODO: Define what happens when you shift too far.
+            code::BinaryOp::Lsl => {
+                self.shift_binary(Shl, prec, dest, src1, src2);
+            },
+            code::BinaryOp::Lsr => {
+                self.shift_binary(Shr, prec, dest, src1, src2);
+            },
+            code::BinaryOp::Asr => {
+                self.shift_binary(Sar, prec, dest, src1, src2);
+            },
+            code::BinaryOp::RotL => {
+                self.shift_binary(Rol, prec, dest, src1, src2);
+            },
+            code::BinaryOp::RotR => {
+                self.shift_binary(Ror, prec, dest, src1, src2);
+            },
+            code::BinaryOp::And => {
+                self.symmetric_binary(dest, src1, src2, |l, dest, src| {
+                    l.value_op(And, prec, dest, src);
+                });
+            },
+            code::BinaryOp::Or => {
+                self.symmetric_binary(dest, src1, src2, |l, dest, src| {
+                    l.value_op(Or, prec, dest, src);
+                });
+            },
+            code::BinaryOp::Xor => {
+                self.symmetric_binary(dest, src1, src2, |l, dest, src| {
+                    l.value_op(Xor, prec, dest, src);
+                });
+            },
+            code::BinaryOp::Lt => {
+                self.compare_binary(prec, dest, src1, src2, |l, dest, _| {
+                    l.a.const_preserving_flags(prec, dest, -1);
+                    l.a.load_pc_relative_if(Condition::GE, prec, dest, ZERO_ADDRESS);
+                });
+            },
+            code::BinaryOp::Ult => {
+                self.compare_binary(prec, dest, src1, src2, |l, dest, _| {
+                    l.a.const_preserving_flags(prec, dest, -1);
+                    l.a.load_pc_relative_if(Condition::AE, prec, dest, ZERO_ADDRESS);
+                });
+            },
+            code::BinaryOp::Eq => {
+                self.compare_binary(prec, dest, src1, src2, |l, dest, _| {
+                    l.a.const_preserving_flags(prec, dest, -1);
+                    l.a.load_pc_relative_if(Condition::NZ, prec, dest, ZERO_ADDRESS);
+                });
+            },
+            code::BinaryOp::Max => {
+                self.compare_binary(prec, dest, src1, src2, |l, dest, src1| {
+                    if Value::Register(dest) == src2.into() {
+                        l.value_move_if(Condition::GE, prec, dest, src1);
+                    } else {
+                        l.move_(dest, src1);
+                        l.value_move_if(Condition::L, prec, dest, src2);
+                    }
+                });
+            },
+            code::BinaryOp::Min => {
+                self.compare_binary(prec, dest, src1, src2, |l, dest, src1| {
+                    if Value::Register(dest) == src2.into() {
+                        l.value_move_if(Condition::LE, prec, dest, src1);
+                    } else {
+                        l.move_(dest, src1);
+                        l.value_move_if(Condition::G, prec, dest, src2);
+                    }
+                });
+            },
+            code::BinaryOp::UMax => {
+                self.compare_binary(prec, dest, src1, src2, |l, dest, src1| {
+                    if Value::Register(dest) == src2.into() {
+                        l.value_move_if(Condition::AE, prec, dest, src1);
+                    } else {
+                        l.move_(dest, src1);
+                        l.value_move_if(Condition::B, prec, dest, src2);
+                    }
+                });
+            },
+            code::BinaryOp::UMin => {
+                self.compare_binary(prec, dest, src1, src2, |l, dest, src1| {
+                    if Value::Register(dest) == src2.into() {
+                        l.value_move_if(Condition::BE, prec, dest, src1);
+                    } else {
+                        l.move_(dest, src1);
+                        l.value_move_if(Condition::A, prec, dest, src2);
+                    }
+                });
+            },
+        };
+    }
+
+    /// Try to assemble `first` and `second` together, and return `true` if
+    /// successful. The recognised pairs are:
+    ///  - `Constant` into a register `r`, followed by an `Add`, `Sub`, `And`,
+    ///    `Or` or `Xor` that reads `r` once and overwrites it. The constant
+    ///    becomes an immediate operand, provided it fits in 32 bits.
+    ///  - `Not` into a register `r`, followed by an `And` of the same
+    ///    precision that reads `r` once and overwrites it. The pair becomes
+    ///    `and_not()`.
+    fn fuse(&mut self, first: Action, second: Action) -> bool {
+        if let (
+            Action::Constant(const_prec, r, value),
+            Action::Binary(op, prec, dest, src1, src2),
+        ) = (first, second) {
+            let op = match op {
+                code::BinaryOp::Add => Add,
+                code::BinaryOp::Sub => Sub,
+                code::BinaryOp::And => And,
+                code::BinaryOp::Or => Or,
+                code::BinaryOp::Xor => Xor,
+                _ => return false,
+            };
+            if dest != r { return false; }
+            let other = if src2 == r.into() {
+                src1
+            } else if src1 == r.into() && op != Sub {
+                src2
+            } else {
+                return false;
+            };
+            if other == r.into() { return false; }
+            let value = match const_prec {
+                P32 => value as u32 as i64,
+                P64 => value,
+            };
+            let imm = match prec {
+                P32 => value as i32,
+                P64 => match i32::try_from(value) {
+                    Ok(imm) => imm,
+                    Err(_) => return false,
+                },
+            };
+            let other = self.src_to_register(other, dest);
+            self.move_(dest, other);
+            self.const_op(op, prec, dest, imm);
+            return true;
+        }
+        if let (
+            Action::Constant(const_prec, r, value),
+            Action::Load(dest, addr),
+        ) = (first, second) {
+            if dest != r || addr.base != r.into() { return false; }
+            let value = match const_prec {
+                P32 => value as u32 as i64,
+                P64 => value,
+            };
+            let address = match value.checked_add(addr.offset.into()).map(i32::try_from) {
+                Some(Ok(address)) => address,
+                _ => return false,
+            };
+            self.a.load_narrow_absolute(P64, addr.width.into(), dest.into(), address);
+            return true;
+        }
+        if let (
+            Action::Unary(code::UnaryOp::Not, not_prec, r, src),
+            Action::Binary(code::BinaryOp::And, prec, dest, src1, src2),
+        ) = (first, second) {
+            if dest != r || not_prec != prec { return false; }
+            let other = if src1 == r.into() { src2 } else if src2 == r.into() { src1 } else { return false; };
+            if other == r.into() { return false; }
+            self.and_not(prec, dest, src, other);
+            return true;
+        }
+        false
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+impl<B: Buffer> Lower for Lowerer<B> {
+    fn slots_used_mut(&mut self) -> &mut usize { &mut self.slots_used }
+
+    fn here(&self) -> Label { Label::new(Some(self.a.get_pos())) }
+
+    fn code(&self) -> &[u8] { self.a.code() }
+
+    fn relocations(&self) -> &[usize] { self.a.relocations() }
+
+    #[cfg(feature = "disassemble")]
+    fn disassemble(&self, start: usize, end: usize) -> Vec<(usize, String)> {
+        use iced_x86::{Decoder, DecoderOptions, Formatter, NasmFormatter};
+        let mut decoder = Decoder::with_ip(64, &self.a.code()[start..end], start as u64, DecoderOptions::NONE);
+        let mut formatter = NasmFormatter::new();
+        decoder.iter().map(|instruction| {
+            let mut assembly = String::with_capacity(80);
+            formatter.format(&instruction, &mut assembly);
+            (instruction.ip() as usize, assembly)
+        }).collect()
+    }
+
+    fn patch(&mut self, patch: Patch, old_target: Option<usize>, new_target: Option<usize>) {
+        self.a.patch(patch, old_target, new_target);
+    }
+
+    fn jump(&mut self, label: &mut Label) {
+        self.const_jump(label);
+    }
+
+    fn prologue(&mut self) {
+        if CALLEE_SAVES.len() & 1 != 1 {
+            // Adjust alignment of RSP to be 16-byte aligned.
+            self.a.push(CALLEE_SAVES[0]);
+        }
+        for &r in CALLEE_SAVES.iter().rev() {
+            self.a.push(r);
+        }
+        self.move_(GLOBAL, ARGUMENTS[0]);
+    }
+
+    fn epilogue(&mut self) {
+        self.move_(RESULTS[0], RESULT);
+        for &r in &CALLEE_SAVES {
+            self.a.pop(r);
+        }
+        if CALLEE_SAVES.len() & 1 != 1 {
+            // Adjust alignment of RSP to be 16-byte aligned.
+            self.a.pop(CALLEE_SAVES[0]);
+        }
+        self.a.ret();
+    }
+
+    fn count(&mut self, counter: &AtomicU64) {
+        self.a.const_address(TEMP, counter as *const AtomicU64 as usize);
+        self.a.increment(P64, (TEMP, 0));
+    }
+
+    fn if_ne(
+        &mut self,
+        guard: (Variable, u64),
+        ne_label: &mut Label,
+    ) {
+        let (discriminant, value) = guard;
+        self.const_(P64, TEMP, value as i64);
+        self.value_op(Cmp, P64, TEMP, discriminant);
+        self.jump_if(Condition::NZ, ne_label);
+    }
+
+    fn if_eq(
+        &mut self,
+        guard: (Variable, u64),
+        eq_label: &mut Label,
+    ) {
+        let (discriminant, value) = guard;
+        self.const_(P64, TEMP, value as i64);
+        self.value_op(Cmp, P64, TEMP, discriminant);
+        self.jump_if(Condition::Z, eq_label);
+    }
+
+    fn jump_table(
+        &mut self,
+        discriminant: Variable,
+        cases: &mut [Label],
+        default_: &mut Label,
+    ) {
+        if cases.len() < MIN_JUMP_TABLE {
+            for (index, label) in cases.iter_mut().enumerate() {
+                self.if_eq((discriminant, index as u64), label);
+            }
+            self.const_jump(default_);
+            return;
+        }
+        let d = self.src_to_register(discriminant, TEMP);
+        self.move_(TEMP, d);
+        self.const_op(Cmp, P64, TEMP, cases.len() as i32);
+        self.jump_if(Condition::AE, default_);
+        self.a.const_mul(P64, TEMP, TEMP, JUMP_SIZE as i32);
+        // Compute the address of the table in `TEMP`.
+        self.a.push(RA);
+        let table = self.a.get_pos() + DISPATCH_SIZE;
+        self.a.lea_pc_relative(RA, table);
+        self.a.op(Add, P64, TEMP, RA);
+        self.a.pop(RA);
+        self.a.jump(TEMP);
+        assert_eq!(self.a.get_pos(), table);
+        // Assemble the table.
+        for label in cases.iter_mut() {
+            self.const_jump(label);
+        }
+        assert_eq!(self.a.get_pos(), table + JUMP_SIZE * cases.len());
+    }
+
+    fn action(
+        &mut self,
+        action: Action,
+    ) {
+        match action {
+            Action::Move(dest, src) => {
+                match dest {
+                    code::Variable::Register(dest) => {
+                        let src = self.src_to_register(src, dest);
+                        self.move_(dest, src);
+                    },
+                    code::Variable::Slot(slot) => {
+                        let src = self.src_to_register(src, TEMP);
+                        self.a.store(P64, self.slot_address(slot), src);
+                    },
+                }
+            },
+            Action::Constant(prec, dest, value) => {
+                self.const_(prec, dest, value);
+            },
+            Action::Unary(op, prec, dest, src) => {
+                self.unary_op(op, prec, dest, src);
+            },
+            Action::Binary(op, prec, dest, src1, src2) => {
+                self.binary_op(op, prec, dest, src1, src2);
+            },
+            Action::Select(prec, dest, cond, src1, src2) => {
+                self.select(prec, dest, cond, src1, src2);
+            },
+            Action::Load(dest, addr) => {
+                let dest = dest.into();
+                let base = self.src_to_register(addr.base, dest);
+                let width = addr.width.into();
+                self.a.load_narrow(P64, width, dest, (base, addr.offset));
+            },
+            Action::Store(dest, src, addr) => {
+                let dest = Register::from(dest);
+                let src = self.src_to_register(src, TEMP);
+                let temp = if dest == src { TEMP } else { dest };
+                let base = self.src_to_register(addr.base, temp);
+                let width = addr.width.into();
+                self.a.store_narrow(width, (base, addr.offset), src);
+                self.move_(dest, base);
+            },
+            Action::Send(dest, src1, _) => {
+                let src1 = self.src_to_register(src1, dest);
+                self.move_(dest, src1);
+            },
+            Action::Push(src1, src2) => {
+                match (src1, src2) {
+                    (Some(src1), Some(src2)) => {
+                        let src1 = self.src_to_register(src1, TEMP);
+                        let src2 = self.src_to_register(src2, TEMP);
+                        self.a.push(src2);
+                        self.a.push(src1);
+                    },
+                    (Some(src1), None) => {
+                        let src1 = self.src_to_register(src1, TEMP);
+                        self.a.const_op(BinaryOp::Sub, P64, RSP, 16);
+                        self.a.store(P64, (RSP, 0), src1);
+                    },
+                    (None, Some(src2)) => {
+                        let src2 = self.src_to_register(src2, TEMP);
+                        self.a.const_op(BinaryOp::Sub, P64, RSP, 16);
+                        self.a.store(P64, (RSP, 8), src2);
+                    },
+                    (None, None) => {
+                        self.a.const_op(BinaryOp::Sub, P64, RSP, 16);
+                    },
+                }
+                *self.slots_used_mut() += 2;
+            },
+            Action::Drop(n) => {
+                assert!(*self.slots_used_mut() >= n);
+                self.a.const_op(BinaryOp::Add, P64, RSP, (n as i32) * 16);
+                *self.slots_used_mut() -= 2 * n;
+            },
+            Action::Debug(x) => {
+                let x = self.src_to_register(x, TEMP);
+                self.a.debug(x);
+            },
+            Action::Call(dest, function, src1, src2) => {
+                self.call(dest, function, src1, src2);
+            },
+            Action::Fence(order) => {
+                self.a.fence(order);
+            },
+            Action::AtomicCas(dest, expected, new, addr) => {
+                self.atomic_cas(dest, expected, new, addr);
+            },
+            Action::SlotAddress(dest, slot) => {
+                let address = self.slot_address(slot);
+                self.a.lea(dest.into(), address);
+            },
+        };
+    }
+
+    fn actions(&mut self, actions: &[Action]) {
+        let mut i = 0;
+        while i < actions.len() {
+            if i + 1 < actions.len() && self.fuse(actions[i], actions[i + 1]) {
+                i += 2;
+            } else {
+                self.action(actions[i]);
+                i += 1;
+            }
+        }
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+impl<P: Protect> super::Execute for Lowerer<Mmap<P>> {
+    fn execute<T>(
+        &mut self,
+        label: &Label,
+        callback: impl FnOnce(super::ExecuteFn) -> T,
+    ) -> T {
+        let target = label.target().expect("Label is not defined");
+        self.a.use_buffer(|b| {
+            b.execute(|bytes| {
+                let f = unsafe { std::mem::transmute(&bytes[target]) };
+                callback(f)
+            })
+        })
+    }
+
+    fn make_executable(&mut self) {
+        self.a.use_buffer(|b| b.execute(|_| ()));
+    }
+
+    fn execute_shared<T>(
+        &self,
+        label: &Label,
+        callback: impl FnOnce(super::ExecuteFn) -> T,
+    ) -> Option<T> {
+        let target = label.target().expect("Label is not defined");
+        self.a.buffer().executable().map(|bytes| {
+            let f = unsafe { std::mem::transmute::<&u8, super::ExecuteFn>(&bytes[target]) };
+            callback(f)
+        })
+    }
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use std::mem::{size_of};
+
+    use super::*;
+    use super::super::assembler::tests::{disassemble};
+    use super::super::Condition::Z;
+    use super::super::{Execute};
+
+    const LABEL: usize = 0x02461357;
+
+    #[test]
+    fn allocatable_regs() {
+        for &r in &ALLOCATABLE_REGISTERS {
+            assert_ne!(r, TEMP);
+        }
+    }
+
+    /// Test that `Slot`s are packed at 8-byte intervals from `RSP`.
+    #[test]
+    fn slot_address() {
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        lo.slots_used = 4;
+        for i in 0..4 {
+            assert_eq!(lo.slot_address(Slot(i)), (RSP, (24 - 8 * i) as i32));
+        }
+    }
+
+    /// Test that a `Slot` that is not in use is rejected.
+    #[test]
+    #[should_panic(expected = "Slot(4) out of bounds (slots_used=4)")]
+    fn slot_address_out_of_bounds() {
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        lo.slots_used = 4;
+        let _ = lo.slot_address(Slot(4));
+    }
+
+    #[test]
+    fn slot_address_action() {
+        use code::{REGISTERS};
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        lo.slots_used = 4;
+        let start = lo.here().target().unwrap();
+        lo.action(Action::SlotAddress(REGISTERS[1], Slot(1)));
+        disassemble(&lo.a, start, vec![
+            "lea rdx,[rsp+10h]",
+        ]).unwrap();
+    }
+
+    /// Test that each combination of `Register` and `Slot` in a `Move` uses
+    /// the fewest instructions, and that slot-to-slot goes through `TEMP`.
+    #[test]
+    fn move_() {
+        use code::{REGISTERS};
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        lo.slots_used = 2;
+        let start = lo.here().target().unwrap();
+        lo.action(Action::Move(REGISTERS[1].into(), REGISTERS[2].into()));
+        lo.action(Action::Move(REGISTERS[1].into(), REGISTERS[1].into()));
+        lo.action(Action::Move(Slot(0).into(), REGISTERS[1].into()));
+        lo.action(Action::Move(REGISTERS[1].into(), Slot(1).into()));
+        lo.action(Action::Move(Slot(0).into(), Slot(1).into()));
+        disassemble(&lo.a, start, vec![
+            "mov rdx,rcx",
+            "mov [rsp+8],rdx",
+            "mov rdx,[rsp]",
+            "mov r12,[rsp]",
+            "mov [rsp+8],r12",
+        ]).unwrap();
+    }
+
+    /// Test that `Select` tests the condition once and then uses `CMOV`,
+    /// including when the operands are `Slot`s or the destination.
+    #[test]
+    fn select() {
+        use code::{REGISTERS};
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        lo.slots_used = 2;
+        let start = lo.here().target().unwrap();
+        let (r1, r2, r3) = (REGISTERS[1], REGISTERS[2], REGISTERS[3]);
+        lo.action(Action::Select(P64, r1, r2.into(), r3.into(), r1.into()));
+        lo.action(Action::Select(P32, r1, r2.into(), r1.into(), r3.into()));
+        lo.action(Action::Select(P64, r1, Slot(0).into(), Slot(1).into(), r3.into()));
+        lo.action(Action::Select(P64, r1, r2.into(), r3.into(), Slot(1).into()));
+        disassemble(&lo.a, start, vec![
+            "cmp rcx,0", "cmovne rdx,rbx",
+            "cmp ecx,0", "cmove edx,ebx",
+            "mov r12,[rsp+8]", "cmp r12,0", "mov rdx,[rsp]", "cmove rdx,rbx",
+            "cmp rcx,0", "mov rdx,rbx", "cmove rdx,[rsp]",
+        ]).unwrap();
+    }
+
+    /// Test that `extract()` uses `BEXTR` if and only if it is allowed to,
+    /// and that both versions work.
+    #[test]
+    fn extract() {
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        let start = lo.here().target().unwrap();
+        lo.extract(P32, RA, RD, 8, 8);
+        lo.extract(P32, RA, RD, 24, 8);
+        lo.extract(P64, RA, RD, 0, 32);
+        lo.extract(P64, RA, RD, 4, 40);
+        disassemble(&lo.a, start, vec![
+            "mov rax,rdx", "shr eax,8", "and eax,0FFh",
+            "mov rax,rdx", "shr eax,18h",
+            "mov rax,rdx", "mov eax,eax",
+            "mov rax,rdx", "shl rax,14h", "shr rax,18h",
+        ]).unwrap();
+        let mut lo = Lowerer::<Vec<u8>>::with_features(Features {bmi1: true, ..Features::NONE});
+        let start = lo.here().target().unwrap();
+        lo.extract(P32, RA, RD, 8, 8);
+        lo.extract(P64, RA, RA, 4, 40);
+        disassemble(&lo.a, start, vec![
+            "mov r12d,808h", "bextr eax,edx,r12d",
+            "mov r12d,2804h", "bextr rax,rax,r12",
+        ]).unwrap();
+        // Run both versions, if possible.
+        for bmi1 in [false, Features::detect().bmi1] {
+            for prec in [P32, P64] {
+                for (shift, bits) in [(0, 1), (8, 8), (24, 8), (0, 32), (32, 32), (4, 40), (1, 63)] {
+                    if shift as usize + bits as usize > prec.bits() { continue; }
+                    let mut lo = Lowerer::<Mmap>::with_features(Features {bmi1, ..Features::NONE});
+                    let entry = lo.here();
+                    lo.prologue();
+                    lo.action(Action::Unary(code::UnaryOp::Extract(shift, bits), prec, RESULT, GLOBAL.into()));
+                    lo.epilogue();
+                    for x in [0u64, 1, 0x8000_0000, 0xFFFF_FFFF, 0x0123_4567_89AB_CDEF, !0] {
+                        let expected = bits::extract(x, shift.into(), bits.into());
+                        let observed = lo.execute(&entry, |f| unsafe { f(x as *mut ()) });
+                        assert_eq!(observed, Word {u: expected});
+                    }
+                }
+            }
+        }
+    }
+
+    /// Test that `Uxt` and `Sxt` use `MOVZX` and `MOVSX`, from registers
+    /// and from `Slot`s.
+    #[test]
+    fn extend() {
+        use code::{REGISTERS, UnaryOp::*, Width::*};
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        lo.slots_used = 1;
+        let start = lo.here().target().unwrap();
+        for prec in [P32, P64] {
+            for width in [One, Two, Four] {
+                for op in [Uxt(width), Sxt(width)] {
+                    lo.action(Action::Unary(op, prec, REGISTERS[1], REGISTERS[2].into()));
+                    lo.action(Action::Unary(op, prec, REGISTERS[1], Slot(0).into()));
+                }
+            }
+        }
+        disassemble(&lo.a, start, vec![
+            "movzx edx,cl", "movzx edx,byte [rsp]",
+            "movsx edx,cl", "movsx edx,byte [rsp]",
+            "movzx edx,cx", "movzx edx,word [rsp]",
+            "movsx edx,cx", "movsx edx,word [rsp]",
+            "mov edx,ecx", "mov edx,[rsp]",
+            "movsxd edx,ecx", "movsxd edx,[rsp]",
+            "movzx rdx,cl", "movzx rdx,byte [rsp]",
+            "movsx rdx,cl", "movsx rdx,byte [rsp]",
+            "movzx rdx,cx", "movzx rdx,word [rsp]",
+            "movsx rdx,cx", "movsx rdx,word [rsp]",
+            "mov edx,ecx", "mov edx,[rsp]",
+            "movsxd rdx,ecx", "movsxd rdx,[rsp]",
+        ]).unwrap();
+    }
+
+    #[test]
+    fn jump_table() {
+        use code::{REGISTERS};
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        let start = lo.here().target().unwrap();
+        let mut cases: Vec<Label> = (0..4).map(|_| Label::new(None)).collect();
+        let mut default_ = Label::new(None);
+        lo.jump_table(REGISTERS[1].into(), &mut cases, &mut default_);
+        disassemble(&lo.a, start, vec![
+            "mov r12,rdx",
+            "cmp r12,4",
+            "jae near 0FFFFFFFF80000050h",
+            "imul r12,6",
+            "push rax",
+            "lea rax,[rel 68h]",
+            "add r12,rax",
+            "pop rax",
+            "jmp r12",
+            "jmp 0FFFFFFFF8000006Eh",
+            "jmp 0FFFFFFFF80000074h",
+            "jmp 0FFFFFFFF8000007Ah",
+            "jmp 0FFFFFFFF80000080h",
+        ]).unwrap();
+    }
+
+    /// Test that we can patch jumps and calls.
+    #[test]
+    fn steal() {
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        let start = lo.here().target().unwrap();
+        let mut label = Label::new(None);
+        lo.jump_if(Z, &mut label);
+        lo.const_jump(&mut label);
+        lo.const_call(&mut label);
+        disassemble(&lo.a, start, vec![
+            "je near 0FFFFFFFF80000046h",
+            "jmp 0FFFFFFFF8000004Ch",
+            "call 0FFFFFFFF80000052h",
+        ]).unwrap();
+        let mut new_label = Label::new(Some(LABEL));
+        lo.steal(&mut label, &mut new_label);
+        label = new_label;
+        disassemble(&lo.a, start, vec![
+            "je near 0000000002461357h",
+            "jmp 0000000002461357h",
+            "call 0000000002461357h",
+        ]).unwrap();
+        let mut new_label = Label::new(Some(LABEL));
+        lo.steal(&mut label, &mut new_label);
+        disassemble(&lo.a, start, vec![
+            "je near 0000000002461357h",
+            "jmp 0000000002461357h",
+            "call 0000000002461357h",
+        ]).unwrap();
+    }
+
+    /// Test that the compiled code still works if moved to a different
+    /// address, after applying the relocations. The relocation of the `Call`
+    /// is pointed at a different function, which changes the result.
+    #[test]
+    fn relocate() {
+        use code::{REGISTERS, BinaryOp::*, NativeFunction, debug_word};
+        use code::tests::{add};
+        extern "C" fn sub(x: u64, y: u64) -> u64 { x.wrapping_sub(y) }
+        let mut lo = Lowerer::<Mmap>::new();
+        let entry = lo.here().target().unwrap();
+        lo.prologue();
+        // `Lt` reads `CONSTANTS`, and `Debug` and `Call` call host functions.
+        lo.action(Action::Constant(P64, REGISTERS[1], 3));
+        lo.action(Action::Constant(P64, REGISTERS[2], 42));
+        lo.action(Action::Binary(Lt, P64, RESULT, REGISTERS[2].into(), REGISTERS[1].into()));
+        lo.action(Action::Debug(REGISTERS[2].into()));
+        lo.action(Action::Binary(Add, P64, RESULT, RESULT.into(), REGISTERS[2].into()));
+        lo.action(Action::Call(REGISTERS[3], NativeFunction(add), REGISTERS[2].into(), REGISTERS[1].into()));
+        lo.action(Action::Binary(Add, P64, RESULT, RESULT.into(), REGISTERS[3].into()));
+        lo.epilogue();
+        // Copy the code to a buffer with a different base address.
+        const OFFSET: usize = 0x1230;
+        let len = lo.a.get_pos();
+        let code = lo.a.use_buffer(|b| b[..len].to_vec());
+        let mut moved: Mmap = Mmap::new();
+        moved.resize(OFFSET + len);
+        moved[OFFSET..][..len].copy_from_slice(&code);
+        assert_eq!(lo.a.relocations().len(), 2);
+        // `debug_word` has not moved, but rewrite it anyway.
+        moved.write(OFFSET + lo.a.relocations()[0], debug_word as *const () as u64, 8);
+        moved.write(OFFSET + lo.a.relocations()[1], sub as *const () as u64, 8);
+        // Run it.
+        let result = moved.execute(|bytes| unsafe {
+            let f: crate::target::ExecuteFn = std::mem::transmute(&bytes[OFFSET + entry]);
+            f(std::ptr::null_mut())
+        });
+        assert_eq!(result, Word {s: 42 + (42 - 3)});
+    }
+
+    /// Test that the buffer is read-write while code is assembled and
+    /// read-execute while it runs, and that its permissions only change
+    /// when they are wrong.
+    #[test]
+    fn write_xor_execute() {
+        use buffer::tests::{Recorder, Permissions::*};
+        let mut lo = Lowerer::<Mmap<Recorder>>::new();
+        let compile = |lo: &mut Lowerer<_>, value| {
+            let entry = lo.here();
+            lo.prologue();
+            lo.action(Action::Constant(P64, RESULT, value));
+            lo.epilogue();
+            entry
+        };
+        let run = |lo: &mut Lowerer<_>, entry| lo.execute(entry, |f| unsafe { f(std::ptr::null_mut()).s });
+        let first = compile(&mut lo, 1);
+        assert_eq!(Recorder::take(), []);
+        assert_eq!(run(&mut lo, &first), 1);
+        assert_eq!(run(&mut lo, &first), 1);
+        assert_eq!(Recorder::take(), [ReadExecute]);
+        let second = compile(&mut lo, 2);
+        assert_eq!(Recorder::take(), [ReadWrite]);
+        assert_eq!(run(&mut lo, &second), 2);
+        assert_eq!(run(&mut lo, &first), 1);
+        assert_eq!(Recorder::take(), [ReadExecute]);
+    }
+
+    /// Test that `and_not()` uses `ANDN` if and only if it is allowed to.
+    #[test]
+    fn and_not() {
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        let start = lo.here().target().unwrap();
+        lo.and_not(P64, RA, RD, RC);
+        lo.and_not(P64, RA, RA, RC);
+        lo.and_not(P32, RA, RC, RA);
+        disassemble(&lo.a, start, vec![
+            "mov rax,rdx", "xor rax,0FFFFFFFFFFFFFFFFh", "and rax,rcx",
+            "xor rax,0FFFFFFFFFFFFFFFFh", "and rax,rcx",
+            "mov r12,rax", "mov rax,rcx", "xor eax,0FFFFFFFFh", "and eax,r12d",
+        ]).unwrap();
+        let mut lo = Lowerer::<Vec<u8>>::with_features(Features {bmi1: true, ..Features::NONE});
+        let start = lo.here().target().unwrap();
+        lo.and_not(P64, RA, RD, RC);
+        lo.and_not(P64, RA, RA, RC);
+        lo.and_not(P32, RA, RC, RA);
+        disassemble(&lo.a, start, vec![
+            "andn rax,rdx,rcx",
+            "andn rax,rax,rcx",
+            "andn eax,ecx,eax",
+        ]).unwrap();
+    }
+
+    /// Test that `actions()` fuses a `Not` into a following `And`, using
+    /// `ANDN` if and only if it is allowed to, and that both versions work.
+    #[test]
+    fn fuse_not_and() {
+        use code::{REGISTERS, UnaryOp::*, BinaryOp::*};
+        const R1: code::Register = REGISTERS[1];
+        const R2: code::Register = REGISTERS[2];
+        let not_and = |prec| [
+            Action::Unary(Not, prec, R1, R2.into()),
+            Action::Binary(And, prec, R1, R2.into(), R1.into()),
+        ];
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        let start = lo.here().target().unwrap();
+        lo.actions(&not_and(P64));
+        lo.actions(&not_and(P32));
+        // Not fused: the precisions differ.
+        lo.actions(&[
+            Action::Unary(Not, P32, R1, R2.into()),
+            Action::Binary(And, P64, R1, R2.into(), R1.into()),
+        ]);
+        disassemble(&lo.a, start, vec![
+            "mov rdx,rcx", "xor rdx,0FFFFFFFFFFFFFFFFh", "and rdx,rcx",
+            "mov rdx,rcx", "xor edx,0FFFFFFFFh", "and edx,ecx",
+            "mov rdx,rcx", "xor edx,0FFFFFFFFh", "and rdx,rcx",
+        ]).unwrap();
+        let mut lo = Lowerer::<Vec<u8>>::with_features(Features {bmi1: true, ..Features::NONE});
+        let start = lo.here().target().unwrap();
+        lo.actions(&not_and(P64));
+        lo.actions(&not_and(P32));
+        disassemble(&lo.a, start, vec![
+            "andn rdx,rcx,rcx",
+            "andn edx,ecx,ecx",
+        ]).unwrap();
+        // Run both versions, if possible.
+        for bmi1 in [false, Features::detect().bmi1] {
+            for prec in [P32, P64] {
+                let mut lo = Lowerer::<Mmap>::with_features(Features {bmi1, ..Features::NONE});
+                let entry = lo.here();
+                lo.prologue();
+                lo.action(Action::Constant(P64, R2, 0x0123_4567_89AB_CDEF));
+                lo.actions(&[
+                    Action::Unary(Not, prec, RESULT, GLOBAL.into()),
+                    Action::Binary(And, prec, RESULT, R2.into(), RESULT.into()),
+                ]);
+                lo.epilogue();
+                for x in [0u64, 1, 0x8000_0000, 0xFFFF_FFFF, 0x0F0F_0F0F_0F0F_0F0F, !0] {
+                    let expected = match prec {
+                        P32 => u64::from(!(x as u32) & 0x89AB_CDEF),
+                        P64 => !x & 0x0123_4567_89AB_CDEF,
+                    };
+                    let observed = lo.execute(&entry, |f| unsafe { f(x as *mut ()) });
+                    assert_eq!(observed, Word {u: expected});
+                }
+            }
+        }
+    }
+
+    /// Test that `count_leading_zeros()` uses `LZCNT` if and only if it is
+    /// allowed to, and that both versions work.
+    #[test]
+    fn count_leading_zeros() {
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        let start = lo.here().target().unwrap();
+        lo.count_leading_zeros(P32, RA, RD);
+        lo.count_leading_zeros(P64, RA, RA);
+        disassemble(&lo.a, start, vec![
+            "mov r12d,3Fh", "bsr eax,edx", "cmove eax,r12d", "xor eax,1Fh",
+            "mov r12d,7Fh", "bsr rax,rax", "cmove rax,r12", "xor rax,3Fh",
+        ]).unwrap();
+        let mut lo = Lowerer::<Vec<u8>>::with_features(Features {lzcnt: true, ..Features::NONE});
+        let start = lo.here().target().unwrap();
+        lo.count_leading_zeros(P32, RA, RD);
+        lo.count_leading_zeros(P64, RA, RA);
+        disassemble(&lo.a, start, vec![
+            "lzcnt eax,edx",
+            "lzcnt rax,rax",
+        ]).unwrap();
+        // Run both versions, if possible.
+        for lzcnt in [false, Features::detect().lzcnt] {
+            for prec in [P32, P64] {
+                let mut lo = Lowerer::<Mmap>::with_features(Features {lzcnt, ..Features::NONE});
+                let entry = lo.here();
+                lo.prologue();
+                lo.action(Action::Unary(code::UnaryOp::Clz, prec, RESULT, GLOBAL.into()));
+                lo.epilogue();
+                for x in [0u64, 1, 0x8000_0000, 0xFFFF_FFFF, 0x1_0000_0000, !0] {
+                    let expected = match prec {
+                        P32 => (x as u32).leading_zeros(),
+                        P64 => x.leading_zeros(),
+                    };
+                    let observed = lo.execute(&entry, |f| unsafe { f(x as *mut ()) });
+                    assert_eq!(observed, Word {u: expected.into()});
+                }
+            }
+        }
+    }
+
+    /// Test that `count_trailing_zeros()` uses `TZCNT` if and only if it is
+    /// allowed to, and that both versions work.
+    #[test]
+    fn count_trailing_zeros() {
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        let start = lo.here().target().unwrap();
+        lo.count_trailing_zeros(P32, RA, RD);
+        lo.count_trailing_zeros(P64, RA, RA);
+        disassemble(&lo.a, start, vec![
+            "mov r12d,20h", "bsf eax,edx", "cmove eax,r12d",
+            "mov r12d,40h", "bsf rax,rax", "cmove rax,r12",
+        ]).unwrap();
+        let mut lo = Lowerer::<Vec<u8>>::with_features(Features {bmi1: true, ..Features::NONE});
+        let start = lo.here().target().unwrap();
+        lo.count_trailing_zeros(P32, RA, RD);
+        lo.count_trailing_zeros(P64, RA, RA);
+        disassemble(&lo.a, start, vec![
+            "tzcnt eax,edx",
+            "tzcnt rax,rax",
+        ]).unwrap();
+        // Run both versions, if possible.
+        for bmi1 in [false, Features::detect().bmi1] {
+            for prec in [P32, P64] {
+                let mut lo = Lowerer::<Mmap>::with_features(Features {bmi1, ..Features::NONE});
+                let entry = lo.here();
+                lo.prologue();
+                lo.action(Action::Unary(code::UnaryOp::Ctz, prec, RESULT, GLOBAL.into()));
+                lo.epilogue();
+                for x in [0u64, 1, 0x8000_0000, 0xFFFF_FFFF, 0x1_0000_0000, !0] {
+                    let expected = match prec {
+                        P32 => (x as u32).trailing_zeros(),
+                        P64 => x.trailing_zeros(),
+                    };
+                    let observed = lo.execute(&entry, |f| unsafe { f(x as *mut ()) });
+                    assert_eq!(observed, Word {u: expected.into()});
+                }
+            }
+        }
+    }
+
+    /// Test that `count_ones()` uses `POPCNT` if and only if it is allowed
+    /// to, and that both versions work.
+    #[test]
+    fn count_ones() {
+        let mut lo = Lowerer::<Vec<u8>>::with_features(Features {popcnt: true, ..Features::NONE});
+        let start = lo.here().target().unwrap();
+        lo.count_ones(P32, RA, RD);
+        lo.count_ones(P64, RA, RA);
+        disassemble(&lo.a, start, vec![
+            "popcnt eax,edx",
+            "popcnt rax,rax",
+        ]).unwrap();
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        let start = lo.here().target().unwrap();
+        lo.count_ones(P32, RA, RD);
+        disassemble(&lo.a, start, vec![
+            "mov rax,rdx",
+            "mov r12d,55555555h", "and r12d,eax", "xor eax,r12d", "shr eax,1", "add eax,r12d",
+            "mov r12d,33333333h", "and r12d,eax", "xor eax,r12d", "shr eax,2", "add eax,r12d",
+            "mov r12d,eax", "shr r12d,4", "add eax,r12d",
+            "mov r12d,0F0F0F0Fh", "and eax,r12d",
+            "mov r12d,eax", "shr r12d,8", "add eax,r12d",
+            "mov r12d,eax", "shr r12d,10h", "add eax,r12d",
+            "and eax,7Fh",
+        ]).unwrap();
+        // Run both versions, if possible.
+        for popcnt in [false, Features::detect().popcnt] {
+            for prec in [P32, P64] {
+                let mut lo = Lowerer::<Mmap>::with_features(Features {popcnt, ..Features::NONE});
+                let entry = lo.here();
+                lo.prologue();
+                lo.action(Action::Unary(code::UnaryOp::Popcount, prec, RESULT, GLOBAL.into()));
+                lo.epilogue();
+                for x in [0u64, 1, 0x8000_0000, 0xFFFF_FFFF, 0x1_0000_0000, 0x1234_5678_9ABC_DEF0, !0] {
+                    let expected = match prec {
+                        P32 => (x as u32).count_ones(),
+                        P64 => x.count_ones(),
+                    };
+                    let observed = lo.execute(&entry, |f| unsafe { f(x as *mut ()) });
+                    assert_eq!(observed, Word {u: expected.into()});
+                }
+            }
+        }
+    }
+
+    /// Test that `actions()` fuses a `Constant` into a following `Binary`
+    /// when it can, and otherwise lowers each `Action` separately.
+    #[test]
+    fn fuse() {
+        use code::{REGISTERS, BinaryOp::*};
+        const R1: code::Register = REGISTERS[1];
+        const R2: code::Register = REGISTERS[2];
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        let start = lo.here().target().unwrap();
+        lo.actions(&[
+            Action::Constant(P64, R1, 7),
+            Action::Binary(Add, P64, R1, R1.into(), R2.into()),
+            Action::Constant(P64, R1, -1),
+            Action::Binary(Sub, P32, R1, R2.into(), R1.into()),
+            // The optimizer turns `x - 4` into this.
+            Action::Constant(P64, R1, -4),
+            Action::Binary(Add, P64, R1, R2.into(), R1.into()),
+            // Not fused: the constant does not fit in 32 bits.
+            Action::Constant(P64, R1, 0x123456789),
+            Action::Binary(Xor, P64, R1, R2.into(), R1.into()),
+            // Not fused: `Sub` is asymmetric.
+            Action::Constant(P64, R1, 7),
+            Action::Binary(Sub, P64, R1, R1.into(), R2.into()),
+            Action::Constant(P64, R1, 0x1000),
+            Action::Load(R1, code::Address {base: R1.into(), offset: 8, width: code::Width::Four}),
+            // Not fused: the address does not fit in 32 bits.
+            Action::Constant(P64, R1, 0x123456789),
+            Action::Load(R1, code::Address {base: R1.into(), offset: 0, width: code::Width::One}),
+            // Not fused: the constant is used again.
+            Action::Constant(P64, R1, 0x1000),
+            Action::Load(R2, code::Address {base: R1.into(), offset: 0, width: code::Width::One}),
+        ]);
+        disassemble(&lo.a, start, vec![
+            "mov rdx,rcx", "add rdx,7",
+            "mov rdx,rcx", "sub edx,0FFFFFFFFh",
+            "mov rdx,rcx", "add rdx,0FFFFFFFFFFFFFFFCh",
+            "mov rdx,123456789h", "xor rdx,rcx",
+            "mov edx,7", "sub rdx,rcx",
+            "mov edx,[1008h]",
+            "mov rdx,123456789h", "movzx rdx,byte [rdx]",
+            "mov edx,1000h", "movzx rcx,byte [rdx]",
+        ]).unwrap();
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(CONSTANTS[ZERO_ADDRESS / size_of::<Word>()], Word {u: 0});
+    }
+
+    #[test]
+    fn shift_binary() {
+        let mut lo = Lowerer::<Vec<u8>>::new();
+        let start = lo.here().target().unwrap();
+        for (dest, src1, src2) in [
+            (RA, RA, RA),
+            (RA, RA, RD),
+            (RA, RA, RC),
+            (RA, RC, RD),
+            (RA, RC, RC),
+
+            (RD, RA, RA),
+            (RD, RA, RD),
+            (RD, RA, RC),
+            (RD, RC, RD),
+            (RD, RC, RC),
+
+            (RC, RA, RA),
+            (RC, RA, RD),
+            (RC, RA, RC),
+            (RC, RC, RD),
+            (RC, RC, RC),
+        ] {
+            lo.shift_binary(Shl, P32, dest, src1, src2);
+        }
+        disassemble(&lo.a, start, vec![
+            "mov r12,rcx", "mov rcx,rax", "shl eax,cl", "mov rcx,r12",
+            "mov r12,rcx", "mov rcx,rdx", "shl eax,cl", "mov rcx,r12",
+            "shl eax,cl",
+            "mov r12,rcx", "mov rcx,rdx", "mov rax,r12", "shl eax,cl", "mov rcx,r12",
+            "mov rax,rcx", "shl eax,cl",
+
+            "mov r12,rcx", "mov rcx,rax", "mov rdx,rax", "shl edx,cl", "mov rcx,r12",
+            "mov r12,rcx", "mov rcx,rdx", "mov rdx,rax", "shl edx,cl", "mov rcx,r12",
+            "mov rdx,rax", "shl edx,cl",
+            "mov r12,rcx", "mov rcx,rdx", "mov rdx,r12", "shl edx,cl", "mov rcx,r12",
+            "mov rdx,rcx", "shl edx,cl",
+
+            "mov rcx,rax", "mov r12,rax", "shl r12d,cl", "mov rcx,r12",
+            "mov rcx,rdx", "mov r12,rax", "shl r12d,cl", "mov rcx,r12",
+            "mov r12,rax", "shl r12d,cl", "mov rcx,r12",
+            "mov r12,rcx", "mov rcx,rdx", "shl r12d,cl", "mov rcx,r12",
+            "shl ecx,cl",
+        ]).unwrap();
+    }
+}
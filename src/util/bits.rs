@@ -0,0 +1,56 @@
+/// Returns a word whose low `bits` bits are set. `bits` may be `64`.
+pub fn mask(bits: u32) -> u64 {
+    assert!(bits <= 64);
+    if bits == 0 { 0 } else { !0 >> (64 - bits) }
+}
+
+/// If `x` is [`mask`]`(n)` for some `n`, returns `n`.
+pub fn mask_width(x: u64) -> Option<u32> {
+    if x & x.wrapping_add(1) == 0 { Some(x.trailing_ones()) } else { None }
+}
+
+/// Returns the bit-field of `x` that is `bits` bits wide and starts at bit
+/// `shift`. `shift` must be less than `64`.
+pub fn extract(x: u64, shift: u32, bits: u32) -> u64 {
+    (x >> shift) & mask(bits)
+}
+
+//-----------------------------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_() {
+        assert_eq!(mask(0), 0);
+        assert_eq!(mask(1), 1);
+        assert_eq!(mask(31), 0x7FFF_FFFF);
+        assert_eq!(mask(32), 0xFFFF_FFFF);
+        assert_eq!(mask(63), 0x7FFF_FFFF_FFFF_FFFF);
+        assert_eq!(mask(64), !0);
+    }
+
+    #[test]
+    fn mask_width_() {
+        for n in 0..=64 {
+            assert_eq!(mask_width(mask(n)), Some(n));
+        }
+        assert_eq!(mask_width(2), None);
+        assert_eq!(mask_width(0xF0), None);
+        assert_eq!(mask_width(0x8000_0000_0000_0000), None);
+        assert_eq!(mask_width(!1), None);
+    }
+
+    #[test]
+    fn extract_() {
+        let x = 0x0123_4567_89AB_CDEF;
+        assert_eq!(extract(x, 0, 0), 0);
+        assert_eq!(extract(x, 0, 64), x);
+        assert_eq!(extract(x, 4, 8), 0xDE);
+        assert_eq!(extract(x, 60, 4), 0x0);
+        assert_eq!(extract(x, 60, 8), 0x0);
+        assert_eq!(extract(!0, 63, 1), 1);
+        assert_eq!(extract(!0, 32, 64), 0xFFFF_FFFF);
+    }
+}
@@ -11,5 +11,7 @@ pub use comma_separated::{CommaSeparated};
 mod rotate;
 pub use rotate::{rotate_left, rotate_right};
 
+pub mod bits;
+
 mod usage;
 pub use usage::{Usage};